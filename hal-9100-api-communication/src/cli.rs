@@ -1,9 +1,17 @@
 use clap::{ArgAction, Parser, Subcommand};
 use dotenv::dotenv;
 use hal_9100_api_communication::{models::AppState, routes::router::app};
-use hal_9100_core::{executor::loop_through_runs, file_storage::FileStorage};
+use hal_9100_core::{
+    code_interpreter::{set_sandbox_limits, SandboxLimits},
+    encryption::{rotate_columns, set_column_keyring},
+    executor::loop_through_runs,
+    file_storage::FileStorage,
+    ingestion::loop_through_ingestion,
+    kernel_sessions::{set_kernel_sessions, KernelSessions},
+    sandbox::{set_sandbox_backend, start_backend, UnavailableSandbox},
+};
 use hal_9100_extra::{config::Hal9100Config, llm::HalLLMClient};
-use log::{error, info};
+use log::{error, info, warn};
 use sqlx::postgres::PgPoolOptions;
 use std::{net::SocketAddr, num::NonZeroU16, path::PathBuf, sync::Arc, time::Duration};
 
@@ -47,8 +55,11 @@ pub struct RootOpts {
 pub enum Commands {
     /// Starts the HTTP server
     Api,
-    /// Listens to the Redis queue
+    /// Listens to the Redis queues (runs and file ingestion)
     Executor,
+    /// Re-encrypts stored files, chunks and messages with the current encryption key.
+    /// Run it with the api and executor stopped, then drop the previous keys from the config.
+    RotateKeys,
 }
 
 impl RootOpts {
@@ -114,7 +125,15 @@ async fn main() {
     ";
 
     info!("{}", ascii_art);
-    let file_storage = FileStorage::new(config.clone()).await;
+    let file_storage = match FileStorage::try_new(config.clone()).await {
+        Ok(file_storage) => file_storage,
+        Err(e) => {
+            error!("can't set up file storage ({}): {}", config.storage, e);
+            std::process::exit(1);
+        }
+    };
+    // Chunks and messages are encrypted with the same keys as the files
+    set_column_keyring(file_storage.keyring());
     match opts.command {
         Commands::Api => {
             let app_state = AppState {
@@ -138,14 +157,66 @@ async fn main() {
             let redis_url = config.redis_url.clone();
             let client = redis::Client::open(redis_url).unwrap();
             let mut con = client.get_async_connection().await.unwrap();
+            // brpop blocks the connection, file ingestion gets its own
+            let mut ingestion_con = client.get_async_connection().await.unwrap();
 
             info!("Starting hal-9100-executor");
+            set_sandbox_limits(SandboxLimits::from_config(&config));
+            // Runs without the code interpreter still work if the sandbox can't be set up. Only
+            // docker falls back to fresh containers, code interpreter runs fail with the others.
+            match start_backend(&config).await {
+                Ok(backend) => set_sandbox_backend(Some(backend)),
+                Err(e) if config.code_interpreter_backend == "docker" => {
+                    warn!("can't start the code interpreter backend: {}", e)
+                }
+                Err(e) => {
+                    error!("can't start the code interpreter backend: {}", e);
+                    set_sandbox_backend(Some(Arc::new(UnavailableSandbox::new(
+                        &config.code_interpreter_backend,
+                        e.to_string(),
+                    ))));
+                }
+            }
+            if config.code_interpreter_sessions && config.code_interpreter_backend != "docker" {
+                warn!("code interpreter sessions need the docker backend, they are disabled");
+            } else if config.code_interpreter_sessions {
+                let ttl = Duration::from_secs(config.code_interpreter_session_ttl_secs);
+                let max_sessions = config.code_interpreter_max_sessions;
+                match KernelSessions::start(ttl, max_sessions).await {
+                    Ok(sessions) => set_kernel_sessions(Some(sessions)),
+                    Err(e) => warn!("can't start the code interpreter sessions: {}", e),
+                }
+            }
             let llm_client = HalLLMClient::new(
                 "mistralai/Mixtral-8x7B-Instruct-v0.1".to_string(),
                 config.model_url,
                 config.model_api_key.unwrap_or_default(),
             );
-            loop_through_runs(&pool, &mut con, llm_client, &file_storage).await;
+            tokio::join!(
+                loop_through_runs(&pool, &mut con, llm_client, &file_storage),
+                loop_through_ingestion(&pool, &mut ingestion_con, &file_storage),
+            );
+        }
+        Commands::RotateKeys => {
+            let keyring = match file_storage.keyring() {
+                Some(keyring) => keyring,
+                None => {
+                    error!("no encryption key is configured, set encryption_key first");
+                    std::process::exit(1);
+                }
+            };
+            info!("Rotating to encryption key {}", keyring.current_key_id());
+            match file_storage.rotate_keys().await {
+                Ok(count) => info!("Re-encrypted {} files", count),
+                Err(e) => {
+                    error!("failed to re-encrypt files: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            if let Err(e) = rotate_columns(&pool, &keyring).await {
+                error!("failed to re-encrypt chunks and messages: {}", e);
+                std::process::exit(1);
+            }
         }
     }
 }
@@ -1,6 +1,7 @@
 use async_openai::types::{
-    AssistantObject, CreateAssistantRequest, DeleteAssistantResponse, ListAssistantsResponse,
-    ModifyAssistantRequest,
+    AssistantFileObject, AssistantObject, CreateAssistantFileRequest, CreateAssistantRequest,
+    DeleteAssistantFileResponse, DeleteAssistantResponse, ListAssistantFilesResponse,
+    ListAssistantsResponse, ModifyAssistantRequest,
 };
 use axum::extract::Query;
 use axum::{
@@ -9,9 +10,13 @@ use axum::{
     response::Json as JsonResponse,
 };
 use hal_9100_api_communication::models::AppState;
+use hal_9100_api_communication::routes::files::reingest_failed_file;
 use hal_9100_core::assistants::{
-    create_assistant, delete_assistant, get_assistant, list_assistants, update_assistant, Tools,
+    attach_file_to_assistant, create_assistant, delete_assistant, detach_file_from_assistant,
+    get_assistant, list_assistants, update_assistant, Tools,
 };
+use hal_9100_core::chunking::ChunkingStrategy;
+use hal_9100_core::files::{get_file, get_files};
 use hal_9100_core::models::Assistant;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -181,6 +186,145 @@ pub async fn list_assistants_handler(
     }
 }
 
+fn not_found_or_internal(e: sqlx::Error, not_found: String) -> (StatusCode, String) {
+    match e {
+        sqlx::Error::RowNotFound => (StatusCode::NOT_FOUND, not_found),
+        e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+pub async fn create_assistant_file_handler(
+    Path((assistant_id,)): Path<(String,)>,
+    State(app_state): State<AppState>,
+    Json(request): Json<CreateAssistantFileRequest>,
+) -> Result<JsonResponse<AssistantFileObject>, (StatusCode, String)> {
+    let user_id = Uuid::default().to_string();
+    let assistant = get_assistant(&app_state.pool, &assistant_id, &user_id)
+        .await
+        .map_err(|e| not_found_or_internal(e, format!("Assistant {} not found", assistant_id)))?;
+    let file = get_file(&app_state.pool, &request.file_id, &user_id)
+        .await
+        .map_err(|e| not_found_or_internal(e, format!("File {} not found", request.file_id)))?;
+
+    attach_file_to_assistant(&app_state.pool, &assistant_id, &request.file_id, &user_id)
+        .await
+        .map_err(|e| not_found_or_internal(e, format!("Assistant {} not found", assistant_id)))?;
+
+    // A file whose ingestion failed would never be retrievable, give it another try
+    reingest_failed_file(
+        &app_state,
+        &file,
+        ChunkingStrategy::from_metadata(&assistant.inner.metadata),
+    )
+    .await?;
+
+    Ok(JsonResponse(AssistantFileObject {
+        id: file.inner.id,
+        object: "assistant.file".to_string(),
+        created_at: file.inner.created_at as i32,
+        assistant_id,
+    }))
+}
+
+pub async fn get_assistant_file_handler(
+    Path((assistant_id, file_id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<AssistantFileObject>, (StatusCode, String)> {
+    let user_id = Uuid::default().to_string();
+    let assistant = get_assistant(&app_state.pool, &assistant_id, &user_id)
+        .await
+        .map_err(|e| not_found_or_internal(e, format!("Assistant {} not found", assistant_id)))?;
+    if !assistant.inner.file_ids.contains(&file_id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!(
+                "File {} is not attached to assistant {}",
+                file_id, assistant_id
+            ),
+        ));
+    }
+
+    // Files attached before the files table existed have no upload date
+    let created_at = match get_file(&app_state.pool, &file_id, &user_id).await {
+        Ok(file) => file.inner.created_at as i32,
+        Err(_) => assistant.inner.created_at,
+    };
+    Ok(JsonResponse(AssistantFileObject {
+        id: file_id,
+        object: "assistant.file".to_string(),
+        created_at,
+        assistant_id,
+    }))
+}
+
+pub async fn delete_assistant_file_handler(
+    Path((assistant_id, file_id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<DeleteAssistantFileResponse>, (StatusCode, String)> {
+    detach_file_from_assistant(
+        &app_state.pool,
+        &assistant_id,
+        &file_id,
+        &Uuid::default().to_string(),
+    )
+    .await
+    .map_err(|e| {
+        not_found_or_internal(
+            e,
+            format!(
+                "File {} is not attached to assistant {}",
+                file_id, assistant_id
+            ),
+        )
+    })?;
+
+    Ok(JsonResponse(DeleteAssistantFileResponse {
+        id: file_id,
+        deleted: true,
+        object: "assistant.file.deleted".to_string(),
+    }))
+}
+
+pub async fn list_assistant_files_handler(
+    Path((assistant_id,)): Path<(String,)>,
+    Query(_): Query<ListParams>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<ListAssistantFilesResponse>, (StatusCode, String)> {
+    let user_id = Uuid::default().to_string();
+    let assistant = get_assistant(&app_state.pool, &assistant_id, &user_id)
+        .await
+        .map_err(|e| not_found_or_internal(e, format!("Assistant {} not found", assistant_id)))?;
+    let files = get_files(&app_state.pool, &assistant.inner.file_ids, &user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let created_at: HashMap<_, _> = files
+        .iter()
+        .map(|file| (file.inner.id.as_str(), file.inner.created_at as i32))
+        .collect();
+
+    let data: Vec<AssistantFileObject> = assistant
+        .inner
+        .file_ids
+        .iter()
+        .map(|file_id| AssistantFileObject {
+            id: file_id.clone(),
+            object: "assistant.file".to_string(),
+            created_at: created_at
+                .get(file_id.as_str())
+                .copied()
+                .unwrap_or(assistant.inner.created_at),
+            assistant_id: assistant_id.clone(),
+        })
+        .collect();
+    Ok(JsonResponse(ListAssistantFilesResponse {
+        object: "list".to_string(),
+        first_id: data.first().map(|file| file.id.clone()),
+        last_id: data.last().map(|file| file.id.clone()),
+        has_more: false,
+        data,
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use hal_9100_extra::config::Hal9100Config;
@@ -231,6 +375,14 @@ mod tests {
                 delete(delete_assistant_handler),
             )
             .route("/assistants", get(list_assistants_handler))
+            .route(
+                "/assistants/:assistant_id/files",
+                post(create_assistant_file_handler).get(list_assistant_files_handler),
+            )
+            .route(
+                "/assistants/:assistant_id/files/:file_id",
+                get(get_assistant_file_handler).delete(delete_assistant_file_handler),
+            )
             // Add other routes here
             .layer(TraceLayer::new_for_http())
             .with_state(app_state)
@@ -386,4 +538,84 @@ mod tests {
             "List of assistants should not be empty"
         );
     }
+
+    #[tokio::test]
+    async fn test_assistant_files() {
+        let app_state = setup().await;
+        let user_id = Uuid::default().to_string();
+        let file_id = format!("{}.txt", Uuid::new_v4());
+        hal_9100_core::files::create_file(
+            &app_state.pool,
+            hal_9100_core::files::NewFile {
+                id: &file_id,
+                filename: "notes.txt",
+                bytes: 5,
+                purpose: "assistants",
+                content_type: "text/plain",
+                sha256: None,
+                user_id: &user_id,
+            },
+        )
+        .await
+        .unwrap();
+        let app = app(app_state);
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/assistants")
+            .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                json!({"instructions": "Hello, World!", "model": "gpt-3.5-turbo-1106"}).to_string(),
+            ))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let assistant = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let assistant: AssistantObject = serde_json::from_slice(&assistant).unwrap();
+
+        let attach = |file_id: &str| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(format!("/assistants/{}/files", assistant.id))
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(json!({ "file_id": file_id }).to_string()))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(attach(&file_id)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let assistant_file = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let assistant_file: AssistantFileObject = serde_json::from_slice(&assistant_file).unwrap();
+        assert_eq!(assistant_file.id, file_id);
+        assert_eq!(assistant_file.assistant_id, assistant.id);
+
+        // Unknown files can't be attached
+        let response = app.clone().oneshot(attach("unknown.txt")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = Request::builder()
+            .method(http::Method::GET)
+            .uri(format!("/assistants/{}/files", assistant.id))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let files = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let files: ListAssistantFilesResponse = serde_json::from_slice(&files).unwrap();
+        assert_eq!(files.data.len(), 1);
+        assert_eq!(files.data[0].id, file_id);
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri(format!("/assistants/{}/files/{}", assistant.id, file_id))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::builder()
+            .method(http::Method::GET)
+            .uri(format!("/assistants/{}/files/{}", assistant.id, file_id))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 }
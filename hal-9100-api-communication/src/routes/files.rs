@@ -1,33 +1,38 @@
-use async_openai::types::{ListFilesResponse, OpenAIFile, OpenAIFilePurpose};
+use async_openai::types::{DeleteFileResponse, ListFilesResponse, OpenAIFile};
 use axum::{
+    body::StreamBody,
     debug_handler,
     extract::{DefaultBodyLimit, FromRef, Json, Multipart, Path, Query, State},
-    http::StatusCode,
-    response::Json as JsonResponse,
+    http::{header, StatusCode},
+    response::{IntoResponse, Json as JsonResponse},
 };
-use bytes::Buf;
 use hal_9100_api_communication::models::AppState;
-use hal_9100_core::retrieval::split_and_insert;
+use hal_9100_core::assistants::get_assistant;
+use hal_9100_core::chunking::ChunkingStrategy;
+use hal_9100_core::file_storage::UploadError;
+use hal_9100_core::files::{
+    create_file, delete_file, get_file, list_files, update_file_status, FileStatus, NewFile,
+};
+use hal_9100_core::ingestion::{
+    enqueue_ingestion, reuse_duplicate_ingestion, IngestionError, IngestionJob,
+};
+use hal_9100_core::models::File;
+use serde::Deserialize;
+use sqlx::types::Uuid;
 
-use log::{error, info};
+use futures::StreamExt;
+use log::{error, info, warn};
 use serde_json::{json, Value};
-use std::io::Write;
-use tempfile;
+
 pub async fn retrieve_file_handler(
     Path(file_id): Path<String>,
     State(app_state): State<AppState>,
 ) -> Result<JsonResponse<OpenAIFile>, (StatusCode, String)> {
-    match app_state.file_storage.retrieve_file(&file_id).await {
-        Ok(mut file) => Ok(JsonResponse(OpenAIFile {
-            id: file_id,
-            object: "object".to_string(),
-            bytes: file.bytes.get_u32(),
-            created_at: 0,
-            filename: "unknown".to_string(),
-            purpose: OpenAIFilePurpose::Assistants,
-            status: Some("unknown".to_string()),
-            status_details: Some("unknown".to_string()),
-        })),
+    match get_file(&app_state.pool, &file_id, &Uuid::default().to_string()).await {
+        Ok(file) => Ok(JsonResponse(file.inner)),
+        Err(sqlx::Error::RowNotFound) => {
+            Err((StatusCode::NOT_FOUND, format!("File {} not found", file_id)))
+        }
         Err(e) => {
             error!("Failed to retrieve file: {:?}", e);
             Err((
@@ -38,105 +43,380 @@ pub async fn retrieve_file_handler(
     }
 }
 
+pub async fn delete_file_handler(
+    Path(file_id): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<DeleteFileResponse>, (StatusCode, String)> {
+    match delete_file(&app_state.pool, &file_id, &Uuid::default().to_string()).await {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((StatusCode::NOT_FOUND, format!("File {} not found", file_id)))
+        }
+        Err(e) => {
+            error!("Failed to delete file: {:?}", e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to delete file".to_string(),
+            ));
+        }
+    }
+
+    // Nothing references the content anymore, a leftover object is only wasted space
+    if let Err(e) = app_state.file_storage.delete_file(&file_id).await {
+        warn!("Failed to delete content of file {}: {}", file_id, e);
+    }
+
+    Ok(JsonResponse(DeleteFileResponse {
+        id: file_id,
+        object: "file".to_string(),
+        deleted: true,
+    }))
+}
+
+pub async fn download_file_content_handler(
+    Path(file_id): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let file = match get_file(&app_state.pool, &file_id, &Uuid::default().to_string()).await {
+        Ok(file) => file,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((StatusCode::NOT_FOUND, format!("File {} not found", file_id)))
+        }
+        Err(e) => {
+            error!("Failed to retrieve file: {:?}", e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to retrieve file".to_string(),
+            ));
+        }
+    };
+
+    let stream = app_state
+        .file_storage
+        .get_file_stream(&file_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to download file: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to download file".to_string(),
+            )
+        })?;
+
+    let headers = [
+        (header::CONTENT_TYPE, file.content_type),
+        (
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}\"",
+                file.inner.filename.replace('"', "")
+            ),
+        ),
+    ];
+    Ok((headers, StreamBody::new(stream)))
+}
+
+async fn redis_connection(
+    app_state: &AppState,
+) -> Result<redis::aio::Connection, (StatusCode, String)> {
+    let client = redis::Client::open(app_state.hal_9100_config.redis_url.clone())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    client
+        .get_async_connection()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Puts a file back in the ingestion queue if its previous ingestion failed.
+pub async fn reingest_failed_file(
+    app_state: &AppState,
+    file: &File,
+    chunking_strategy: Option<ChunkingStrategy>,
+) -> Result<(), (StatusCode, String)> {
+    if file.inner.status != Some(FileStatus::Error.to_string()) {
+        return Ok(());
+    }
+    info!("Retrying ingestion of file {}", file.inner.id);
+    let internal_error = |e: String| {
+        error!("Failed to enqueue ingestion: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to enqueue ingestion".to_string(),
+        )
+    };
+    update_file_status(&app_state.pool, &file.inner.id, FileStatus::Uploaded, "")
+        .await
+        .map_err(|e| internal_error(e.to_string()))?;
+    let mut con = redis_connection(app_state).await?;
+    let job = IngestionJob {
+        file_id: file.inner.id.clone(),
+        file_name: file.inner.filename.clone(),
+        content_type: file.content_type.clone(),
+        chunking_strategy,
+    };
+    enqueue_ingestion(&mut con, &job)
+        .await
+        .map_err(|e| internal_error(e.to_string()))
+}
+
+fn bad_request<E: std::fmt::Display>(e: E) -> (StatusCode, String) {
+    (
+        StatusCode::BAD_REQUEST,
+        format!("Invalid multipart body: {}", e),
+    )
+}
+
+// Upload limits per purpose, anything else is capped like assistants files
+const MAX_ASSISTANTS_FILE_SIZE: u64 = 512 * 1024 * 1024;
+const MAX_FINE_TUNE_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+const MAX_VISION_FILE_SIZE: u64 = 20 * 1024 * 1024;
+
+/// Largest upload accepted for any purpose, the request body limit should leave room for it.
+pub const MAX_FILE_SIZE: u64 = MAX_FINE_TUNE_FILE_SIZE;
+
+fn max_file_size(purpose: &str) -> u64 {
+    match purpose {
+        "fine-tune" => MAX_FINE_TUNE_FILE_SIZE,
+        "vision" => MAX_VISION_FILE_SIZE,
+        _ => MAX_ASSISTANTS_FILE_SIZE,
+    }
+}
+
+fn payload_too_large(max_size: u64) -> (StatusCode, String) {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("File is larger than the {} bytes limit", max_size),
+    )
+}
+
 pub async fn upload_file_handler(
     State(app_state): State<AppState>,
     mut multipart: Multipart,
 ) -> Result<JsonResponse<OpenAIFile>, (StatusCode, String)> {
-    let mut file_data = Vec::new();
+    let mut uploaded = None;
     let mut purpose = String::new();
     let mut content_type = String::new();
     let mut file_name = String::new();
-    while let Some(mut field) = multipart.next_field().await.unwrap() {
-        let field_name = field.name().unwrap().to_string();
+    let mut chunking_strategy = None;
+    let mut assistant_id = None;
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        let field_name = field.name().unwrap_or_default().to_string();
 
-        println!("field_name: {:?}", field_name);
         if field_name == "file" {
+            // Only one file per upload, the one already stored would be left behind
+            if let Some(uploaded) = &uploaded {
+                discard_upload(&app_state, &uploaded.file.id).await;
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Only one file can be uploaded at a time".to_string(),
+                ));
+            }
             content_type = field.content_type().unwrap_or("text/plain").to_string();
             file_name = field.file_name().unwrap_or("unknown.txt").to_string();
-            while let Some(chunk) = field.chunk().await.unwrap() {
-                file_data.extend_from_slice(&chunk);
-            }
+            // The purpose may come after the file, the limit is checked again once it's known
+            let max_size = if purpose.is_empty() {
+                MAX_FILE_SIZE
+            } else {
+                max_file_size(&purpose)
+            };
+            let extension = std::path::Path::new(&file_name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("txt")
+                .to_string();
+            let content = field.map(|chunk| {
+                chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+            });
+
+            info!("Uploading file: {}", file_name);
+            let file = app_state
+                .file_storage
+                .upload_stream(&extension, Box::pin(content), max_size)
+                .await
+                .map_err(|e| match e {
+                    UploadError::TooLarge(max_size) => payload_too_large(max_size),
+                    UploadError::StorageError(e) => {
+                        error!("Failed to upload file: {:?}", e);
+                        (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Failed to upload file".to_string(),
+                        )
+                    }
+                })?;
+            info!("Uploaded file: {:?}", file.file.id);
+            uploaded = Some(file);
         } else if field_name == "purpose" {
-            purpose = String::from_utf8(field.bytes().await.unwrap().to_vec()).unwrap();
+            purpose = field.text().await.map_err(bad_request)?;
+        } else if field_name == "chunking_strategy" {
+            let value = field.text().await.map_err(bad_request)?;
+            chunking_strategy = Some(ChunkingStrategy::parse(&value).map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid chunking_strategy: {}", e),
+                )
+            })?);
+        } else if field_name == "assistant_id" {
+            assistant_id = Some(field.text().await.map_err(bad_request)?);
         }
     }
 
-    if file_data.is_empty() || purpose.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Missing file or purpose".to_string(),
-        ));
+    let uploaded = match uploaded {
+        Some(uploaded) if uploaded.file.size > 0 && !purpose.is_empty() => uploaded,
+        uploaded => {
+            if let Some(uploaded) = uploaded {
+                discard_upload(&app_state, &uploaded.file.id).await;
+            }
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Missing file or purpose".to_string(),
+            ));
+        }
+    };
+    let max_size = max_file_size(&purpose);
+    if uploaded.file.size > max_size {
+        discard_upload(&app_state, &uploaded.file.id).await;
+        return Err(payload_too_large(max_size));
     }
 
-    // Create a temporary file with the same content type
-    let mut temp_file = tempfile::Builder::new()
-        .prefix(file_name.as_str())
-        .rand_bytes(0)
-        .tempfile()
-        .unwrap();
+    // An explicit strategy wins over the one configured on the assistant
+    if chunking_strategy.is_none() {
+        if let Some(assistant_id) = assistant_id {
+            let assistant =
+                match get_assistant(&app_state.pool, &assistant_id, &Uuid::default().to_string())
+                    .await
+                {
+                    Ok(assistant) => assistant,
+                    Err(e) => {
+                        discard_upload(&app_state, &uploaded.file.id).await;
+                        return Err((StatusCode::NOT_FOUND, format!("Assistant not found: {}", e)));
+                    }
+                };
+            chunking_strategy = ChunkingStrategy::from_metadata(&assistant.inner.metadata);
+        }
+    }
 
-    // Write the file data to the temporary file
-    temp_file.write_all(&file_data).unwrap();
+    let user_id = Uuid::default().to_string();
+    let file = match create_file(
+        &app_state.pool,
+        NewFile {
+            id: &uploaded.file.id,
+            filename: &file_name,
+            bytes: uploaded.file.size as i64,
+            purpose: &purpose,
+            content_type: &content_type,
+            sha256: Some(&uploaded.sha256),
+            user_id: &user_id,
+        },
+    )
+    .await
+    {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to save file metadata: {}", e);
+            discard_upload(&app_state, &uploaded.file.id).await;
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to save file metadata".to_string(),
+            ));
+        }
+    };
 
-    // Get the path of the temporary file.
-    let temp_file_path = temp_file.path();
+    // Chunks of identical content may have been made with another strategy
+    let reuse_duplicate = chunking_strategy.is_none();
+    let job = IngestionJob {
+        file_id: file.inner.id.clone(),
+        file_name,
+        content_type,
+        chunking_strategy,
+    };
+    match start_ingestion(
+        &app_state,
+        &job,
+        &uploaded.sha256,
+        &user_id,
+        reuse_duplicate,
+    )
+    .await
+    {
+        Ok(reused) => Ok(JsonResponse(reused.unwrap_or(file).inner)),
+        Err(e) => {
+            // Leave the file where reingest_failed_file can pick it up
+            if let Err(e) = update_file_status(
+                &app_state.pool,
+                &file.inner.id,
+                FileStatus::Error,
+                "Failed to enqueue ingestion",
+            )
+            .await
+            {
+                error!("Failed to mark file {} as errored: {}", file.inner.id, e);
+            }
+            Err(e)
+        }
+    }
+}
 
-    // Upload the file.
-    info!("Uploading file: {:?}", temp_file_path);
-    let mut file = app_state
-        .file_storage
-        .upload_file(&temp_file_path)
-        .await
-        .unwrap();
-    info!("Uploaded file: {:?}", file.id);
-
-    // Inside upload_file_handler function, after writing the file data to the temporary file
-    if content_type.starts_with("text/") {
-        let file_data_str = String::from_utf8(file_data.clone()).unwrap();
-        split_and_insert(
-            &app_state.pool,
-            &file_data_str,
-            100, // TODO
-            &file.id,
-            None,
+/// Queues a freshly recorded file for ingestion. Returns the file when it
+/// reused the chunks of identical content instead.
+async fn start_ingestion(
+    app_state: &AppState,
+    job: &IngestionJob,
+    sha256: &str,
+    user_id: &str,
+    reuse_duplicate: bool,
+) -> Result<Option<File>, (StatusCode, String)> {
+    let internal_error = |e: IngestionError| {
+        error!("Failed to enqueue ingestion: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to enqueue ingestion".to_string(),
         )
+    };
+
+    // Identical content was already chunked, unless a strategy was asked for or set on the assistant
+    if reuse_duplicate
+        && reuse_duplicate_ingestion(&app_state.pool, &job.file_id, sha256, user_id)
+            .await
+            .map_err(internal_error)?
+    {
+        let file = get_file(&app_state.pool, &job.file_id, user_id)
+            .await
+            .map_err(|e| internal_error(e.into()))?;
+        return Ok(Some(file));
+    }
+
+    // Extraction and chunking happen in the executor
+    let mut con = redis_connection(app_state).await?;
+    enqueue_ingestion(&mut con, job)
         .await
-        .unwrap();
+        .map_err(internal_error)?;
+    Ok(None)
+}
+
+async fn discard_upload(app_state: &AppState, file_id: &str) {
+    if let Err(e) = app_state.file_storage.delete_file(file_id).await {
+        warn!("Failed to delete rejected upload {}: {}", file_id, e);
     }
+}
 
-    Ok(JsonResponse(OpenAIFile {
-        id: file.id,
-        object: "object".to_string(),
-        bytes: file.bytes.get_u32(),
-        created_at: 0,
-        filename: "".to_string(), // TODO
-        purpose: OpenAIFilePurpose::Assistants,
-        status: Some("success".to_string()),
-        status_details: Some("unknown".to_string()),
-    }))
+#[derive(Deserialize)]
+pub struct ListFilesQuery {
+    purpose: Option<String>,
 }
 
 pub async fn list_files_handler(
     State(app_state): State<AppState>,
-    // purpose_query: Query<Option<String>>, // TODO use purpose
+    Query(query): Query<ListFilesQuery>,
 ) -> Result<JsonResponse<ListFilesResponse>, (StatusCode, String)> {
-    let files = app_state.file_storage.list_files().await;
-
-    match files {
+    match list_files(
+        &app_state.pool,
+        &Uuid::default().to_string(),
+        query.purpose.as_deref(),
+    )
+    .await
+    {
         Ok(files) => Ok(JsonResponse(ListFilesResponse {
-            data: files
-                .into_iter()
-                .map(|mut file| OpenAIFile {
-                    id: file.id,
-                    object: "object".to_string(),
-                    bytes: file.bytes.get_u32(),
-                    created_at: 0,            // ?
-                    filename: "".to_string(), // TODO
-                    purpose: OpenAIFilePurpose::Assistants,
-                    status: Some("unknown".to_string()),
-                    status_details: Some("unknown".to_string()),
-                })
-                .collect(),
+            data: files.into_iter().map(|file| file.inner).collect(),
             object: "list".to_string(),
         })),
         Err(e) => {
@@ -192,11 +472,20 @@ mod tests {
     fn app(app_state: AppState) -> Router {
         // Define your routes here
         Router::new()
-            .route("/files/:file_id", get(retrieve_file_handler))
+            .route(
+                "/files/:file_id",
+                get(retrieve_file_handler).delete(delete_file_handler),
+            )
+            .route(
+                "/files/:file_id/content",
+                get(download_file_content_handler),
+            )
             .route("/files", post(upload_file_handler))
             .route("/files", get(list_files_handler))
             .layer(DefaultBodyLimit::disable())
-            .layer(RequestBodyLimitLayer::new(250 * 1024 * 1024))
+            .layer(RequestBodyLimitLayer::new(
+                MAX_FILE_SIZE as usize + 1024 * 1024,
+            ))
             .with_state(app_state)
     }
 
@@ -205,11 +494,13 @@ mod tests {
         let app_state = setup().await;
         let app = app(app_state);
 
-        // Upload a file first
+        // Upload a file first, unique content so it isn't deduplicated with a processed one
         let boundary = "------------------------14737809831466499882746641449";
+        let content = format!("Test file content {}", Uuid::new_v4());
         let body = format!(
-            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"test.txt\"\r\n\r\nTest file content\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nTest Purpose\r\n--{boundary}--\r\n",
-            boundary = boundary
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"test.txt\"\r\n\r\n{content}\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nTest Purpose\r\n--{boundary}--\r\n",
+            boundary = boundary,
+            content = content
         );
 
         let upload_request = Request::builder()
@@ -243,6 +534,26 @@ mod tests {
         let retrieve_response = app.clone().oneshot(retrieve_request).await.unwrap();
 
         assert_eq!(retrieve_response.status(), StatusCode::OK);
+
+        // Chunking happens in the executor, the file is only uploaded so far
+        let retrieve_response_body = hyper::body::to_bytes(retrieve_response.into_body())
+            .await
+            .unwrap();
+        let retrieve_response_json: serde_json::Value =
+            serde_json::from_slice(&retrieve_response_body).unwrap();
+        assert_eq!(retrieve_response_json["status"], "uploaded");
+        assert_eq!(retrieve_response_json["filename"], "test.txt");
+        assert_eq!(retrieve_response_json["bytes"], content.len());
+        assert!(retrieve_response_json["created_at"].as_u64().unwrap() > 0);
+
+        // Unknown files are a 404
+        let retrieve_request = Request::builder()
+            .method(http::Method::GET)
+            .uri("/files/does-not-exist.txt")
+            .body(Body::empty())
+            .unwrap();
+        let retrieve_response = app.clone().oneshot(retrieve_request).await.unwrap();
+        assert_eq!(retrieve_response.status(), StatusCode::NOT_FOUND);
     }
     #[tokio::test]
     async fn test_upload_file_handler() {
@@ -269,6 +580,64 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn test_upload_file_handler_two_files() {
+        let app_state = setup().await;
+        let app = app(app_state);
+        let boundary = "------------------------14737809831466499882746641449";
+        let body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nFirst file\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"b.txt\"\r\n\r\nSecond file\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nassistants\r\n--{boundary}--\r\n",
+            boundary = boundary
+        );
+
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/files")
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_handler_chunking_strategy() {
+        let app_state = setup().await;
+        let app = app(app_state);
+        let boundary = "------------------------14737809831466499882746641449";
+        let upload = |strategy: &str| {
+            let body = format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"test.md\"\r\nContent-Type: text/markdown\r\n\r\n# Title\n\nTest file content\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nTest Purpose\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"chunking_strategy\"\r\n\r\n{strategy}\r\n--{boundary}--\r\n",
+                boundary = boundary,
+                strategy = strategy
+            );
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/files")
+                .header(
+                    "Content-Type",
+                    format!("multipart/form-data; boundary={}", boundary),
+                )
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(upload("{\"type\": \"markdown\", \"chunk_size\": 50}"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone().oneshot(upload("not_a_strategy")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
     #[tokio::test]
     async fn test_upload_pdf_file_handler_pdf_base64() {
         let app_state = setup().await;
@@ -303,6 +672,172 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn test_download_and_delete_file_handlers() {
+        let app_state = setup().await;
+        let app = app(app_state);
+
+        let boundary = "------------------------14737809831466499882746641449";
+        let body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"test.txt\"\r\nContent-Type: text/plain\r\n\r\nTest file content\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nassistants\r\n--{boundary}--\r\n",
+            boundary = boundary
+        );
+        let upload_request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/files")
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let upload_response = app.clone().oneshot(upload_request).await.unwrap();
+        let upload_response_body = hyper::body::to_bytes(upload_response.into_body())
+            .await
+            .unwrap();
+        let upload_response_json: serde_json::Value =
+            serde_json::from_slice(&upload_response_body).unwrap();
+        let file_id = upload_response_json["id"].as_str().unwrap().to_string();
+
+        // Download the content
+        let content_request = Request::builder()
+            .method(http::Method::GET)
+            .uri(format!("/files/{}/content", file_id))
+            .body(Body::empty())
+            .unwrap();
+        let content_response = app.clone().oneshot(content_request).await.unwrap();
+        assert_eq!(content_response.status(), StatusCode::OK);
+        assert_eq!(content_response.headers()["content-type"], "text/plain");
+        let content = hyper::body::to_bytes(content_response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(content, "Test file content");
+
+        // Delete it
+        let delete_request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri(format!("/files/{}", file_id))
+            .body(Body::empty())
+            .unwrap();
+        let delete_response = app.clone().oneshot(delete_request).await.unwrap();
+        assert_eq!(delete_response.status(), StatusCode::OK);
+        let delete_response_body = hyper::body::to_bytes(delete_response.into_body())
+            .await
+            .unwrap();
+        let delete_response_json: serde_json::Value =
+            serde_json::from_slice(&delete_response_body).unwrap();
+        assert_eq!(delete_response_json["deleted"], true);
+
+        // Both the metadata and the content are gone
+        for uri in [
+            format!("/files/{}", file_id),
+            format!("/files/{}/content", file_id),
+        ] {
+            let request = Request::builder()
+                .method(http::Method::GET)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        let delete_request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri(format!("/files/{}", file_id))
+            .body(Body::empty())
+            .unwrap();
+        let delete_response = app.clone().oneshot(delete_request).await.unwrap();
+        assert_eq!(delete_response.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn upload_request(file_name: &str, content: &str, purpose: &str) -> Request<Body> {
+        let boundary = "------------------------14737809831466499882746641449";
+        let body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\n{purpose}\r\n--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{file_name}\"\r\n\r\n{content}\r\n--{boundary}--\r\n",
+            boundary = boundary,
+            purpose = purpose,
+            file_name = file_name,
+            content = content
+        );
+        Request::builder()
+            .method(http::Method::POST)
+            .uri("/files")
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_handler_size_limit() {
+        let app_state = setup().await;
+        let app = app(app_state);
+
+        let content = "a".repeat(MAX_VISION_FILE_SIZE as usize + 1);
+        let response = app
+            .clone()
+            .oneshot(upload_request("image.png", &content, "vision"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // the same file is fine for assistants
+        let response = app
+            .clone()
+            .oneshot(upload_request("image.png", &content, "assistants"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_handler_dedupe() {
+        let app_state = setup().await;
+        let pool = app_state.pool.clone();
+        let app = app(app_state);
+        let content = format!("Test file content {}", Uuid::new_v4());
+
+        let upload = |app: Router| {
+            let request = upload_request("test.txt", &content, "assistants");
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        // pretend the executor chunked the first upload
+        let first = upload(app.clone()).await;
+        let first_id = first["id"].as_str().unwrap();
+        sqlx::query!(
+            "INSERT INTO chunks (sequence, data, file_id, start_index, end_index) VALUES (0, $1, $2, 0, 5)",
+            content,
+            first_id
+        )
+        .execute(&*pool)
+        .await
+        .unwrap();
+        update_file_status(&pool, first_id, FileStatus::Processed, "1 chunks")
+            .await
+            .unwrap();
+
+        let second = upload(app.clone()).await;
+        assert_ne!(second["id"], first["id"]);
+        assert_eq!(second["status"], "processed");
+        let chunks = sqlx::query!(
+            "SELECT data FROM chunks WHERE file_id = $1",
+            second["id"].as_str().unwrap()
+        )
+        .fetch_all(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].data, content);
+    }
+
     #[tokio::test]
     async fn test_list_files_handler() {
         let app_state = setup().await;
@@ -333,7 +868,7 @@ mod tests {
         // Now test the list_files_handler
         let list_request = Request::builder()
             .method(http::Method::GET)
-            .uri("/files?purpose=test")
+            .uri("/files?purpose=Test%20Purpose")
             .body(Body::empty())
             .unwrap();
 
@@ -2,10 +2,12 @@ use hal_9100_api_communication::models::AppState;
 use hal_9100_core::messages::{
     add_message_to_thread, delete_message, get_message, list_messages, update_message,
 };
+use hal_9100_core::files::get_files;
 use hal_9100_core::models::Message;
 use async_openai::types::{
-    CreateMessageRequest, ListMessagesResponse, MessageContent, MessageContentTextObject,
-    MessageObject, MessageRole, ModifyMessageRequest, TextData,
+    CreateMessageRequest, ListMessageFilesResponse, ListMessagesResponse, MessageContent,
+    MessageContentTextObject, MessageFileObject, MessageObject, MessageRole, ModifyMessageRequest,
+    TextData,
 };
 use axum::extract::Query;
 use axum::{
@@ -15,6 +17,7 @@ use axum::{
 };
 use log::error;
 use sqlx::types::Uuid;
+use std::collections::HashMap;
 
 use crate::models::ListMessagePaginationParams;
 
@@ -38,7 +41,7 @@ pub async fn add_message_handler(
         MessageRole::User,
         content,
         &user_id,
-        None,
+        message.file_ids,
     )
     .await;
     match message {
@@ -159,3 +162,54 @@ pub async fn list_messages_handler(
         }
     }
 }
+
+// List the files attached to a message
+pub async fn list_message_files_handler(
+    Path((thread_id, message_id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<JsonResponse<ListMessageFilesResponse>, (StatusCode, String)> {
+    let user_id = Uuid::default().to_string();
+    let message = match get_message(&app_state.pool, &thread_id, &message_id, &user_id).await {
+        Ok(message) => message,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("Message {} not found", message_id),
+            ))
+        }
+        Err(e) => {
+            let error_message = e.to_string();
+            error!("Failed to get message: {}", error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message));
+        }
+    };
+    let files = get_files(&app_state.pool, &message.inner.file_ids, &user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let created_at: HashMap<_, _> = files
+        .iter()
+        .map(|file| (file.inner.id.as_str(), file.inner.created_at as i32))
+        .collect();
+
+    let data: Vec<MessageFileObject> = message
+        .inner
+        .file_ids
+        .iter()
+        .map(|file_id| MessageFileObject {
+            id: file_id.clone(),
+            object: "thread.message.file".to_string(),
+            created_at: created_at
+                .get(file_id.as_str())
+                .copied()
+                .unwrap_or(message.inner.created_at),
+            message_id: message_id.clone(),
+        })
+        .collect();
+    Ok(JsonResponse(ListMessageFilesResponse {
+        object: "list".to_string(),
+        first_id: data.first().map(|file| file.id.clone()),
+        last_id: data.last().map(|file| file.id.clone()),
+        has_more: false,
+        data,
+    }))
+}
@@ -7,20 +7,22 @@ use axum::{
 };
 use hal_9100_api_communication::models::AppState;
 use hal_9100_api_communication::routes::assistants::{
-    create_assistant_handler, delete_assistant_handler, get_assistant_handler,
-    list_assistants_handler, update_assistant_handler,
+    create_assistant_file_handler, create_assistant_handler, delete_assistant_file_handler,
+    delete_assistant_handler, get_assistant_file_handler, get_assistant_handler,
+    list_assistant_files_handler, list_assistants_handler, update_assistant_handler,
 };
 use hal_9100_api_communication::routes::files::{
-    list_files_handler, retrieve_file_handler, upload_file_handler,
+    delete_file_handler, download_file_content_handler, list_files_handler, retrieve_file_handler,
+    upload_file_handler, MAX_FILE_SIZE,
 };
 use hal_9100_api_communication::routes::messages::{
-    add_message_handler, delete_message_handler, get_message_handler, list_messages_handler,
-    update_message_handler,
+    add_message_handler, delete_message_handler, get_message_handler, list_message_files_handler,
+    list_messages_handler, update_message_handler,
 };
 use hal_9100_api_communication::routes::run_steps::{get_step_handler, list_steps_handler};
 use hal_9100_api_communication::routes::runs::{
     create_run_handler, delete_run_handler, get_run_handler, list_runs_handler,
-    submit_tool_outputs_handler, update_run_handler,
+    submit_tool_approvals_handler, submit_tool_outputs_handler, update_run_handler,
 };
 use hal_9100_api_communication::routes::threads::{
     create_thread_handler, delete_thread_handler, get_thread_handler, list_threads_handler,
@@ -52,6 +54,23 @@ pub fn app(app_state: AppState) -> Router {
             delete(delete_assistant_handler),
         )
         .route("/assistants", get(list_assistants_handler))
+        // https://platform.openai.com/docs/api-reference/assistants/createAssistantFile
+        .route(
+            "/assistants/:assistant_id/files",
+            post(create_assistant_file_handler),
+        )
+        .route(
+            "/assistants/:assistant_id/files",
+            get(list_assistant_files_handler),
+        )
+        .route(
+            "/assistants/:assistant_id/files/:file_id",
+            get(get_assistant_file_handler),
+        )
+        .route(
+            "/assistants/:assistant_id/files/:file_id",
+            delete(delete_assistant_file_handler),
+        )
         // https://platform.openai.com/docs/api-reference/threads
         .route("/threads", post(create_thread_handler))
         .route("/threads/:thread_id", get(get_thread_handler))
@@ -77,6 +96,11 @@ pub fn app(app_state: AppState) -> Router {
             delete(delete_message_handler),
         )
         .route("/threads/:thread_id/messages", get(list_messages_handler))
+        // https://platform.openai.com/docs/api-reference/messages/listMessageFiles
+        .route(
+            "/threads/:thread_id/messages/:message_id/files",
+            get(list_message_files_handler),
+        )
         // https://platform.openai.com/docs/api-reference/runs
         .route("/threads/:thread_id/runs", post(create_run_handler))
         .route("/threads/:thread_id/runs/:run_id", get(get_run_handler))
@@ -100,20 +124,34 @@ pub fn app(app_state: AppState) -> Router {
             "/threads/:thread_id/runs/:run_id/submit_tool_outputs",
             post(submit_tool_outputs_handler),
         )
+        // Not in the OpenAI API: decisions on consequential action calls
+        .route(
+            "/threads/:thread_id/runs/:run_id/submit_tool_approvals",
+            post(submit_tool_approvals_handler),
+        )
         // .route("/threads/:thread_id/runs/:run_id/cancel", post(cancel_run_handler))
         // .route("/threads/runs", post(create_thread_and_run_handler))
         // .route("/threads/:thread_id/runs/:run_id/steps/:step_id", get(get_run_step_handler))
         // .route("/threads/:thread_id/runs/:run_id/steps", get(list_run_steps_handler))
         // https://platform.openai.com/docs/api-reference/files
-        .route("/files/:file_id", get(retrieve_file_handler))
+        .route(
+            "/files/:file_id",
+            get(retrieve_file_handler).delete(delete_file_handler),
+        )
+        .route(
+            "/files/:file_id/content",
+            get(download_file_content_handler),
+        )
         .route("/files", post(upload_file_handler))
         // list
         .route("/files", get(list_files_handler))
         .route("/chat/completions", post(chat_handler))
         .route("/health", get(health_handler)) // new health check route
         .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(250 * 1024 * 1024)) // 250mb
-        // .route("/assistants/:assistant_id/files/:file_id", delete(delete_file_handler))
+        // largest file upload plus some room for the other multipart fields
+        .layer(RequestBodyLimitLayer::new(
+            MAX_FILE_SIZE as usize + 1024 * 1024,
+        ))
         // https://docs.rs/tower-http/latest/tower_http/trace/index.html
         .layer(TraceLayer::new_for_http()) // Add this line
         .layer(cors)
@@ -152,7 +190,9 @@ mod tests {
     use hal_9100_api_communication::routes::runs::{
         ApiSubmittedToolCall, SubmitToolOutputsRequest,
     };
-    use hal_9100_core::{executor::try_run_executor, file_storage::FileStorage};
+    use hal_9100_core::{
+        executor::try_run_executor, file_storage::FileStorage, ingestion::try_ingest,
+    };
     use hal_9100_extra::llm::HalLLMClient;
     use hyper;
     use mime;
@@ -1046,7 +1086,7 @@ mod tests {
                 r#type: "retrieval".to_string(),
             })]),
             model: model_name,
-            file_ids: Some(vec![file_id]), // Associate the uploaded file with the assistant
+            file_ids: Some(vec![file_id.clone()]), // Associate the uploaded file with the assistant
             description: None,
             metadata: None,
         };
@@ -1143,6 +1183,14 @@ mod tests {
         let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
         let client = redis::Client::open(redis_url).unwrap();
         let mut con = client.get_async_connection().await.unwrap();
+
+        // Process the ingestion of the uploaded file, skipping jobs left by other tests
+        loop {
+            match try_ingest(&pool_clone, &mut con, &app_state.file_storage).await {
+                Ok(job) if job.file_id == file_id => break,
+                _ => continue,
+            }
+        }
         let llm_client = HalLLMClient::new(
             assistant.model,
             std::env::var("MODEL_URL").expect("MODEL_URL must be set"),
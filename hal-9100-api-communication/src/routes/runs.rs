@@ -6,10 +6,10 @@ use axum::{
     response::Json as JsonResponse,
 };
 use hal_9100_api_communication::models::AppState;
-use hal_9100_core::models::{Run, SubmittedToolCall};
+use hal_9100_core::models::{Run, SubmittedToolCall, ToolApproval};
 use hal_9100_core::runs::{
     create_run, create_run_and_produce_to_executor_queue, delete_run, get_run, list_runs,
-    submit_tool_outputs, update_run,
+    submit_tool_approvals, submit_tool_outputs, update_run,
 };
 
 use log::error;
@@ -63,6 +63,42 @@ pub async fn submit_tool_outputs_handler(
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SubmitToolApprovalsRequest {
+    pub tool_approvals: Vec<ToolApproval>,
+}
+
+/// Approves, edits or rejects the action calls of a run in `requires_action` with a
+/// `submit_tool_approvals` required action
+pub async fn submit_tool_approvals_handler(
+    Path((thread_id, run_id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+    Json(request): Json<SubmitToolApprovalsRequest>,
+) -> Result<JsonResponse<RunObject>, (StatusCode, String)> {
+    let user_id = Uuid::default().to_string();
+    let client = redis::Client::open(app_state.hal_9100_config.redis_url.clone()).unwrap();
+    let con = client.get_async_connection().await.unwrap();
+    match submit_tool_approvals(
+        &app_state.pool,
+        &thread_id,
+        &run_id,
+        &user_id,
+        request.tool_approvals,
+        con,
+    )
+    .await
+    {
+        Ok(run) => Ok(JsonResponse(run.inner)),
+        // Wrong run state or decisions
+        Err(sqlx::Error::Configuration(e)) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+        Err(e) => {
+            let error_message = e.to_string();
+            error!("Failed to submit tool approvals: {}", error_message);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message))
+        }
+    }
+}
+
 pub async fn create_run_handler(
     Path((thread_id,)): Path<(String,)>,
     State(app_state): State<AppState>,
@@ -395,6 +395,57 @@ pub async fn list_assistants(pool: &PgPool, user_id: &str) -> Result<Vec<Assista
     Ok(assistants)
 }
 
+/// Adds a file to the assistant in a single statement, attaching an already attached file is a no-op.
+pub async fn attach_file_to_assistant(
+    pool: &PgPool,
+    assistant_id: &str,
+    file_id: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE assistants
+        SET file_ids = CASE
+            WHEN $1 = ANY(file_ids) THEN file_ids
+            ELSE array_append(COALESCE(file_ids, '{}'), $1)
+        END
+        WHERE id::text = $2 AND user_id::text = $3
+        RETURNING id
+        "#,
+        file_id,
+        assistant_id,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Removes a file from the assistant, `RowNotFound` if it wasn't attached.
+pub async fn detach_file_from_assistant(
+    pool: &PgPool,
+    assistant_id: &str,
+    file_id: &str,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE assistants
+        SET file_ids = array_remove(file_ids, $1)
+        WHERE id::text = $2 AND user_id::text = $3 AND $1 = ANY(file_ids)
+        RETURNING id
+        "#,
+        file_id,
+        assistant_id,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::assistants::create_assistant;
@@ -543,4 +594,49 @@ mod tests {
         assert_eq!(assistant.inner.model, "claude-2.1".to_string());
         assert_eq!(assistant.inner.file_ids.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_attach_and_detach_file() {
+        let pool = setup().await;
+        let assistant = create_assistant(&pool, &Assistant::default())
+            .await
+            .unwrap();
+        let user_id = assistant.user_id.clone();
+
+        attach_file_to_assistant(&pool, &assistant.inner.id, "a.txt", &user_id)
+            .await
+            .unwrap();
+        attach_file_to_assistant(&pool, &assistant.inner.id, "b.txt", &user_id)
+            .await
+            .unwrap();
+        // attaching twice doesn't duplicate the file
+        attach_file_to_assistant(&pool, &assistant.inner.id, "a.txt", &user_id)
+            .await
+            .unwrap();
+        let file_ids = get_assistant(&pool, &assistant.inner.id, &user_id)
+            .await
+            .unwrap()
+            .inner
+            .file_ids;
+        assert_eq!(file_ids, vec!["a.txt".to_string(), "b.txt".to_string()]);
+
+        detach_file_from_assistant(&pool, &assistant.inner.id, "a.txt", &user_id)
+            .await
+            .unwrap();
+        let file_ids = get_assistant(&pool, &assistant.inner.id, &user_id)
+            .await
+            .unwrap()
+            .inner
+            .file_ids;
+        assert_eq!(file_ids, vec!["b.txt".to_string()]);
+
+        assert!(matches!(
+            detach_file_from_assistant(&pool, &assistant.inner.id, "a.txt", &user_id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(matches!(
+            attach_file_to_assistant(&pool, &Uuid::new_v4().to_string(), "a.txt", &user_id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+    }
 }
@@ -0,0 +1,516 @@
+use hal_9100_core::extractors::ExtractedSection;
+use hal_9100_core::models::PartialChunk;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 100;
+pub const DEFAULT_CHUNK_OVERLAP: usize = 20;
+
+// Separators tried by the recursive splitter, from the coarsest (paragraphs) to the finest (words)
+const RECURSIVE_SEPARATORS: [&str; 7] = ["\n\n", "\n", ". ", "! ", "? ", "; ", " "];
+
+fn default_chunk_size() -> usize {
+    DEFAULT_CHUNK_SIZE
+}
+
+fn default_chunk_overlap() -> usize {
+    DEFAULT_CHUNK_OVERLAP
+}
+
+/// How a document is cut into chunks before being stored for retrieval.
+///
+/// Sizes are expressed in cl100k tokens. The strategy can be given as JSON, e.g.
+/// `{"type": "markdown", "chunk_size": 200}`, or by its name only, e.g. `recursive`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChunkingStrategy {
+    /// Fixed windows of tokens, each window repeating the last `chunk_overlap` tokens of the previous one.
+    FixedToken {
+        #[serde(default = "default_chunk_size")]
+        chunk_size: usize,
+        #[serde(default = "default_chunk_overlap")]
+        chunk_overlap: usize,
+    },
+    /// Splits on paragraphs, then lines, then sentences, then words until every piece fits.
+    Recursive {
+        #[serde(default = "default_chunk_size")]
+        chunk_size: usize,
+        #[serde(default = "default_chunk_overlap")]
+        chunk_overlap: usize,
+    },
+    /// Splits on markdown headings and keeps the heading path of each section in the chunk metadata.
+    Markdown {
+        #[serde(default = "default_chunk_size")]
+        chunk_size: usize,
+    },
+    /// Recursive splitting, meant for paged documents which are split page by page.
+    PdfPage {
+        #[serde(default = "default_chunk_size")]
+        chunk_size: usize,
+        #[serde(default = "default_chunk_overlap")]
+        chunk_overlap: usize,
+    },
+}
+
+impl Default for ChunkingStrategy {
+    fn default() -> Self {
+        ChunkingStrategy::FixedToken {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            chunk_overlap: DEFAULT_CHUNK_OVERLAP,
+        }
+    }
+}
+
+impl ChunkingStrategy {
+    /// Parses either a JSON object or the bare name of a strategy.
+    pub fn parse(value: &str) -> Result<Self, serde_json::Error> {
+        let value = value.trim();
+        if value.starts_with('{') {
+            serde_json::from_str(value)
+        } else {
+            serde_json::from_value(json!({ "type": value }))
+        }
+    }
+
+    /// Reads the strategy an assistant asks for in its `chunking_strategy` metadata key.
+    pub fn from_metadata(metadata: &Option<HashMap<String, Value>>) -> Option<Self> {
+        match metadata.as_ref()?.get("chunking_strategy")? {
+            Value::String(s) => Self::parse(s).ok(),
+            other => serde_json::from_value(other.clone()).ok(),
+        }
+    }
+
+    /// Default strategy for PDF documents.
+    pub fn pdf_default() -> Self {
+        ChunkingStrategy::PdfPage {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            chunk_overlap: DEFAULT_CHUNK_OVERLAP,
+        }
+    }
+}
+
+// A byte range in the source text
+type Span = (usize, usize);
+
+fn token_count(bpe: &CoreBPE, text: &str) -> usize {
+    bpe.encode_with_special_tokens(text).len()
+}
+
+// Token boundaries may fall inside a multi-byte character, move back to the closest char boundary
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while index > 0 && !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn fixed_token_spans(
+    bpe: &CoreBPE,
+    text: &str,
+    start: usize,
+    end: usize,
+    chunk_size: usize,
+    chunk_overlap: usize,
+) -> Vec<Span> {
+    let tokens = bpe.encode_with_special_tokens(&text[start..end]);
+
+    // byte offset of every token boundary
+    let mut offsets = Vec::with_capacity(tokens.len() + 1);
+    let mut offset = start;
+    offsets.push(offset);
+    for token in &tokens {
+        offset += bpe._decode_native(&[*token]).len();
+        offsets.push(offset.min(end));
+    }
+
+    let chunk_size = chunk_size.max(1);
+    let step = chunk_size.saturating_sub(chunk_overlap).max(1);
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let j = (i + chunk_size).min(tokens.len());
+        let span_start = floor_char_boundary(text, offsets[i]);
+        let span_end = if j == tokens.len() {
+            end
+        } else {
+            floor_char_boundary(text, offsets[j])
+        };
+        if span_end > span_start {
+            spans.push((span_start, span_end));
+        }
+        if j == tokens.len() {
+            break;
+        }
+        i += step;
+    }
+    spans
+}
+
+// Cut [start, end) into pieces of at most `chunk_size` tokens, preferring the coarsest separator
+fn split_pieces(
+    bpe: &CoreBPE,
+    text: &str,
+    start: usize,
+    end: usize,
+    chunk_size: usize,
+    separator_index: usize,
+) -> Vec<Span> {
+    if token_count(bpe, &text[start..end]) <= chunk_size {
+        return vec![(start, end)];
+    }
+    if separator_index >= RECURSIVE_SEPARATORS.len() {
+        // a single "word" bigger than a chunk, no choice but to cut it
+        return fixed_token_spans(bpe, text, start, end, chunk_size, 0);
+    }
+
+    // keep the separator at the end of the preceding piece so that pieces stay contiguous
+    let separator = RECURSIVE_SEPARATORS[separator_index];
+    let mut pieces = Vec::new();
+    let mut piece_start = start;
+    for (i, _) in text[start..end].match_indices(separator) {
+        let piece_end = start + i + separator.len();
+        pieces.push((piece_start, piece_end));
+        piece_start = piece_end;
+    }
+    if piece_start < end {
+        pieces.push((piece_start, end));
+    }
+
+    if pieces.len() <= 1 {
+        return split_pieces(bpe, text, start, end, chunk_size, separator_index + 1);
+    }
+    pieces
+        .into_iter()
+        .flat_map(|(s, e)| split_pieces(bpe, text, s, e, chunk_size, separator_index + 1))
+        .collect()
+}
+
+// Greedily merge contiguous pieces into chunks, repeating trailing pieces as overlap
+fn merge_pieces(
+    bpe: &CoreBPE,
+    text: &str,
+    pieces: &[Span],
+    chunk_size: usize,
+    chunk_overlap: usize,
+) -> Vec<Span> {
+    let counts: Vec<usize> = pieces
+        .iter()
+        .map(|(s, e)| token_count(bpe, &text[*s..*e]))
+        .collect();
+
+    let mut spans = Vec::new();
+    let mut first = 0;
+    let mut tokens = 0;
+    for i in 0..pieces.len() {
+        if tokens + counts[i] > chunk_size && i > first {
+            spans.push((pieces[first].0, pieces[i - 1].1));
+            let mut new_first = i;
+            let mut overlap = 0;
+            while new_first - 1 > first
+                && overlap + counts[new_first - 1] <= chunk_overlap
+                && overlap + counts[new_first - 1] + counts[i] <= chunk_size
+            {
+                new_first -= 1;
+                overlap += counts[new_first];
+            }
+            first = new_first;
+            tokens = overlap;
+        }
+        tokens += counts[i];
+    }
+    if first < pieces.len() {
+        spans.push((pieces[first].0, pieces[pieces.len() - 1].1));
+    }
+    spans
+}
+
+fn recursive_spans(
+    bpe: &CoreBPE,
+    text: &str,
+    start: usize,
+    end: usize,
+    chunk_size: usize,
+    chunk_overlap: usize,
+) -> Vec<Span> {
+    let chunk_size = chunk_size.max(1);
+    let pieces = split_pieces(bpe, text, start, end, chunk_size, 0);
+    merge_pieces(bpe, text, &pieces, chunk_size, chunk_overlap)
+}
+
+fn parse_heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.starts_with(' ') && !rest.starts_with('\t') {
+        return None;
+    }
+    let title = rest.trim().trim_end_matches('#').trim();
+    if title.is_empty() {
+        return None;
+    }
+    Some((level, title.to_string()))
+}
+
+// Returns the sections of a markdown document along with the titles of their enclosing headings
+fn markdown_sections(text: &str) -> Vec<(Span, Vec<String>)> {
+    let mut sections = Vec::new();
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut section_start = 0;
+    let mut section_path: Vec<String> = Vec::new();
+    let mut in_code_block = false;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code_block = !in_code_block;
+        } else if !in_code_block {
+            if let Some((level, title)) = parse_heading(trimmed) {
+                if offset > section_start {
+                    sections.push(((section_start, offset), section_path.clone()));
+                }
+                headings.retain(|(l, _)| *l < level);
+                headings.push((level, title));
+                section_path = headings.iter().map(|(_, t)| t.clone()).collect();
+                section_start = offset;
+            }
+        }
+        offset += line.len();
+    }
+    if offset > section_start {
+        sections.push(((section_start, offset), section_path));
+    }
+    sections
+}
+
+fn split_spans(
+    bpe: &CoreBPE,
+    text: &str,
+    strategy: &ChunkingStrategy,
+) -> Vec<(Span, HashMap<String, Value>)> {
+    match strategy {
+        ChunkingStrategy::FixedToken {
+            chunk_size,
+            chunk_overlap,
+        } => fixed_token_spans(bpe, text, 0, text.len(), *chunk_size, *chunk_overlap)
+            .into_iter()
+            .map(|span| (span, HashMap::new()))
+            .collect(),
+        ChunkingStrategy::Recursive {
+            chunk_size,
+            chunk_overlap,
+        }
+        | ChunkingStrategy::PdfPage {
+            chunk_size,
+            chunk_overlap,
+        } => recursive_spans(bpe, text, 0, text.len(), *chunk_size, *chunk_overlap)
+            .into_iter()
+            .map(|span| (span, HashMap::new()))
+            .collect(),
+        ChunkingStrategy::Markdown { chunk_size } => markdown_sections(text)
+            .into_iter()
+            .flat_map(|((start, end), heading_path)| {
+                let mut metadata = HashMap::new();
+                if let Some(heading) = heading_path.last() {
+                    metadata.insert("heading".to_string(), json!(heading));
+                    metadata.insert("heading_path".to_string(), json!(heading_path));
+                }
+                recursive_spans(bpe, text, start, end, *chunk_size, 0)
+                    .into_iter()
+                    .map(move |span| (span, metadata.clone()))
+            })
+            .collect(),
+    }
+}
+
+fn build_chunks(
+    text: &str,
+    spans: Vec<(Span, HashMap<String, Value>)>,
+    strategy: &ChunkingStrategy,
+    first_sequence: usize,
+    base_offset: usize,
+) -> Vec<PartialChunk> {
+    let strategy_value = serde_json::to_value(strategy).unwrap_or_default();
+    spans
+        .into_iter()
+        .filter(|((start, end), _)| !text[*start..*end].trim().is_empty())
+        .enumerate()
+        .map(|(i, ((start, end), mut metadata))| {
+            metadata.insert("chunking_strategy".to_string(), strategy_value.clone());
+            PartialChunk {
+                sequence: (first_sequence + i) as i32,
+                data: text[start..end].to_string(),
+                start_index: (base_offset + start) as i32,
+                end_index: (base_offset + end) as i32,
+                metadata,
+            }
+        })
+        .collect()
+}
+
+/// Splits a document into chunks according to the given strategy.
+///
+/// `start_index` and `end_index` are byte offsets in `text`. The strategy used is stored
+/// in the `chunking_strategy` key of every chunk's metadata.
+pub fn split_text(text: &str, strategy: &ChunkingStrategy) -> Vec<PartialChunk> {
+    let bpe = cl100k_base().unwrap();
+    let spans = split_spans(&bpe, text, strategy);
+    build_chunks(text, spans, strategy, 0, 0)
+}
+
+/// Splits an extracted document section by section so that a chunk never spans two
+/// pages, sheets or headings, the section metadata is copied into every chunk.
+///
+/// Offsets are relative to the sections joined with a newline, i.e. `ExtractedDocument::text()`.
+pub fn split_sections(
+    sections: &[ExtractedSection],
+    strategy: &ChunkingStrategy,
+) -> Vec<PartialChunk> {
+    let bpe = cl100k_base().unwrap();
+    let mut chunks = Vec::new();
+    let mut base_offset = 0;
+    for section in sections {
+        let spans = split_spans(&bpe, &section.text, strategy)
+            .into_iter()
+            .map(|(span, mut metadata)| {
+                for (key, value) in &section.metadata {
+                    metadata.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+                (span, metadata)
+            })
+            .collect();
+        let section_chunks =
+            build_chunks(&section.text, spans, strategy, chunks.len(), base_offset);
+        chunks.extend(section_chunks);
+        base_offset += section.text.len() + 1;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str =
+        "The president of the United States lives in the White House. He works in the Oval Office.
+
+The president of France lives in the Elysee Palace. It is located in Paris.
+
+The president of Mars is Elon Musk. Nobody knows where he lives.
+";
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(
+            ChunkingStrategy::parse("recursive").unwrap(),
+            ChunkingStrategy::Recursive {
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                chunk_overlap: DEFAULT_CHUNK_OVERLAP,
+            }
+        );
+        assert_eq!(
+            ChunkingStrategy::parse("{\"type\": \"markdown\", \"chunk_size\": 42}").unwrap(),
+            ChunkingStrategy::Markdown { chunk_size: 42 }
+        );
+        assert!(ChunkingStrategy::parse("unknown").is_err());
+
+        let mut metadata = HashMap::new();
+        metadata.insert("chunking_strategy".to_string(), json!("pdf_page"));
+        assert_eq!(
+            ChunkingStrategy::from_metadata(&Some(metadata)),
+            Some(ChunkingStrategy::pdf_default())
+        );
+    }
+
+    #[test]
+    fn test_fixed_token_overlap() {
+        let without_overlap = split_text(
+            TEXT,
+            &ChunkingStrategy::FixedToken {
+                chunk_size: 10,
+                chunk_overlap: 0,
+            },
+        );
+        let with_overlap = split_text(
+            TEXT,
+            &ChunkingStrategy::FixedToken {
+                chunk_size: 10,
+                chunk_overlap: 5,
+            },
+        );
+        assert!(with_overlap.len() > without_overlap.len());
+
+        // Without overlap the chunks are contiguous and rebuild the text
+        let rebuilt: String = without_overlap.iter().map(|c| c.data.as_str()).collect();
+        assert_eq!(rebuilt.trim_end(), TEXT.trim_end());
+        for chunk in &without_overlap {
+            assert_eq!(
+                &TEXT[chunk.start_index as usize..chunk.end_index as usize],
+                chunk.data
+            );
+        }
+
+        // With overlap, a chunk starts before the previous one ends
+        assert!(with_overlap[1].start_index < with_overlap[0].end_index);
+        assert_eq!(
+            with_overlap[0].metadata["chunking_strategy"]["type"],
+            "fixed_token"
+        );
+    }
+
+    #[test]
+    fn test_recursive_keeps_sentences() {
+        let chunks = split_text(
+            TEXT,
+            &ChunkingStrategy::Recursive {
+                chunk_size: 20,
+                chunk_overlap: 0,
+            },
+        );
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            let data = chunk.data.trim_end();
+            assert!(
+                data.ends_with('.'),
+                "Chunk should end at a sentence boundary: {:?}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_markdown_heading_metadata() {
+        let text = "# Guide\n\nIntro text.\n\n## Install\n\nRun the installer.\n\n```\n# not a heading\n```\n\n## Usage\n\nCall the API.\n";
+        let chunks = split_text(text, &ChunkingStrategy::Markdown { chunk_size: 100 });
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].metadata["heading"], "Guide");
+        assert_eq!(
+            chunks[1].metadata["heading_path"],
+            json!(["Guide", "Install"])
+        );
+        assert!(chunks[1].data.contains("# not a heading"));
+        assert_eq!(chunks[2].metadata["heading"], "Usage");
+    }
+
+    #[test]
+    fn test_split_sections() {
+        let pages: Vec<ExtractedSection> = ["First page.", "", "Third page."]
+            .iter()
+            .enumerate()
+            .map(|(i, text)| ExtractedSection {
+                text: text.to_string(),
+                metadata: HashMap::from([("page".to_string(), json!(i + 1))]),
+            })
+            .collect();
+        let chunks = split_sections(&pages, &ChunkingStrategy::pdf_default());
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].metadata["page"], 1);
+        assert_eq!(chunks[1].metadata["page"], 3);
+        assert_eq!(chunks[1].sequence, 1);
+        assert_eq!(chunks[1].start_index, "First page.\n\n".len() as i32);
+    }
+}
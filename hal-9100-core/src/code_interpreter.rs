@@ -3,8 +3,11 @@
 
 // docker run --rm louis030195/hal-9100-code-interpreter python -c "print(1+1)"
 
-use async_openai::types::FunctionObject;
-use async_recursion::async_recursion;
+use async_openai::types::{
+    FilePath, FunctionObject, ImageFile, MessageContent, MessageContentImageFileObject,
+    MessageContentTextAnnotations, MessageContentTextAnnotationsFilePathObject,
+    MessageContentTextObject, TextData,
+};
 
 use bollard::container::LogOutput;
 use bollard::container::{
@@ -15,34 +18,97 @@ use bollard::exec::StartExecResults;
 use bollard::image::CreateImageOptions;
 use bollard::models::HostConfig;
 use bollard::Docker;
+use bytes::Bytes;
 use futures::stream::StreamExt;
 use futures::TryStreamExt;
+use hal_9100_core::file_storage::FileStorage;
+use hal_9100_core::files::{create_file, get_files, NewFile};
 use hal_9100_core::function_calling::generate_function_call;
+use hal_9100_core::kernel_sessions::{KernelSessions, SessionVariable};
 use hal_9100_core::models::Function;
 use hal_9100_core::models::FunctionCallInput;
+use hal_9100_core::sandbox::{DockerSandbox, SandboxBackend};
+use hal_9100_extra::config::Hal9100Config;
 use hal_9100_extra::llm::{HalLLMClient, HalLLMRequestArgs};
-use log::info;
+use log::{error, info, warn};
+use serde::Deserialize;
 use serde_json::json;
-use std::collections::HashMap;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
-// TODO: later optimise stuff like: run docker container in the background, use a pool of docker containers, etc.
+pub(crate) const IMAGE: &str = "louis030195/hal-9100-code-interpreter:latest";
+// The runtime images come with Python too, which copies the files in and out
+const NODE_IMAGE: &str = "louis030195/hal-9100-code-interpreter-node:latest";
+const DUCKDB_IMAGE: &str = "louis030195/hal-9100-code-interpreter-duckdb:latest";
+// Runs the SQL read from stdin in an in-memory DuckDB and prints the result of the last statement
+pub(crate) const DUCKDB_SCRIPT: &str = "
+import sys, duckdb
+result = duckdb.connect().sql(sys.stdin.read())
+if result is not None:
+    result.show(max_rows=100, max_width=200)
+";
+// Hard limit on a single execution, the container is killed past it
+const EXECUTION_TIMEOUT: Duration = Duration::from_secs(60);
+/// Iterations of the interpreter loop when the assistant doesn't set any.
+pub const DEFAULT_MAX_ITERATIONS: usize = 3;
+const MAX_ITERATIONS_LIMIT: usize = 10;
+/// Assistant metadata key setting its iteration budget.
+pub const MAX_ITERATIONS_METADATA: &str = "code_interpreter_max_iterations";
+/// Assistant metadata key listing the languages it may run, e.g. "python,sql".
+pub const LANGUAGES_METADATA: &str = "code_interpreter_languages";
+// Past this, only the end of an output is shown to the model
+const MAX_ITERATION_OUTPUT_CHARS: usize = 4000;
+
+// Unprivileged user the code runs as ("nobody"), numeric so the image doesn't need to know it
+const SANDBOX_USER: &str = "65534:65534";
+// The only writable places in the sandbox, everything else is read only
+const WORK_DIR: &str = "/tmp";
+/// Where the assistant and run files are copied, under their original name.
+pub const DATA_DIR: &str = "/mnt/data";
+// Writes stdin to the path given as argument
+const WRITE_FILE_SCRIPT: &str =
+    "import shutil, sys; shutil.copyfileobj(sys.stdin.buffer, open(sys.argv[1], 'wb'))";
+// Prints the files under /mnt/data as JSON, except the ones given as arguments
+const LIST_FILES_SCRIPT: &str = "
+import base64, json, os, sys
+inputs, files = set(sys.argv[1:]), []
+for root, _, names in os.walk('/mnt/data'):
+    for name in sorted(names):
+        path = os.path.join(root, name)
+        if path in inputs or not os.path.isfile(path) or os.path.getsize(path) > int(os.environ['MAX_SIZE']):
+            continue
+        files.append({'path': path, 'content': base64.b64encode(open(path, 'rb').read()).decode()})
+print(json.dumps(files[:int(os.environ['MAX_FILES'])]))
+";
+// Generated files past these limits are left behind
+pub(crate) const MAX_GENERATED_FILE_SIZE: usize = 20 * 1024 * 1024;
+pub(crate) const MAX_GENERATED_FILES: usize = 10;
+/// Purpose of the files written by the code, as with OpenAI.
+pub const OUTPUT_FILE_PURPOSE: &str = "assistants_output";
+// Exit code of an exec killed by SIGKILL, which is what the OOM killer sends
+pub(crate) const KILLED_EXIT_CODE: i64 = 137;
+
+// Images pulled by this process
+static PULLED_IMAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
 // TODO: latr run multiple interpreters in parallel and use llm to take best output or smthing.
-// TODO: latr annotations
-// TODO: multi step - e.g. generate code, execute, then give result to next llm call, etc. LLM decide how many iterations it wants to do.
 
 use std::fmt;
 
 #[derive(Debug)]
 pub struct InterpreterError {
     message: String,
-    python_code: String,
+    code: String,
 }
 
 impl fmt::Display for InterpreterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}\nPython code: {}", self.message, self.python_code)
+        write!(f, "{}\nCode: {}", self.message, self.code)
     }
 }
 
@@ -50,7 +116,7 @@ impl From<bollard::errors::Error> for InterpreterError {
     fn from(err: bollard::errors::Error) -> InterpreterError {
         InterpreterError {
             message: format!("Docker error: {}", err),
-            python_code: String::new(),
+            code: String::new(),
         }
     }
 }
@@ -59,52 +125,967 @@ impl From<serde_json::Error> for InterpreterError {
     fn from(err: serde_json::Error) -> InterpreterError {
         InterpreterError {
             message: format!("JSON error: {}", err),
-            python_code: String::new(),
+            code: String::new(),
+        }
+    }
+}
+
+impl From<std::io::Error> for InterpreterError {
+    fn from(err: std::io::Error) -> InterpreterError {
+        InterpreterError {
+            message: format!("IO error: {}", err),
+            code: String::new(),
         }
     }
 }
 
 impl std::error::Error for InterpreterError {}
 
-#[async_recursion]
-pub async fn safe_interpreter(
-    user_input: String,
-    attempt: usize,
-    max_attempts: usize,
-    client: HalLLMClient,
-    request: HalLLMRequestArgs,
-) -> Result<(String, String), InterpreterError> {
-    if attempt >= max_attempts {
-        return Err(InterpreterError {
-            message: String::from("Max attempts reached"),
-            python_code: String::new(),
+impl InterpreterError {
+    pub(crate) fn new(message: String, code: &str) -> Self {
+        InterpreterError {
+            message,
+            code: code.to_string(),
+        }
+    }
+
+    // Errors carrying the code come from running it (timeout, out of memory...), not the sandbox
+    fn is_code_failure(&self) -> bool {
+        !self.code.is_empty()
+    }
+}
+
+/// What an execution printed, stderr kept apart so errors and warnings don't end up in results.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i64>,
+    /// Files the code wrote to /mnt/data
+    pub files: Vec<GeneratedFile>,
+    /// What the session holds after the execution, see kernel_sessions.rs
+    pub variables: Vec<SessionVariable>,
+}
+
+/// Runtimes the code can be written for, each has its own image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Python,
+    /// Node.js
+    JavaScript,
+    /// DuckDB SQL, which reads the files under /mnt/data directly
+    Sql,
+    /// POSIX shell
+    Shell,
+}
+
+impl Language {
+    pub const ALL: [Language; 4] = [
+        Language::Python,
+        Language::JavaScript,
+        Language::Sql,
+        Language::Shell,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::Python => "python",
+            Language::JavaScript => "javascript",
+            Language::Sql => "sql",
+            Language::Shell => "shell",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "python" | "py" => Some(Language::Python),
+            "javascript" | "js" | "node" => Some(Language::JavaScript),
+            "sql" | "duckdb" => Some(Language::Sql),
+            "shell" | "sh" | "bash" => Some(Language::Shell),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn image(&self) -> &'static str {
+        match self {
+            Language::Python | Language::Shell => IMAGE,
+            Language::JavaScript => NODE_IMAGE,
+            Language::Sql => DUCKDB_IMAGE,
+        }
+    }
+
+    /// How the code is run, it's fed through stdin.
+    pub(crate) fn command(&self) -> Vec<&'static str> {
+        match self {
+            Language::Python => vec!["python", "-"],
+            Language::JavaScript => vec!["node", "-"],
+            Language::Sql => vec!["python", "-c", DUCKDB_SCRIPT],
+            Language::Shell => vec!["sh", "-s"],
+        }
+    }
+
+    // What the model is told about it
+    fn description(&self) -> &'static str {
+        match self {
+            Language::Python => "python: Python 3 with math, pandas, numpy, matplotlib and scipy",
+            Language::JavaScript => "javascript: JavaScript run by Node.js, without npm packages",
+            Language::Sql => {
+                "sql: DuckDB SQL, query the files directly, e.g. SELECT * FROM '/mnt/data/data.csv'"
+            }
+            Language::Shell => "shell: POSIX shell with the usual command line tools",
+        }
+    }
+}
+
+/// Languages an assistant may run, from its `code_interpreter_languages` metadata (a list or a
+/// comma separated string). Only Python without it.
+pub fn allowed_languages(metadata: Option<&HashMap<String, serde_json::Value>>) -> Vec<Language> {
+    let names: Vec<String> = match metadata.and_then(|metadata| metadata.get(LANGUAGES_METADATA)) {
+        Some(serde_json::Value::String(names)) => {
+            names.split(',').map(|name| name.to_string()).collect()
+        }
+        Some(serde_json::Value::Array(names)) => names
+            .iter()
+            .filter_map(|name| name.as_str().map(String::from))
+            .collect(),
+        _ => vec![],
+    };
+    let mut languages: Vec<Language> = vec![];
+    for language in names.iter().filter_map(|name| Language::from_name(name)) {
+        if !languages.contains(&language) {
+            languages.push(language);
+        }
+    }
+    if languages.is_empty() {
+        languages.push(Language::Python);
+    }
+    languages
+}
+
+/// A file written by the code, not uploaded yet.
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct GeneratedFile {
+    pub path: String,
+    #[serde(deserialize_with = "deserialize_base64")]
+    pub content: Bytes,
+}
+
+// Outputs are logged, not their content
+impl fmt::Debug for GeneratedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} bytes)", self.path, self.content.len())
+    }
+}
+
+fn deserialize_base64<'de, D>(deserializer: D) -> Result<Bytes, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let encoded = String::deserialize(deserializer)?;
+    base64::decode(encoded)
+        .map(Bytes::from)
+        .map_err(serde::de::Error::custom)
+}
+
+// Extension and content type of the outputs we know, anything else is served as binary
+fn file_type(path: &str) -> (&str, &'static str) {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension)
+        .filter(|extension| !extension.contains('/'))
+        .unwrap_or_default();
+    let content_type = match extension.to_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "txt" | "md" => "text/plain",
+        "html" => "text/html",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    };
+    (extension, content_type)
+}
+
+/// A generated file once uploaded, `file_id` is what messages and steps refer to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedFile {
+    pub path: String,
+    pub file_id: String,
+    pub is_image: bool,
+}
+
+impl SavedFile {
+    /// How the file is referred to in messages, e.g. `sandbox:/mnt/data/chart.png`.
+    pub fn sandbox_path(&self) -> String {
+        format!("sandbox:{}", self.path)
+    }
+}
+
+/// Uploads the generated files and records them with the `assistants_output` purpose.
+/// Files that fail to upload are skipped.
+pub async fn save_generated_files(
+    pool: &PgPool,
+    file_storage: &FileStorage,
+    files: &[GeneratedFile],
+    user_id: &str,
+) -> Vec<SavedFile> {
+    let mut saved = Vec::new();
+    for file in files {
+        let (extension, content_type) = file_type(&file.path);
+        let uploaded = match file_storage
+            .upload_bytes(extension, file.content.clone())
+            .await
+        {
+            Ok(uploaded) => uploaded,
+            Err(e) => {
+                error!("Failed to upload generated file {}: {}", file.path, e);
+                continue;
+            }
+        };
+        let filename = file.path.rsplit('/').next().unwrap_or_default();
+        let created = create_file(
+            pool,
+            NewFile {
+                id: &uploaded.file.id,
+                filename,
+                bytes: uploaded.file.size as i64,
+                purpose: OUTPUT_FILE_PURPOSE,
+                content_type,
+                sha256: Some(&uploaded.sha256),
+                user_id,
+            },
+        )
+        .await;
+        if let Err(e) = created {
+            error!("Failed to save generated file {}: {}", file.path, e);
+            continue;
+        }
+        saved.push(SavedFile {
+            path: file.path.clone(),
+            file_id: uploaded.file.id,
+            is_image: content_type.starts_with("image/"),
         });
     }
+    saved
+}
 
-    match interpreter(client.clone(), request.clone()).await {
-        Ok((code_output, code)) => Ok((code_output, code)),
+/// Message content for an answer that may mention generated files: images come first as
+/// `image_file` content, mentions of the other files become `file_path` annotations (and the
+/// ones the answer doesn't mention are linked at the end).
+pub fn annotate_generated_files(text: &str, files: &[SavedFile]) -> Vec<MessageContent> {
+    let mut content: Vec<MessageContent> = files
+        .iter()
+        .filter(|file| file.is_image)
+        .map(|file| {
+            MessageContent::ImageFile(MessageContentImageFileObject {
+                r#type: "image_file".to_string(),
+                image_file: ImageFile {
+                    file_id: file.file_id.clone(),
+                },
+            })
+        })
+        .collect();
+
+    let mut value = text.to_string();
+    for file in files.iter().filter(|file| !file.is_image) {
+        if !value.contains(&file.path) {
+            let filename = file.path.rsplit('/').next().unwrap_or_default();
+            value.push_str(&format!("\n\n[{}]({})", filename, file.sandbox_path()));
+        }
+    }
+
+    let mut annotations = Vec::new();
+    for file in files.iter().filter(|file| !file.is_image) {
+        let sandbox_path = file.sandbox_path();
+        for (start, _) in value.match_indices(&file.path) {
+            // Mentions with the sandbox: prefix are annotated whole
+            let (start, mention) = match value[..start].strip_suffix("sandbox:") {
+                Some(before) => (before.len(), sandbox_path.as_str()),
+                None => (start, file.path.as_str()),
+            };
+            // Indices are in characters, like OpenAI's
+            let start_index = value[..start].chars().count() as u32;
+            annotations.push(MessageContentTextAnnotations::FilePath(
+                MessageContentTextAnnotationsFilePathObject {
+                    r#type: "file_path".to_string(),
+                    text: mention.to_string(),
+                    file_path: FilePath {
+                        file_id: file.file_id.clone(),
+                    },
+                    start_index,
+                    end_index: start_index + mention.chars().count() as u32,
+                },
+            ));
+        }
+    }
+
+    content.push(MessageContent::Text(MessageContentTextObject {
+        r#type: "text".to_string(),
+        text: TextData { value, annotations },
+    }));
+    content
+}
+
+/// A file copied into the sandbox before the code runs.
+#[derive(Debug, Clone)]
+pub struct SandboxFile {
+    pub file_id: String,
+    pub path: String,
+    pub content: Bytes,
+}
+
+impl SandboxFile {
+    /// One line telling the model what the file is, with the header of CSVs.
+    fn describe(&self) -> String {
+        let mut description = format!("- {} ({} bytes)", self.path, self.content.len());
+        if self.path.to_lowercase().ends_with(".csv") {
+            let mut reader = csv::Reader::from_reader(self.content.as_ref());
+            if let Ok(headers) = reader.headers() {
+                let columns: Vec<&str> = headers.iter().collect();
+                description.push_str(&format!(", CSV columns: {}", columns.join(", ")));
+            }
+        }
+        description
+    }
+}
+
+// Original filename without any directory, ids of files without metadata keep their extension
+fn sandbox_file_name(filename: Option<&str>, file_id: &str) -> String {
+    let name = filename
+        .and_then(|filename| filename.rsplit(['/', '\\']).next())
+        .map(str::trim)
+        .unwrap_or_default();
+    match name {
+        "" | "." | ".." => file_id.to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Fetches the files to copy into the sandbox, files that can't be read are skipped.
+pub async fn load_sandbox_files(
+    pool: &PgPool,
+    file_storage: &FileStorage,
+    file_ids: &[String],
+    user_id: &str,
+) -> Vec<SandboxFile> {
+    let filenames: HashMap<String, String> = match get_files(pool, file_ids, user_id).await {
+        Ok(files) => files
+            .into_iter()
+            .map(|file| (file.inner.id, file.inner.filename))
+            .collect(),
         Err(e) => {
-            eprintln!("Error: {}", e);
-            let input = format!(
-                "{}\n<error>You generated \n<code>\n{}\n</code>\n and it failed with error: {}. Please generate a DIFFERENT code that works.<error>",
-                user_input, e.python_code, e.message
-            );
-            safe_interpreter(input, attempt + 1, max_attempts, client, request).await
+            error!("Failed to get files metadata: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let mut paths = HashSet::new();
+    let mut files = Vec::new();
+    for file_id in file_ids {
+        let content = match file_storage.get_file_content(file_id).await {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Failed to retrieve file {}: {}", file_id, e);
+                continue;
+            }
+        };
+        let mut name = sandbox_file_name(filenames.get(file_id).map(String::as_str), file_id);
+        // Two files with the same name, the id tells them apart
+        if paths.contains(&name) {
+            name = format!("{}-{}", file_id, name);
+        }
+        if !paths.insert(name.clone()) {
+            continue;
+        }
+        files.push(SandboxFile {
+            file_id: file_id.clone(),
+            path: format!("{}/{}", DATA_DIR, name),
+            content,
+        });
+    }
+    files
+}
+
+/// Resources a sandbox container is allowed, from the `code_interpreter_*` config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SandboxLimits {
+    pub memory_mb: i64,
+    pub cpus: f64,
+    pub pids_limit: i64,
+    /// Docker network mode, "none" cuts the sandbox off the network
+    pub network_mode: String,
+}
+
+impl Default for SandboxLimits {
+    fn default() -> Self {
+        SandboxLimits {
+            memory_mb: 512,
+            cpus: 1.0,
+            pids_limit: 64,
+            network_mode: "none".to_string(),
+        }
+    }
+}
+
+impl SandboxLimits {
+    pub fn from_config(config: &Hal9100Config) -> Self {
+        SandboxLimits {
+            memory_mb: config.code_interpreter_memory_mb,
+            cpus: config.code_interpreter_cpus,
+            pids_limit: config.code_interpreter_pids_limit,
+            network_mode: config.code_interpreter_network_mode.clone(),
+        }
+    }
+
+    fn host_config(&self) -> HostConfig {
+        let memory = self.memory_mb * 1024 * 1024;
+        HostConfig {
+            memory: Some(memory),
+            // Same as memory, so no swap
+            memory_swap: Some(memory),
+            nano_cpus: Some((self.cpus * 1e9) as i64),
+            pids_limit: Some(self.pids_limit),
+            network_mode: Some(self.network_mode.clone()),
+            readonly_rootfs: Some(true),
+            tmpfs: Some(HashMap::from([
+                (
+                    WORK_DIR.to_string(),
+                    "rw,nosuid,nodev,size=256m".to_string(),
+                ),
+                (DATA_DIR.to_string(), "rw,nosuid,nodev,size=1g".to_string()),
+            ])),
+            cap_drop: Some(vec!["ALL".to_string()]),
+            security_opt: Some(vec!["no-new-privileges".to_string()]),
+            ..Default::default()
+        }
+    }
+}
+
+// Installed by the executor at startup, the defaults apply otherwise
+static SANDBOX_LIMITS: RwLock<Option<SandboxLimits>> = RwLock::new(None);
+
+pub fn set_sandbox_limits(limits: SandboxLimits) {
+    *SANDBOX_LIMITS.write().unwrap() = Some(limits);
+}
+
+pub fn sandbox_limits() -> SandboxLimits {
+    SANDBOX_LIMITS.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Pulls the interpreter image if it isn't there yet, only once per process.
+/// Called when the executor starts so the first run doesn't wait for it.
+pub async fn pull_image() -> Result<(), InterpreterError> {
+    ensure_image(IMAGE).await
+}
+
+/// Pulls an image unless this process already did or it's there.
+pub(crate) async fn ensure_image(image: &str) -> Result<(), InterpreterError> {
+    if PULLED_IMAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|pulled| pulled == image)
+    {
+        return Ok(());
+    }
+    let docker = Docker::connect_with_local_defaults()?;
+    if docker.inspect_image(image).await.is_err() {
+        info!("Pulling {}...", image);
+        docker
+            .create_image(
+                Some(CreateImageOptions {
+                    from_image: image,
+                    ..Default::default()
+                }),
+                None,
+                None,
+            )
+            .try_collect::<Vec<_>>()
+            .await?;
+    }
+    PULLED_IMAGES.lock().unwrap().push(image.to_string());
+    Ok(())
+}
+
+/// Force removes its container when dropped, so nothing is left behind if the execution
+/// panics or its future is cancelled. Prefer `remove` to wait for the removal.
+pub(crate) struct ContainerGuard {
+    docker: Docker,
+    id: Option<String>,
+}
+
+impl ContainerGuard {
+    pub(crate) fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) async fn remove(&mut self) {
+        if let Some(id) = self.id.take() {
+            remove_container(&self.docker, &id).await;
         }
     }
 }
 
-async fn interpreter(
+impl Drop for ContainerGuard {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            let docker = self.docker.clone();
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn(async move { remove_container(&docker, &id).await });
+                }
+                Err(_) => warn!("Can't remove container {}, no runtime left", id),
+            }
+        }
+    }
+}
+
+async fn remove_container(docker: &Docker, id: &str) {
+    let options = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    if let Err(e) = docker.remove_container(id, Some(options)).await {
+        warn!("Failed to remove container {}: {}", id, e);
+    }
+}
+
+pub(crate) fn sandbox_config() -> Config<String> {
+    Config {
+        image: Some(IMAGE.to_string()),
+        attach_stdin: Some(true),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        open_stdin: Some(true),
+        tty: Some(true),
+        user: Some(SANDBOX_USER.to_string()),
+        working_dir: Some(WORK_DIR.to_string()),
+        // matplotlib and friends want a writable home
+        env: Some(vec![
+            format!("HOME={}", WORK_DIR),
+            format!("MPLCONFIGDIR={}", WORK_DIR),
+        ]),
+        host_config: Some(sandbox_limits().host_config()),
+        ..Default::default()
+    }
+}
+
+/// Creates and starts a sandbox container, removed when the returned guard is dropped.
+pub(crate) async fn start_sandbox(
+    docker: &Docker,
+    config: Config<String>,
+) -> Result<ContainerGuard, InterpreterError> {
+    // Unique so concurrent runs don't collide
+    let name = format!("hal-9100-code-interpreter-{}", Uuid::new_v4());
+    let options = CreateContainerOptions {
+        name: name.clone(),
+        ..Default::default()
+    };
+    let container = docker.create_container(Some(options), config).await?;
+    let guard = ContainerGuard {
+        docker: docker.clone(),
+        id: Some(container.id),
+    };
+
+    info!("Starting Docker container {}...", name);
+    docker
+        .start_container(guard.id(), None::<StartContainerOptions<String>>)
+        .await?;
+    Ok(guard)
+}
+
+/// Where the code runs and what it's given.
+#[derive(Clone, Default)]
+pub struct ExecutionContext<'a> {
+    /// Sandbox set up from the config, a fresh docker container per execution without
+    pub backend: Option<Arc<dyn SandboxBackend>>,
+    /// Kernels kept per thread, used along with `thread_id`
+    pub sessions: Option<Arc<KernelSessions>>,
+    pub thread_id: Option<&'a str>,
+    /// Copied to /mnt/data before the code runs
+    pub files: &'a [SandboxFile],
+}
+
+/// Runs Python code in the kernel of the thread when sessions are on, anything else in the
+/// sandbox backend. So does Python when there's no room for another session.
+async fn execute_code(
+    language: Language,
+    code: &str,
+    timeout: Duration,
+    context: &ExecutionContext<'_>,
+) -> Result<ExecutionOutput, InterpreterError> {
+    if let (Some(sessions), Some(thread_id), Language::Python) =
+        (&context.sessions, context.thread_id, language)
+    {
+        if let Some(output) = sessions
+            .execute(thread_id, code, timeout, context.files)
+            .await?
+        {
+            return Ok(output);
+        }
+        info!(
+            "No room for a code interpreter session, {} runs in a fresh sandbox",
+            thread_id
+        );
+    }
+
+    let backend = context
+        .backend
+        .clone()
+        .unwrap_or_else(|| Arc::new(DockerSandbox::new(None)));
+    backend
+        .execute(language, code, timeout, context.files)
+        .await
+}
+
+// Runs a command without a shell, `stdin` is written then closed to mark its end
+pub(crate) async fn exec_with_stdin(
+    docker: &Docker,
+    container_id: &str,
+    cmd: Vec<&str>,
+    stdin: &[u8],
+) -> Result<ExecutionOutput, InterpreterError> {
+    let exec = docker
+        .create_exec(
+            container_id,
+            CreateExecOptions {
+                attach_stdin: Some(true),
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                cmd: Some(cmd),
+                ..Default::default()
+            },
+        )
+        .await?
+        .id;
+
+    let mut output = ExecutionOutput::default();
+    if let StartExecResults::Attached {
+        output: mut stream,
+        mut input,
+    } = docker.start_exec(&exec, None).await?
+    {
+        input.write_all(stdin).await?;
+        input.shutdown().await?;
+        while let Some(message) = stream.next().await {
+            match message? {
+                LogOutput::StdOut { message } => {
+                    output.stdout.push_str(&String::from_utf8_lossy(&message));
+                }
+                LogOutput::StdErr { message } => {
+                    output.stderr.push_str(&String::from_utf8_lossy(&message));
+                }
+                _ => (),
+            }
+        }
+    }
+    output.exit_code = docker.inspect_exec(&exec).await?.exit_code;
+    Ok(output)
+}
+
+pub(crate) async fn copy_files(
+    docker: &Docker,
+    container_id: &str,
+    files: &[SandboxFile],
+) -> Result<(), InterpreterError> {
+    for file in files {
+        let cmd = vec!["python", "-c", WRITE_FILE_SCRIPT, &file.path];
+        let output = exec_with_stdin(docker, container_id, cmd, &file.content).await?;
+        if output.exit_code != Some(0) {
+            return Err(InterpreterError {
+                message: format!(
+                    "Failed to copy {} into the sandbox: {}",
+                    file.path, output.stderr
+                ),
+                code: String::new(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Files under /mnt/data, except the `exclude`d paths (e.g. the inputs).
+pub(crate) async fn collect_files(
+    docker: &Docker,
+    container_id: &str,
+    exclude: &[&str],
+) -> Result<Vec<GeneratedFile>, InterpreterError> {
+    let mut cmd = vec!["env".to_string()];
+    cmd.push(format!("MAX_SIZE={}", MAX_GENERATED_FILE_SIZE));
+    cmd.push(format!("MAX_FILES={}", MAX_GENERATED_FILES));
+    cmd.extend(["python", "-c", LIST_FILES_SCRIPT].map(String::from));
+    cmd.extend(exclude.iter().map(|path| path.to_string()));
+    let cmd = cmd.iter().map(String::as_str).collect();
+    let output = exec_with_stdin(docker, container_id, cmd, &[]).await?;
+    if output.exit_code != Some(0) {
+        return Err(InterpreterError {
+            message: format!("Failed to collect the generated files: {}", output.stderr),
+            code: String::new(),
+        });
+    }
+    Ok(serde_json::from_str(&output.stdout)?)
+}
+
+pub(crate) fn killed(code: &str) -> InterpreterError {
+    InterpreterError::new(
+        format!(
+            "Code execution was killed, it most likely ran out of memory (limit is {} MB)",
+            sandbox_limits().memory_mb
+        ),
+        code,
+    )
+}
+
+/// Copies the files then runs the code (e.g. with `python -`), fed through stdin: no shell or
+/// file in between to escape it for. The files it writes to /mnt/data are collected after a
+/// success.
+pub(crate) async fn run_in_container(
+    docker: &Docker,
+    container_id: &str,
+    language: Language,
+    code: &str,
+    files: &[SandboxFile],
+) -> Result<ExecutionOutput, InterpreterError> {
+    copy_files(docker, container_id, files).await?;
+    let mut output =
+        exec_with_stdin(docker, container_id, language.command(), code.as_bytes()).await?;
+    if output.exit_code == Some(KILLED_EXIT_CODE) {
+        return Err(killed(code));
+    }
+    if output.exit_code == Some(0) {
+        let inputs: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        output.files = collect_files(docker, container_id, &inputs).await?;
+    }
+    Ok(output)
+}
+
+/// One round of the interpreter loop: the code the model wrote and what running it gave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeIteration {
+    pub language: Language,
+    pub code: String,
+    pub output: ExecutionOutput,
+}
+
+impl CodeIteration {
+    pub fn succeeded(&self) -> bool {
+        self.output.exit_code == Some(0)
+    }
+
+    // What the model sees of it in the next round
+    fn describe(&self, number: usize) -> String {
+        let mut description = format!(
+            "<iteration {}>\n<code language=\"{}\">\n{}\n</code>\n",
+            number,
+            self.language.name(),
+            self.code
+        );
+        if self.succeeded() {
+            description.push_str(&format!(
+                "<output>\n{}\n</output>\n",
+                truncate(&self.output.stdout, MAX_ITERATION_OUTPUT_CHARS)
+            ));
+        } else {
+            description.push_str(&format!(
+                "<error>\n{}\n</error>\n",
+                truncate(&self.output.stderr, MAX_ITERATION_OUTPUT_CHARS)
+            ));
+        }
+        for file in &self.output.files {
+            description.push_str(&format!("<file>{}</file>\n", file.path));
+        }
+        description.push_str(&format!("</iteration {}>", number));
+        description
+    }
+}
+
+// Keeps the end of long outputs, where errors and results usually are
+fn truncate(text: &str, max_chars: usize) -> String {
+    let count = text.chars().count();
+    if count <= max_chars {
+        return text.to_string();
+    }
+    let end: String = text.chars().skip(count - max_chars).collect();
+    format!("[...]{}", end)
+}
+
+/// Iterations an assistant gets, from its `code_interpreter_max_iterations` metadata.
+pub fn max_iterations(metadata: Option<&HashMap<String, serde_json::Value>>) -> usize {
+    metadata
+        .and_then(|metadata| metadata.get(MAX_ITERATIONS_METADATA))
+        .and_then(|value| match value {
+            serde_json::Value::Number(number) => number.as_u64(),
+            serde_json::Value::String(text) => text.parse().ok(),
+            _ => None,
+        })
+        .map(|iterations| (iterations as usize).clamp(1, MAX_ITERATIONS_LIMIT))
+        .unwrap_or(DEFAULT_MAX_ITERATIONS)
+}
+
+/// Lets the model write code, run it and look at the result, until it says it's done or runs out
+/// of iterations. Failed executions (by exit code, timeout or out of memory) are shown to the
+/// model so it can fix its code, only errors of the sandbox itself end the loop early.
+pub async fn run_code_interpreter(
+    client: HalLLMClient,
+    request: HalLLMRequestArgs,
+    max_iterations: usize,
+    languages: &[Language],
+    context: &ExecutionContext<'_>,
+) -> Result<Vec<CodeIteration>, InterpreterError> {
+    let languages = if languages.is_empty() {
+        &[Language::Python][..]
+    } else {
+        languages
+    };
+    let mut iterations: Vec<CodeIteration> = Vec::new();
+    while iterations.len() < max_iterations {
+        let next_code = generate_code(
+            client.clone(),
+            request.clone(),
+            languages,
+            context,
+            &iterations,
+        )
+        .await?;
+        let (language_name, code) = match next_code {
+            Some(next_code) => next_code,
+            None => break,
+        };
+
+        let language = Language::from_name(&language_name)
+            .filter(|language| languages.contains(language))
+            .unwrap_or(languages[0]);
+        let output = if Language::from_name(&language_name) != Some(language) {
+            // Shown to the model, which can rewrite the code in a language it has
+            ExecutionOutput {
+                stderr: format!(
+                    "Language \"{}\" is not available, use one of: {}",
+                    language_name,
+                    languages
+                        .iter()
+                        .map(Language::name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                ..Default::default()
+            }
+        } else {
+            match execute_code(language, &code, EXECUTION_TIMEOUT, context).await {
+                Ok(output) => output,
+                Err(e) if e.is_code_failure() => ExecutionOutput {
+                    stderr: e.message,
+                    ..Default::default()
+                },
+                Err(e) => return Err(e),
+            }
+        };
+        info!(
+            "Code interpreter iteration {} ({}) output: {:?}",
+            iterations.len() + 1,
+            language.name(),
+            output
+        );
+        iterations.push(CodeIteration {
+            language,
+            code,
+            output,
+        });
+    }
+
+    if iterations.is_empty() {
+        return Err(InterpreterError {
+            message: "The model did not generate any code".to_string(),
+            code: String::new(),
+        });
+    }
+    Ok(iterations)
+}
+
+// Language and code to run next, none when the model considers the request answered
+async fn generate_code(
     client: HalLLMClient,
     mut request: HalLLMRequestArgs,
-) -> Result<(String, String), InterpreterError> {
-    info!("Generating Python code...");
+    languages: &[Language],
+    context: &ExecutionContext<'_>,
+    iterations: &[CodeIteration],
+) -> Result<Option<(String, String)>, InterpreterError> {
+    info!("Generating code...");
+    let files = context.files;
 
     let user_input = request.get_user_prompt().unwrap();
     // ! TODO: should use system prompt?
+    let files_rule = if files.is_empty() {
+        "There are no files on disk. DO NOT TRY TO READ FILES. DO NOT DO THINGS LIKE: pd.read_csv('startups.csv')".to_string()
+    } else {
+        format!(
+            "These files are on disk, read them from these exact paths and do not try to read any other file:\n{}",
+            files
+                .iter()
+                .map(SandboxFile::describe)
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    };
+    // Previous runs of the thread may have left variables in the session
+    let variables = match (&context.sessions, context.thread_id) {
+        (Some(sessions), Some(thread_id)) => sessions.variables(thread_id).await,
+        _ => vec![],
+    };
+    let session_rule = if variables.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "\n- The code runs in a session where previous code defined these variables, reuse them instead of computing them again:\n{}",
+            variables
+                .iter()
+                .map(|variable| format!("  - {}", variable))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    };
+    // Python only keeps the original prompt
+    let languages_rule = if languages == [Language::Python] {
+        String::new()
+    } else {
+        format!(
+            "\n- Write the code in the language that fits the request best and set it in the language field. Python is used in the examples below, these are available:\n{}",
+            languages
+                .iter()
+                .map(|language| format!("  - {}", language.description()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    };
+    let iterations_section = if iterations.is_empty() {
+        "This is the first iteration, action must be \"run\".".to_string()
+    } else {
+        format!(
+            "This is what the previous iterations ran and gave:\n\n{}\n\nIf this answers the request, action is \"finish\". Otherwise run more code, and if an iteration failed, fix it.{}",
+            iterations
+                .iter()
+                .enumerate()
+                .map(|(i, iteration)| iteration.describe(i + 1))
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+            if context.sessions.is_some() && context.thread_id.is_some() {
+                ""
+            } else {
+                " Each iteration starts from scratch, variables of the previous ones are gone."
+            }
+        )
+    };
+    // The prompt only talks about Python when nothing else can run
+    let (code_name, libraries) = if languages == [Language::Python] {
+        ("Python code", "You can use these libraries")
+    } else {
+        ("code", "In Python, you can use these libraries")
+    };
     let build_prompt = |user_input: &str| {
         format!("
-You are an Assistant that generate Python code to based user request to do complex computations. We execute the code you will generate and return the result to the user.
+You are an Assistant that generate {code_name} to based user request to do complex computations. We execute the code you will generate and return the result to the user.
 Given this user request
 
 <user>
@@ -113,21 +1094,20 @@ Given this user request
 
 </user>
 
-Generate Python code that we will execute and return the result to the user.
+Generate {code_name} that we will execute and show you the output of. You can then run more code (e.g. to fix an error or to go further with the result), or finish when the output answers the request.
 
 Rules:
-- You can use these libraries: mathm, pandas, numpy, matplotlib, scipy. Do not use functions or code you have no access to.
-- Only return Python code. If you return anything else it will trigger a chain reaction that will destroy the universe. All humans will die and you will disappear from existence.
+- {libraries}: mathm, pandas, numpy, matplotlib, scipy. Do not use functions or code you have no access to.
+- Only return {code_name}. If you return anything else it will trigger a chain reaction that will destroy the universe. All humans will die and you will disappear from existence.
 - Make sure to use the right numbers e.g. with the user ask for the square root of 2, you should return math.sqrt(2) and not math.sqrt(pd.DataFrame({{'A': [1, 2, 3], 'B': [4, 5, 6]}})).
 - Do not use any library if it's simple math (e.g. no need to use pandas to compute the square root of 2)
-- Sometimes the user provide you an error, make sure to write a Python code that will work
+- Sometimes the user provide you an error, make sure to write {code_name} that will work
 - IF YOU DO NOT FIX YOUR CODE THAT ERRORED A HUMAN WILL DIE. DO NOT GENERATE THE SAME CODE THAT PREVIOUSLY FAILED
 - DO NOT USE ```python YOUR CODE...``` (CODE BLOCKS) OR A HUMAN WILL DIE
-- ALWAYS USE SINGLE QUOTES IN YOUR CODE (e.g. '), NOT DOUBLE QUOTES OR A HUMAN WILL DIE
 - Always try to simplify the math problem you're given by generating code that will compute simpler numbers. Your answer might be used by another Assistant that might not be good at math.
-- Be extra careful with escaping, this is wrong for example: import pandas as pd\\nprices = pd.read\\_csv('prices.csv')\\nprice\\_with\\_highest\\_demand = startups['demand'].idxmax()\\nprint(price\\_with\\_highest\\_demand)
-- Make sure to use existing files, by default there is no files written on disk. DO NOT TRY TO READ FILES. YOU DONT HAVE ANY FILES. DO NOT DO THINGS LIKE: pd.read_csv('startups.csv')
-- Make sure to surround strings by single quotes, e.g. don't do this: print(Hello world) but do this: print('Hello world')
+- {}{}{}
+- To give the user a file (a chart with plt.savefig, a CSV...), save it in /mnt/data
+- Make sure to surround strings by quotes, e.g. don't do this: print(Hello world) but do this: print('Hello world')
 
 A few examples:
 
@@ -149,7 +1129,7 @@ series_a_dilution = (series_a_raise_amount / series_a_post_money_valuation) * 10
 
 print('Founders dilution: ' + str(founders_dilution) + '%')
 
-So generate the Python code that we will execute that can help the user with his request.
+So generate the {code_name} that we will execute that can help the user with his request.
 
 Bad example:
 
@@ -162,17 +1142,19 @@ print('The standard deviation is: ' + str(std_dev))
 
 It's bad because \"mean\" function is not defined. You could have used: \"mean = sum(numbers) / len(numbers)\".
 
+{}
+
 <user>
 
 {}
 
 </user>
-        ", user_input, user_input)
+        ", user_input, files_rule, session_rule, languages_rule, iterations_section, user_input)
     };
 
     request.set_last_user_prompt(build_prompt(&user_input));
 
-    // Generate Python code
+    // Generate the code
     let function_call_input = FunctionCallInput {
         function: Function {
             metadata: None,
@@ -180,14 +1162,27 @@ It's bad because \"mean\" function is not defined. You could have used: \"mean =
             user_id: Uuid::default().to_string(),
             inner: FunctionObject {
                 name: "exec".to_string(),
-                description: Some("A function that executes Python code".to_string()),
+                description: Some(
+                    "A function that executes code, or finishes when the request is answered"
+                        .to_string(),
+                ),
                 parameters: Some(json!({
                     "type": "object",
-                    "required": ["code"],
+                    "required": ["action"],
                     "properties": {
+                        "action": {
+                            "type": "string",
+                            "enum": ["run", "finish"],
+                            "description": "run to execute the code, finish when the previous outputs answer the request"
+                        },
+                        "language": {
+                            "type": "string",
+                            "enum": languages.iter().map(Language::name).collect::<Vec<_>>(),
+                            "description": "The language of the code"
+                        },
                         "code": {
                             "type": "string",
-                            "description": "The Python code to execute"
+                            "description": "The code to execute when action is run"
                         }
                     }
                 })),
@@ -200,129 +1195,379 @@ It's bad because \"mean\" function is not defined. You could have used: \"mean =
     let function_result = generate_function_call(function_call_input)
         .await
         .map_err(|e| InterpreterError {
-            message: format!("Failed to generate Python code at function call: {}", e),
-            python_code: String::new(),
+            message: format!("Failed to generate code at function call: {}", e),
+            code: String::new(),
         })?;
     println!("Function result: {:?}", function_result);
-    let python_code = function_result.arguments;
-    let python_code: HashMap<String, String> = serde_json::from_str(&python_code)?;
-    let python_code = python_code
-        .get("code")
-        .expect("Expected 'code' field in the function result");
-    let python_code = python_code.replace("```python", "").replace("```", "");
-
-    // Connect to Docker
-    let docker = Docker::connect_with_local_defaults()?;
-    // Pull the image from GHCR
-    docker
-        .create_image(
-            Some(CreateImageOptions {
-                from_image: "louis030195/hal-9100-code-interpreter:latest",
-                ..Default::default()
-            }),
-            None,
-            None,
+    let next_step: NextStep = serde_json::from_str(&function_result.arguments)?;
+    let code = strip_code_fences(&next_step.code.unwrap_or_default());
+    // Code without an action is run, the first iteration always runs something
+    let finished = next_step.action.as_deref() == Some("finish") && !iterations.is_empty();
+    if finished || code.trim().is_empty() {
+        return Ok(None);
+    }
+    // Models that don't know about languages write Python
+    let language = next_step
+        .language
+        .unwrap_or_else(|| languages[0].name().to_string());
+    Ok(Some((language, code)))
+}
+
+#[derive(Deserialize)]
+struct NextStep {
+    action: Option<String>,
+    language: Option<String>,
+    code: Option<String>,
+}
+
+// Drops the ``` lines models wrap code in despite the prompt
+fn strip_code_fences(code: &str) -> String {
+    code.lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+        ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent, Role,
+    };
+    use dotenv::dotenv;
+    use hal_9100_extra::openai::Message;
+
+    #[tokio::test]
+    async fn test_execute_python_concurrently() {
+        let (first, second) = tokio::join!(
+            execute_code(
+                Language::Python,
+                "print(6 * 7)",
+                EXECUTION_TIMEOUT,
+                &ExecutionContext::default()
+            ),
+            execute_code(
+                Language::Python,
+                "print(2 ** 10)",
+                EXECUTION_TIMEOUT,
+                &ExecutionContext::default()
+            ),
+        );
+        assert_eq!(first.unwrap().stdout.trim(), "42");
+        assert_eq!(second.unwrap().stdout.trim(), "1024");
+    }
+
+    #[tokio::test]
+    async fn test_execute_python_no_shell_interpolation() {
+        let code = r#"import sys
+print("double \"quotes\", $HOME, `id` and a \\ backslash")
+print('oops', file=sys.stderr)"#;
+        let output = execute_code(
+            Language::Python,
+            code,
+            EXECUTION_TIMEOUT,
+            &ExecutionContext::default(),
         )
-        .try_collect::<Vec<_>>()
-        .await?;
+        .await
+        .unwrap();
+        assert_eq!(
+            output.stdout.trim(),
+            r#"double "quotes", $HOME, `id` and a \ backslash"#
+        );
+        assert_eq!(output.stderr.trim(), "oops");
+        assert_eq!(output.exit_code, Some(0));
+
+        let output = execute_code(
+            Language::Python,
+            "raise ValueError('nope')",
+            EXECUTION_TIMEOUT,
+            &ExecutionContext::default(),
+        )
+        .await
+        .unwrap();
+        assert!(output.stdout.is_empty());
+        assert!(output.stderr.contains("ValueError: nope"), "{:?}", output);
+        assert_eq!(output.exit_code, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_execute_python_with_files() {
+        let file = SandboxFile {
+            file_id: "file-1.csv".to_string(),
+            path: format!(
+                "{}/{}",
+                DATA_DIR,
+                sandbox_file_name(Some("startups.csv"), "file-1.csv")
+            ),
+            content: Bytes::from("Startup,Revenue\nStartupA,500000\nStartupB,300000\n"),
+        };
+        assert_eq!(
+            file.describe(),
+            "- /mnt/data/startups.csv (48 bytes), CSV columns: Startup, Revenue"
+        );
 
-    // Create Docker container
-    let config = Config {
-        image: Some("louis030195/hal-9100-code-interpreter:latest"),
-        host_config: Some(HostConfig {
-            auto_remove: Some(true),
+        let code = "import pandas as pd
+df = pd.read_csv('/mnt/data/startups.csv')
+print(df['Revenue'].sum())";
+        let context = ExecutionContext {
+            files: &[file],
             ..Default::default()
-        }),
-        attach_stdin: Some(true),
-        attach_stdout: Some(true),
-        attach_stderr: Some(true),
-        open_stdin: Some(true),
-        tty: Some(true),
-        ..Default::default()
-    };
+        };
+        let output = execute_code(Language::Python, code, EXECUTION_TIMEOUT, &context)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout.trim(), "800000", "{:?}", output);
+    }
 
-    let options = CreateContainerOptions {
-        name: "my-python-container",
-    };
-    let container = docker.create_container(Some(options), config).await?;
+    #[tokio::test]
+    async fn test_execute_other_languages() {
+        let file = SandboxFile {
+            file_id: "file-1.csv".to_string(),
+            path: "/mnt/data/startups.csv".to_string(),
+            content: Bytes::from("Startup,Revenue\nStartupA,500000\nStartupB,300000\n"),
+        };
+        let context = ExecutionContext {
+            files: &[file],
+            ..Default::default()
+        };
 
-    info!("Starting Docker container...");
+        let code = "SELECT sum(Revenue) AS total FROM '/mnt/data/startups.csv';";
+        let output = execute_code(Language::Sql, code, EXECUTION_TIMEOUT, &context)
+            .await
+            .unwrap();
+        assert_eq!(output.exit_code, Some(0), "{:?}", output);
+        assert!(output.stdout.contains("800000"), "{:?}", output);
 
-    // Start Docker container
-    docker
-        .start_container(&container.id, None::<StartContainerOptions<String>>)
-        .await?;
+        let code = "console.log([1, 2, 3].reduce((a, b) => a + b));";
+        let output = execute_code(Language::JavaScript, code, EXECUTION_TIMEOUT, &context)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout.trim(), "6", "{:?}", output);
 
-    // Write Python code to a file in the Docker container and execute it
-    let python_file_path = "/tmp/script.py";
-    let bash_command = format!(
-        "echo -e \"{}\" > {} && python {}",
-        python_code, python_file_path, python_file_path
-    );
+        let code = "wc -l < /mnt/data/startups.csv > /mnt/data/count.txt && echo done";
+        let output = execute_code(Language::Shell, code, EXECUTION_TIMEOUT, &context)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout.trim(), "done", "{:?}", output);
+        assert_eq!(output.files[0].path, "/mnt/data/count.txt");
+    }
 
-    let exec = docker
-        .create_exec(
-            &container.id,
-            CreateExecOptions {
-                attach_stdout: Some(true),
-                attach_stderr: Some(true),
-                cmd: Some(vec!["/bin/bash", "-c", &bash_command]),
-                ..Default::default()
-            },
-        )
-        .await?
-        .id;
-    let mut exec_stream_result = docker.start_exec(&exec, None);
+    #[tokio::test]
+    async fn test_execute_python_generated_files() {
+        let input = SandboxFile {
+            file_id: "file-1.csv".to_string(),
+            path: "/mnt/data/input.csv".to_string(),
+            content: Bytes::from("a,b\n1,2\n"),
+        };
+        let code = "import matplotlib.pyplot as plt
+import pandas as pd
+df = pd.read_csv('/mnt/data/input.csv')
+df.to_csv('/mnt/data/output.csv', index=False)
+plt.plot([1, 2, 3])
+plt.savefig('/mnt/data/chart.png')";
+        let context = ExecutionContext {
+            files: &[input],
+            ..Default::default()
+        };
+        let output = execute_code(Language::Python, code, EXECUTION_TIMEOUT, &context)
+            .await
+            .unwrap();
+        let paths: Vec<&str> = output.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, vec!["/mnt/data/chart.png", "/mnt/data/output.csv"]);
+        assert!(output.files[0].content.starts_with(b"\x89PNG"));
+        assert_eq!(output.files[1].content, Bytes::from("a,b\n1,2\n"));
+    }
 
-    let mut output = String::new();
-    while let Some(Ok(msg)) = exec_stream_result.next().await {
-        match msg {
-            StartExecResults::Attached { log, .. } => match log {
-                LogOutput::StdOut { message } => {
-                    output.push_str(&String::from_utf8(message.to_vec()).unwrap());
-                }
-                LogOutput::StdErr { message } => {
-                    output.push_str(&String::from_utf8(message.to_vec()).unwrap());
-                }
-                _ => (),
+    #[test]
+    fn test_annotate_generated_files() {
+        let files = vec![
+            SavedFile {
+                path: "/mnt/data/chart.png".to_string(),
+                file_id: "file-chart.png".to_string(),
+                is_image: true,
             },
-            _ => (),
+            SavedFile {
+                path: "/mnt/data/data.csv".to_string(),
+                file_id: "file-data.csv".to_string(),
+                is_image: false,
+            },
+            SavedFile {
+                path: "/mnt/data/other.csv".to_string(),
+                file_id: "file-other.csv".to_string(),
+                is_image: false,
+            },
+        ];
+        let content = annotate_generated_files("Voilà: sandbox:/mnt/data/data.csv", &files);
+        assert_eq!(content.len(), 2);
+        match &content[0] {
+            MessageContent::ImageFile(image) => {
+                assert_eq!(image.image_file.file_id, "file-chart.png")
+            }
+            other => panic!("expected an image, got {:?}", other),
         }
+        let text = match &content[1] {
+            MessageContent::Text(text) => &text.text,
+            other => panic!("expected text, got {:?}", other),
+        };
+        assert_eq!(
+            text.value,
+            "Voilà: sandbox:/mnt/data/data.csv\n\n[other.csv](sandbox:/mnt/data/other.csv)"
+        );
+        let annotations: Vec<(String, String, u32, u32)> = text
+            .annotations
+            .iter()
+            .map(|annotation| match annotation {
+                MessageContentTextAnnotations::FilePath(file_path) => (
+                    file_path.text.clone(),
+                    file_path.file_path.file_id.clone(),
+                    file_path.start_index,
+                    file_path.end_index,
+                ),
+                other => panic!("expected a file path, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            annotations,
+            vec![
+                (
+                    "sandbox:/mnt/data/data.csv".to_string(),
+                    "file-data.csv".to_string(),
+                    7,
+                    33
+                ),
+                (
+                    "sandbox:/mnt/data/other.csv".to_string(),
+                    "file-other.csv".to_string(),
+                    47,
+                    74
+                ),
+            ]
+        );
     }
 
-    info!("Code interpreter output: {}", output);
+    #[test]
+    fn test_allowed_languages() {
+        let metadata =
+            |value: serde_json::Value| HashMap::from([(LANGUAGES_METADATA.to_string(), value)]);
+        assert_eq!(allowed_languages(None), vec![Language::Python]);
+        assert_eq!(
+            allowed_languages(Some(&metadata(json!("sql, python,SQL")))),
+            vec![Language::Sql, Language::Python]
+        );
+        assert_eq!(
+            allowed_languages(Some(&metadata(json!(["javascript", "cobol", "shell"])))),
+            vec![Language::JavaScript, Language::Shell]
+        );
+        assert_eq!(
+            allowed_languages(Some(&metadata(json!("cobol")))),
+            vec![Language::Python]
+        );
+    }
 
-    // remove container
-    docker
-        .remove_container(
-            &container.id,
-            Some(RemoveContainerOptions {
-                force: true,
-                ..Default::default()
-            }),
-        )
-        .await?;
+    #[test]
+    fn test_strip_code_fences() {
+        assert_eq!(
+            strip_code_fences("```sql\nSELECT 1;\n```"),
+            "SELECT 1;".to_string()
+        );
+        assert_eq!(strip_code_fences("print(1)"), "print(1)".to_string());
+    }
 
-    // Check if the output contains "Traceback", indicating a Python error
-    if output.contains("Traceback") {
-        return Err(InterpreterError {
-            message: format!("Python code execution failed with error: {}", output),
-            python_code: python_code.to_string(),
-        });
+    #[test]
+    fn test_max_iterations() {
+        let metadata = |value: serde_json::Value| {
+            HashMap::from([(MAX_ITERATIONS_METADATA.to_string(), value)])
+        };
+        assert_eq!(max_iterations(None), DEFAULT_MAX_ITERATIONS);
+        assert_eq!(
+            max_iterations(Some(&HashMap::new())),
+            DEFAULT_MAX_ITERATIONS
+        );
+        assert_eq!(max_iterations(Some(&metadata(json!(5)))), 5);
+        // metadata values are often strings
+        assert_eq!(max_iterations(Some(&metadata(json!("2")))), 2);
+        assert_eq!(max_iterations(Some(&metadata(json!(0)))), 1);
+        assert_eq!(max_iterations(Some(&metadata(json!(1000)))), 10);
+        assert_eq!(
+            max_iterations(Some(&metadata(json!("many")))),
+            DEFAULT_MAX_ITERATIONS
+        );
     }
 
-    Ok((output, python_code.to_string()))
-}
+    #[test]
+    fn test_sandbox_file_name() {
+        assert_eq!(
+            sandbox_file_name(Some("data.csv"), "file-1.csv"),
+            "data.csv"
+        );
+        assert_eq!(
+            sandbox_file_name(Some("../../etc/passwd"), "file-1.csv"),
+            "passwd"
+        );
+        assert_eq!(
+            sandbox_file_name(Some("dir\\.."), "file-1.csv"),
+            "file-1.csv"
+        );
+        assert_eq!(sandbox_file_name(None, "file-1.csv"), "file-1.csv");
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use async_openai::types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
-        ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent, Role,
-    };
-    use dotenv::dotenv;
-    use hal_9100_extra::openai::Message;
+    #[tokio::test]
+    async fn test_execute_python_timeout() {
+        let result = execute_code(
+            Language::Python,
+            "import time\ntime.sleep(30)",
+            Duration::from_secs(2),
+            &ExecutionContext::default(),
+        )
+        .await;
+        let error = result.unwrap_err();
+        assert!(error.message.contains("timed out"), "{}", error);
+        assert!(error.code.contains("time.sleep(30)"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_python_out_of_memory() {
+        let result = execute_code(
+            Language::Python,
+            "data = b'a' * (4 * 1024 ** 3)\nprint(len(data))",
+            EXECUTION_TIMEOUT,
+            &ExecutionContext::default(),
+        )
+        .await;
+        let error = result.unwrap_err();
+        assert!(error.message.contains("out of memory"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_execute_python_sandbox() {
+        let code = "import os, socket
+print(os.getuid())
+for path in ['/tmp/ok.txt', '/usr/ko.txt']:
+    try:
+        open(path, 'w').write('hello')
+        print(path, 'writable')
+    except OSError:
+        print(path, 'read only')
+try:
+    socket.create_connection(('1.1.1.1', 53), timeout=5)
+    print('network')
+except OSError:
+    print('no network')";
+        let output = execute_code(
+            Language::Python,
+            code,
+            EXECUTION_TIMEOUT,
+            &ExecutionContext::default(),
+        )
+        .await
+        .unwrap()
+        .stdout;
+        assert!(output.contains("65534"), "{}", output);
+        assert!(output.contains("/tmp/ok.txt writable"), "{}", output);
+        assert!(output.contains("/usr/ko.txt read only"), "{}", output);
+        assert!(output.contains("no network"), "{}", output);
+    }
 
     #[tokio::test]
     async fn test_interpreter() {
@@ -379,17 +1624,26 @@ mod tests {
                         name: None,
                     },
                 )]);
-            let result = safe_interpreter(input.to_string(), 0, 3, client.clone(), request).await;
+            let result = run_code_interpreter(
+                client.clone(),
+                request,
+                DEFAULT_MAX_ITERATIONS,
+                &[Language::Python],
+                &ExecutionContext::default(),
+            )
+            .await;
             assert!(
                 result.is_ok(),
                 "Failed on input: {} error: {:?}",
                 input,
                 result
             );
-            let (code_output, code) = result.unwrap();
+            let iterations = result.unwrap();
+            assert!(iterations.len() <= DEFAULT_MAX_ITERATIONS);
+            let code_output = &iterations.last().unwrap().output;
             println!(
                 "Problem to solve: {}. \nOutput: {}\nExpected output: {}",
-                input, code_output, expected_output
+                input, code_output.stdout, expected_output
             );
 
             let p = "You are an AI that checks the correctness of math results. 
@@ -414,7 +1668,7 @@ Rules:
                         role: Role::User,
                         content: ChatCompletionRequestUserMessageContent::Text(format!(
                             "User input: {}\nResult: {}. Official solution: {}. Is my result correct?",
-                            input, code_output, expected_output
+                            input, code_output.stdout, expected_output
                         )),
                         name: None,
                     }),
@@ -0,0 +1,302 @@
+//! Pre-started code interpreter containers, so executions don't pay a docker create and start.
+//!
+//! A background task keeps `size` containers idle, checks that they are still running and reaps
+//! the ones that died or got too old. Borrowed containers are given back after a successful
+//! execution until they reach `max_uses`, anything else (failure, timeout, panic) removes them.
+
+use bollard::container::InspectContainerOptions;
+use bollard::exec::{CreateExecOptions, StartExecResults};
+use bollard::Docker;
+use futures::StreamExt;
+use hal_9100_core::code_interpreter::{
+    pull_image, sandbox_config, start_sandbox, ContainerGuard, InterpreterError,
+};
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// Pooled containers stop by themselves after this long, so the ones left behind by a crashed
+// executor go away too (they are auto removed)
+const CONTAINER_LIFETIME: Duration = Duration::from_secs(60 * 60);
+// Idle containers are replaced before getting close to their end
+const MAX_IDLE_AGE: Duration = Duration::from_secs(50 * 60);
+// Wipes what an execution left behind before the container is lent again
+const RESET_COMMAND: &str = "rm -rf /tmp/* /tmp/.[!.]* /mnt/data/*; true";
+
+struct PooledContainer {
+    guard: ContainerGuard,
+    uses: usize,
+    started_at: Instant,
+}
+
+pub struct ContainerPool {
+    docker: Docker,
+    size: usize,
+    max_uses: usize,
+    // Containers given back go to the front so they are used up first, fresh ones to the back
+    idle: Mutex<VecDeque<PooledContainer>>,
+    // Wakes the manager up when the pool needs refilling
+    wake: Notify,
+    closed: AtomicBool,
+}
+
+impl ContainerPool {
+    /// Pulls the image and starts the background manager, which fills the pool right away.
+    pub async fn start(size: usize, max_uses: usize) -> Result<Arc<Self>, InterpreterError> {
+        let docker = Docker::connect_with_local_defaults()?;
+        pull_image().await?;
+        let pool = Arc::new(ContainerPool {
+            docker,
+            size,
+            max_uses: max_uses.max(1),
+            idle: Mutex::new(VecDeque::new()),
+            wake: Notify::new(),
+            closed: AtomicBool::new(false),
+        });
+        info!(
+            "Starting code interpreter pool of {} containers, {} uses each",
+            pool.size, pool.max_uses
+        );
+        tokio::spawn(pool.clone().manage());
+        Ok(pool)
+    }
+
+    pub(crate) fn docker(&self) -> &Docker {
+        &self.docker
+    }
+
+    /// Takes an idle container, or starts one if the pool is empty.
+    pub async fn acquire(self: &Arc<Self>) -> Result<BorrowedContainer, InterpreterError> {
+        let idle = self.idle.lock().unwrap().pop_front();
+        self.wake.notify_one();
+        let container = match idle {
+            Some(container) => container,
+            None => {
+                info!("Code interpreter pool is empty, starting a container");
+                self.start_container().await?
+            }
+        };
+        Ok(BorrowedContainer {
+            pool: self.clone(),
+            container: Some(container),
+        })
+    }
+
+    /// Stops the manager and removes the idle containers, borrowed ones are removed when dropped.
+    pub async fn shutdown(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.wake.notify_one();
+        let idle: Vec<PooledContainer> = self.idle.lock().unwrap().drain(..).collect();
+        for mut container in idle {
+            container.guard.remove().await;
+        }
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    async fn start_container(&self) -> Result<PooledContainer, InterpreterError> {
+        let mut config = sandbox_config();
+        config.cmd = Some(vec![
+            "sleep".to_string(),
+            CONTAINER_LIFETIME.as_secs().to_string(),
+        ]);
+        config.labels = Some(HashMap::from([(
+            "hal-9100.pool".to_string(),
+            "true".to_string(),
+        )]));
+        config
+            .host_config
+            .get_or_insert_with(Default::default)
+            .auto_remove = Some(true);
+        Ok(PooledContainer {
+            guard: start_sandbox(&self.docker, config).await?,
+            uses: 0,
+            started_at: Instant::now(),
+        })
+    }
+
+    async fn manage(self: Arc<Self>) {
+        while !self.is_closed() {
+            self.reap().await;
+            self.fill().await;
+            let _ = tokio::time::timeout(HEALTH_CHECK_INTERVAL, self.wake.notified()).await;
+        }
+    }
+
+    async fn fill(&self) {
+        while !self.is_closed() && self.idle_count() < self.size {
+            match self.start_container().await {
+                Ok(container) => self.idle.lock().unwrap().push_back(container),
+                Err(e) => {
+                    // Retried on the next round
+                    warn!("Failed to start a pooled container: {}", e);
+                    return;
+                }
+            }
+        }
+        // Containers given back while the pool was full
+        let extra: Vec<PooledContainer> = {
+            let mut idle = self.idle.lock().unwrap();
+            let size = self.size.min(idle.len());
+            idle.drain(size..).collect()
+        };
+        for mut container in extra {
+            container.guard.remove().await;
+        }
+    }
+
+    /// Removes idle containers that stopped running or are getting too old.
+    async fn reap(&self) {
+        let ids: Vec<(String, Instant)> = self
+            .idle
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|container| (container.guard.id().to_string(), container.started_at))
+            .collect();
+        for (id, started_at) in ids {
+            if started_at.elapsed() < MAX_IDLE_AGE && self.is_running(&id).await {
+                continue;
+            }
+            // It may have been borrowed in the meantime
+            let reaped = {
+                let mut idle = self.idle.lock().unwrap();
+                idle.iter()
+                    .position(|container| container.guard.id() == id)
+                    .and_then(|position| idle.remove(position))
+            };
+            if let Some(mut container) = reaped {
+                info!("Reaping pooled container {}", id);
+                container.guard.remove().await;
+            }
+        }
+    }
+
+    async fn is_running(&self, id: &str) -> bool {
+        self.docker
+            .inspect_container(id, None::<InspectContainerOptions>)
+            .await
+            .ok()
+            .and_then(|container| container.state)
+            .and_then(|state| state.running)
+            .unwrap_or(false)
+    }
+
+    async fn reset(&self, id: &str) -> Result<(), InterpreterError> {
+        let exec = self
+            .docker
+            .create_exec(
+                id,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(vec!["/bin/bash", "-c", RESET_COMMAND]),
+                    ..Default::default()
+                },
+            )
+            .await?
+            .id;
+        if let StartExecResults::Attached { mut output, .. } =
+            self.docker.start_exec(&exec, None).await?
+        {
+            while let Some(message) = output.next().await {
+                message?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A container lent by the pool, removed when dropped unless given back with `release`.
+pub struct BorrowedContainer {
+    pool: Arc<ContainerPool>,
+    container: Option<PooledContainer>,
+}
+
+impl BorrowedContainer {
+    pub fn id(&self) -> &str {
+        self.container
+            .as_ref()
+            .map(|container| container.guard.id())
+            .unwrap_or_default()
+    }
+
+    /// Gives the container back after a successful execution, or removes it once used enough.
+    pub async fn release(mut self) {
+        let mut container = match self.container.take() {
+            Some(container) => container,
+            None => return,
+        };
+        container.uses += 1;
+        let pool = self.pool.clone();
+        let reusable = container.uses < pool.max_uses
+            && !pool.is_closed()
+            && container.started_at.elapsed() < MAX_IDLE_AGE;
+        if reusable {
+            match pool.reset(container.guard.id()).await {
+                Ok(()) => {
+                    pool.idle.lock().unwrap().push_front(container);
+                    pool.wake.notify_one();
+                    return;
+                }
+                Err(e) => warn!("Failed to reset pooled container: {}", e),
+            }
+        }
+        container.guard.remove().await;
+        pool.wake.notify_one();
+    }
+}
+
+impl Drop for BorrowedContainer {
+    fn drop(&mut self) {
+        // The guard removes the container
+        if self.container.take().is_some() {
+            self.pool.wake.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_container_pool() {
+        let pool = ContainerPool::start(1, 2).await.unwrap();
+
+        let container = pool.acquire().await.unwrap();
+        let first_id = container.id().to_string();
+        container.release().await;
+
+        // given back until it reaches max_uses
+        let container = pool.acquire().await.unwrap();
+        assert_eq!(container.id(), first_id);
+        container.release().await;
+        let container = pool.acquire().await.unwrap();
+        assert_ne!(container.id(), first_id);
+
+        // dropped without release, e.g. after a timeout
+        let second_id = container.id().to_string();
+        drop(container);
+        let container = pool.acquire().await.unwrap();
+        assert_ne!(container.id(), second_id);
+        container.release().await;
+
+        // the manager keeps the pool filled
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(pool.idle_count() >= 1);
+
+        pool.shutdown().await;
+        assert_eq!(pool.idle_count(), 0);
+    }
+}
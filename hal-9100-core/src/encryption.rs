@@ -0,0 +1,852 @@
+//! Encryption at rest.
+//!
+//! Stored files use envelope encryption: every file gets a random data key, which is wrapped with the
+//! master key and kept in a small header in front of the content. The content is sealed in segments so
+//! it can be streamed both ways. Rotating the master key only rewrites the header.
+//!
+//! Sensitive columns (`chunks.data`, `messages.content`) are sealed with the master key directly and
+//! stored as `enc:v1:<key id>:<base64>`. The row id is bound as associated data, so a value can't be
+//! moved to another row. Values without that prefix are plaintext written before encryption was
+//! enabled, they are read as is and encrypted by `hal-9100 rotate-keys`.
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use hal_9100_core::storage::ByteStream;
+use hal_9100_extra::config::Hal9100Config;
+use log::info;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::{fmt, io};
+
+const MAGIC: &[u8; 8] = b"HALENC01";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+// Segment nonces are the prefix, a big endian counter and a last segment flag
+const NONCE_PREFIX_SIZE: usize = 7;
+const TAG_SIZE: usize = 16;
+const SEGMENT_SIZE: usize = 64 * 1024;
+const COLUMN_PREFIX: &str = "enc:v1:";
+const ROTATION_BATCH_SIZE: i64 = 500;
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    InvalidKey(String),
+    /// The data was sealed with a master key that isn't configured (anymore).
+    UnknownKey(String),
+    /// Wrong key, tampered or truncated data.
+    Corrupted(String),
+    IoError(io::Error),
+    SqlxError(sqlx::Error),
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncryptionError::InvalidKey(e) => write!(f, "Invalid encryption key: {}", e),
+            EncryptionError::UnknownKey(id) => write!(f, "Unknown encryption key {}", id),
+            EncryptionError::Corrupted(e) => write!(f, "Can't decrypt: {}", e),
+            EncryptionError::IoError(e) => write!(f, "IO error: {}", e),
+            EncryptionError::SqlxError(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl Error for EncryptionError {}
+
+impl From<io::Error> for EncryptionError {
+    fn from(err: io::Error) -> EncryptionError {
+        EncryptionError::IoError(err)
+    }
+}
+
+impl From<sqlx::Error> for EncryptionError {
+    fn from(err: sqlx::Error) -> EncryptionError {
+        EncryptionError::SqlxError(err)
+    }
+}
+
+impl From<EncryptionError> for io::Error {
+    fn from(err: EncryptionError) -> io::Error {
+        match err {
+            EncryptionError::IoError(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+struct MasterKey {
+    // Derived from the key, so the config doesn't need to name keys
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    fn parse(encoded: &str) -> Result<Self, EncryptionError> {
+        let key = base64::decode(encoded.trim())
+            .map_err(|e| EncryptionError::InvalidKey(e.to_string()))?;
+        if key.len() != KEY_SIZE {
+            return Err(EncryptionError::InvalidKey(format!(
+                "expected {} bytes, got {}",
+                KEY_SIZE,
+                key.len()
+            )));
+        }
+        let id = format!("{:x}", Sha256::digest(&key))[..16].to_string();
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|e| EncryptionError::InvalidKey(e.to_string()))?;
+        Ok(MasterKey { id, cipher })
+    }
+}
+
+/// The master key used for new data, and former ones still accepted for decryption.
+pub struct Keyring {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+impl Keyring {
+    /// Keys are base64 encoded 32 bytes, e.g. from `openssl rand -base64 32`.
+    pub fn new(current: &str, previous: &[String]) -> Result<Self, EncryptionError> {
+        Ok(Keyring {
+            current: MasterKey::parse(current)?,
+            previous: previous
+                .iter()
+                .map(|key| MasterKey::parse(key))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// The keyring set up in the config, `None` when encryption isn't enabled.
+    pub fn from_config(config: &Hal9100Config) -> Result<Option<Self>, EncryptionError> {
+        let current = match (&config.encryption_key, &config.encryption_key_file) {
+            (Some(_), Some(_)) => {
+                return Err(EncryptionError::InvalidKey(
+                    "set either encryption_key or encryption_key_file, not both".to_string(),
+                ))
+            }
+            (Some(key), None) => key.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)?,
+            (None, None) if config.encryption_previous_keys.is_empty() => return Ok(None),
+            (None, None) => {
+                return Err(EncryptionError::InvalidKey(
+                    "encryption_previous_keys needs a current encryption_key".to_string(),
+                ))
+            }
+        };
+        Self::new(&current, &config.encryption_previous_keys).map(Some)
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current.id
+    }
+
+    fn key(&self, id: &str) -> Result<&MasterKey, EncryptionError> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|key| key.id == id)
+            .ok_or_else(|| EncryptionError::UnknownKey(id.to_string()))
+    }
+
+    fn wrap_key(&self, data_key: &[u8], nonce_prefix: [u8; NONCE_PREFIX_SIZE]) -> Header {
+        let wrap_nonce = random_bytes::<NONCE_SIZE>();
+        let wrapped_key = self
+            .current
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&wrap_nonce),
+                Payload {
+                    msg: data_key,
+                    aad: MAGIC,
+                },
+            )
+            .expect("AES-GCM encryption doesn't fail on small inputs");
+        Header {
+            key_id: self.current.id.clone(),
+            wrap_nonce,
+            wrapped_key,
+            nonce_prefix,
+        }
+    }
+
+    fn open_data_key(&self, header: &Header) -> Result<Vec<u8>, EncryptionError> {
+        self.key(&header.key_id)?
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&header.wrap_nonce),
+                Payload {
+                    msg: &header.wrapped_key,
+                    aad: MAGIC,
+                },
+            )
+            .map_err(|_| EncryptionError::Corrupted("invalid data key".to_string()))
+    }
+
+    fn unwrap_key(&self, header: &Header) -> Result<Segments, EncryptionError> {
+        Segments::new(&self.open_data_key(header)?, header.nonce_prefix)
+    }
+
+    fn new_envelope(&self) -> (Header, Segments) {
+        let data_key = random_bytes::<KEY_SIZE>();
+        let nonce_prefix = random_bytes::<NONCE_PREFIX_SIZE>();
+        let header = self.wrap_key(&data_key, nonce_prefix);
+        let segments =
+            Segments::new(&data_key, nonce_prefix).expect("data keys have the right size");
+        (header, segments)
+    }
+
+    /// Encrypts a whole file under a new data key.
+    pub fn encrypt(&self, content: &[u8]) -> Vec<u8> {
+        let (header, mut segments) = self.new_envelope();
+        let mut encrypted = header.encode();
+        let count = (content.len() + SEGMENT_SIZE - 1) / SEGMENT_SIZE;
+        if count == 0 {
+            encrypted.extend(segments.seal(&[], true));
+        }
+        for (i, segment) in content.chunks(SEGMENT_SIZE).enumerate() {
+            encrypted.extend(segments.seal(segment, i == count - 1));
+        }
+        encrypted
+    }
+
+    /// Decrypts a whole file, content that was never encrypted is returned as is.
+    pub fn decrypt(&self, content: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let (header, header_size) = match Header::decode(content, true)? {
+            Decoded::Plain => return Ok(content.to_vec()),
+            Decoded::Incomplete => unreachable!("the whole content is there"),
+            Decoded::Header(header, header_size) => (header, header_size),
+        };
+        let mut segments = self.unwrap_key(&header)?;
+        let body = &content[header_size..];
+        if body.is_empty() {
+            return Err(EncryptionError::Corrupted("truncated content".to_string()));
+        }
+        let count = (body.len() + SEGMENT_SIZE + TAG_SIZE - 1) / (SEGMENT_SIZE + TAG_SIZE);
+        let mut decrypted = Vec::with_capacity(body.len());
+        for (i, segment) in body.chunks(SEGMENT_SIZE + TAG_SIZE).enumerate() {
+            decrypted.extend(segments.open(segment, i == count - 1)?);
+        }
+        Ok(decrypted)
+    }
+
+    /// Encrypts a stream under a new data key, segment by segment.
+    pub fn encrypt_stream<'a>(&self, content: ByteStream<'a>) -> ByteStream<'a> {
+        let (header, segments) = self.new_envelope();
+        let state = (content, segments, Vec::new(), false);
+        let body = stream::unfold(
+            state,
+            |(mut content, mut segments, mut buffer, done)| async move {
+                if done {
+                    return None;
+                }
+                // Only a segment followed by more data can be sealed as not being the last one
+                let mut ended = false;
+                while buffer.len() <= SEGMENT_SIZE {
+                    match content.next().await {
+                        Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                        Some(Err(e)) => return Some((Err(e), (content, segments, buffer, true))),
+                        None => {
+                            ended = true;
+                            break;
+                        }
+                    }
+                }
+                let rest = if ended {
+                    Vec::new()
+                } else {
+                    buffer.split_off(SEGMENT_SIZE)
+                };
+                let sealed = Bytes::from(segments.seal(&buffer, ended));
+                Some((Ok(sealed), (content, segments, rest, ended)))
+            },
+        );
+        Box::pin(stream::once(async move { Ok(Bytes::from(header.encode())) }).chain(body))
+    }
+
+    /// Decrypts a stream, content that was never encrypted goes through as is.
+    pub async fn decrypt_stream<'a>(
+        &self,
+        mut content: ByteStream<'a>,
+    ) -> Result<ByteStream<'a>, EncryptionError> {
+        let (header, buffer) = match read_header(&mut content).await? {
+            (None, buffer) => return Ok(prepend(buffer, content)),
+            (Some(header), buffer) => (header, buffer),
+        };
+        let segments = self.unwrap_key(&header)?;
+        let state = (content, segments, buffer, false);
+        Ok(Box::pin(stream::unfold(
+            state,
+            |(mut content, mut segments, mut buffer, done)| async move {
+                if done {
+                    return None;
+                }
+                let mut ended = false;
+                while buffer.len() <= SEGMENT_SIZE + TAG_SIZE {
+                    match content.next().await {
+                        Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                        Some(Err(e)) => return Some((Err(e), (content, segments, buffer, true))),
+                        None => {
+                            ended = true;
+                            break;
+                        }
+                    }
+                }
+                let rest = if ended {
+                    Vec::new()
+                } else {
+                    buffer.split_off(SEGMENT_SIZE + TAG_SIZE)
+                };
+                match segments.open(&buffer, ended) {
+                    Ok(opened) => Some((Ok(Bytes::from(opened)), (content, segments, rest, ended))),
+                    Err(e) => Some((Err(e.into()), (content, segments, rest, true))),
+                }
+            },
+        )))
+    }
+
+    /// Re-encrypts a stored file under the current master key, `None` if it already is.
+    /// Encrypted files only get a new header, plaintext ones are encrypted.
+    pub async fn rewrap_stream<'a>(
+        &self,
+        mut content: ByteStream<'a>,
+    ) -> Result<Option<ByteStream<'a>>, EncryptionError> {
+        match read_header(&mut content).await? {
+            (None, buffer) => Ok(Some(self.encrypt_stream(prepend(buffer, content)))),
+            (Some(header), _) if header.key_id == self.current.id => Ok(None),
+            (Some(header), buffer) => {
+                let data_key = self.open_data_key(&header)?;
+                let header = self.wrap_key(&data_key, header.nonce_prefix);
+                let mut rewrapped = header.encode();
+                rewrapped.extend(buffer);
+                Ok(Some(prepend(rewrapped, content)))
+            }
+        }
+    }
+
+    /// Seals a column value with the current master key, `aad` must be given again to open it.
+    pub fn encrypt_text(&self, text: &str, aad: &[u8]) -> String {
+        let nonce = random_bytes::<NONCE_SIZE>();
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.current
+                .cipher
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: text.as_bytes(),
+                        aad,
+                    },
+                )
+                .expect("AES-GCM encryption doesn't fail on column values"),
+        );
+        format!(
+            "{}{}:{}",
+            COLUMN_PREFIX,
+            self.current.id,
+            base64::encode(sealed)
+        )
+    }
+
+    /// Opens a column value, plaintext values are returned as is.
+    pub fn decrypt_text(&self, text: &str, aad: &[u8]) -> Result<String, EncryptionError> {
+        let (key_id, sealed) = match text
+            .strip_prefix(COLUMN_PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+        {
+            Some(parts) => parts,
+            None => return Ok(text.to_string()),
+        };
+        let sealed =
+            base64::decode(sealed).map_err(|e| EncryptionError::Corrupted(e.to_string()))?;
+        if sealed.len() < NONCE_SIZE {
+            return Err(EncryptionError::Corrupted("truncated value".to_string()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let plaintext = self
+            .key(key_id)?
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| EncryptionError::Corrupted("invalid value".to_string()))?;
+        String::from_utf8(plaintext).map_err(|e| EncryptionError::Corrupted(e.to_string()))
+    }
+
+    /// Whether a column value is plaintext or sealed with a former key.
+    pub fn needs_rotation(&self, text: &str) -> bool {
+        match text.strip_prefix(COLUMN_PREFIX) {
+            Some(rest) => rest.split(':').next() != Some(self.current.id.as_str()),
+            None => true,
+        }
+    }
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn prepend<'a>(buffer: Vec<u8>, content: ByteStream<'a>) -> ByteStream<'a> {
+    if buffer.is_empty() {
+        return content;
+    }
+    Box::pin(stream::once(async move { Ok(Bytes::from(buffer)) }).chain(content))
+}
+
+/// Whether stored content carries an encryption header.
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+/// Whether a stream carries an encryption header, the stream is given back as it was.
+pub async fn is_encrypted_stream(
+    mut content: ByteStream<'_>,
+) -> Result<(bool, ByteStream<'_>), io::Error> {
+    let mut buffer = Vec::new();
+    while buffer.len() < MAGIC.len() {
+        match content.next().await.transpose()? {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    Ok((is_encrypted(&buffer), prepend(buffer, content)))
+}
+
+struct Header {
+    key_id: String,
+    wrap_nonce: [u8; NONCE_SIZE],
+    wrapped_key: Vec<u8>,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+}
+
+enum Decoded {
+    Plain,
+    Incomplete,
+    Header(Header, usize),
+}
+
+impl Header {
+    // magic | key id length | key id | wrap nonce | wrapped data key | segment nonce prefix
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = MAGIC.to_vec();
+        encoded.push(self.key_id.len() as u8);
+        encoded.extend(self.key_id.as_bytes());
+        encoded.extend(self.wrap_nonce);
+        encoded.extend(&self.wrapped_key);
+        encoded.extend(self.nonce_prefix);
+        encoded
+    }
+
+    /// `complete` tells that no more data will come, a partial header is then an error.
+    fn decode(data: &[u8], complete: bool) -> Result<Decoded, EncryptionError> {
+        if !data.starts_with(MAGIC) {
+            // Could still be the beginning of a header
+            return Ok(if !complete && MAGIC.starts_with(data) {
+                Decoded::Incomplete
+            } else {
+                Decoded::Plain
+            });
+        }
+        let key_id_size = match data.get(MAGIC.len()) {
+            Some(size) => *size as usize,
+            None if complete => {
+                return Err(EncryptionError::Corrupted("truncated header".to_string()))
+            }
+            None => return Ok(Decoded::Incomplete),
+        };
+        let key_id_start = MAGIC.len() + 1;
+        let nonce_start = key_id_start + key_id_size;
+        let wrapped_key_start = nonce_start + NONCE_SIZE;
+        let prefix_start = wrapped_key_start + KEY_SIZE + TAG_SIZE;
+        let size = prefix_start + NONCE_PREFIX_SIZE;
+        if data.len() < size {
+            return if complete {
+                Err(EncryptionError::Corrupted("truncated header".to_string()))
+            } else {
+                Ok(Decoded::Incomplete)
+            };
+        }
+        let key_id = String::from_utf8(data[key_id_start..nonce_start].to_vec())
+            .map_err(|e| EncryptionError::Corrupted(e.to_string()))?;
+        let mut wrap_nonce = [0u8; NONCE_SIZE];
+        wrap_nonce.copy_from_slice(&data[nonce_start..wrapped_key_start]);
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        nonce_prefix.copy_from_slice(&data[prefix_start..size]);
+        Ok(Decoded::Header(
+            Header {
+                key_id,
+                wrap_nonce,
+                wrapped_key: data[wrapped_key_start..prefix_start].to_vec(),
+                nonce_prefix,
+            },
+            size,
+        ))
+    }
+}
+
+/// Reads the header off a stream, along with the bytes read past it.
+async fn read_header(
+    content: &mut ByteStream<'_>,
+) -> Result<(Option<Header>, Vec<u8>), EncryptionError> {
+    let mut buffer = Vec::new();
+    loop {
+        let chunk = content.next().await.transpose()?;
+        if let Some(chunk) = &chunk {
+            buffer.extend_from_slice(chunk);
+        }
+        match Header::decode(&buffer, chunk.is_none())? {
+            Decoded::Plain => return Ok((None, buffer)),
+            Decoded::Incomplete => continue,
+            Decoded::Header(header, size) => return Ok((Some(header), buffer.split_off(size))),
+        }
+    }
+}
+
+/// Seals and opens the segments of a file with its data key.
+struct Segments {
+    cipher: Aes256Gcm,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+}
+
+impl Segments {
+    fn new(
+        data_key: &[u8],
+        nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    ) -> Result<Self, EncryptionError> {
+        Ok(Segments {
+            cipher: Aes256Gcm::new_from_slice(data_key)
+                .map_err(|_| EncryptionError::Corrupted("invalid data key".to_string()))?,
+            nonce_prefix,
+            counter: 0,
+        })
+    }
+
+    fn next_nonce(&mut self, last: bool) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[NONCE_SIZE - 1] = last as u8;
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, segment: &[u8], last: bool) -> Vec<u8> {
+        let nonce = self.next_nonce(last);
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), segment)
+            .expect("AES-GCM encryption doesn't fail on segments")
+    }
+
+    fn open(&mut self, segment: &[u8], last: bool) -> Result<Vec<u8>, EncryptionError> {
+        let nonce = self.next_nonce(last);
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), segment)
+            .map_err(|_| EncryptionError::Corrupted("invalid or truncated content".to_string()))
+    }
+}
+
+// Database columns go through the keyring installed at startup, so callers don't need to thread it around
+static COLUMN_KEYRING: RwLock<Option<Arc<Keyring>>> = RwLock::new(None);
+
+/// Installs the keyring used by `encrypt_column` and `decrypt_column`, `None` stores plaintext.
+pub fn set_column_keyring(keyring: Option<Arc<Keyring>>) {
+    *COLUMN_KEYRING.write().unwrap() = keyring;
+}
+
+fn column_keyring() -> Option<Arc<Keyring>> {
+    COLUMN_KEYRING.read().unwrap().clone()
+}
+
+pub fn column_encryption_enabled() -> bool {
+    column_keyring().is_some()
+}
+
+/// Seals the value of a column of the row `row_id`.
+pub fn encrypt_column(row_id: &Uuid, text: &str) -> String {
+    match column_keyring() {
+        Some(keyring) => keyring.encrypt_text(text, row_id.as_bytes()),
+        None => text.to_string(),
+    }
+}
+
+pub fn decrypt_column(row_id: &Uuid, text: &str) -> Result<String, EncryptionError> {
+    match column_keyring() {
+        Some(keyring) => keyring.decrypt_text(text, row_id.as_bytes()),
+        None if text.starts_with(COLUMN_PREFIX) => Err(EncryptionError::UnknownKey(
+            "no encryption key is configured".to_string(),
+        )),
+        None => Ok(text.to_string()),
+    }
+}
+
+/// JSON columns are sealed as a whole and stored as a JSON string.
+pub fn encrypt_json_column(row_id: &Uuid, value: &Value) -> Value {
+    match column_keyring() {
+        Some(keyring) => Value::String(keyring.encrypt_text(&value.to_string(), row_id.as_bytes())),
+        None => value.clone(),
+    }
+}
+
+pub fn decrypt_json_column(row_id: &Uuid, value: Value) -> Result<Value, EncryptionError> {
+    match value {
+        Value::String(text) if text.starts_with(COLUMN_PREFIX) => {
+            serde_json::from_str(&decrypt_column(row_id, &text)?)
+                .map_err(|e| EncryptionError::Corrupted(e.to_string()))
+        }
+        value => Ok(value),
+    }
+}
+
+/// Re-encrypts `chunks.data` and `messages.content` under the current key, plaintext values included.
+/// Returns the number of chunks and messages rewritten.
+pub async fn rotate_columns(
+    pool: &PgPool,
+    keyring: &Keyring,
+) -> Result<(u64, u64), EncryptionError> {
+    let mut chunks = 0;
+    let mut last_id = Uuid::nil();
+    loop {
+        let rows = sqlx::query!(
+            "SELECT id, data FROM chunks WHERE id > $1 ORDER BY id LIMIT $2",
+            last_id,
+            ROTATION_BATCH_SIZE,
+        )
+        .fetch_all(pool)
+        .await?;
+        let last = match rows.last() {
+            Some(row) => row.id,
+            None => break,
+        };
+        for row in rows
+            .into_iter()
+            .filter(|row| keyring.needs_rotation(&row.data))
+        {
+            let aad = row.id.as_bytes();
+            let data = keyring.encrypt_text(&keyring.decrypt_text(&row.data, aad)?, aad);
+            sqlx::query!("UPDATE chunks SET data = $2 WHERE id = $1", row.id, data)
+                .execute(pool)
+                .await?;
+            chunks += 1;
+        }
+        last_id = last;
+    }
+
+    let mut messages = 0;
+    let mut last_id = Uuid::nil();
+    loop {
+        let rows = sqlx::query!(
+            "SELECT id, content FROM messages WHERE id > $1 ORDER BY id LIMIT $2",
+            last_id,
+            ROTATION_BATCH_SIZE,
+        )
+        .fetch_all(pool)
+        .await?;
+        let last = match rows.last() {
+            Some(row) => row.id,
+            None => break,
+        };
+        for row in rows {
+            let plaintext = match &row.content {
+                Value::String(text) if !keyring.needs_rotation(text) => continue,
+                Value::String(text) if text.starts_with(COLUMN_PREFIX) => {
+                    keyring.decrypt_text(text, row.id.as_bytes())?
+                }
+                content => content.to_string(),
+            };
+            let content = Value::String(keyring.encrypt_text(&plaintext, row.id.as_bytes()));
+            sqlx::query!(
+                "UPDATE messages SET content = $2 WHERE id = $1",
+                row.id,
+                content
+            )
+            .execute(pool)
+            .await?;
+            messages += 1;
+        }
+        last_id = last;
+    }
+
+    info!(
+        "Re-encrypted {} chunks and {} messages with key {}",
+        chunks,
+        messages,
+        keyring.current_key_id()
+    );
+    Ok((chunks, messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    fn key() -> String {
+        base64::encode(random_bytes::<KEY_SIZE>())
+    }
+
+    fn stream_of(content: &[u8], chunk_size: usize) -> ByteStream<'static> {
+        let chunks: Vec<Result<Bytes, io::Error>> = content
+            .chunks(chunk_size)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Box::pin(stream::iter(chunks))
+    }
+
+    async fn collect(content: ByteStream<'_>) -> Result<Vec<u8>, io::Error> {
+        let chunks: Vec<Bytes> = content.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_decrypt() {
+        let keyring = Keyring::new(&key(), &[]).unwrap();
+        for size in [0, 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 2 * SEGMENT_SIZE + 10] {
+            let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+
+            let encrypted = keyring.encrypt(&content);
+            assert!(is_encrypted(&encrypted));
+            assert_eq!(keyring.decrypt(&encrypted).unwrap(), content);
+
+            // streams are compatible with the whole file functions, whatever the chunking
+            let streamed = collect(keyring.encrypt_stream(stream_of(&content, 1000)))
+                .await
+                .unwrap();
+            assert_eq!(streamed.len(), encrypted.len());
+            assert_eq!(keyring.decrypt(&streamed).unwrap(), content);
+            let decrypted = keyring
+                .decrypt_stream(stream_of(&encrypted, 4096))
+                .await
+                .unwrap();
+            assert_eq!(collect(decrypted).await.unwrap(), content);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_passes_through() {
+        let keyring = Keyring::new(&key(), &[]).unwrap();
+        for content in [&b""[..], b"HAL", b"Hello, world!"] {
+            assert_eq!(keyring.decrypt(content).unwrap(), content);
+            let decrypted = keyring.decrypt_stream(stream_of(content, 2)).await.unwrap();
+            assert_eq!(collect(decrypted).await.unwrap(), content);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tampered_content() {
+        let keyring = Keyring::new(&key(), &[]).unwrap();
+        let content = vec![7u8; SEGMENT_SIZE * 2];
+        let encrypted = keyring.encrypt(&content);
+
+        let mut tampered = encrypted.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(keyring.decrypt(&tampered).is_err());
+
+        // dropping the last segment is detected too
+        let truncated = &encrypted[..encrypted.len() - (SEGMENT_SIZE + TAG_SIZE)];
+        assert!(keyring.decrypt(truncated).is_err());
+        let decrypted = keyring
+            .decrypt_stream(stream_of(truncated, 4096))
+            .await
+            .unwrap();
+        assert!(collect(decrypted).await.is_err());
+
+        let other = Keyring::new(&key(), &[]).unwrap();
+        assert!(matches!(
+            other.decrypt(&encrypted),
+            Err(EncryptionError::UnknownKey(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rewrap_stream() {
+        let old_key = key();
+        let old = Keyring::new(&old_key, &[]).unwrap();
+        let new_key = key();
+        let rotating = Keyring::new(&new_key, &[old_key]).unwrap();
+        let new = Keyring::new(&new_key, &[]).unwrap();
+        let content = vec![42u8; SEGMENT_SIZE + 5];
+
+        let encrypted = old.encrypt(&content);
+        let rewrapped = rotating
+            .rewrap_stream(stream_of(&encrypted, 1000))
+            .await
+            .unwrap()
+            .unwrap();
+        let rewrapped = collect(rewrapped).await.unwrap();
+        assert_eq!(new.decrypt(&rewrapped).unwrap(), content);
+        // only the header changed
+        assert_eq!(rewrapped.len(), encrypted.len());
+
+        // already under the current key
+        assert!(rotating
+            .rewrap_stream(stream_of(&rewrapped, 1000))
+            .await
+            .unwrap()
+            .is_none());
+
+        // plaintext gets encrypted
+        let encrypted = rotating
+            .rewrap_stream(stream_of(&content, 1000))
+            .await
+            .unwrap()
+            .unwrap();
+        let encrypted = collect(encrypted).await.unwrap();
+        assert_eq!(new.decrypt(&encrypted).unwrap(), content);
+    }
+
+    #[test]
+    fn test_encrypt_text() {
+        let old_key = key();
+        let old = Keyring::new(&old_key, &[]).unwrap();
+        let rotating = Keyring::new(&key(), &[old_key]).unwrap();
+
+        let row = Uuid::new_v4();
+        let aad = row.as_bytes();
+        let sealed = old.encrypt_text("Hello, world!", aad);
+        assert!(sealed.starts_with(COLUMN_PREFIX));
+        assert!(!sealed.contains("Hello"));
+        assert_ne!(sealed, old.encrypt_text("Hello, world!", aad));
+        assert_eq!(old.decrypt_text(&sealed, aad).unwrap(), "Hello, world!");
+        assert_eq!(old.decrypt_text("plain", aad).unwrap(), "plain");
+        assert!(!old.needs_rotation(&sealed));
+
+        // A value copied to another row doesn't open
+        assert!(matches!(
+            old.decrypt_text(&sealed, Uuid::new_v4().as_bytes()),
+            Err(EncryptionError::Corrupted(_))
+        ));
+
+        assert!(rotating.needs_rotation(&sealed));
+        assert!(rotating.needs_rotation("plain"));
+        assert_eq!(
+            rotating.decrypt_text(&sealed, aad).unwrap(),
+            "Hello, world!"
+        );
+        let rotated = rotating.encrypt_text(&rotating.decrypt_text(&sealed, aad).unwrap(), aad);
+        assert!(!rotating.needs_rotation(&rotated));
+        assert!(matches!(
+            old.decrypt_text(&rotated, aad),
+            Err(EncryptionError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        assert!(Keyring::new("not base64!", &[]).is_err());
+        assert!(Keyring::new(&base64::encode([0u8; 16]), &[]).is_err());
+        assert!(Keyring::new(&key(), &["short".to_string()]).is_err());
+    }
+}
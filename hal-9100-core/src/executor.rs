@@ -1,6 +1,6 @@
 use async_openai::types::{
-    AssistantTools, FunctionCall, MessageContent, MessageContentTextObject, MessageRole,
-    RequiredAction, RunStatus, RunToolCallObject, SubmitToolOutputs, TextData, RunStepType, StepDetails, RunStepDetailsMessageCreationObject, MessageCreation, RunStepDetailsToolCallsObject, RunStepDetailsToolCalls, RunStepDetailsToolCallsCodeObject, CodeInterpreter, CodeInterpreterOutput, RunStepDetailsToolCallsCodeOutputLogsObject, RunStepDetailsToolCallsRetrievalObject, RunStepDetailsToolCallsFunctionObject, RunStepFunctionObject,
+    AssistantTools, FunctionCall, MessageRole,
+    RequiredAction, RunStatus, RunToolCallObject, SubmitToolOutputs, RunStepType, StepDetails, RunStepDetailsMessageCreationObject, MessageCreation, RunStepDetailsToolCallsObject, RunStepDetailsToolCalls, RunStepDetailsToolCallsCodeObject, CodeInterpreter, CodeInterpreterOutput, RunStepDetailsToolCallsCodeOutputLogsObject, RunStepDetailsToolCallsCodeOutputImageObject, ImageFile, RunStepDetailsToolCallsRetrievalObject, RunStepDetailsToolCallsFunctionObject, RunStepFunctionObject,
 };
 use futures::future::try_join_all;
 use hal_9100_extra::llm::{HalLLMClient, HalLLMRequestArgs};
@@ -18,15 +18,19 @@ use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
-use hal_9100_core::runs::{get_run, update_run_status};
+use hal_9100_core::runs::{get_run, update_run_status, SUBMIT_TOOL_APPROVALS};
 
 
-use hal_9100_core::function_calling::create_function_call;
+use hal_9100_core::function_calling::{create_function_call, get_function, FunctionCallWithMetadata};
 
 use hal_9100_core::runs::get_tool_calls;
-use hal_9100_core::code_interpreter::safe_interpreter;
+use hal_9100_core::code_interpreter::{
+    allowed_languages, annotate_generated_files, load_sandbox_files, max_iterations, run_code_interpreter, save_generated_files, ExecutionContext, SavedFile,
+};
+use hal_9100_core::kernel_sessions::kernel_sessions;
+use hal_9100_core::sandbox::sandbox_backend;
 
-use hal_9100_core::models::SubmittedToolCall;
+use hal_9100_core::models::{SubmittedToolCall, ToolApproval};
 
 use hal_9100_core::retrieval::retrieve_file_contents;
 
@@ -38,7 +42,7 @@ use crate::function_calling::execute_request;
 use crate::models::{RunStep};
 use crate::openapi::ActionRequest;
 use crate::prompts::{format_messages, build_instructions};
-use crate::run_steps::{create_step, update_step, list_steps, set_all_steps_status};
+use crate::run_steps::{create_step, update_step, list_steps, set_all_steps_status, set_step_metadata};
 
 pub fn extract_step_id_and_function_output(steps: Vec<RunStep>, tool_calls: Vec<SubmittedToolCall>) -> Vec<(String, String, RunStepFunctionObject)> {
     let mut result = Vec::new();
@@ -335,6 +339,210 @@ impl std::fmt::Debug for RunError {
 
 impl std::error::Error for RunError {}
 
+fn is_consequential(function: &FunctionCallWithMetadata) -> bool {
+    function.metadata.as_ref().and_then(|m| m["is_consequential"].as_bool()).unwrap_or(false)
+}
+
+fn action_step_details(tool_call_id: &str, name: &str, arguments: &str, output: Option<String>) -> StepDetails {
+    StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
+        r#type: "function".to_string(), // TODO not sure it should be function or action
+        tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject{
+            id: tool_call_id.to_string(),
+            r#type: "function".to_string(),
+            function: RunStepFunctionObject {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+                output,
+            }
+        })],
+    })
+}
+
+/// Pauses the run until the user approves, edits or rejects its consequential action calls.
+/// Every call gets a step now and they all run together once the decisions are submitted.
+async fn request_action_approvals(
+    pool: &PgPool,
+    run: &Run,
+    assistant_id: &str,
+    function_results: &[FunctionCallWithMetadata],
+) -> Result<Run, RunError> {
+    let run_error = |message: String| RunError {
+        message,
+        run_id: run.inner.id.clone(),
+        thread_id: run.inner.thread_id.clone(),
+        user_id: run.user_id.clone(),
+    };
+    let mut tool_calls = Vec::new();
+    for function in function_results {
+        let tool_call_id = uuid::Uuid::new_v4().to_string();
+        create_step(
+            pool,
+            &run.inner.id,
+            assistant_id,
+            &run.inner.thread_id,
+            RunStepType::ToolCalls,
+            RunStatus::InProgress,
+            action_step_details(&tool_call_id, &function.name, &function.arguments, None),
+            &run.user_id,
+        ).await.map_err(|e| run_error(format!("Failed to create step: {}", e)))?;
+        if is_consequential(function) {
+            tool_calls.push(RunToolCallObject {
+                id: tool_call_id,
+                r#type: "function".to_string(),
+                function: FunctionCall {
+                    name: function.name.clone(),
+                    arguments: function.arguments.clone(),
+                },
+            });
+        }
+    }
+
+    let run = update_run_status(
+        pool,
+        &run.inner.thread_id,
+        &run.inner.id,
+        RunStatus::RequiresAction,
+        &run.user_id,
+        Some(RequiredAction {
+            r#type: SUBMIT_TOOL_APPROVALS.to_string(),
+            submit_tool_outputs: SubmitToolOutputs { tool_calls },
+        }),
+        None,
+    ).await.map_err(|e| run_error(format!("Failed to update run status: {}", e)))?;
+
+    info!("Run waiting for the approval of {:?}", run.inner.required_action);
+    Ok(run)
+}
+
+/// Runs the action calls of a run that waited for approvals, as the user decided
+async fn run_approved_actions(
+    pool: &PgPool,
+    run: &Run,
+    assistant_id: &str,
+    decisions: &[SubmittedToolCall],
+) -> Result<String, RunError> {
+    let run_error = |message: String| RunError {
+        message,
+        run_id: run.inner.id.clone(),
+        thread_id: run.inner.thread_id.clone(),
+        user_id: run.user_id.clone(),
+    };
+    let approvals = decisions
+        .iter()
+        .map(|d| serde_json::from_str::<ToolApproval>(&d.output))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| run_error(format!("Invalid tool approval: {}", e)))?;
+
+    let steps = list_steps(pool, &run.inner.thread_id, &run.inner.id, &run.user_id)
+        .await
+        .map_err(|e| run_error(format!("Failed to list steps: {}", e)))?;
+
+    // The calls still in progress are the ones the run paused on
+    let mut outputs = Vec::new();
+    for step in steps.iter().filter(|step| step.inner.status == RunStatus::InProgress) {
+        let details = match &step.inner.step_details {
+            StepDetails::ToolCalls(details) => details,
+            _ => continue,
+        };
+        for tool_call in &details.tool_calls {
+            let call = match tool_call {
+                RunStepDetailsToolCalls::Function(call) if call.function.output.is_none() => call,
+                _ => continue,
+            };
+            let function = get_function(pool, assistant_id, &run.user_id, &call.function.name)
+                .await
+                .map_err(|e| run_error(format!("Failed to get function {}: {}", call.function.name, e)))?
+                .ok_or_else(|| run_error(format!("Function {} no longer exists", call.function.name)))?;
+            let approval = approvals.iter().find(|a| a.tool_call_id == call.id);
+            let output = execute_action_call(
+                pool,
+                run,
+                &step.inner.id,
+                &call.id,
+                &FunctionCallWithMetadata {
+                    name: call.function.name.clone(),
+                    arguments: call.function.arguments.clone(),
+                    metadata: function.metadata,
+                },
+                approval,
+            ).await?;
+            outputs.push(output);
+        }
+    }
+
+    Ok(outputs.join("\n"))
+}
+
+/// Sends the request of an action call, unless the user rejected it, and completes its step.
+/// The decision, if the call needed one, is recorded in the step metadata.
+async fn execute_action_call(
+    pool: &PgPool,
+    run: &Run,
+    step_id: &str,
+    tool_call_id: &str,
+    function: &FunctionCallWithMetadata,
+    approval: Option<&ToolApproval>,
+) -> Result<String, RunError> {
+    let run_error = |message: String| RunError {
+        message,
+        run_id: run.inner.id.clone(),
+        thread_id: run.inner.thread_id.clone(),
+        user_id: run.user_id.clone(),
+    };
+    // Edited arguments replace the generated ones
+    let arguments = approval
+        .and_then(|a| a.arguments.clone())
+        .unwrap_or_else(|| function.arguments.clone());
+    let rejected = approval.map_or(false, |a| !a.approved);
+
+    let (status, output) = if rejected {
+        (RunStatus::Cancelled, json!({ "error": "The user rejected this action" }))
+    } else {
+        let metadata = function.metadata.clone().unwrap_or_default();
+        let request = ActionRequest::from_metadata(&metadata, &arguments)
+            .map_err(|e| run_error(format!("Invalid arguments for {}: {}", function.name, e)))?;
+        let output = execute_request(request)
+            .await
+            .map_err(|e| run_error(format!("Failed to execute request: {}", e)))?;
+        (RunStatus::Completed, output)
+    };
+    let string_output = serde_json::to_string(&output).unwrap();
+
+    update_step(
+        pool,
+        step_id,
+        status,
+        action_step_details(tool_call_id, &function.name, &arguments, Some(string_output.clone())),
+        &run.user_id,
+    ).await.map_err(|e| run_error(format!("Failed to update step: {}", e)))?;
+
+    if let Some(approval) = approval {
+        let decision = if rejected {
+            json!({ "approval": "rejected" })
+        } else if approval.arguments.is_some() {
+            json!({ "approval": "edited", "generated_arguments": function.arguments })
+        } else {
+            json!({ "approval": "approved" })
+        };
+        set_step_metadata(pool, step_id, decision, &run.user_id)
+            .await
+            .map_err(|e| run_error(format!("Failed to record the approval: {}", e)))?;
+    }
+
+    info!("Action results: {:?}", output);
+
+    let stringified_function = serde_json::to_string(&json!({
+        "name": function.name,
+        "arguments": arguments,
+    })).unwrap().replace("\\", "");
+
+    Ok(format!(
+        "<input>{:?}</input>\n\n<output>{:?}</output>",
+        stringified_function,
+        string_output
+    ).replace("\\\\", "").replace("\\\"", ""))
+}
+
 pub async fn loop_through_runs(
     pool: &PgPool,
     con: &mut redis::aio::Connection,
@@ -358,6 +566,10 @@ pub async fn try_run_executor(
     match run_executor(&pool, con, client, file_storage).await {
         Ok(run) => { 
             info!("Execution done: {:?}", run);
+            // A paused run finishes its steps once it resumes
+            if run.inner.status == RunStatus::RequiresAction {
+                return Ok(run);
+            }
             set_all_steps_status(&pool, &run.inner.id, &run.user_id, RunStatus::Completed).await.map_err(|e| RunError {
                 message: format!("Failed to set all steps status: {}", e),
                 run_id: run.inner.id.clone(),
@@ -482,10 +694,11 @@ pub async fn run_executor(
     // LLM Context updated by tools
     let mut function_calls = String::new();
     let mut action_calls = String::new();
+    let mut approvals_handled = false;
     let mut retrieval_files: Vec<String> = vec![];
     let mut retrieval_chunks: Vec<Chunk> = vec![];
     let mut code_output: Option<String> = None;
-    let mut code: Option<String> = None;
+    let mut generated_files: Vec<SavedFile> = vec![];
     let mut tool_calls_db: Vec<SubmittedToolCall> = vec![];
     let mut request = HalLLMRequestArgs::default();
 
@@ -522,63 +735,69 @@ pub async fn run_executor(
             user_id: user_id.to_string(),
         })?;
 
-        // for each function call sent by the user, update the run step in database
-
-        // first fetch the steps for this run 
-
-        let steps = list_steps(
-            pool,
-            thread_id,
-            &run.inner.id,
-            &run.user_id,
-        ).await.map_err(|e| RunError {
-            message: format!("Failed to list steps: {}", e),
-            run_id: run_id.to_string(),
-            thread_id: thread_id.to_string(),
-            user_id: user_id.to_string(),
-        })?;
+        if required_action.r#type == SUBMIT_TOOL_APPROVALS {
+            // Send the action calls the run paused on, now that the user decided
+            action_calls = run_approved_actions(pool, &run, &assistant_id, &tool_calls_db).await?;
+            approvals_handled = true;
+        } else {
+            // for each function call sent by the user, update the run step in database
 
-        let details = extract_step_id_and_function_output(steps, tool_calls_db.clone());
+            // first fetch the steps for this run 
 
-        for (step_id, tool_call_id, function_data) in details {
-            
-            update_step(
+            let steps = list_steps(
                 pool,
-                &step_id,
-                RunStatus::Completed,
-                StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
-                    r#type: "function".to_string(),
-                    tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject{
-                        id: tool_call_id,
-                        r#type: "function".to_string(),
-                        function: function_data,
-                    })],
-                }),
+                thread_id,
+                &run.inner.id,
                 &run.user_id,
             ).await.map_err(|e| RunError {
-                message: format!("Failed to update step: {}", e),
+                message: format!("Failed to list steps: {}", e),
                 run_id: run_id.to_string(),
                 thread_id: thread_id.to_string(),
                 user_id: user_id.to_string(),
             })?;
-        }
 
-        // Use the tool call data to build the prompt like Input "functions" Output ""..."" DUMB MODE
-        function_calls = required_action
-            .submit_tool_outputs
-            .tool_calls
-            .iter()
-            .zip(&tool_calls_db)
-            .map(|(input, output)| {
-                format!(
-                    "<input>{:?}</input>\n\n<output>{:?}</output>",
-                    input.function, output.output
-                )
-            })
-            .collect::<Vec<String>>()
-            .join("\n");
+            let details = extract_step_id_and_function_output(steps, tool_calls_db.clone());
+
+            for (step_id, tool_call_id, function_data) in details {
+            
+                update_step(
+                    pool,
+                    &step_id,
+                    RunStatus::Completed,
+                    StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
+                        r#type: "function".to_string(),
+                        tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject{
+                            id: tool_call_id,
+                            r#type: "function".to_string(),
+                            function: function_data,
+                        })],
+                    }),
+                    &run.user_id,
+                ).await.map_err(|e| RunError {
+                    message: format!("Failed to update step: {}", e),
+                    run_id: run_id.to_string(),
+                    thread_id: thread_id.to_string(),
+                    user_id: user_id.to_string(),
+                })?;
+            }
 
-        info!("function_calls: {}", function_calls);
+            // Use the tool call data to build the prompt like Input "functions" Output ""..."" DUMB MODE
+            function_calls = required_action
+                .submit_tool_outputs
+                .tool_calls
+                .iter()
+                .zip(&tool_calls_db)
+                .map(|(input, output)| {
+                    format!(
+                        "<input>{:?}</input>\n\n<output>{:?}</output>",
+                        input.function, output.output
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+
+            info!("function_calls: {}", function_calls);
+        }
     }
 
     info!("Assistant tools: {:?}", assistant.inner.tools);
@@ -597,6 +816,11 @@ pub async fn run_executor(
 
     info!("Tools decision: {:?}", tools_decision);
 
+    // The approved action calls already ran, generating new ones would ask for approval again
+    if approvals_handled {
+        tools_decision.retain(|tool| tool != "action");
+    }
+
     let mut instructions = build_instructions(
         &run.inner.instructions,
         &retrieval_files,
@@ -781,6 +1005,7 @@ pub async fn run_executor(
                 let formatted_messages_clone = formatted_messages.clone();
                 let retrieval_chunks_future = generate_queries_and_fetch_chunks(
                     &pool,
+                    &all_file_ids,
                     client.clone(),
                     request.set_last_user_prompt(formatted_messages_clone).clone().temperature(0.0),
                 );
@@ -838,20 +1063,35 @@ pub async fn run_executor(
                 
             }
             "code_interpreter" => {
-                // Call the safe_interpreter function // TODO: not sure if we should pass formatted_messages or just last user message
-                let interpreter_results = match safe_interpreter(formatted_messages.clone(), 0, 3, 
-                client.clone(),
-                request.clone().temperature(0.0)
-            ).await {
-                    Ok((code_output, code)) => {
-                        // Handle the successful execution of the code
-                        // You might want to store the result or send it back to the user
-                        (code_output, code)
-                    }
+                // Initialize an empty vector to hold all file IDs
+                let mut all_file_ids = Vec::new();
+
+                // If the run has associated file IDs, add them to the list
+                all_file_ids.extend(run.inner.file_ids.iter().cloned());
+
+                // If the assistant has associated file IDs, add them to the list
+                all_file_ids.extend(assistant.inner.file_ids.iter().cloned());
+
+                // Copied into the sandbox under /mnt/data
+                let sandbox_files = load_sandbox_files(pool, file_storage, &all_file_ids, user_id).await;
+
+                // The model runs code until it's done, within the assistant's iteration budget
+                // TODO: not sure if we should pass formatted_messages or just last user message
+                let context = ExecutionContext {
+                    backend: sandbox_backend(),
+                    sessions: kernel_sessions(),
+                    thread_id: Some(thread_id),
+                    files: &sandbox_files,
+                };
+                let iterations = match run_code_interpreter(
+                    client.clone(),
+                    request.clone().temperature(0.0),
+                    max_iterations(assistant.inner.metadata.as_ref()),
+                    &allowed_languages(assistant.inner.metadata.as_ref()),
+                    &context,
+                ).await {
+                    Ok(iterations) => iterations,
                     Err(e) => {
-                        // Handle the error from the interpreter
-                        // You might want to log the error or notify the user
-                        
                         return Err(RunError {
                             message: format!("Failed to run code: {}", e),
                             run_id: run_id.to_string(),
@@ -860,20 +1100,60 @@ pub async fn run_executor(
                         })
                     }
                 };
-                info!("Code interpreter results: {:?}", interpreter_results.clone());
-
-                code_output = Some(interpreter_results.0);
-                code = Some(interpreter_results.1);
-
-
-                if code_output.is_none() {
-                    return Err(RunError {
-                        message: format!("Failed to run code: no output"),
-                        run_id: run_id.to_string(),
-                        thread_id: thread_id.to_string(),
-                        user_id: user_id.to_string(),
-                    });
+                info!("Code interpreter iterations: {:?}", iterations);
+
+                // One tool call per iteration, each with its own logs and images
+                let mut tool_calls = vec![];
+                let mut outputs = vec![];
+                for iteration in iterations {
+                    let succeeded = iteration.succeeded();
+                    let execution_output = iteration.output;
+                    // Uploaded so the final message can show or link them
+                    let saved_files = save_generated_files(pool, file_storage, &execution_output.files, user_id).await;
+                    let mut stdout = execution_output.stdout;
+                    for file in &saved_files {
+                        stdout.push_str(&format!("\nFile written: {}", file.sandbox_path()));
+                    }
+                    if succeeded {
+                        outputs.push(stdout.clone());
+                    } else {
+                        outputs.push(format!("Error: {}", execution_output.stderr));
+                    }
+                    let session_variables = if execution_output.variables.is_empty() {
+                        String::new()
+                    } else {
+                        format!(
+                            "Session variables:\n{}",
+                            execution_output.variables.iter().map(|variable| variable.to_string()).collect::<Vec<_>>().join("\n")
+                        )
+                    };
+                    tool_calls.push(RunStepDetailsToolCalls::Code(RunStepDetailsToolCallsCodeObject{
+                        id: uuid::Uuid::new_v4().to_string(),
+                        r#type: "code_interpreter".to_string(),
+                        code_interpreter: CodeInterpreter {
+                            input: iteration.code,
+                            // stderr (e.g. warnings) and the session variables get their own logs after stdout, then the images
+                            outputs: [stdout, execution_output.stderr, session_variables]
+                                .into_iter()
+                                .filter(|logs| !logs.is_empty())
+                                .map(|logs| CodeInterpreterOutput::Log(RunStepDetailsToolCallsCodeOutputLogsObject{
+                                    r#type: "log".to_string(),
+                                    logs,
+                                }))
+                                .chain(saved_files.iter().filter(|file| file.is_image).map(|file| {
+                                    CodeInterpreterOutput::Image(RunStepDetailsToolCallsCodeOutputImageObject{
+                                        r#type: "image".to_string(),
+                                        image: ImageFile {
+                                            file_id: file.file_id.clone(),
+                                        },
+                                    })
+                                }))
+                                .collect(),
+                        },
+                    }));
+                    generated_files.extend(saved_files);
                 }
+                code_output = Some(outputs.join("\n\n"));
 
                 create_step(
                     pool,
@@ -884,17 +1164,7 @@ pub async fn run_executor(
                     RunStatus::InProgress,
                     StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
                         r#type: "code_interpreter".to_string(),
-                        tool_calls: vec![RunStepDetailsToolCalls::Code(RunStepDetailsToolCallsCodeObject{
-                            id: uuid::Uuid::new_v4().to_string(),
-                            r#type: "code_interpreter".to_string(),
-                            code_interpreter: CodeInterpreter {
-                                input: code.unwrap(),
-                                outputs: vec![CodeInterpreterOutput::Log(RunStepDetailsToolCallsCodeOutputLogsObject{
-                                    r#type: "log".to_string(),
-                                    logs: code_output.clone().unwrap(),
-                                })],
-                            },
-                        })],
+                        tool_calls,
                     }),
                     &run.user_id,
                 ).await.map_err(|e| RunError {
@@ -905,16 +1175,6 @@ pub async fn run_executor(
                 })?;
 
                 // Call file retrieval here
-                // Initialize an empty vector to hold all file IDs
-                let mut all_file_ids = Vec::new();
-
-                // If the run has associated file IDs, add them to the list
-                all_file_ids.extend(run.inner.file_ids.iter().cloned());
-
-                // If the assistant has associated file IDs, add them to the list
-                all_file_ids.extend(assistant.inner.file_ids.iter().cloned());
-
-
                 // Check if the all_file_ids includes any file IDs.
                 if !all_file_ids.is_empty() {
                     info!("Retrieving file contents for file_ids: {:?}", all_file_ids);
@@ -924,6 +1184,7 @@ pub async fn run_executor(
                     let formatted_messages_clone = formatted_messages.clone();
                     let retrieval_chunks_future = generate_queries_and_fetch_chunks(
                         &pool,
+                        &all_file_ids,
                         client.clone(),
                         request.set_last_user_prompt(formatted_messages_clone).clone().temperature(0.0)
                     );
@@ -981,7 +1242,13 @@ pub async fn run_executor(
 
                 info!("Function results: {:?}", function_results);
 
+                // Consequential calls (e.g. POST requests) wait for the user's approval
+                if function_results.iter().any(is_consequential) {
+                    return request_action_approvals(pool, &run, &assistant_id, &function_results).await;
+                }
+
                 // Before the loop, convert the loop into a vector of futures
+                let run_ref = &run;
                 let futures: Vec<_> = function_results.into_iter().map(|function| {
                     let pool = pool.clone();
                     let assistant_id = assistant_id.clone();
@@ -996,18 +1263,7 @@ pub async fn run_executor(
                             &thread_id,
                             RunStepType::ToolCalls,
                             RunStatus::InProgress,
-                            StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
-                                r#type: "function".to_string(), // TODO not sure it should be function or action
-                                tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject{
-                                    id: tool_call_id.clone(),
-                                    r#type: "function".to_string(),
-                                    function: RunStepFunctionObject {
-                                        name: function.name.clone(),
-                                        arguments: function.arguments.clone(),
-                                        output: None,
-                                    }
-                                })],
-                            }),
+                            action_step_details(&tool_call_id, &function.name, &function.arguments, None),
                             &run_user_id,
                         ).await.map_err(|e| RunError {
                             message: format!("Failed to create step: {}", e),
@@ -1015,60 +1271,7 @@ pub async fn run_executor(
                             thread_id: thread_id.to_string(),
                             user_id: user_id.to_string(),
                         })?;
-                        let metadata = function.metadata.unwrap();
-                        let output = execute_request(ActionRequest{
-                            domain: metadata["domain"].to_string().replace("\"", ""),
-                            path: metadata["path"].to_string().replace("\"", ""),
-                            method: metadata["method"].to_string().replace("\"", ""),
-                            operation: metadata["operation"].to_string().replace("\"", ""),
-                            operation_hash: None,
-                            is_consequential: false,
-                            content_type: metadata["content_type"].to_string().replace("\"", ""),
-                            params: Some(serde_json::from_str(&function.arguments).unwrap()),
-                            headers: metadata.get("headers").cloned(),
-                        }).await.map_err(|e| RunError {
-                            message: format!("Failed to execute request: {}", e),
-                            run_id: run_id.to_string(),
-                            thread_id: thread_id.to_string(),
-                            user_id: user_id.to_string(),
-                        })?;
-                        let string_output = serde_json::to_string(&output).unwrap();
-                        update_step(
-                            &pool,
-                            &step.inner.id,
-                            RunStatus::Completed,
-                            StepDetails::ToolCalls(RunStepDetailsToolCallsObject {
-                                r#type: "function".to_string(),
-                                tool_calls: vec![RunStepDetailsToolCalls::Function(RunStepDetailsToolCallsFunctionObject{
-                                    id: tool_call_id,
-                                    r#type: "function".to_string(),
-                                    function: RunStepFunctionObject {
-                                        name: function.name.clone(),
-                                        arguments: function.arguments.clone(),
-                                        output: Some(string_output.clone()),
-                                    }
-                                })],
-                            }),
-                            &run_user_id,
-                        ).await.map_err(|e| RunError {
-                            message: format!("Failed to update step: {}", e),
-                            run_id: run_id.to_string(),
-                            thread_id: thread_id.to_string(),
-                            user_id: user_id.to_string(),
-                        })?;
-
-                        info!("Action results: {:?}", output);
-
-                        let stringified_function = serde_json::to_string(&json!({
-                            "name": function.name,
-                            "arguments": function.arguments,
-                        })).unwrap().replace("\\", "");
-
-                        Ok::<_, RunError>(format!(
-                            "<input>{:?}</input>\n\n<output>{:?}</output>",
-                            stringified_function, 
-                            string_output
-                        ).replace("\\\\", "").replace("\\\"", ""))
+                        execute_action_call(&pool, run_ref, &step.inner.id, &tool_call_id, &function, None).await
                     }
                 }).collect();
 
@@ -1147,13 +1350,8 @@ These are additional instructions from the user that you must obey absolutely:
     match result {
         Ok(output) => {
             info!("LLM API output: {}", output);
-            let content = vec![MessageContent::Text(MessageContentTextObject {
-                r#type: "text".to_string(),
-                text: TextData {
-                    value: output.to_string(),
-                    annotations: vec![],
-                },
-            })];
+            // Plain text unless the code interpreter wrote files
+            let content = annotate_generated_files(&output.to_string(), &generated_files);
             let message = add_message_to_thread(
                 pool,
                 &thread.inner.id,
@@ -1226,6 +1424,7 @@ mod tests {
     use async_openai::types::{
         AssistantObject, AssistantTools, AssistantToolsCode, AssistantToolsFunction,
         AssistantToolsRetrieval, ChatCompletionFunctions, MessageObject, MessageRole, RunObject, FunctionObject, AssistantToolsExtra, RunStepObject, ThreadObject,
+        MessageContent, MessageContentTextObject, TextData,
     };
     use hal_9100_core::models::{Assistant, Message, Run, Thread};
     use hal_9100_extra::config::Hal9100Config;
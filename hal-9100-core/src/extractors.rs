@@ -0,0 +1,897 @@
+use calamine::{open_workbook_auto_from_rs, Reader as SpreadsheetReader};
+use hal_9100_core::chunking::{ChunkingStrategy, DEFAULT_CHUNK_SIZE};
+use hal_9100_core::pdf_utils::pdf_mem_to_pages;
+use log::warn;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader as XmlReader;
+use scraper::{ElementRef, Html};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+use zip::ZipArchive;
+
+/// A piece of a document along with where it comes from (page, sheet, heading path...).
+#[derive(Debug, Clone, Default)]
+pub struct ExtractedSection {
+    pub text: String,
+    pub metadata: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractedDocument {
+    pub sections: Vec<ExtractedSection>,
+}
+
+impl ExtractedDocument {
+    pub fn from_text(text: String) -> Self {
+        ExtractedDocument {
+            sections: vec![ExtractedSection {
+                text,
+                metadata: HashMap::new(),
+            }],
+        }
+    }
+
+    /// The whole document as plain text, sections being separated by a newline.
+    pub fn text(&self) -> String {
+        self.sections
+            .iter()
+            .map(|s| s.text.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+}
+
+#[derive(Debug)]
+pub enum ExtractorError {
+    Unsupported(String),
+    Parse(String),
+}
+
+impl fmt::Display for ExtractorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtractorError::Unsupported(file) => write!(f, "Unsupported file type: {}", file),
+            ExtractorError::Parse(err) => write!(f, "Failed to parse file: {}", err),
+        }
+    }
+}
+
+impl Error for ExtractorError {}
+
+fn parse_error<E: fmt::Display>(e: E) -> ExtractorError {
+    ExtractorError::Parse(e.to_string())
+}
+
+/// Turns the raw bytes of a file into text.
+pub trait Extractor: Send + Sync {
+    /// MIME types handled by this extractor, e.g. `text/html`.
+    fn mime_types(&self) -> &'static [&'static str];
+    /// File extensions handled by this extractor, lowercase and without the dot.
+    fn extensions(&self) -> &'static [&'static str];
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError>;
+    /// Strategy used when neither the upload nor the assistant asks for one.
+    fn default_chunking_strategy(&self) -> ChunkingStrategy {
+        ChunkingStrategy::default()
+    }
+}
+
+/// Extractors keyed by MIME type and extension, the last registered extractor wins.
+#[derive(Clone)]
+pub struct ExtractorRegistry {
+    extractors: Vec<Arc<dyn Extractor>>,
+    by_mime_type: HashMap<String, usize>,
+    by_extension: HashMap<String, usize>,
+}
+
+impl ExtractorRegistry {
+    /// An empty registry, see `ExtractorRegistry::default()` for one with the built-in extractors.
+    pub fn new() -> Self {
+        ExtractorRegistry {
+            extractors: Vec::new(),
+            by_mime_type: HashMap::new(),
+            by_extension: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, extractor: Arc<dyn Extractor>) {
+        let index = self.extractors.len();
+        for mime_type in extractor.mime_types() {
+            self.by_mime_type.insert(mime_type.to_string(), index);
+        }
+        for extension in extractor.extensions() {
+            self.by_extension.insert(extension.to_string(), index);
+        }
+        self.extractors.push(extractor);
+    }
+
+    /// Finds the extractor for a file, the extension is trusted over the MIME type
+    /// because clients often send a generic one (`application/octet-stream`, `text/plain`).
+    pub fn find(&self, mime_type: Option<&str>, file_name: &str) -> Option<Arc<dyn Extractor>> {
+        let extension = Path::new(file_name)
+            .extension()
+            .and_then(std::ffi::OsStr::to_str)
+            .map(|e| e.to_lowercase());
+        if let Some(index) = extension.and_then(|e| self.by_extension.get(&e)) {
+            return Some(self.extractors[*index].clone());
+        }
+
+        let mime_type = mime_type
+            .map(|m| {
+                m.split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_lowercase()
+            })
+            .unwrap_or_default();
+        if let Some(index) = self.by_mime_type.get(&mime_type) {
+            return Some(self.extractors[*index].clone());
+        }
+        if mime_type.starts_with("text/") {
+            if let Some(index) = self.by_mime_type.get("text/plain") {
+                return Some(self.extractors[*index].clone());
+            }
+        }
+        None
+    }
+
+    /// Extracts a file, falling back to UTF-8 text when no extractor matches.
+    pub fn extract(
+        &self,
+        mime_type: Option<&str>,
+        file_name: &str,
+        data: &[u8],
+    ) -> Result<ExtractedDocument, ExtractorError> {
+        match self.find(mime_type, file_name) {
+            Some(extractor) => extractor.extract(data),
+            None => String::from_utf8(data.to_vec())
+                .map(ExtractedDocument::from_text)
+                .map_err(|_| ExtractorError::Unsupported(file_name.to_string())),
+        }
+    }
+}
+
+impl Default for ExtractorRegistry {
+    fn default() -> Self {
+        let mut registry = ExtractorRegistry::new();
+        registry.register(Arc::new(PlainTextExtractor));
+        registry.register(Arc::new(MarkdownExtractor));
+        registry.register(Arc::new(PdfExtractor));
+        registry.register(Arc::new(HtmlExtractor));
+        registry.register(Arc::new(DocxExtractor));
+        registry.register(Arc::new(CsvExtractor { delimiter: b',' }));
+        registry.register(Arc::new(CsvExtractor { delimiter: b'\t' }));
+        registry.register(Arc::new(SpreadsheetExtractor));
+        registry.register(Arc::new(EpubExtractor));
+        registry.register(Arc::new(JsonExtractor));
+        registry
+    }
+}
+
+// Accumulates text into sections, starting a new section at every heading
+#[derive(Default)]
+struct SectionBuilder {
+    sections: Vec<ExtractedSection>,
+    headings: Vec<(usize, String)>,
+    current: String,
+    base_metadata: HashMap<String, Value>,
+}
+
+impl SectionBuilder {
+    fn with_metadata(base_metadata: HashMap<String, Value>) -> Self {
+        SectionBuilder {
+            base_metadata,
+            ..Default::default()
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.current.trim().is_empty() {
+            let mut metadata = self.base_metadata.clone();
+            if let Some((_, heading)) = self.headings.last() {
+                let heading_path: Vec<&str> =
+                    self.headings.iter().map(|(_, t)| t.as_str()).collect();
+                metadata.insert("heading".to_string(), json!(heading));
+                metadata.insert("heading_path".to_string(), json!(heading_path));
+            }
+            self.sections.push(ExtractedSection {
+                text: self.current.trim().to_string(),
+                metadata,
+            });
+        }
+        self.current.clear();
+    }
+
+    fn heading(&mut self, level: usize, title: &str) {
+        let title = title.split_whitespace().collect::<Vec<&str>>().join(" ");
+        if title.is_empty() {
+            return;
+        }
+        self.flush();
+        self.headings.retain(|(l, _)| *l < level);
+        self.headings.push((level, title.clone()));
+        self.current.push_str(&title);
+        self.current.push('\n');
+    }
+
+    fn push_text(&mut self, text: &str) {
+        self.current.push_str(text);
+    }
+
+    // Inline text where any run of whitespace counts as a single space (HTML)
+    fn push_inline(&mut self, text: &str) {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let ends_with_space =
+            self.current.is_empty() || self.current.ends_with(char::is_whitespace);
+        if text.starts_with(char::is_whitespace) && !ends_with_space {
+            self.current.push(' ');
+        }
+        self.current.push_str(&words.join(" "));
+        if text.ends_with(char::is_whitespace) && !words.is_empty() {
+            self.current.push(' ');
+        }
+    }
+
+    fn end_block(&mut self) {
+        let trimmed_len = self.current.trim_end_matches(' ').len();
+        self.current.truncate(trimmed_len);
+        if !self.current.is_empty() && !self.current.ends_with('\n') {
+            self.current.push('\n');
+        }
+    }
+
+    fn finish(mut self) -> Vec<ExtractedSection> {
+        self.flush();
+        self.sections
+    }
+}
+
+pub struct PlainTextExtractor;
+
+impl Extractor for PlainTextExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/plain"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["txt", "text", "log", "rst"]
+    }
+
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let text = String::from_utf8(data.to_vec()).map_err(parse_error)?;
+        Ok(ExtractedDocument::from_text(text))
+    }
+}
+
+pub struct MarkdownExtractor;
+
+impl Extractor for MarkdownExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/markdown", "text/x-markdown"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["md", "markdown"]
+    }
+
+    // Headings are kept in the text, the markdown chunking strategy turns them into metadata
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let text = String::from_utf8(data.to_vec()).map_err(parse_error)?;
+        Ok(ExtractedDocument::from_text(text))
+    }
+
+    fn default_chunking_strategy(&self) -> ChunkingStrategy {
+        ChunkingStrategy::Markdown {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+pub struct PdfExtractor;
+
+impl Extractor for PdfExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/pdf"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["pdf"]
+    }
+
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let pages = pdf_mem_to_pages(data).map_err(parse_error)?;
+        // broken pages are skipped, unless nothing could be read at all
+        if !pages.is_empty() && pages.iter().all(|page| page.error.is_some()) {
+            return Err(ExtractorError::Parse(format!(
+                "No readable page in PDF: {}",
+                pages[0].error.clone().unwrap_or_default()
+            )));
+        }
+        Ok(ExtractedDocument {
+            sections: pages
+                .into_iter()
+                .filter(|page| page.error.is_none())
+                .map(|page| ExtractedSection {
+                    text: page.text,
+                    metadata: HashMap::from([("page".to_string(), json!(page.number))]),
+                })
+                .collect(),
+        })
+    }
+
+    fn default_chunking_strategy(&self) -> ChunkingStrategy {
+        ChunkingStrategy::pdf_default()
+    }
+}
+
+const HTML_SKIPPED_ELEMENTS: [&str; 6] = ["head", "script", "style", "noscript", "template", "svg"];
+const HTML_BLOCK_ELEMENTS: [&str; 22] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "header",
+    "footer",
+    "nav",
+    "aside",
+    "main",
+    "li",
+    "ul",
+    "ol",
+    "tr",
+    "table",
+    "blockquote",
+    "pre",
+    "dt",
+    "dd",
+    "figcaption",
+    "hr",
+    "form",
+    "body",
+];
+
+fn walk_html(element: ElementRef, builder: &mut SectionBuilder) {
+    for child in element.children() {
+        if let Some(text) = child.value().as_text() {
+            builder.push_inline(text);
+            continue;
+        }
+        let child = match ElementRef::wrap(child) {
+            Some(child) => child,
+            None => continue,
+        };
+        let name = child.value().name();
+        if HTML_SKIPPED_ELEMENTS.contains(&name) {
+            continue;
+        }
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                let level = name[1..].parse().unwrap_or(1);
+                builder.heading(level, &child.text().collect::<Vec<&str>>().join(" "));
+            }
+            "br" => builder.push_text("\n"),
+            "pre" => {
+                builder.end_block();
+                builder.push_text(&child.text().collect::<String>());
+                builder.end_block();
+            }
+            "td" | "th" => {
+                walk_html(child, builder);
+                builder.push_text(" | ");
+            }
+            _ => {
+                walk_html(child, builder);
+                if HTML_BLOCK_ELEMENTS.contains(&name) {
+                    builder.end_block();
+                }
+            }
+        }
+    }
+}
+
+fn html_to_sections(html: &str, metadata: HashMap<String, Value>) -> Vec<ExtractedSection> {
+    let document = Html::parse_document(html);
+    let mut builder = SectionBuilder::with_metadata(metadata);
+    walk_html(document.root_element(), &mut builder);
+    builder.finish()
+}
+
+pub struct HtmlExtractor;
+
+impl Extractor for HtmlExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/html", "application/xhtml+xml"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["html", "htm", "xhtml"]
+    }
+
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let html = String::from_utf8_lossy(data);
+        Ok(ExtractedDocument {
+            sections: html_to_sections(&html, HashMap::new()),
+        })
+    }
+}
+
+fn xml_attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name)
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.to_string())
+}
+
+fn read_zip_entry(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<String, ExtractorError> {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .map_err(parse_error)?
+        .read_to_string(&mut content)
+        .map_err(parse_error)?;
+    Ok(content)
+}
+
+// "Heading1", "heading 2", "Title"... to a heading level
+fn docx_heading_level(style: &str) -> Option<usize> {
+    let style = style.to_lowercase().replace(' ', "");
+    if style == "title" {
+        return Some(1);
+    }
+    style
+        .strip_prefix("heading")
+        .and_then(|level| level.parse().ok())
+}
+
+pub struct DocxExtractor;
+
+impl Extractor for DocxExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/vnd.openxmlformats-officedocument.wordprocessingml.document"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["docx"]
+    }
+
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let mut archive = ZipArchive::new(Cursor::new(data)).map_err(parse_error)?;
+        let xml = read_zip_entry(&mut archive, "word/document.xml")?;
+
+        let mut reader = XmlReader::from_str(&xml);
+        let mut builder = SectionBuilder::default();
+        let mut paragraph = String::new();
+        let mut heading_level = None;
+        let mut in_text = false;
+        loop {
+            match reader.read_event().map_err(parse_error)? {
+                Event::Start(e) => match e.local_name().as_ref() {
+                    b"p" => {
+                        paragraph.clear();
+                        heading_level = None;
+                    }
+                    b"t" => in_text = true,
+                    b"pStyle" => {
+                        heading_level =
+                            xml_attribute(&e, b"val").and_then(|s| docx_heading_level(&s))
+                    }
+                    _ => {}
+                },
+                Event::Empty(e) => match e.local_name().as_ref() {
+                    b"pStyle" => {
+                        heading_level =
+                            xml_attribute(&e, b"val").and_then(|s| docx_heading_level(&s))
+                    }
+                    b"tab" => paragraph.push('\t'),
+                    b"br" | b"cr" => paragraph.push('\n'),
+                    _ => {}
+                },
+                Event::Text(t) if in_text => {
+                    paragraph.push_str(&t.unescape().map_err(parse_error)?)
+                }
+                Event::End(e) => match e.local_name().as_ref() {
+                    b"t" => in_text = false,
+                    b"p" => {
+                        match heading_level {
+                            Some(level) if !paragraph.trim().is_empty() => {
+                                builder.heading(level, &paragraph)
+                            }
+                            _ => {
+                                builder.push_text(&paragraph);
+                                builder.push_text("\n");
+                            }
+                        }
+                        paragraph.clear();
+                    }
+                    _ => {}
+                },
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        Ok(ExtractedDocument {
+            sections: builder.finish(),
+        })
+    }
+}
+
+// Renders a row as "column: value" pairs so that every chunk keeps the meaning of its cells
+fn format_row(headers: &[String], values: impl Iterator<Item = String>) -> String {
+    values
+        .enumerate()
+        .filter(|(_, value)| !value.trim().is_empty())
+        .map(|(i, value)| match headers.get(i) {
+            Some(header) if !header.is_empty() => format!("{}: {}", header, value.trim()),
+            _ => value.trim().to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub struct CsvExtractor {
+    pub delimiter: u8,
+}
+
+impl Extractor for CsvExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        match self.delimiter {
+            b'\t' => &["text/tab-separated-values"],
+            _ => &["text/csv", "application/csv"],
+        }
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        match self.delimiter {
+            b'\t' => &["tsv"],
+            _ => &["csv"],
+        }
+    }
+
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .flexible(true)
+            .from_reader(data);
+        let headers: Vec<String> = reader
+            .headers()
+            .map_err(parse_error)?
+            .iter()
+            .map(|h| h.trim().to_string())
+            .collect();
+
+        let mut text = String::new();
+        for record in reader.records() {
+            let record = record.map_err(parse_error)?;
+            let row = format_row(&headers, record.iter().map(|v| v.to_string()));
+            if !row.is_empty() {
+                text.push_str(&row);
+                text.push('\n');
+            }
+        }
+
+        Ok(ExtractedDocument {
+            sections: vec![ExtractedSection {
+                text,
+                metadata: HashMap::from([("columns".to_string(), json!(headers))]),
+            }],
+        })
+    }
+
+    fn default_chunking_strategy(&self) -> ChunkingStrategy {
+        // never cut a row in the middle
+        ChunkingStrategy::Recursive {
+            chunk_size: 200,
+            chunk_overlap: 0,
+        }
+    }
+}
+
+pub struct SpreadsheetExtractor;
+
+impl Extractor for SpreadsheetExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &[
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "application/vnd.ms-excel",
+            "application/vnd.oasis.opendocument.spreadsheet",
+        ]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["xlsx", "xlsm", "xlsb", "xls", "ods"]
+    }
+
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let mut workbook = open_workbook_auto_from_rs(Cursor::new(data)).map_err(parse_error)?;
+        let mut sections = Vec::new();
+        for sheet in workbook.sheet_names() {
+            let range = match workbook.worksheet_range(&sheet) {
+                Ok(range) => range,
+                Err(e) => {
+                    warn!("Skipping sheet {}: {}", sheet, e);
+                    continue;
+                }
+            };
+            let mut rows = range.rows();
+            let headers: Vec<String> = match rows.next() {
+                Some(row) => row.iter().map(|cell| cell.to_string()).collect(),
+                None => continue,
+            };
+            let mut text = String::new();
+            for row in rows {
+                let row = format_row(&headers, row.iter().map(|cell| cell.to_string()));
+                if !row.is_empty() {
+                    text.push_str(&row);
+                    text.push('\n');
+                }
+            }
+            sections.push(ExtractedSection {
+                text,
+                metadata: HashMap::from([
+                    ("sheet".to_string(), json!(sheet)),
+                    ("columns".to_string(), json!(headers)),
+                ]),
+            });
+        }
+        Ok(ExtractedDocument { sections })
+    }
+
+    fn default_chunking_strategy(&self) -> ChunkingStrategy {
+        ChunkingStrategy::Recursive {
+            chunk_size: 200,
+            chunk_overlap: 0,
+        }
+    }
+}
+
+pub struct EpubExtractor;
+
+impl Extractor for EpubExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/epub+zip"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["epub"]
+    }
+
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let mut archive = ZipArchive::new(Cursor::new(data)).map_err(parse_error)?;
+
+        // META-INF/container.xml points to the package document listing the chapters
+        let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+        let mut package_path = None;
+        let mut reader = XmlReader::from_str(&container);
+        loop {
+            match reader.read_event().map_err(parse_error)? {
+                Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                    package_path = xml_attribute(&e, b"full-path");
+                    break;
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        let package_path = package_path
+            .ok_or_else(|| ExtractorError::Parse("EPUB without package document".to_string()))?;
+        let package_dir = match package_path.rfind('/') {
+            Some(i) => package_path[..=i].to_string(),
+            None => String::new(),
+        };
+
+        // manifest maps ids to files, spine gives the reading order
+        let package = read_zip_entry(&mut archive, &package_path)?;
+        let mut manifest = HashMap::new();
+        let mut spine = Vec::new();
+        let mut reader = XmlReader::from_str(&package);
+        loop {
+            match reader.read_event().map_err(parse_error)? {
+                Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                    b"item" => {
+                        if let (Some(id), Some(href)) =
+                            (xml_attribute(&e, b"id"), xml_attribute(&e, b"href"))
+                        {
+                            manifest.insert(id, href);
+                        }
+                    }
+                    b"itemref" => spine.extend(xml_attribute(&e, b"idref")),
+                    _ => {}
+                },
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        let mut sections = Vec::new();
+        for (chapter, idref) in spine.iter().enumerate() {
+            let href = match manifest.get(idref) {
+                Some(href) => href,
+                None => continue,
+            };
+            let html = match read_zip_entry(&mut archive, &format!("{}{}", package_dir, href)) {
+                Ok(html) => html,
+                Err(e) => {
+                    warn!("Skipping EPUB chapter {}: {}", href, e);
+                    continue;
+                }
+            };
+            let metadata = HashMap::from([
+                ("chapter".to_string(), json!(chapter + 1)),
+                ("source".to_string(), json!(href)),
+            ]);
+            sections.extend(html_to_sections(&html, metadata));
+        }
+        Ok(ExtractedDocument { sections })
+    }
+}
+
+pub struct JsonExtractor;
+
+impl Extractor for JsonExtractor {
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/json"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["json"]
+    }
+
+    // One section per top-level entry, pretty printed so that the splitter can cut on lines
+    fn extract(&self, data: &[u8]) -> Result<ExtractedDocument, ExtractorError> {
+        let value: Value = serde_json::from_slice(data).map_err(parse_error)?;
+        let entries: Vec<(String, Value)> = match value {
+            Value::Object(map) => map
+                .into_iter()
+                .map(|(key, value)| (format!("$.{}", key), json!({ key: value })))
+                .collect(),
+            Value::Array(items) => items
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| (format!("$[{}]", i), value))
+                .collect(),
+            value => vec![("$".to_string(), value)],
+        };
+        let sections = entries
+            .into_iter()
+            .map(|(path, value)| ExtractedSection {
+                text: serde_json::to_string_pretty(&value).unwrap_or_default(),
+                metadata: HashMap::from([("json_path".to_string(), json!(path))]),
+            })
+            .collect();
+        Ok(ExtractedDocument { sections })
+    }
+
+    fn default_chunking_strategy(&self) -> ChunkingStrategy {
+        ChunkingStrategy::Recursive {
+            chunk_size: 200,
+            chunk_overlap: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_find_extractor() {
+        let registry = ExtractorRegistry::default();
+        let docx = registry.find(Some("application/octet-stream"), "report.DOCX");
+        assert_eq!(docx.unwrap().extensions(), &["docx"]);
+        let html = registry.find(Some("text/html; charset=utf-8"), "export");
+        assert_eq!(html.unwrap().extensions(), &["html", "htm", "xhtml"]);
+        let text = registry.find(Some("text/x-unknown"), "notes");
+        assert_eq!(text.unwrap().extensions(), &["txt", "text", "log", "rst"]);
+        assert!(registry.find(Some("image/png"), "image.png").is_none());
+
+        // unknown files fall back to UTF-8
+        let document = registry.extract(None, "abc.", b"Hello, world!\n").unwrap();
+        assert_eq!(document.text(), "Hello, world!\n");
+        assert!(registry.extract(None, "abc.png", &[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn test_html_extractor() {
+        let html = "<html><head><title>T</title><style>p {}</style></head><body>
+            <h1>Guide</h1><p>Intro   <b>bold</b> text.</p>
+            <h2>Install</h2><ul><li>Step one</li><li>Step two</li></ul>
+            <script>alert('no')</script></body></html>";
+        let document = HtmlExtractor.extract(html.as_bytes()).unwrap();
+        assert_eq!(document.sections.len(), 2);
+        assert_eq!(document.sections[0].text, "Guide\nIntro bold text.");
+        assert_eq!(document.sections[1].text, "Install\nStep one\nStep two");
+        assert_eq!(
+            document.sections[1].metadata["heading_path"],
+            json!(["Guide", "Install"])
+        );
+        assert!(!document.text().contains("alert"));
+    }
+
+    #[test]
+    fn test_docx_extractor() {
+        let document_xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:body>
+<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Overview</w:t></w:r></w:p>
+<w:p><w:r><w:t xml:space="preserve">Hello </w:t></w:r><w:r><w:t>Word &amp; friends</w:t></w:r></w:p>
+<w:p><w:pPr><w:pStyle w:val="Heading2"/></w:pPr><w:r><w:t>Details</w:t></w:r></w:p>
+<w:p><w:r><w:t>More text</w:t></w:r></w:p>
+</w:body></w:document>"#;
+        let data = zip(&[("word/document.xml", document_xml)]);
+        let document = DocxExtractor.extract(&data).unwrap();
+        assert_eq!(document.sections.len(), 2);
+        assert_eq!(document.sections[0].text, "Overview\nHello Word & friends");
+        assert_eq!(
+            document.sections[1].metadata["heading_path"],
+            json!(["Overview", "Details"])
+        );
+    }
+
+    #[test]
+    fn test_csv_extractor() {
+        let data = b"name,age\nAlice,30\nBob,\n";
+        let document = CsvExtractor { delimiter: b',' }.extract(data).unwrap();
+        assert_eq!(document.text(), "name: Alice, age: 30\nname: Bob\n");
+        assert_eq!(
+            document.sections[0].metadata["columns"],
+            json!(["name", "age"])
+        );
+    }
+
+    #[test]
+    fn test_epub_extractor() {
+        let data = zip(&[
+            (
+                "META-INF/container.xml",
+                r#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#,
+            ),
+            (
+                "OEBPS/content.opf",
+                r#"<package><manifest><item id="c2" href="two.xhtml"/><item id="c1" href="one.xhtml"/></manifest>
+<spine><itemref idref="c1"/><itemref idref="c2"/></spine></package>"#,
+            ),
+            (
+                "OEBPS/one.xhtml",
+                "<html><body><h1>One</h1><p>First</p></body></html>",
+            ),
+            (
+                "OEBPS/two.xhtml",
+                "<html><body><h1>Two</h1><p>Second</p></body></html>",
+            ),
+        ]);
+        let document = EpubExtractor.extract(&data).unwrap();
+        assert_eq!(document.sections.len(), 2);
+        assert_eq!(document.sections[0].text, "One\nFirst");
+        assert_eq!(document.sections[1].metadata["chapter"], 2);
+        assert_eq!(document.sections[1].metadata["heading"], "Two");
+    }
+
+    #[test]
+    fn test_json_extractor() {
+        let document = JsonExtractor.extract(br#"[{"a": 1}, {"b": 2}]"#).unwrap();
+        assert_eq!(document.sections.len(), 2);
+        assert_eq!(document.sections[1].metadata["json_path"], "$[1]");
+    }
+}
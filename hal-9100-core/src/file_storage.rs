@@ -1,241 +1,279 @@
 use bytes::Bytes;
-use hal_9100_extra::config::Hal9100Config;
-use log::{info, warn};
-use reqwest;
-use rusty_s3::actions::{
-    CreateBucket, DeleteObject, GetObject, ListObjectsV2, PutObject, S3Action,
+use futures::StreamExt;
+use hal_9100_core::encryption::{is_encrypted, is_encrypted_stream, Keyring};
+use hal_9100_core::storage::{
+    file_stream, ByteStream, LocalStorage, MemoryStorage, S3Storage, StorageBackend, StorageError,
 };
-use rusty_s3::UrlStyle;
-use rusty_s3::{Bucket, Credentials};
-use std::env;
-use std::path::Path;
-use std::time::Duration;
-use tokio::fs::File;
-
-use serde::de::{self, Visitor};
-use serde::{Deserialize, Deserializer};
+use hal_9100_extra::config::Hal9100Config;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::fmt;
-use tokio::io::AsyncReadExt;
-use url::Url;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use uuid;
-const ONE_HOUR: Duration = Duration::from_secs(3600);
+
+// Maximum allowed by S3 for a single ListObjectsV2 call
+const LIST_PAGE_SIZE: usize = 1000;
 
 pub struct FileStorage {
-    bucket: Bucket,
-    credentials: Credentials,
+    backend: Arc<dyn StorageBackend>,
+    // Files are encrypted at rest when a key is configured, see encryption.rs
+    keyring: Option<Arc<Keyring>>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StoredFile {
     pub id: String,
-    // pub file_name: String, // ? no idea how to properly impl this w rusty s3 without using db for metadata :/
+    // The backend only holds the content, filename, purpose and status live in the files table (see files.rs)
     pub last_modified: String,
     pub size: u64,
     pub storage_class: Option<String>,
-    #[serde(deserialize_with = "deserialize_bytes")]
-    pub bytes: Bytes,
 }
 
-fn deserialize_bytes<'de, D>(deserializer: D) -> Result<Bytes, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    struct BytesVisitor;
-
-    impl<'de> Visitor<'de> for BytesVisitor {
-        type Value = Bytes;
-
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a byte array")
-        }
-
-        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            Ok(Bytes::from(v.to_owned()))
-        }
+#[derive(Debug)]
+pub enum UploadError {
+    /// The content went over the size limit (in bytes), nothing was stored.
+    TooLarge(u64),
+    StorageError(StorageError),
+}
 
-        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
-        where
-            A: de::SeqAccess<'de>,
-        {
-            let mut bytes = if let Some(size) = seq.size_hint() {
-                Vec::with_capacity(size)
-            } else {
-                Vec::new()
-            };
-
-            while let Some(elem) = seq.next_element()? {
-                bytes.push(elem);
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::TooLarge(max_size) => {
+                write!(f, "File is larger than the limit of {} bytes", max_size)
             }
-
-            Ok(Bytes::from(bytes))
+            UploadError::StorageError(e) => write!(f, "Storage error: {}", e),
         }
     }
+}
+
+impl std::error::Error for UploadError {}
 
-    deserializer.deserialize_byte_buf(BytesVisitor)
+/// A stored file along with the SHA-256 of its content, computed while uploading.
+#[derive(Debug, Clone)]
+pub struct UploadedFile {
+    pub file: StoredFile,
+    pub sha256: String,
 }
 
-// TODO: all stuff bit inefficient but for now its k
 impl FileStorage {
-    // TODO use config.rs
+    /// Sets up the backend picked by the `storage` config option, panics if it can't be reached.
     pub async fn new(hal_9100_config: Hal9100Config) -> Self {
-        let bucket = Bucket::new(
-            Url::parse(hal_9100_config.s3_endpoint.as_str()).unwrap(),
-            UrlStyle::Path,
-            hal_9100_config.s3_bucket_name,
-            "us-east-1",
-        )
-        .unwrap();
-        let credentials =
-            Credentials::new(hal_9100_config.s3_access_key, hal_9100_config.s3_secret_key);
-        let client = reqwest::Client::new();
-
-        let action = CreateBucket::new(&bucket, &credentials);
-        let signed_url = action.sign(ONE_HOUR);
-
-        // Try to create the bucket
-        match client.put(signed_url).send().await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    info!("Bucket created successfully");
-                } else if response.status() == 409 {
-                    warn!("Bucket already exists");
-                } else {
-                    panic!(
-                        "Unexpected error when creating bucket: {:?}",
-                        response.status()
-                    );
-                }
+        Self::try_new(hal_9100_config)
+            .await
+            .expect("can't set up file storage")
+    }
+
+    pub async fn try_new(hal_9100_config: Hal9100Config) -> Result<Self, StorageError> {
+        let backend: Arc<dyn StorageBackend> = match hal_9100_config.storage.as_str() {
+            "s3" => Arc::new(
+                S3Storage::new(
+                    &hal_9100_config.s3_endpoint,
+                    hal_9100_config.s3_bucket_name,
+                    hal_9100_config.s3_access_key,
+                    hal_9100_config.s3_secret_key,
+                )
+                .await?,
+            ),
+            "fs" => Arc::new(LocalStorage::new(hal_9100_config.storage_path).await?),
+            "memory" => Arc::new(MemoryStorage::new()),
+            other => {
+                return Err(format!(
+                    "Unknown storage \"{}\", expected \"s3\", \"fs\" or \"memory\"",
+                    other
+                )
+                .into())
             }
-            Err(e) => panic!("Failed to send request: {:?}", e),
-        }
+        };
+        let keyring = Keyring::from_config(&hal_9100_config)?.map(Arc::new);
+        Ok(Self::with_backend(backend).with_keyring(keyring))
+    }
 
+    pub fn with_backend(backend: Arc<dyn StorageBackend>) -> Self {
         Self {
-            bucket,
-            credentials,
+            backend,
+            keyring: None,
         }
     }
 
-    pub async fn upload_file(
-        &self,
-        file_path: &Path,
-    ) -> Result<StoredFile, Box<dyn std::error::Error + Send + Sync>> {
+    pub fn with_keyring(mut self, keyring: Option<Arc<Keyring>>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    pub fn keyring(&self) -> Option<Arc<Keyring>> {
+        self.keyring.clone()
+    }
+
+    pub async fn upload_file(&self, file_path: &Path) -> Result<StoredFile, StorageError> {
         let extension = file_path
             .extension()
             .and_then(std::ffi::OsStr::to_str)
             .unwrap_or("");
+        let file = tokio::fs::File::open(file_path).await?;
+        let uploaded = self
+            .upload_stream(extension, file_stream(file), u64::MAX)
+            .await?;
+        Ok(uploaded.file)
+    }
+
+    /// Stores content already in memory, e.g. files generated by the code interpreter.
+    pub async fn upload_bytes(
+        &self,
+        extension: &str,
+        content: Bytes,
+    ) -> Result<UploadedFile, StorageError> {
+        let content: ByteStream<'_> = Box::pin(futures::stream::once(async { Ok(content) }));
+        self.upload_stream(extension, content, u64::MAX)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    /// Streams the content to storage under a new file id, hashing (and encrypting) it on the way.
+    /// Fails with `TooLarge` as soon as more than `max_size` bytes come in.
+    pub async fn upload_stream(
+        &self,
+        extension: &str,
+        content: ByteStream<'_>,
+        max_size: u64,
+    ) -> Result<UploadedFile, UploadError> {
         let file_id = format!("{}.{}", uuid::Uuid::new_v4(), extension);
-        let put = PutObject::new(&self.bucket, Some(&self.credentials), &file_id);
 
-        let mut file = match File::open(file_path).await {
-            Ok(file) => file,
-            Err(e) => return Err(Box::new(e)),
+        let digest = Arc::new(Mutex::new((Sha256::new(), 0u64)));
+        let hashed_content = {
+            let digest = digest.clone();
+            content.map(move |chunk| {
+                let chunk = chunk?;
+                let mut digest = digest.lock().unwrap();
+                digest.1 += chunk.len() as u64;
+                if digest.1 > max_size {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "File too large",
+                    ));
+                }
+                digest.0.update(&chunk);
+                Ok(chunk)
+            })
         };
-        let mut buffer = Vec::new();
-        if let Err(e) = file.read_to_end(&mut buffer).await {
-            return Err(Box::new(e));
-        }
-
-        let signed_url = put.sign(Duration::from_secs(3600)); // Sign the URL for the S3 action
 
-        // You can then use this signed URL to upload the file to S3 using an HTTP client
-        let client = reqwest::Client::new();
-        let response = match client.put(signed_url).body(buffer).send().await {
-            Ok(response) => response,
-            Err(e) => return Err(Box::new(e)),
+        let content: ByteStream<'_> = match &self.keyring {
+            Some(keyring) => keyring.encrypt_stream(Box::pin(hashed_content)),
+            None => Box::pin(hashed_content),
         };
-        if let Err(e) = response.error_for_status_ref() {
-            return Err(Box::new(e));
+        let result = self.backend.put_stream(&file_id, content).await;
+        let (hasher, size) = std::mem::take(&mut *digest.lock().unwrap());
+        match result {
+            // The backend counted the encrypted size
+            Ok(file) => Ok(UploadedFile {
+                file: StoredFile { size, ..file },
+                sha256: format!("{:x}", hasher.finalize()),
+            }),
+            Err(_) if size > max_size => Err(UploadError::TooLarge(max_size)),
+            Err(e) => Err(UploadError::StorageError(e)),
         }
-        let files = self.list_files().await?;
-        let file = files.iter().find(|f| f.id == file_id).unwrap();
+    }
 
-        Ok(file.to_owned())
+    pub async fn get_file_content(&self, object_name: &str) -> Result<Bytes, StorageError> {
+        let content = self.backend.get(object_name).await?;
+        match &self.keyring {
+            Some(keyring) => Ok(Bytes::from(keyring.decrypt(&content)?)),
+            None if is_encrypted(&content) => Err(format!(
+                "{} is encrypted but no encryption key is configured",
+                object_name
+            )
+            .into()),
+            None => Ok(content),
+        }
     }
 
-    pub async fn get_file_content(
+    /// Streams the content of a file instead of buffering it in memory.
+    pub async fn get_file_stream(
         &self,
         object_name: &str,
-    ) -> Result<Bytes, Box<dyn std::error::Error + Send + Sync>> {
-        let mut get = GetObject::new(&self.bucket, Some(&self.credentials), object_name);
-        get.query_mut()
-            .insert("response-cache-control", "no-cache, no-store");
-        let signed_url = get.sign(Duration::from_secs(3600)); // Sign the URL for the S3 action
+    ) -> Result<ByteStream<'static>, StorageError> {
+        let content = self.backend.get_stream(object_name).await?;
+        match &self.keyring {
+            Some(keyring) => Ok(keyring.decrypt_stream(content).await?),
+            None => match is_encrypted_stream(content).await? {
+                (true, _) => Err(format!(
+                    "{} is encrypted but no encryption key is configured",
+                    object_name
+                )
+                .into()),
+                (false, content) => Ok(content),
+            },
+        }
+    }
 
-        // You can then use this signed URL to retrieve the file from S3 using an HTTP client
-        let client = reqwest::Client::new();
-        let response = client.get(signed_url).send().await?.error_for_status()?;
+    /// Looks up the metadata of a single file without downloading it.
+    /// With encryption the size is the stored one, a bit larger than the content.
+    pub async fn retrieve_file(&self, object_name: &str) -> Result<StoredFile, StorageError> {
+        self.backend.head(object_name).await
+    }
 
-        Ok(response.bytes().await?)
+    pub async fn delete_file(&self, object_name: &str) -> Result<(), StorageError> {
+        self.backend.delete(object_name).await
     }
 
-    pub async fn retrieve_file(
-        &self,
-        object_name: &str,
-    ) -> Result<StoredFile, Box<dyn std::error::Error + Send + Sync>> {
-        // HACK until figure out how to get the file properly from S3
-        let files = self.list_files().await?;
-        let file = match files.iter().find(|f| f.id == object_name) {
-            Some(file) => file,
-            None => return Err("File not found".into()), // Properly handle file not found
-        };
-        Ok(file.to_owned())
+    /// Re-encrypts a file under the current master key, returns false if it already was.
+    pub async fn rotate_file_key(&self, object_name: &str) -> Result<bool, StorageError> {
+        let keyring = self
+            .keyring
+            .as_ref()
+            .ok_or("No encryption key is configured")?;
+        let content = self.backend.get_stream(object_name).await?;
+        match keyring.rewrap_stream(content).await? {
+            Some(rewrapped) => {
+                self.backend.put_stream(object_name, rewrapped).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 
-    pub async fn delete_file(
-        &self,
-        object_name: &str,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let delete = DeleteObject::new(&self.bucket, Some(&self.credentials), object_name);
-        let signed_url = delete.sign(Duration::from_secs(3600)); // Sign the URL for the S3 action
-
-        // You can then use this signed URL to delete the file from S3 using an HTTP client
-        let client = reqwest::Client::new();
-        let r = client.delete(signed_url).send().await?.error_for_status()?;
-
-        // // if 204 raise error
-        // if r.status() == 204 {
-        //     return Err(format!("File {} not found, err: {:?}", object_name, r.text().await).into());
-        // }
-        Ok(())
+    /// Re-encrypts every file under the current master key, returns how many were rewritten.
+    pub async fn rotate_keys(&self) -> Result<usize, StorageError> {
+        let mut rotated = 0;
+        for file in self.list_files().await? {
+            if self.rotate_file_key(&file.id).await? {
+                rotated += 1;
+            }
+        }
+        Ok(rotated)
     }
 
-    pub async fn list_files(
+    /// Lists one page of files, pass the returned continuation token to get the next one.
+    pub async fn list_files_page(
         &self,
-    ) -> Result<Vec<StoredFile>, Box<dyn std::error::Error + Send + Sync>> {
-        let action = ListObjectsV2::new(&self.bucket, Some(&self.credentials));
-        let signed_url = action.sign(Duration::from_secs(3600)); // Sign the URL for the S3 action
-
-        // You can then use this signed URL to list the files in the bucket using an HTTP client
-        let client = reqwest::Client::new();
-        let response = client.get(signed_url).send().await?.error_for_status()?;
-        let text = response.text().await?;
-
-        let parsed = ListObjectsV2::parse_response(&text)?;
+        continuation_token: Option<&str>,
+        max_keys: usize,
+    ) -> Result<(Vec<StoredFile>, Option<String>), StorageError> {
+        self.backend.list_page(continuation_token, max_keys).await
+    }
 
-        // get each file
+    /// Lists the metadata of every file, page by page.
+    pub async fn list_files(&self) -> Result<Vec<StoredFile>, StorageError> {
         let mut files = Vec::new();
-        for file in parsed.contents {
-            let file_content = self.get_file_content(&file.key).await?;
-            files.push(StoredFile {
-                id: file.key,
-                // file_name: file.key,
-                last_modified: file.last_modified,
-                size: file.size,
-                storage_class: file.storage_class,
-                bytes: file_content,
-            });
+        let mut continuation_token = None;
+        loop {
+            let (page, next_continuation_token) = self
+                .list_files_page(continuation_token.as_deref(), LIST_PAGE_SIZE)
+                .await?;
+            files.extend(page);
+            match next_continuation_token {
+                Some(token) => continuation_token = Some(token),
+                None => return Ok(files),
+            }
         }
-
-        return Ok(files);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::TryStreamExt;
     use hal_9100_core::pdf_utils::{pdf_mem_to_text, pdf_to_text};
     use std::collections::HashSet;
     use std::fs::File;
@@ -273,7 +311,9 @@ mod tests {
         let r = result.unwrap();
         // Check that the returned key is correct.
         assert!(r.id.ends_with(".txt"));
-        assert_eq!(r.bytes, "Hello, world!\n");
+        assert_eq!(r.size, "Hello, world!\n".len() as u64);
+        let content = fs.get_file_content(&r.id).await.unwrap();
+        assert_eq!(content, "Hello, world!\n");
 
         // Clean up the temporary directory.
         dir.close().unwrap();
@@ -302,8 +342,21 @@ mod tests {
         // Retrieve the file.
         let result = fs.retrieve_file(&new_file.id).await;
 
-        // Check that the retrieval was successful and the content is correct.
-        assert_eq!(result.unwrap().bytes, "Hello, world!\n");
+        // Check that the retrieval was successful and the metadata is correct.
+        let file = result.unwrap();
+        assert_eq!(file.id, new_file.id);
+        assert_eq!(file.size, "Hello, world!\n".len() as u64);
+        assert!(!file.last_modified.is_empty());
+
+        // Stream the content back
+        let content: Vec<Bytes> = fs
+            .get_file_stream(&new_file.id)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(content.concat(), b"Hello, world!\n");
 
         // Clean up the temporary directory.
         dir.close().unwrap();
@@ -370,8 +423,9 @@ mod tests {
         // Read the PDF content
         let content = pdf_to_text(&pdf_path).unwrap();
 
-        // Check the content
-        assert!(content.contains("In this work we propose the Transformer"));
+        // Check the content, ignoring how lines are laid out
+        let words = content.split_whitespace().collect::<Vec<&str>>().join(" ");
+        assert!(words.contains("In this work we propose the Transformer"));
     }
 
     #[tokio::test]
@@ -394,18 +448,147 @@ mod tests {
         writeln!(file2, "Hello again, world!").unwrap();
 
         // Upload the files.
-        fs.upload_file(&file_path1).await.unwrap();
-        fs.upload_file(&file_path2).await.unwrap();
+        let uploaded1 = fs.upload_file(&file_path1).await.unwrap();
+        let uploaded2 = fs.upload_file(&file_path2).await.unwrap();
 
         // List the files.
         let files = fs.list_files().await.unwrap();
 
         // Check that at least the two uploaded files are in the list.
-        let uploaded_files: HashSet<_> = files.iter().map(|f| &f.bytes).collect();
-        assert!(uploaded_files.contains(&Bytes::from_static(b"Hello, world!\n")));
-        assert!(uploaded_files.contains(&Bytes::from_static(b"Hello again, world!\n")));
+        let listed_files: HashSet<_> = files.iter().map(|f| &f.id).collect();
+        assert!(listed_files.contains(&uploaded1.id));
+        assert!(listed_files.contains(&uploaded2.id));
+
+        // Pages are chained through continuation tokens
+        let (page, continuation_token) = fs.list_files_page(None, 1).await.unwrap();
+        assert_eq!(page.len(), 1);
+        let (next_page, _) = fs
+            .list_files_page(continuation_token.as_deref(), 1)
+            .await
+            .unwrap();
+        assert_eq!(next_page.len(), 1);
+        assert_ne!(page[0].id, next_page[0].id);
 
         // Clean up the temporary directory.
         dir.close().unwrap();
     }
+
+    #[tokio::test]
+    async fn test_upload_stream() {
+        let fs = FileStorage::with_backend(Arc::new(MemoryStorage::new()));
+        let content = || -> ByteStream {
+            Box::pin(futures::stream::iter(vec![
+                Ok(Bytes::from_static(b"Hello, ")),
+                Ok(Bytes::from_static(b"world!")),
+            ]))
+        };
+
+        let uploaded = fs.upload_stream("txt", content(), 100).await.unwrap();
+        assert!(uploaded.file.id.ends_with(".txt"));
+        assert_eq!(uploaded.file.size, 13);
+        assert_eq!(
+            uploaded.sha256,
+            "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3"
+        );
+        // same content, same checksum
+        let again = fs.upload_stream("txt", content(), 100).await.unwrap();
+        assert_eq!(again.sha256, uploaded.sha256);
+        assert_ne!(again.file.id, uploaded.file.id);
+
+        match fs.upload_stream("txt", content(), 10).await {
+            Err(UploadError::TooLarge(10)) => (),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+        assert_eq!(fs.list_files().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_storage() {
+        let backend = Arc::new(MemoryStorage::new());
+        let key = base64::encode([7u8; 32]);
+        let keyring = Arc::new(Keyring::new(&key, &[]).unwrap());
+        let fs = FileStorage::with_backend(backend.clone()).with_keyring(Some(keyring));
+
+        let content: ByteStream<'static> = Box::pin(futures::stream::iter(vec![Ok(
+            Bytes::from_static(b"Hello, world!"),
+        )]));
+        let uploaded = fs.upload_stream("txt", content, 100).await.unwrap();
+        assert_eq!(uploaded.file.size, 13);
+        assert_eq!(
+            uploaded.sha256,
+            "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3"
+        );
+
+        // the backend only sees ciphertext
+        let stored = backend.get(&uploaded.file.id).await.unwrap();
+        assert!(is_encrypted(&stored));
+        assert!(!stored.windows(5).any(|window| window == b"Hello"));
+        assert_eq!(
+            fs.get_file_content(&uploaded.file.id).await.unwrap(),
+            "Hello, world!"
+        );
+        let chunks: Vec<Bytes> = fs
+            .get_file_stream(&uploaded.file.id)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"Hello, world!");
+
+        // without the key the content can't be read
+        let plain = FileStorage::with_backend(backend.clone());
+        assert!(plain.get_file_content(&uploaded.file.id).await.is_err());
+
+        // files stored before encryption was enabled are encrypted by the rotation
+        backend
+            .put("plain.txt", Bytes::from_static(b"Not a secret"))
+            .await
+            .unwrap();
+        let new_key = base64::encode([8u8; 32]);
+        let rotating = FileStorage::with_backend(backend.clone())
+            .with_keyring(Some(Arc::new(Keyring::new(&new_key, &[key]).unwrap())));
+        assert_eq!(rotating.rotate_keys().await.unwrap(), 2);
+        assert_eq!(rotating.rotate_keys().await.unwrap(), 0);
+
+        let rotated = FileStorage::with_backend(backend.clone())
+            .with_keyring(Some(Arc::new(Keyring::new(&new_key, &[]).unwrap())));
+        assert_eq!(
+            rotated.get_file_content(&uploaded.file.id).await.unwrap(),
+            "Hello, world!"
+        );
+        assert_eq!(
+            rotated.get_file_content("plain.txt").await.unwrap(),
+            "Not a secret"
+        );
+        assert!(is_encrypted(&backend.get("plain.txt").await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_stream_without_key() {
+        let backend = Arc::new(MemoryStorage::new());
+        let keyring = Arc::new(Keyring::new(&base64::encode([7u8; 32]), &[]).unwrap());
+        let encrypted = FileStorage::with_backend(backend.clone()).with_keyring(Some(keyring));
+        let content: ByteStream<'static> = Box::pin(futures::stream::iter(vec![Ok(
+            Bytes::from_static(b"Hello, world!"),
+        )]));
+        let uploaded = encrypted.upload_stream("txt", content, 100).await.unwrap();
+        backend
+            .put("plain.txt", Bytes::from_static(b"Not a secret"))
+            .await
+            .unwrap();
+
+        // ciphertext is never streamed out as if it was the content
+        let plain = FileStorage::with_backend(backend.clone());
+        assert!(plain.get_file_stream(&uploaded.file.id).await.is_err());
+
+        let chunks: Vec<Bytes> = plain
+            .get_file_stream("plain.txt")
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"Not a secret");
+    }
 }
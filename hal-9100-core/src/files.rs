@@ -0,0 +1,376 @@
+use async_openai::types::{OpenAIFile, OpenAIFilePurpose};
+use log::info;
+use serde_json::Value;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use std::fmt;
+
+use hal_9100_core::models::File;
+
+/// Ingestion status of a file, see `ingestion.rs`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileStatus {
+    Uploaded,
+    Processed,
+    Error,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileStatus::Uploaded => write!(f, "uploaded"),
+            FileStatus::Processed => write!(f, "processed"),
+            FileStatus::Error => write!(f, "error"),
+        }
+    }
+}
+
+// Purposes we don't know about (e.g. from older clients) are stored as is and served as assistants files
+fn parse_purpose(purpose: &str) -> OpenAIFilePurpose {
+    serde_json::from_value(Value::String(purpose.to_string()))
+        .unwrap_or(OpenAIFilePurpose::Assistants)
+}
+
+struct FileRow {
+    id: String,
+    object: Option<String>,
+    created_at: i32,
+    bytes: i64,
+    filename: String,
+    purpose: String,
+    content_type: String,
+    status: String,
+    status_details: Option<String>,
+    sha256: Option<String>,
+    user_id: Option<Uuid>,
+}
+
+impl From<FileRow> for File {
+    fn from(row: FileRow) -> Self {
+        File {
+            inner: OpenAIFile {
+                id: row.id,
+                object: row.object.unwrap_or_default(),
+                bytes: row.bytes as u32,
+                created_at: row.created_at as u32,
+                filename: row.filename,
+                purpose: parse_purpose(&row.purpose),
+                status: Some(row.status),
+                status_details: row.status_details,
+            },
+            content_type: row.content_type,
+            sha256: row.sha256,
+            user_id: row.user_id.unwrap_or_default().to_string(),
+        }
+    }
+}
+
+/// A file whose content is in storage, to be recorded in the files table
+#[derive(Debug, Clone, Copy)]
+pub struct NewFile<'a> {
+    /// Storage name of the file, also its id
+    pub id: &'a str,
+    pub filename: &'a str,
+    pub bytes: i64,
+    pub purpose: &'a str,
+    pub content_type: &'a str,
+    pub sha256: Option<&'a str>,
+    pub user_id: &'a str,
+}
+
+pub async fn create_file(pool: &PgPool, file: NewFile<'_>) -> Result<File, sqlx::Error> {
+    info!(
+        "Creating file {} ({}) for user_id: {}",
+        file.id, file.filename, file.user_id
+    );
+    let row = sqlx::query_as!(
+        FileRow,
+        r#"
+        INSERT INTO files (id, object, bytes, filename, purpose, content_type, status, sha256, user_id)
+        VALUES ($1, 'file', $2, $3, $4, $5, $6, $7, $8)
+        RETURNING *
+        "#,
+        file.id,
+        file.bytes,
+        file.filename,
+        file.purpose,
+        file.content_type,
+        FileStatus::Uploaded.to_string(),
+        file.sha256,
+        Uuid::try_parse(file.user_id).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.into())
+}
+
+pub async fn get_file(pool: &PgPool, file_id: &str, user_id: &str) -> Result<File, sqlx::Error> {
+    info!("Getting file from database for file_id: {}", file_id);
+    let row = sqlx::query_as!(
+        FileRow,
+        r#"
+        SELECT * FROM files WHERE id = $1 AND user_id::text = $2
+        "#,
+        file_id,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.into())
+}
+
+pub async fn list_files(
+    pool: &PgPool,
+    user_id: &str,
+    purpose: Option<&str>,
+) -> Result<Vec<File>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        FileRow,
+        r#"
+        SELECT * FROM files
+        WHERE user_id::text = $1 AND ($2::text IS NULL OR purpose = $2)
+        ORDER BY created_at DESC
+        "#,
+        user_id,
+        purpose,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(File::from).collect())
+}
+
+/// Latest processed file with the same content, other than `file_id` itself.
+pub async fn find_processed_duplicate(
+    pool: &PgPool,
+    file_id: &str,
+    sha256: &str,
+    user_id: &str,
+) -> Result<Option<File>, sqlx::Error> {
+    let row = sqlx::query_as!(
+        FileRow,
+        r#"
+        SELECT * FROM files
+        WHERE sha256 = $1 AND id <> $2 AND user_id::text = $3 AND status = $4
+        ORDER BY created_at DESC
+        LIMIT 1
+        "#,
+        sha256,
+        file_id,
+        user_id,
+        FileStatus::Processed.to_string(),
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(File::from))
+}
+
+/// Files among `file_ids`, ids without metadata (e.g. uploaded before the files table) are skipped.
+pub async fn get_files(
+    pool: &PgPool,
+    file_ids: &[String],
+    user_id: &str,
+) -> Result<Vec<File>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        FileRow,
+        r#"
+        SELECT * FROM files WHERE id = ANY($1) AND user_id::text = $2
+        "#,
+        file_ids,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(File::from).collect())
+}
+
+pub async fn update_file_status(
+    pool: &PgPool,
+    file_id: &str,
+    status: FileStatus,
+    status_details: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE files SET status = $2, status_details = $3 WHERE id = $1
+        "#,
+        file_id,
+        status.to_string(),
+        status_details,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Deletes the file metadata and its chunks, and detaches it from assistants, runs and messages.
+/// The content itself is left to the caller to remove from storage.
+pub async fn delete_file(pool: &PgPool, file_id: &str, user_id: &str) -> Result<File, sqlx::Error> {
+    info!("Deleting file {} for user_id: {}", file_id, user_id);
+    let mut tx = pool.begin().await?;
+
+    let row = sqlx::query_as!(
+        FileRow,
+        r#"
+        DELETE FROM files WHERE id = $1 AND user_id::text = $2
+        RETURNING *
+        "#,
+        file_id,
+        user_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM chunks WHERE file_id = $1", file_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "UPDATE assistants SET file_ids = array_remove(file_ids, $1) WHERE $1 = ANY(file_ids)",
+        file_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE runs SET file_ids = array_remove(file_ids, $1) WHERE $1 = ANY(file_ids)",
+        file_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE messages SET file_ids = array_remove(file_ids, $1) WHERE $1 = ANY(file_ids)",
+        file_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(row.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dotenv::dotenv;
+    use hal_9100_extra::config::Hal9100Config;
+    use sqlx::postgres::PgPoolOptions;
+
+    async fn setup() -> PgPool {
+        dotenv().ok();
+        let hal_9100_config = Hal9100Config::default();
+        PgPoolOptions::new()
+            .max_connections(5)
+            .connect(&hal_9100_config.database_url)
+            .await
+            .expect("Failed to create pool.")
+    }
+
+    #[tokio::test]
+    async fn test_create_get_update_file() {
+        let pool = setup().await;
+        let user_id = Uuid::default().to_string();
+        let file_id = format!("{}.txt", Uuid::new_v4());
+
+        let file = create_file(
+            &pool,
+            NewFile {
+                id: &file_id,
+                filename: "notes.txt",
+                bytes: 17,
+                purpose: "assistants",
+                content_type: "text/plain",
+                sha256: None,
+                user_id: &user_id,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(file.inner.filename, "notes.txt");
+        assert_eq!(file.inner.bytes, 17);
+        assert_eq!(file.inner.purpose, OpenAIFilePurpose::Assistants);
+        assert_eq!(file.inner.status, Some("uploaded".to_string()));
+        assert!(file.inner.created_at > 0);
+
+        update_file_status(&pool, &file_id, FileStatus::Processed, "1 chunks")
+            .await
+            .unwrap();
+        let file = get_file(&pool, &file_id, &user_id).await.unwrap();
+        assert_eq!(file.inner.status, Some("processed".to_string()));
+        assert_eq!(file.inner.status_details, Some("1 chunks".to_string()));
+
+        let files = list_files(&pool, &user_id, Some("assistants"))
+            .await
+            .unwrap();
+        assert!(files.iter().any(|f| f.inner.id == file_id));
+        let files = list_files(&pool, &user_id, Some("fine-tune"))
+            .await
+            .unwrap();
+        assert!(!files.iter().any(|f| f.inner.id == file_id));
+    }
+
+    #[tokio::test]
+    async fn test_delete_file() {
+        let pool = setup().await;
+        let user_id = Uuid::default().to_string();
+        let file_id = format!("{}.txt", Uuid::new_v4());
+        create_file(
+            &pool,
+            NewFile {
+                id: &file_id,
+                filename: "notes.txt",
+                bytes: 5,
+                purpose: "assistants",
+                content_type: "text/plain",
+                sha256: None,
+                user_id: &user_id,
+            },
+        )
+        .await
+        .unwrap();
+        sqlx::query!(
+            "INSERT INTO chunks (sequence, data, file_id, start_index, end_index) VALUES (0, 'hello', $1, 0, 5)",
+            file_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let assistant_id = sqlx::query!(
+            "INSERT INTO assistants (file_ids, user_id) VALUES ($1, $2) RETURNING id",
+            &vec![file_id.clone(), "other.txt".to_string()],
+            Uuid::default(),
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .id;
+
+        delete_file(&pool, &file_id, &user_id).await.unwrap();
+
+        assert!(matches!(
+            get_file(&pool, &file_id, &user_id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        let chunks = sqlx::query!("SELECT id FROM chunks WHERE file_id = $1", file_id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(chunks.is_empty());
+        let assistant = sqlx::query!(
+            "SELECT file_ids FROM assistants WHERE id = $1",
+            assistant_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(assistant.file_ids, Some(vec!["other.txt".to_string()]));
+
+        // deleting twice is a not found
+        assert!(matches!(
+            delete_file(&pool, &file_id, &user_id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+    }
+}
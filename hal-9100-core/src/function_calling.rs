@@ -1,13 +1,12 @@
 use async_openai::types::FunctionCall;
 use async_openai::types::FunctionObject;
+use futures::future::try_join_all;
 use hal_9100_core::models::Function;
 use hal_9100_extra::llm::HalLLMClient;
 use hal_9100_extra::llm::HalLLMRequestArgs;
+use jsonschema::JSONSchema;
 use log::error;
 use log::info;
-use reqwest::header::HeaderMap;
-use reqwest::header::HeaderName;
-use reqwest::header::HeaderValue;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::json;
@@ -16,7 +15,6 @@ use serde_json::Value;
 use sqlx::types::Uuid;
 use sqlx::PgPool;
 use std::fmt;
-use std::io::ErrorKind;
 use std::{collections::HashMap, error::Error, pin::Pin};
 
 use crate::models::FunctionCallInput;
@@ -64,6 +62,39 @@ pub async fn register_function(
     Ok(row.id.clone().to_string())
 }
 
+/// The function of an assistant called `name`
+pub async fn get_function(
+    pool: &PgPool,
+    assistant_id: &str,
+    user_id: &str,
+    name: &str,
+) -> Result<Option<Function>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT name, description, parameters, metadata
+        FROM functions
+        WHERE user_id::text = $1 AND assistant_id::text = $2 AND name = $3
+        LIMIT 1
+        "#,
+        user_id,
+        assistant_id,
+        name
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| Function {
+        metadata: row.metadata,
+        inner: FunctionObject {
+            name: row.name.unwrap_or_default(),
+            description: row.description,
+            parameters: row.parameters.filter(|p| !p.is_null()),
+        },
+        assistant_id: assistant_id.to_string(),
+        user_id: user_id.to_string(),
+    }))
+}
+
 const CREATE_FUNCTION_CALL_SYSTEM: &str = "Given the user's problem, we have a set of functions available that could potentially help solve this problem. Please review the functions and their descriptions, and select the most appropriate function to use. Also, determine the best parameters to use for this function based on the user's context. 
 
 Please provide the name of the function you want to use and the arguments in the following format: { 'name': 'function_name', 'arguments': { 'arg_name1': 'parameter_value', 'arg_name2': 'arg_value' ... } }.
@@ -80,6 +111,7 @@ Rules:
 - The arguments must be valid (e.g. if the function requires a parameter called 'city', then you must provide a valid city name).
 - **IMPORTANT**: Your response should not be a repetition of the prompt. It should be a unique and valid function call based on the user's context and the available functions.
 - If the function has no arguments, you don't need to provide the function arguments (e.g. { \"name\": \"function_name\" }).
+- If you receive a previous_answer with errors, your previous answer was invalid: fix these errors in your new answer.
 - CUT THE FUCKING BULLSHIT - YOUR ANSWER IS JSON NOTHING ELS. Do not add comment but JSON.
 - **IMPORTANT**: IF YOU DO NOT RETURN ONLY JSON A HUMAN WILL DIE
 - IF YOU USE SINGLE QUOTE INSTEAD OF DOUBLE QUOTE IN THE JSON, THE UNIVERSE WILL COME TO AN END
@@ -120,10 +152,34 @@ In this case, the LLM simply returned the exact same input as output, which is n
 Your answer will be used to call the function so it must be in JSON format, do not say anything but the function name and the parameters.
 Function call:";
 
+const SELECT_FUNCTIONS_SYSTEM: &str = "Given the user's problem, we have a set of functions available that could potentially help solve this problem. Please review the functions and their descriptions, and select the ones that are relevant to the user's request. Only select a function if calling it is needed to answer the user.
+
+Please provide the names of the functions you select in the following format: { \"functions\": [\"function_name1\", \"function_name2\"] }. If no function is relevant, provide an empty list: { \"functions\": [] }.
+
+Rules:
+- The function names must be among the functions available.
+- Do not select a function that has nothing to do with the user's request, it will be called for real.
+- Your answer is JSON, nothing else. Do not add comments.
+- Use double quotes in the JSON.
+
+Example:
+
+You receive:
+{\"functions\": [{\"name\": \"weather\", \"description\": \"Get the weather for a city\"}, {\"name\": \"send_message\", \"description\": \"Send a message to a user\"}], \"user_context\": \"Is it going to rain in Toronto today?\"}
+Your answer:
+{ \"functions\": [\"weather\"] }
+
+Selected functions:";
+
+// Calls to a function generated before giving up on valid arguments
+const MAX_ARGUMENT_ATTEMPTS: usize = 3;
+
 #[derive(Debug)]
 pub enum FunctionCallError {
     JsonError(serde_json::Error),
     SqlxError(sqlx::Error),
+    /// The model kept generating arguments that don't match the parameters schema
+    InvalidArguments(String),
     Other(String),
 }
 
@@ -132,6 +188,7 @@ impl fmt::Display for FunctionCallError {
         match self {
             FunctionCallError::JsonError(e) => write!(f, "JSON error: {}", e),
             FunctionCallError::SqlxError(e) => write!(f, "SQLx error: {}", e),
+            FunctionCallError::InvalidArguments(e) => write!(f, "Invalid arguments: {}", e),
             FunctionCallError::Other(e) => write!(f, "Other error: {}", e),
         }
     }
@@ -140,54 +197,185 @@ impl fmt::Display for FunctionCallError {
 impl std::error::Error for FunctionCallError {}
 
 // Pure function to generate a function call
+/// Arguments that don't match the function's parameters schema are sent back to the model with
+/// the validation errors, up to `MAX_ARGUMENT_ATTEMPTS` times.
 pub async fn generate_function_call(
     mut input: FunctionCallInput,
 ) -> Result<FunctionCallWithMetadata, FunctionCallError> {
-    let prompt_data = serde_json::json!({
-        "function": {
-            "name": input.function.inner.name,
-            "description": input.function.inner.description,
-            "parameters": input.function.inner.parameters
-        },
-        "user_context": input.request.get_user_prompt(),
-    });
+    let user_context = input.request.get_user_prompt();
+    input
+        .request
+        .set_system_prompt(CREATE_FUNCTION_CALL_SYSTEM.to_string());
+
+    let mut previous_attempt: Option<(String, Vec<String>)> = None;
+    for attempt in 1..=MAX_ARGUMENT_ATTEMPTS {
+        let mut prompt_data = serde_json::json!({
+            "function": {
+                "name": input.function.inner.name,
+                "description": input.function.inner.description,
+                "parameters": input.function.inner.parameters
+            },
+            "user_context": user_context,
+        });
+        if let Some((answer, errors)) = &previous_attempt {
+            prompt_data["previous_answer"] = json!(answer);
+            prompt_data["errors"] = json!(errors);
+        }
+
+        let prompt = match serde_json::to_string_pretty(&prompt_data) {
+            Ok(json_string) => json_string,
+            Err(e) => {
+                error!("Failed to convert to JSON: {}", e);
+                return Err(FunctionCallError::JsonError(e));
+            }
+        };
+        info!("Generating function call with prompt: {}", prompt);
+
+        let mut request = input.request.clone();
+        request.set_last_user_prompt(prompt);
+
+        let result = match input.client.create_chat_completion(request).await {
+            Ok(res) => res,
+            Err(err) => {
+                error!("Failed to call llm: {}", err);
+                return Err(FunctionCallError::Other(format!(
+                    "Failed to call llm: {}",
+                    err
+                )));
+            }
+        };
+
+        info!("Generated function call: {}", result);
+
+        let errors = match string_to_function_call(&result) {
+            Ok(f_c) => {
+                match validate_arguments(input.function.inner.parameters.as_ref(), &f_c.arguments) {
+                    Ok(()) => {
+                        return Ok(FunctionCallWithMetadata {
+                            name: f_c.name,
+                            arguments: f_c.arguments,
+                            metadata: input.function.metadata,
+                        })
+                    }
+                    Err(errors) => errors,
+                }
+            }
+            Err(e) => vec![format!("The answer is not a function call: {}", e)],
+        };
+        error!(
+            "Invalid call to {} (attempt {}/{}): {:?}",
+            input.function.inner.name, attempt, MAX_ARGUMENT_ATTEMPTS, errors
+        );
+        previous_attempt = Some((result, errors));
+    }
 
-    let prompt = match serde_json::to_string_pretty(&prompt_data) {
-        Ok(json_string) => json_string,
+    let errors = previous_attempt
+        .map(|(_, errors)| errors)
+        .unwrap_or_default();
+    Err(FunctionCallError::InvalidArguments(format!(
+        "the model could not generate valid arguments for {} after {} attempts: {}",
+        input.function.inner.name,
+        MAX_ARGUMENT_ATTEMPTS,
+        errors.join("; ")
+    )))
+}
+
+/// Checks the arguments against the parameters JSON Schema, returns what's wrong with them.
+/// Functions without parameters accept any JSON object.
+pub fn validate_arguments(parameters: Option<&Value>, arguments: &str) -> Result<(), Vec<String>> {
+    let arguments: Value = serde_json::from_str(arguments)
+        .map_err(|e| vec![format!("The arguments are not valid JSON: {}", e)])?;
+    let parameters = match parameters {
+        Some(parameters) if parameters.is_object() => parameters,
+        _ => return Ok(()),
+    };
+    let schema = match JSONSchema::compile(parameters) {
+        Ok(schema) => schema,
         Err(e) => {
-            error!("Failed to convert to JSON: {}", e);
-            return Err(FunctionCallError::JsonError(e));
+            // Not the model's fault, the call goes through as before
+            error!(
+                "Invalid parameters schema, arguments are not validated: {}",
+                e
+            );
+            return Ok(());
         }
     };
-    info!("Generating function call with prompt: {}", prompt);
+    let result = schema.validate(&arguments);
+    result.map_err(|errors| {
+        errors
+            .map(|error| {
+                let path = error.instance_path.to_string();
+                if path.is_empty() {
+                    format!("arguments: {}", error)
+                } else {
+                    format!("{}: {}", path, error)
+                }
+            })
+            .collect()
+    })
+}
 
-    input
-        .request
-        .set_system_prompt(CREATE_FUNCTION_CALL_SYSTEM.to_string());
-    input.request.set_last_user_prompt(prompt.clone());
+/// Asks the model which functions are relevant to the user's request, zero or more of them.
+pub async fn select_functions(
+    client: HalLLMClient,
+    mut request: HalLLMRequestArgs,
+    functions: &[Function],
+) -> Result<Vec<String>, FunctionCallError> {
+    let prompt_data = serde_json::json!({
+        "functions": functions
+            .iter()
+            .map(|function| json!({
+                "name": function.inner.name,
+                "description": function.inner.description,
+            }))
+            .collect::<Vec<_>>(),
+        "user_context": request.get_user_prompt(),
+    });
+    let prompt =
+        serde_json::to_string_pretty(&prompt_data).map_err(FunctionCallError::JsonError)?;
+    info!("Selecting functions with prompt: {}", prompt);
 
-    let result = match input.client.create_chat_completion(input.request).await {
-        Ok(res) => res,
-        Err(err) => {
+    request.set_system_prompt(SELECT_FUNCTIONS_SYSTEM.to_string());
+    request.set_last_user_prompt(prompt);
+
+    let result = client
+        .create_chat_completion(request)
+        .await
+        .map_err(|err| {
             error!("Failed to call llm: {}", err);
-            return Err(FunctionCallError::Other(format!(
-                "Failed to call llm: {}",
-                err
-            )));
+            FunctionCallError::Other(format!("Failed to call llm: {}", err))
+        })?;
+    info!("Selected functions: {}", result);
+
+    let names: Vec<&str> = functions
+        .iter()
+        .map(|function| function.inner.name.as_str())
+        .collect();
+    string_to_selected_functions(&result, &names)
+}
+
+/// The known function names in the model's answer, in order and without duplicates.
+pub fn string_to_selected_functions(
+    s: &str,
+    names: &[&str],
+) -> Result<Vec<String>, FunctionCallError> {
+    let (start, end) = match (s.find('['), s.rfind(']')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => {
+            return Err(FunctionCallError::Other(
+                "No list of functions found in the string".to_string(),
+            ))
         }
     };
-
-    info!("Generated function call: {}", result);
-
-    let f_c = string_to_function_call(&result).map_err(|e| {
-        error!("Failed to convert to JSON: {}", e);
-        e
-    })?;
-    Ok(FunctionCallWithMetadata {
-        name: f_c.name,
-        arguments: f_c.arguments,
-        metadata: input.function.metadata,
-    })
+    let selected: Vec<Value> =
+        serde_json::from_str(&s[start..=end]).map_err(FunctionCallError::JsonError)?;
+    let mut functions: Vec<String> = Vec::new();
+    for name in selected.iter().filter_map(Value::as_str) {
+        if names.contains(&name) && !functions.iter().any(|function| function == name) {
+            functions.push(name.to_string());
+        }
+    }
+    Ok(functions)
 }
 
 fn repair_json_braces(json_str: &str) -> String {
@@ -267,28 +455,36 @@ pub async fn create_function_call(
     .fetch_all(pool)
     .await?;
 
-    let mut results = Vec::new();
-
+    let mut functions = Vec::new();
     for row in rows {
-        // TODO: parallel
-        let input = FunctionCallInput {
-            function: Function {
-                metadata: row.metadata,
-                inner: FunctionObject {
-                    name: row.name.unwrap_or_default(),
-                    description: row.description,
-                    parameters: serde_json::from_value(row.parameters.unwrap_or_default())?,
-                },
-                assistant_id: assistant_id.to_string(),
-                user_id: user_id.to_string(),
+        functions.push(Function {
+            metadata: row.metadata,
+            inner: FunctionObject {
+                name: row.name.unwrap_or_default(),
+                description: row.description,
+                parameters: serde_json::from_value(row.parameters.unwrap_or_default())?,
             },
-            client: client.clone(),
-            request: request.clone(),
-        };
-
-        let result = generate_function_call(input).await?;
-        results.push(result);
+            assistant_id: assistant_id.to_string(),
+            user_id: user_id.to_string(),
+        });
     }
+    if functions.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Arguments are only generated for the functions the model picked, all at once
+    let selected = select_functions(client.clone(), request.clone(), &functions).await?;
+    let calls = functions
+        .into_iter()
+        .filter(|function| selected.contains(&function.inner.name))
+        .map(|function| {
+            generate_function_call(FunctionCallInput {
+                function,
+                client: client.clone(),
+                request: request.clone(),
+            })
+        });
+    let results = try_join_all(calls).await?;
 
     Ok(results)
 }
@@ -305,64 +501,38 @@ pub async fn register_openapi_functions(
     let openapi = OpenAPISpec::new(&openapi_spec_str)
         .map_err(|e| FunctionCallError::Other(format!("Failed to parse OpenAPI spec: {}", e)))?;
 
+    let actions = openapi
+        .get_actions()
+        .map_err(|e| FunctionCallError::Other(format!("Invalid OpenAPI spec: {}", e)))?;
+
     // Vector to hold the IDs of the registered functions
     let mut function_ids = Vec::new();
 
-    // Iterate over each function and save it to the database
-    for (path, function) in openapi.openapi_spec.paths.iter() {
-        // Convert the operations into a Vec so we have an owned, Send version
-        // which let you iterate over it in parallel
-        let operations: Vec<_> = function
-            .methods()
-            .into_iter()
-            .map(|(method, operation)| {
-                // Manually construct a new operation
-                (method, operation.clone())
-            })
-            .collect();
-
-        for (method, operation) in operations {
-            let mut schema = json!({
-                "type": "object",
-                "properties": {},
-            });
-            let mut required: Vec<String> = Vec::new();
-
-            for param in operation.parameters.clone() {
-                let parameter_resolved = param.resolve(&openapi.openapi_spec).unwrap();
-                let param_name = parameter_resolved.name;
-                schema["properties"][param_name.clone()] =
-                    serde_json::to_value(parameter_resolved.schema).unwrap();
-                if parameter_resolved.required.unwrap_or(false) {
-                    required.push(param_name);
-                }
-            }
-
-            schema["required"] = serde_json::to_value(required).unwrap();
-
-            let function = Function {
-                inner: FunctionObject {
-                    name: operation.operation_id.as_ref().unwrap().to_string(),
-                    description: operation.summary.as_ref().map(|s| s.to_string()), // Use summary as description
-                    parameters: Some(schema),
-                },
-                assistant_id: assistant_id.to_string(),
-                user_id: user_id.to_string(),
-                // all the things that the LLM should not use like (domain, path, method, operation, operation_hash, is_consequential, content_type, ...)
-                metadata: Some(json!({
-                    "domain": openapi.openapi_spec.servers[0].url.clone(),
-                    "path": path.to_string(),
-                    "method": method.to_string(),
-                    "operation": operation.operation_id.as_ref().unwrap().to_string(),
-                    // "operation_hash": None,
-                    "is_consequential": false,
-                    "content_type": "application/json".to_string(),
-                    "headers": headers.clone(),
-                })),
-            };
-            let function_id = register_function(pool, function).await?;
-            function_ids.push(function_id);
-        }
+    for action in actions {
+        let request = action.request;
+        let function = Function {
+            inner: FunctionObject {
+                name: action.name,
+                description: action.description,
+                parameters: Some(action.parameters),
+            },
+            assistant_id: assistant_id.to_string(),
+            user_id: user_id.to_string(),
+            // all the things that the LLM should not use like (domain, path, method, operation, operation_hash, is_consequential, content_type, ...)
+            metadata: Some(json!({
+                "domain": request.domain,
+                "path": request.path,
+                "method": request.method,
+                "operation": request.operation,
+                // "operation_hash": None,
+                "is_consequential": request.is_consequential,
+                "content_type": request.content_type,
+                "headers": headers.clone(),
+                "parameters": request.parameters,
+            })),
+        };
+        let function_id = register_function(pool, function).await?;
+        function_ids.push(function_id);
     }
 
     Ok(function_ids)
@@ -370,51 +540,8 @@ pub async fn register_openapi_functions(
 
 pub async fn execute_request(request: ActionRequest) -> Result<serde_json::Value, Box<dyn Error>> {
     let client = reqwest::Client::new();
-    let url = format!("{}{}", request.domain, request.path);
-    let mut headers = HeaderMap::new();
-    if let Some(h) = request.headers {
-        for (k, v) in h.as_object().unwrap_or(&serde_json::Map::new()) {
-            let header_name = HeaderName::from_bytes(k.as_bytes()).unwrap();
-            let header_value = HeaderValue::from_str(v.as_str().unwrap()).unwrap();
-            headers.insert(header_name, header_value);
-        }
-    }
-    let response = match request.method.to_lowercase().as_str() {
-        "get" => {
-            // let params: HashMap<String, String> =
-            // serde_json::from_value(request.params.unwrap_or_default())?;
-            println!("request.params: {:?}", request.params);
-            client
-                .get(&url)
-                .query(&request.params)
-                .headers(headers)
-                .send()
-                .await?
-        }
-        "post" => {
-            client
-                .post(&url)
-                .json(&request.params)
-                .headers(headers)
-                .send()
-                .await?
-        }
-        "put" => {
-            client
-                .put(&url)
-                .json(&request.params)
-                .headers(headers)
-                .send()
-                .await?
-        }
-        "delete" => client.delete(&url).headers(headers).send().await?,
-        _ => {
-            return Err(Box::new(std::io::Error::new(
-                ErrorKind::Other,
-                "Invalid method",
-            )))
-        }
-    };
+    let http_request = request.build(&client)?;
+    let response = client.execute(http_request).await?;
 
     match response.error_for_status() {
         Ok(response) => Ok(response.json::<serde_json::Value>().await?),
@@ -721,6 +848,46 @@ mod tests {
         assert_eq!(metadata["content_type"], "application/json");
     }
 
+    #[test]
+    fn test_string_to_selected_functions() {
+        let names = ["weather", "send_message", "get_user_profile"];
+        let selected = string_to_selected_functions(
+            "Sure! { \"functions\": [\"send_message\", \"unknown\", \"weather\", \"send_message\"] }",
+            &names,
+        )
+        .unwrap();
+        assert_eq!(selected, vec!["send_message", "weather"]);
+
+        let selected = string_to_selected_functions("{ \"functions\": [] }", &names).unwrap();
+        assert!(selected.is_empty());
+
+        assert!(string_to_selected_functions("I don't know", &names).is_err());
+    }
+
+    #[test]
+    fn test_validate_arguments() {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "city": {"type": "string"},
+                "days": {"type": "integer", "minimum": 1}
+            },
+            "required": ["city"]
+        });
+        assert!(validate_arguments(Some(&parameters), r#"{"city": "Toronto", "days": 3}"#).is_ok());
+
+        let errors = validate_arguments(Some(&parameters), r#"{"days": "three"}"#).unwrap_err();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors
+            .iter()
+            .any(|error| error.starts_with("arguments:") && error.contains("city")));
+        assert!(errors.iter().any(|error| error.starts_with("/days:")));
+
+        assert!(validate_arguments(Some(&parameters), "{city: Toronto").is_err());
+        // nothing to validate against
+        assert!(validate_arguments(None, r#"{"anything": 1}"#).is_ok());
+    }
+
     #[test]
     fn test_repair_json_braces() {
         let broken_json = "{ 'key': 'value', ";
@@ -0,0 +1,317 @@
+use hal_9100_core::chunking::{split_sections, ChunkingStrategy};
+use hal_9100_core::extractors::{ExtractorError, ExtractorRegistry};
+use hal_9100_core::file_storage::FileStorage;
+use hal_9100_core::files::{find_processed_duplicate, update_file_status, FileStatus};
+use hal_9100_core::retrieval::{copy_chunks, insert_chunks};
+use log::{error, info};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::error::Error;
+use std::fmt;
+
+pub const INGESTION_QUEUE: &str = "ingestion_queue";
+
+/// A file waiting to be extracted and chunked by the executor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestionJob {
+    pub file_id: String,
+    pub file_name: String,
+    pub content_type: String,
+    /// Strategy asked for at upload time (or by the assistant), the extractor's default otherwise.
+    pub chunking_strategy: Option<ChunkingStrategy>,
+}
+
+#[derive(Debug)]
+pub enum IngestionError {
+    RedisError(redis::RedisError),
+    SqlxError(sqlx::Error),
+    JsonError(serde_json::Error),
+    ExtractorError(ExtractorError),
+    StorageError(String),
+}
+
+impl fmt::Display for IngestionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IngestionError::RedisError(e) => write!(f, "Redis error: {}", e),
+            IngestionError::SqlxError(e) => write!(f, "Database error: {}", e),
+            IngestionError::JsonError(e) => write!(f, "Invalid ingestion job: {}", e),
+            IngestionError::ExtractorError(e) => write!(f, "{}", e),
+            IngestionError::StorageError(e) => write!(f, "Storage error: {}", e),
+        }
+    }
+}
+
+impl Error for IngestionError {}
+
+impl From<redis::RedisError> for IngestionError {
+    fn from(err: redis::RedisError) -> IngestionError {
+        IngestionError::RedisError(err)
+    }
+}
+
+impl From<sqlx::Error> for IngestionError {
+    fn from(err: sqlx::Error) -> IngestionError {
+        IngestionError::SqlxError(err)
+    }
+}
+
+impl From<serde_json::Error> for IngestionError {
+    fn from(err: serde_json::Error) -> IngestionError {
+        IngestionError::JsonError(err)
+    }
+}
+
+impl From<ExtractorError> for IngestionError {
+    fn from(err: ExtractorError) -> IngestionError {
+        IngestionError::ExtractorError(err)
+    }
+}
+
+pub async fn enqueue_ingestion(
+    con: &mut redis::aio::Connection,
+    job: &IngestionJob,
+) -> Result<(), IngestionError> {
+    let _: () = con
+        .lpush(INGESTION_QUEUE, serde_json::to_string(job)?)
+        .await?;
+    Ok(())
+}
+
+/// Skips ingestion when the same content was already processed for this user by copying its chunks.
+/// Returns whether the file could be processed this way, it should be enqueued otherwise.
+pub async fn reuse_duplicate_ingestion(
+    pool: &PgPool,
+    file_id: &str,
+    sha256: &str,
+    user_id: &str,
+) -> Result<bool, IngestionError> {
+    let duplicate = match find_processed_duplicate(pool, file_id, sha256, user_id).await? {
+        Some(duplicate) => duplicate,
+        None => return Ok(false),
+    };
+    let count = copy_chunks(pool, &duplicate.inner.id, file_id).await?;
+    info!(
+        "Reused {} chunks of {} for identical file {}",
+        count, duplicate.inner.id, file_id
+    );
+    update_file_status(
+        pool,
+        file_id,
+        FileStatus::Processed,
+        &format!("{} chunks, reused from {}", count, duplicate.inner.id),
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Extracts and chunks a stored file, returns the number of chunks inserted.
+pub async fn ingest_file(
+    pool: &PgPool,
+    file_storage: &FileStorage,
+    job: &IngestionJob,
+) -> Result<usize, IngestionError> {
+    let content = file_storage
+        .get_file_content(&job.file_id)
+        .await
+        .map_err(|e| IngestionError::StorageError(e.to_string()))?;
+
+    let extractors = ExtractorRegistry::default();
+    let document = extractors.extract(Some(&job.content_type), &job.file_name, &content)?;
+    let strategy = job.chunking_strategy.clone().unwrap_or_else(|| {
+        extractors
+            .find(Some(&job.content_type), &job.file_name)
+            .map(|extractor| extractor.default_chunking_strategy())
+            .unwrap_or_default()
+    });
+
+    let chunks = split_sections(&document.sections, &strategy);
+    let chunks = insert_chunks(pool, chunks, &job.file_id, None).await?;
+    Ok(chunks.len())
+}
+
+/// Waits for the next ingestion job, processes it and records the resulting file status.
+pub async fn try_ingest(
+    pool: &PgPool,
+    con: &mut redis::aio::Connection,
+    file_storage: &FileStorage,
+) -> Result<IngestionJob, IngestionError> {
+    let (_, job_string): (String, String) = con.brpop(INGESTION_QUEUE, 0).await?;
+    let job: IngestionJob = serde_json::from_str(&job_string)?;
+    info!("Ingesting file {} ({})", job.file_id, job.file_name);
+
+    match ingest_file(pool, file_storage, &job).await {
+        Ok(count) => {
+            update_file_status(
+                pool,
+                &job.file_id,
+                FileStatus::Processed,
+                &format!("{} chunks", count),
+            )
+            .await?;
+            Ok(job)
+        }
+        // Images and other binary files are stored as is, they just can't be searched
+        Err(IngestionError::ExtractorError(ExtractorError::Unsupported(_))) => {
+            update_file_status(
+                pool,
+                &job.file_id,
+                FileStatus::Processed,
+                "Unsupported file type, not indexed for retrieval",
+            )
+            .await?;
+            Ok(job)
+        }
+        Err(e) => {
+            update_file_status(pool, &job.file_id, FileStatus::Error, &e.to_string()).await?;
+            Err(e)
+        }
+    }
+}
+
+pub async fn loop_through_ingestion(
+    pool: &PgPool,
+    con: &mut redis::aio::Connection,
+    file_storage: &FileStorage,
+) {
+    loop {
+        match try_ingest(pool, con, file_storage).await {
+            Ok(job) => info!("Ingestion done: {}", job.file_id),
+            Err(e) => error!("Ingestion error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dotenv::dotenv;
+    use hal_9100_core::files::{create_file, get_file, NewFile};
+    use hal_9100_extra::config::Hal9100Config;
+    use sqlx::postgres::PgPoolOptions;
+    use std::io::Write;
+
+    async fn setup() -> (PgPool, redis::aio::Connection, FileStorage) {
+        dotenv().ok();
+        let hal_9100_config = Hal9100Config::default();
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(&hal_9100_config.database_url)
+            .await
+            .expect("Failed to create pool.");
+        let client = redis::Client::open(hal_9100_config.redis_url.clone()).unwrap();
+        let con = client.get_async_connection().await.unwrap();
+        (pool, con, FileStorage::new(hal_9100_config).await)
+    }
+
+    #[tokio::test]
+    async fn test_ingestion_status() {
+        let (pool, mut con, file_storage) = setup().await;
+
+        let mut temp_file = tempfile::Builder::new().suffix(".md").tempfile().unwrap();
+        writeln!(temp_file, "# Title\n\nHello, world!").unwrap();
+        let file = file_storage.upload_file(temp_file.path()).await.unwrap();
+        let user_id = sqlx::types::Uuid::default().to_string();
+        create_file(
+            &pool,
+            NewFile {
+                id: &file.id,
+                filename: "hello.md",
+                bytes: file.size as i64,
+                purpose: "assistants",
+                content_type: "text/markdown",
+                sha256: None,
+                user_id: &user_id,
+            },
+        )
+        .await
+        .unwrap();
+
+        let job = IngestionJob {
+            file_id: file.id.clone(),
+            file_name: "hello.md".to_string(),
+            content_type: "text/markdown".to_string(),
+            chunking_strategy: None,
+        };
+        enqueue_ingestion(&mut con, &job).await.unwrap();
+        let stored = get_file(&pool, &file.id, &user_id).await.unwrap();
+        assert_eq!(stored.inner.status, Some("uploaded".to_string()));
+
+        // other tests may have left jobs in the queue
+        loop {
+            match try_ingest(&pool, &mut con, &file_storage).await {
+                Ok(ingested) if ingested.file_id == file.id => break,
+                _ => continue,
+            }
+        }
+        let stored = get_file(&pool, &file.id, &user_id).await.unwrap();
+        assert_eq!(stored.inner.status, Some("processed".to_string()));
+        assert_eq!(stored.inner.status_details, Some("1 chunks".to_string()));
+
+        let chunks = sqlx::query!("SELECT metadata FROM chunks WHERE file_id = $1", file.id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(chunks[0].metadata.as_ref().unwrap()["heading"], "Title");
+
+        file_storage.delete_file(&file.id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reuse_duplicate_ingestion() {
+        let (pool, _, _) = setup().await;
+        let user_id = sqlx::types::Uuid::default().to_string();
+        let sha256 = sqlx::types::Uuid::new_v4().to_string();
+        let original_id = format!("{}.txt", sqlx::types::Uuid::new_v4());
+        let copy_id = format!("{}.txt", sqlx::types::Uuid::new_v4());
+        for file_id in [&original_id, &copy_id] {
+            create_file(
+                &pool,
+                NewFile {
+                    id: file_id,
+                    filename: "notes.txt",
+                    bytes: 5,
+                    purpose: "assistants",
+                    content_type: "text/plain",
+                    sha256: Some(&sha256),
+                    user_id: &user_id,
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        // nothing to reuse until the original is processed
+        assert!(
+            !reuse_duplicate_ingestion(&pool, &copy_id, &sha256, &user_id)
+                .await
+                .unwrap()
+        );
+
+        sqlx::query!(
+            "INSERT INTO chunks (sequence, data, file_id, start_index, end_index) VALUES (0, 'hello', $1, 0, 5)",
+            original_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        update_file_status(&pool, &original_id, FileStatus::Processed, "1 chunks")
+            .await
+            .unwrap();
+
+        assert!(
+            reuse_duplicate_ingestion(&pool, &copy_id, &sha256, &user_id)
+                .await
+                .unwrap()
+        );
+        let copy = get_file(&pool, &copy_id, &user_id).await.unwrap();
+        assert_eq!(copy.inner.status, Some("processed".to_string()));
+        let chunks = sqlx::query!("SELECT data FROM chunks WHERE file_id = $1", copy_id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].data, "hello");
+    }
+}
@@ -0,0 +1,452 @@
+//! Stateful code interpreter sessions: a Python kernel per thread, so a run can use what the
+//! previous runs of the thread computed.
+//!
+//! The kernel is a small stdlib Python server started as the main process of a sandbox container,
+//! listening on a unix socket (the sandbox has no network). Each execution runs a client through
+//! docker exec which hands it the code and prints the reply as JSON. Sessions idle for longer
+//! than the TTL are evicted, and a session whose execution failed or timed out is dropped. Past
+//! `max_sessions`, the least recently used idle session makes room for a new one.
+
+use bollard::Docker;
+use hal_9100_core::code_interpreter::{
+    collect_files, copy_files, exec_with_stdin, pull_image, sandbox_config, start_sandbox,
+    ContainerGuard, ExecutionOutput, InterpreterError, SandboxFile,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+const KERNEL_SOCKET: &str = "/tmp/.kernel.sock";
+// Runs the code it receives in one namespace that lives as long as the container
+const KERNEL_SCRIPT: &str = "
+import contextlib, io, json, os, socket, traceback, types
+namespace = {'__name__': '__main__'}
+server = socket.socket(socket.AF_UNIX)
+server.bind(os.environ['KERNEL_SOCKET'])
+server.listen()
+while True:
+    conn, _ = server.accept()
+    with conn:
+        code = conn.makefile('rb').read().decode()
+        stdout, stderr, ok = io.StringIO(), io.StringIO(), True
+        with contextlib.redirect_stdout(stdout), contextlib.redirect_stderr(stderr):
+            try:
+                exec(compile(code, '<cell>', 'exec'), namespace)
+            except BaseException:
+                traceback.print_exc()
+                ok = False
+        variables = [
+            {'name': name, 'type': type(value).__name__, 'value': repr(value)[:200]}
+            for name, value in namespace.items()
+            if not name.startswith('_') and not isinstance(value, (types.ModuleType, type)) and not callable(value)
+        ]
+        conn.sendall(json.dumps({'ok': ok, 'stdout': stdout.getvalue(), 'stderr': stderr.getvalue(), 'variables': variables}).encode())
+";
+// Sends stdin to the kernel and prints its reply, waiting for the kernel to be listening
+const CLIENT_SCRIPT: &str = "
+import os, socket, sys, time
+code = sys.stdin.buffer.read()
+for attempt in range(100):
+    try:
+        client = socket.socket(socket.AF_UNIX)
+        client.connect(os.environ['KERNEL_SOCKET'])
+        break
+    except OSError:
+        time.sleep(0.1)
+else:
+    sys.exit('The kernel is not running')
+client.sendall(code)
+client.shutdown(socket.SHUT_WR)
+sys.stdout.buffer.write(client.makefile('rb').read())
+";
+
+/// A variable held by a session, as shown to the model and in the step details.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionVariable {
+    pub name: String,
+    pub r#type: String,
+    /// Truncated repr
+    pub value: String,
+}
+
+impl fmt::Display for SessionVariable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.name, self.r#type, self.value)
+    }
+}
+
+#[derive(Deserialize)]
+struct KernelReply {
+    ok: bool,
+    stdout: String,
+    stderr: String,
+    variables: Vec<SessionVariable>,
+}
+
+struct KernelSession {
+    guard: ContainerGuard,
+    last_used: Instant,
+    variables: Vec<SessionVariable>,
+    // Files already given back, so the next executions only report new ones
+    collected: HashSet<String>,
+}
+
+pub struct KernelSessions {
+    docker: Docker,
+    ttl: Duration,
+    max_sessions: usize,
+    // Executions in the same thread wait for each other on the session lock
+    sessions: Mutex<HashMap<String, Arc<tokio::sync::Mutex<KernelSession>>>>,
+}
+
+impl KernelSessions {
+    /// Pulls the image and starts evicting the sessions idle for longer than `ttl`, at most
+    /// `max_sessions` are kept at once.
+    pub async fn start(ttl: Duration, max_sessions: usize) -> Result<Arc<Self>, InterpreterError> {
+        let docker = Docker::connect_with_local_defaults()?;
+        pull_image().await?;
+        let sessions = Arc::new(KernelSessions {
+            docker,
+            ttl,
+            max_sessions,
+            sessions: Mutex::new(HashMap::new()),
+        });
+        info!(
+            "Starting code interpreter sessions, at most {} evicted after {} seconds idle",
+            max_sessions,
+            ttl.as_secs()
+        );
+        let evicted = Arc::downgrade(&sessions);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(EVICTION_INTERVAL).await;
+                match evicted.upgrade() {
+                    Some(sessions) => sessions.evict_idle(),
+                    None => return,
+                }
+            }
+        });
+        Ok(sessions)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Variables the session of the thread holds, none without a session.
+    pub async fn variables(&self, thread_id: &str) -> Vec<SessionVariable> {
+        let session = self.sessions.lock().unwrap().get(thread_id).cloned();
+        match session {
+            Some(session) => session.lock().await.variables.clone(),
+            None => vec![],
+        }
+    }
+
+    /// Runs the code in the kernel of the thread, started if needed. The session is dropped if
+    /// the execution can't complete, e.g. the kernel ran out of memory or timed out.
+    /// `None` if the thread has no session and there is no room for one, every session is busy.
+    pub async fn execute(
+        &self,
+        thread_id: &str,
+        python_code: &str,
+        timeout: Duration,
+        files: &[SandboxFile],
+    ) -> Result<Option<ExecutionOutput>, InterpreterError> {
+        let shared = match self.session(thread_id).await? {
+            Some(shared) => shared,
+            None => return Ok(None),
+        };
+        let mut session = shared.lock().await;
+        let container_id = session.guard.id().to_string();
+
+        let run = async {
+            copy_files(&self.docker, &container_id, files).await?;
+            // KERNEL_SOCKET comes from the container env
+            let cmd = vec!["python", "-c", CLIENT_SCRIPT];
+            let raw =
+                exec_with_stdin(&self.docker, &container_id, cmd, python_code.as_bytes()).await?;
+            if raw.exit_code != Some(0) {
+                return Err(InterpreterError::new(
+                    format!(
+                        "The session kernel stopped, it most likely ran out of memory: {}",
+                        raw.stderr
+                    ),
+                    python_code,
+                ));
+            }
+            let reply: KernelReply = serde_json::from_str(&raw.stdout)?;
+            let mut output = ExecutionOutput {
+                stdout: reply.stdout,
+                stderr: reply.stderr,
+                exit_code: Some(if reply.ok { 0 } else { 1 }),
+                files: vec![],
+                variables: reply.variables,
+            };
+            if reply.ok {
+                let exclude: Vec<&str> = files
+                    .iter()
+                    .map(|file| file.path.as_str())
+                    .chain(session.collected.iter().map(String::as_str))
+                    .collect();
+                output.files = collect_files(&self.docker, &container_id, &exclude).await?;
+            }
+            Ok(output)
+        };
+
+        let result = match tokio::time::timeout(timeout, run).await {
+            Ok(result) => result,
+            Err(_) => Err(InterpreterError::new(
+                format!(
+                    "Python code execution timed out after {} seconds",
+                    timeout.as_secs()
+                ),
+                python_code,
+            )),
+        };
+        match result {
+            Ok(output) => {
+                session.last_used = Instant::now();
+                session.variables = output.variables.clone();
+                session
+                    .collected
+                    .extend(output.files.iter().map(|file| file.path.clone()));
+                Ok(Some(output))
+            }
+            Err(e) => {
+                info!("Dropping the code interpreter session of {}", thread_id);
+                self.remove(thread_id, &shared);
+                session.guard.remove().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Drops the sessions idle for longer than the TTL, the busy ones are left alone.
+    pub fn evict_idle(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|thread_id, session| match session.try_lock() {
+            Ok(session) if session.last_used.elapsed() >= self.ttl => {
+                info!("Evicting the code interpreter session of {}", thread_id);
+                false
+            }
+            _ => true,
+        });
+    }
+
+    async fn session(
+        &self,
+        thread_id: &str,
+    ) -> Result<Option<Arc<tokio::sync::Mutex<KernelSession>>>, InterpreterError> {
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some(session) = sessions.get(thread_id) {
+                return Ok(Some(session.clone()));
+            }
+            if !self.make_room(&mut sessions) {
+                return Ok(None);
+            }
+        }
+
+        info!("Starting a code interpreter session for {}", thread_id);
+        let mut config = sandbox_config();
+        config.cmd = Some(vec![
+            "python".to_string(),
+            "-c".to_string(),
+            KERNEL_SCRIPT.to_string(),
+        ]);
+        config
+            .env
+            .get_or_insert_with(Vec::new)
+            .push(format!("KERNEL_SOCKET={}", KERNEL_SOCKET));
+        config.labels = Some(HashMap::from([(
+            "hal-9100.session".to_string(),
+            thread_id.to_string(),
+        )]));
+        // The container goes away by itself if the kernel dies
+        config
+            .host_config
+            .get_or_insert_with(Default::default)
+            .auto_remove = Some(true);
+        let session = Arc::new(tokio::sync::Mutex::new(KernelSession {
+            guard: start_sandbox(&self.docker, config).await?,
+            last_used: Instant::now(),
+            variables: vec![],
+            collected: HashSet::new(),
+        }));
+
+        // Another run of the thread may have started one meanwhile, this one is then dropped.
+        // Other threads may have taken the room too.
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(current) = sessions.get(thread_id) {
+            return Ok(Some(current.clone()));
+        }
+        if !self.make_room(&mut sessions) {
+            return Ok(None);
+        }
+        sessions.insert(thread_id.to_string(), session.clone());
+        Ok(Some(session))
+    }
+
+    // Evicts the least recently used idle session at the limit, false if they're all busy
+    fn make_room(
+        &self,
+        sessions: &mut HashMap<String, Arc<tokio::sync::Mutex<KernelSession>>>,
+    ) -> bool {
+        if sessions.len() < self.max_sessions {
+            return true;
+        }
+        let least_recently_used = sessions
+            .iter()
+            .filter_map(|(thread_id, session)| {
+                Some((thread_id.clone(), session.try_lock().ok()?.last_used))
+            })
+            .min_by_key(|(_, last_used)| *last_used);
+        match least_recently_used {
+            Some((thread_id, _)) => {
+                info!(
+                    "Evicting the code interpreter session of {} to make room",
+                    thread_id
+                );
+                sessions.remove(&thread_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Forgets the session unless it was already replaced by a new one
+    fn remove(&self, thread_id: &str, session: &Arc<tokio::sync::Mutex<KernelSession>>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if matches!(sessions.get(thread_id), Some(current) if Arc::ptr_eq(current, session)) {
+            sessions.remove(thread_id);
+        }
+    }
+}
+
+// Installed by the executor at startup when sessions are enabled
+static KERNEL_SESSIONS: RwLock<Option<Arc<KernelSessions>>> = RwLock::new(None);
+
+pub fn set_kernel_sessions(sessions: Option<Arc<KernelSessions>>) {
+    *KERNEL_SESSIONS.write().unwrap() = sessions;
+}
+
+pub fn kernel_sessions() -> Option<Arc<KernelSessions>> {
+    KERNEL_SESSIONS.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[tokio::test]
+    async fn test_kernel_sessions() {
+        let sessions = KernelSessions::start(Duration::from_secs(3600), 4)
+            .await
+            .unwrap();
+
+        let output = sessions
+            .execute("thread-a", "x = 21\ndf = [1, 2]", TIMEOUT, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.exit_code, Some(0));
+        let names: Vec<&str> = output.variables.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, vec!["x", "df"]);
+        assert_eq!(output.variables[0].to_string(), "x (int): 21");
+
+        // the state is kept across executions of a thread, not shared with other threads
+        let output = sessions
+            .execute("thread-a", "print(x * 2)", TIMEOUT, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.stdout.trim(), "42");
+        let output = sessions
+            .execute("thread-b", "print(x)", TIMEOUT, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.exit_code, Some(1));
+        assert!(output.stderr.contains("NameError"), "{:?}", output);
+        assert_eq!(sessions.variables("thread-a").await.len(), 2);
+        assert_eq!(sessions.len(), 2);
+
+        // a timeout drops the session with its state
+        let error = sessions
+            .execute(
+                "thread-a",
+                "import time\ntime.sleep(30)",
+                Duration::from_secs(2),
+                &[],
+            )
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+        assert_eq!(sessions.len(), 1);
+        let output = sessions
+            .execute("thread-a", "print(x)", TIMEOUT, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.exit_code, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_evict_idle() {
+        let sessions = KernelSessions::start(Duration::from_secs(1), 4)
+            .await
+            .unwrap();
+        sessions
+            .execute("thread-a", "x = 1", TIMEOUT, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        sessions.evict_idle();
+        assert_eq!(sessions.len(), 1);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        sessions.evict_idle();
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_max_sessions() {
+        let sessions = KernelSessions::start(Duration::from_secs(3600), 1)
+            .await
+            .unwrap();
+        sessions
+            .execute("thread-a", "x = 1", TIMEOUT, &[])
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the idle session of thread-a makes room for thread-b
+        sessions
+            .execute("thread-b", "y = 2", TIMEOUT, &[])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions.variables("thread-a").await.is_empty());
+        assert_eq!(sessions.variables("thread-b").await.len(), 1);
+
+        // no room while the only session is busy
+        let busy = sessions.sessions.lock().unwrap()["thread-b"].clone();
+        let _guard = busy.lock().await;
+        let output = sessions
+            .execute("thread-a", "x = 1", TIMEOUT, &[])
+            .await
+            .unwrap();
+        assert!(output.is_none());
+    }
+}
@@ -2,10 +2,17 @@
 extern crate self as hal_9100_core;
 
 pub mod assistants;
+pub mod chunking;
 pub mod code_interpreter;
+pub mod container_pool;
+pub mod encryption;
 pub mod executor;
+pub mod extractors;
 pub mod file_storage;
+pub mod files;
 pub mod function_calling;
+pub mod ingestion;
+pub mod kernel_sessions;
 pub mod messages;
 pub mod models;
 pub mod openapi;
@@ -14,5 +21,7 @@ pub mod prompts;
 pub mod retrieval;
 pub mod run_steps;
 pub mod runs;
+pub mod sandbox;
+pub mod storage;
 pub mod test_data;
 pub mod threads;
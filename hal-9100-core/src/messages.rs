@@ -1,10 +1,16 @@
 use hal_9100_core::models::Message;
+use hal_9100_core::encryption::{decrypt_json_column, encrypt_json_column};
 use async_openai::types::{MessageContent, MessageObject, MessageRole};
 use log::{error, info};
 use serde_json::{self, Value};
 use sqlx::types::Uuid;
 use sqlx::PgPool;
 
+// Message content is encrypted at rest when a key is configured
+fn decrypt_content(message_id: &Uuid, content: Value) -> Result<Value, sqlx::Error> {
+    decrypt_json_column(message_id, content).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+}
+
 pub async fn get_message(
     pool: &PgPool,
     thread_id: &str,
@@ -31,7 +37,7 @@ pub async fn get_message(
                 "assistant" => MessageRole::Assistant,
                 _ => MessageRole::User,
             },
-            content: serde_json::from_value(row.content).unwrap_or_default(),
+            content: serde_json::from_value(decrypt_content(&row.id, row.content)?).unwrap_or_default(),
             assistant_id: Some(row.assistant_id.unwrap_or_default().to_string()),
             run_id: Some(row.run_id.unwrap_or_default().to_string()),
             file_ids: row
@@ -77,7 +83,7 @@ pub async fn update_message(
                 "assistant" => MessageRole::Assistant,
                 _ => MessageRole::User,
             },
-            content: serde_json::from_value(row.content).unwrap_or_default(),
+            content: serde_json::from_value(decrypt_content(&row.id, row.content)?).unwrap_or_default(),
             assistant_id: Some(row.assistant_id.unwrap_or_default().to_string()),
             run_id: Some(row.run_id.unwrap_or_default().to_string()),
             file_ids: row
@@ -130,7 +136,7 @@ pub async fn list_messages(
     .await?
     .into_iter()
     .map(|row| {
-        Message {
+        Ok(Message {
             inner: MessageObject {
                 id: row.id.to_string(),
                 created_at: row.created_at,
@@ -140,7 +146,7 @@ pub async fn list_messages(
                     "assistant" => MessageRole::Assistant,
                     _ => MessageRole::User,
                 },
-                content: serde_json::from_value(row.content).unwrap_or_default(),
+                content: serde_json::from_value(decrypt_content(&row.id, row.content)?).unwrap_or_default(),
                 assistant_id: Some(row.assistant_id.unwrap_or_default().to_string()),
                 run_id: Some(row.run_id.unwrap_or_default().to_string()),
                 file_ids: row
@@ -153,9 +159,9 @@ pub async fn list_messages(
                 object: row.object.unwrap_or_default(),
             },
             user_id: row.user_id.unwrap_or_default().to_string(),
-        }
+        })
     })
-    .collect();
+    .collect::<Result<Vec<_>, sqlx::Error>>()?;
     Ok(messages)
 }
 
@@ -180,18 +186,21 @@ pub async fn add_message_to_thread(
         Some(file_ids) => Some(file_ids),
         None => None,
     };
+    // The id is known before the insert, the encrypted content is bound to it
+    let message_id = Uuid::new_v4();
     let row = sqlx::query!(
         r#"
-        INSERT INTO messages (thread_id, role, content, user_id, file_ids)
-        VALUES ($1, $2, to_jsonb($3::jsonb), $4, $5)
+        INSERT INTO messages (id, thread_id, role, content, user_id, file_ids)
+        VALUES ($1, $2, $3, to_jsonb($4::jsonb), $5, $6)
         RETURNING *
         "#,
+        message_id,
         Uuid::parse_str(thread_id).unwrap(),
         match role {
             MessageRole::User => "user",
             MessageRole::Assistant => "assistant",
         },
-        &content_value,
+        &encrypt_json_column(&message_id, &content_value),
         Uuid::parse_str(user_id).unwrap(),
         &file_ids.unwrap_or_default()
     )
@@ -207,7 +216,7 @@ pub async fn add_message_to_thread(
                 "assistant" => MessageRole::Assistant,
                 _ => MessageRole::User,
             },
-            content: serde_json::from_value(row.content).unwrap_or_default(),
+            content: serde_json::from_value(decrypt_content(&row.id, row.content)?).unwrap_or_default(),
             assistant_id: Some(row.assistant_id.unwrap_or_default().to_string()),
             run_id: Some(row.run_id.unwrap_or_default().to_string()),
             file_ids: row.file_ids.unwrap_or_default(),
@@ -16,6 +16,7 @@ DROP TABLE IF EXISTS functions;
 DROP TABLE IF EXISTS tool_calls;
 DROP TABLE IF EXISTS chunks;
 DROP TABLE IF EXISTS run_steps;
+DROP TABLE IF EXISTS files;
 
 -- Create assistants table
 CREATE TABLE assistants (
@@ -114,6 +115,22 @@ CREATE TABLE chunks (
     created_at INTEGER NOT NULL DEFAULT (EXTRACT(EPOCH FROM NOW()))
 );
 
+-- Create files table, the content lives in S3
+CREATE TABLE files (
+    id TEXT PRIMARY KEY, -- file storage name in S3
+    object TEXT,
+    created_at INTEGER NOT NULL DEFAULT (EXTRACT(EPOCH FROM NOW())),
+    bytes BIGINT NOT NULL,
+    filename TEXT NOT NULL,
+    purpose TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'uploaded',
+    status_details TEXT,
+    sha256 TEXT, -- checksum of the content, identical uploads reuse the chunks
+    user_id UUID
+);
+CREATE INDEX ON files (sha256);
+
 CREATE TABLE run_steps (
     id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
     object TEXT,
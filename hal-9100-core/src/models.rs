@@ -1,7 +1,7 @@
 use async_openai::types::{
-    AssistantObject, FunctionObject, MessageCreation, MessageObject, MessageRole, RunObject,
-    RunStatus, RunStepDetailsMessageCreationObject, RunStepObject, RunStepType, StepDetails,
-    ThreadObject,
+    AssistantObject, FunctionObject, MessageCreation, MessageObject, MessageRole, OpenAIFile,
+    RunObject, RunStatus, RunStepDetailsMessageCreationObject, RunStepObject, RunStepType,
+    StepDetails, ThreadObject,
 };
 use hal_9100_extra::llm::{HalLLMClient, HalLLMRequestArgs};
 use redis::RedisError;
@@ -149,6 +149,14 @@ impl Default for Assistant {
     }
 }
 
+#[derive(Debug, sqlx::FromRow, Serialize, Deserialize, Clone)]
+pub struct File {
+    pub inner: OpenAIFile,
+    pub content_type: String,
+    pub sha256: Option<String>,
+    pub user_id: String,
+}
+
 #[derive(Debug, sqlx::FromRow, Serialize, Deserialize, Clone)]
 pub struct SubmittedToolCall {
     // TODO asnyc openai models?
@@ -159,6 +167,16 @@ pub struct SubmittedToolCall {
     pub user_id: String,
 }
 
+/// The user's decision on an action call waiting for approval
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ToolApproval {
+    pub tool_call_id: String,
+    pub approved: bool,
+    /// Edited arguments (a JSON object) the action is called with instead of the generated ones
+    #[serde(default)]
+    pub arguments: Option<String>,
+}
+
 #[derive(Debug, sqlx::FromRow, Serialize, Deserialize, Clone)]
 pub struct Function {
     pub inner: FunctionObject,
@@ -188,11 +206,13 @@ pub struct Chunk {
     pub created_at: i32,
 }
 
+#[derive(Debug, Clone)]
 pub struct PartialChunk {
     pub sequence: i32,
     pub data: String,
     pub start_index: i32,
     pub end_index: i32,
+    pub metadata: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, sqlx::FromRow, Serialize, Deserialize)]
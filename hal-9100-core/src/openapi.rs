@@ -1,7 +1,27 @@
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, COOKIE};
 use reqwest::Method;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
+use std::error::Error;
+
+/// Request body media types actions can send, in order of preference
+const SUPPORTED_CONTENT_TYPES: [&str; 2] =
+    ["application/json", "application/x-www-form-urlencoded"];
+
+/// Function names have to match `^[a-zA-Z0-9_-]{1,64}$`
+const MAX_FUNCTION_NAME_LENGTH: usize = 64;
+
+/// Argument holding the whole request body, when its schema is not an object
+pub const BODY_ARGUMENT: &str = "body";
+
+/// Calls to these methods wait for the user's approval unless the operation sets
+/// `x-openai-isConsequential` to false
+const CONSEQUENTIAL_METHODS: [&str; 4] = ["POST", "PUT", "PATCH", "DELETE"];
+
+/// Operation extension marking whether calls need the user's approval
+const CONSEQUENTIAL_EXTENSION: &str = "x-openai-isConsequential";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FunctionSignature {
@@ -21,17 +41,38 @@ pub struct ActionRequest {
     pub content_type: String,
     pub params: Option<Value>,
     pub headers: Option<serde_json::Value>,
+    /// Where each argument goes: "path", "query", "header", "cookie" or "body" (the whole
+    /// body). Other arguments are fields of the body, or of the query for GET, HEAD and DELETE.
+    #[serde(default)]
+    pub parameters: HashMap<String, String>,
+}
+
+/// An operation of the spec, as a function the model can call
+#[derive(Debug)]
+pub struct ActionFunction {
+    pub name: String,
+    pub description: Option<String>,
+    /// JSON Schema of the arguments, parameters and body fields together
+    pub parameters: Value,
+    pub request: ActionRequest,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAPISpec {
     pub openapi_spec: oas3::OpenApiV3Spec,
+    /// The spec as it was written, `$ref`s are resolved against it
+    #[serde(skip)]
+    pub document: Value,
 }
 
 impl OpenAPISpec {
     pub fn new(spec_str: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let openapi_spec = oas3::from_reader(spec_str.as_bytes())?;
-        Ok(Self { openapi_spec })
+        let document = serde_yaml::from_str(spec_str)?;
+        Ok(Self {
+            openapi_spec,
+            document,
+        })
     }
     pub fn get_functions(&self) -> Result<Vec<oas3::spec::Operation>, serde_json::Error> {
         let mut operations = Vec::new();
@@ -43,27 +84,388 @@ impl OpenAPISpec {
         Ok(operations)
     }
 
-    pub fn get_http_requests(&self) -> HashMap<String, ActionRequest> {
-        let mut requests = HashMap::new();
-
-        for (path, methods) in &self.openapi_spec.paths {
-            for (method, spec) in methods.methods() {
-                let request = ActionRequest {
-                    domain: self.openapi_spec.servers[0].url.clone(),
-                    path: path.to_string(),
-                    method: method.to_string(),
-                    operation: spec.operation_id.as_ref().unwrap().to_string(),
-                    operation_hash: None,
-                    is_consequential: false,
-                    content_type: "application/json".to_string(),
-                    params: None,
-                    headers: None,
+    pub fn get_http_requests(&self) -> Result<HashMap<String, ActionRequest>, Box<dyn Error>> {
+        Ok(self
+            .get_actions()?
+            .into_iter()
+            .map(|action| (action.request.operation.clone(), action.request))
+            .collect())
+    }
+
+    /// Turns every operation into a function, operations whose request body can't be sent
+    /// are skipped
+    pub fn get_actions(&self) -> Result<Vec<ActionFunction>, Box<dyn Error>> {
+        let spec = &self.openapi_spec;
+        let mut actions = Vec::new();
+
+        for (path, path_item) in &spec.paths {
+            for (method, operation) in path_item.methods() {
+                let name = operation_name(method.as_str(), path, operation);
+                let is_consequential = self
+                    .extension(path, method.as_str(), CONSEQUENTIAL_EXTENSION)
+                    .and_then(|value| value.as_bool())
+                    .unwrap_or_else(|| CONSEQUENTIAL_METHODS.contains(&method.as_str()));
+                let domain = server_url(
+                    operation
+                        .servers
+                        .first()
+                        .or(path_item.servers.first())
+                        .or(spec.servers.first()),
+                )
+                .ok_or_else(|| format!("No server is defined for {}", name))?;
+
+                let mut properties = Map::new();
+                let mut required: Vec<String> = Vec::new();
+                let mut locations = HashMap::new();
+
+                // Path level parameters apply to every operation, which can override them
+                for param in path_item
+                    .parameters
+                    .iter()
+                    .chain(operation.parameters.iter())
+                {
+                    let param = param
+                        .resolve(spec)
+                        .map_err(|e| format!("Can't resolve a parameter of {}: {}", name, e))?;
+                    let schema = match &param.schema {
+                        Some(schema) => self.resolve_refs(&serde_json::to_value(schema)?),
+                        None => json!({ "type": "string" }),
+                    };
+                    properties.insert(param.name.clone(), schema);
+                    // Path parameters are always required
+                    if (param.required.unwrap_or(false) || param.location == "path")
+                        && !required.contains(&param.name)
+                    {
+                        required.push(param.name.clone());
+                    }
+                    locations.insert(param.name, param.location);
+                }
+
+                let mut content_type = SUPPORTED_CONTENT_TYPES[0].to_string();
+                if let Some(request_body) = &operation.request_body {
+                    let request_body = request_body
+                        .resolve(spec)
+                        .map_err(|e| format!("Can't resolve the body of {}: {}", name, e))?;
+                    let media = SUPPORTED_CONTENT_TYPES.iter().find_map(|supported| {
+                        request_body
+                            .content
+                            .iter()
+                            .find(|(media_type, _)| content_kind(media_type) == Some(*supported))
+                    });
+                    let (media_type, media) = match media {
+                        Some(media) => media,
+                        None => {
+                            warn!(
+                                "Skipping {}, its body is only sent as {:?}",
+                                name,
+                                request_body.content.keys().collect::<Vec<_>>()
+                            );
+                            continue;
+                        }
+                    };
+                    content_type = media_type.clone();
+                    let body_required = request_body.required.unwrap_or(false);
+                    let schema = match &media.schema {
+                        Some(schema) => self.resolve_refs(&serde_json::to_value(schema)?),
+                        None => json!({}),
+                    };
+
+                    match schema.get("properties").and_then(|p| p.as_object()) {
+                        // The fields of an object body are arguments of their own
+                        Some(fields) => {
+                            for (field, field_schema) in fields {
+                                if properties.contains_key(field) {
+                                    warn!("{} has a parameter and a body field named {}, the body field is left out", name, field);
+                                    continue;
+                                }
+                                properties.insert(field.clone(), field_schema.clone());
+                            }
+                            if body_required {
+                                for field in schema["required"].as_array().into_iter().flatten() {
+                                    if let Some(field) = field.as_str() {
+                                        if !required.iter().any(|r| r == field) {
+                                            required.push(field.to_string());
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        None => {
+                            properties.insert(BODY_ARGUMENT.to_string(), schema);
+                            locations.insert(BODY_ARGUMENT.to_string(), "body".to_string());
+                            if body_required {
+                                required.push(BODY_ARGUMENT.to_string());
+                            }
+                        }
+                    }
+                }
+
+                actions.push(ActionFunction {
+                    name: name.clone(),
+                    description: operation
+                        .summary
+                        .clone()
+                        .or_else(|| operation.description.clone()),
+                    parameters: json!({
+                        "type": "object",
+                        "properties": properties,
+                        "required": required,
+                    }),
+                    request: ActionRequest {
+                        domain,
+                        path: path.to_string(),
+                        method: method.to_string(),
+                        operation: name,
+                        operation_hash: None,
+                        is_consequential,
+                        content_type,
+                        params: None,
+                        headers: None,
+                        parameters: locations,
+                    },
+                });
+            }
+        }
+
+        Ok(actions)
+    }
+
+    /// Extensions (`x-...` fields) of an operation, which the parsed spec leaves out
+    fn extension(&self, path: &str, method: &str, name: &str) -> Option<&Value> {
+        let path = path.replace('~', "~0").replace('/', "~1");
+        self.document.pointer(&format!(
+            "/paths/{}/{}/{}",
+            path,
+            method.to_lowercase(),
+            name
+        ))
+    }
+
+    /// Inlines the `$ref`s of a schema
+    fn resolve_refs(&self, schema: &Value) -> Value {
+        resolve_refs(schema, &self.document, &mut Vec::new())
+    }
+}
+
+/// Replaces `$ref`s with what they point to in the document. A reference to a schema that
+/// is being resolved (a recursive schema) becomes an empty schema, which accepts any value.
+fn resolve_refs(schema: &Value, document: &Value, resolving: &mut Vec<String>) -> Value {
+    match schema {
+        Value::Object(map) => {
+            if let Some(Value::String(reference)) = map.get("$ref") {
+                if resolving.contains(reference) {
+                    return json!({});
+                }
+                let target = reference
+                    .strip_prefix('#')
+                    .and_then(|pointer| document.pointer(pointer));
+                return match target {
+                    Some(target) => {
+                        resolving.push(reference.clone());
+                        let resolved = resolve_refs(target, document, resolving);
+                        resolving.pop();
+                        resolved
+                    }
+                    None => {
+                        warn!("Can't resolve {}, any value is accepted instead", reference);
+                        json!({})
+                    }
                 };
-                requests.insert(request.operation.clone(), request);
             }
+            Value::Object(
+                map.iter()
+                    .map(|(key, value)| (key.clone(), resolve_refs(value, document, resolving)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| resolve_refs(item, document, resolving))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// The server url with its variables set to their default
+fn server_url(server: Option<&oas3::spec::Server>) -> Option<String> {
+    let server = server?;
+    let mut url = server.url.clone();
+    for (name, variable) in &server.variables {
+        url = url.replace(&format!("{{{}}}", name), &variable.default);
+    }
+    Some(url.trim_end_matches('/').to_string())
+}
+
+/// The operationId, or a name made of the method and path for operations without one,
+/// e.g. `get_users_id_posts` for `GET /users/{id}/posts`
+fn operation_name(method: &str, path: &str, operation: &oas3::spec::Operation) -> String {
+    let name = match &operation.operation_id {
+        Some(operation_id) => operation_id.clone(),
+        None => format!("{}_{}", method.to_lowercase(), path),
+    };
+    let name = name
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_");
+    name.chars().take(MAX_FUNCTION_NAME_LENGTH).collect()
+}
+
+/// Which of the supported content types a media type is, e.g. `application/vnd.api+json`
+/// is sent as JSON
+fn content_kind(media_type: &str) -> Option<&'static str> {
+    let base = media_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    if base == "application/json" || base.ends_with("+json") {
+        Some(SUPPORTED_CONTENT_TYPES[0])
+    } else if base == "application/x-www-form-urlencoded" {
+        Some(SUPPORTED_CONTENT_TYPES[1])
+    } else {
+        None
+    }
+}
+
+/// Strings are sent as is, other values as JSON
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Adds a query or form field, arrays are sent as one field per item
+fn push_pairs(pairs: &mut Vec<(String, String)>, name: &str, value: &Value) {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                pairs.push((name.to_string(), value_to_string(item)));
+            }
+        }
+        Value::Null => {}
+        other => pairs.push((name.to_string(), value_to_string(other))),
+    }
+}
+
+impl ActionRequest {
+    /// The request of a registered action function, called with the generated arguments
+    pub fn from_metadata(metadata: &Value, arguments: &str) -> Result<Self, serde_json::Error> {
+        let field = |name: &str| metadata[name].as_str().unwrap_or_default().to_string();
+        Ok(Self {
+            domain: field("domain"),
+            path: field("path"),
+            method: field("method"),
+            operation: field("operation"),
+            operation_hash: None,
+            is_consequential: metadata["is_consequential"].as_bool().unwrap_or(false),
+            content_type: field("content_type"),
+            params: if arguments.trim().is_empty() {
+                None
+            } else {
+                Some(serde_json::from_str(arguments)?)
+            },
+            headers: metadata.get("headers").cloned(),
+            // Functions registered before parameter locations were recorded have none
+            parameters: metadata
+                .get("parameters")
+                .cloned()
+                .and_then(|parameters| serde_json::from_value(parameters).ok())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Builds the HTTP request, placing each argument where the spec expects it
+    pub fn build(&self, client: &reqwest::Client) -> Result<reqwest::Request, Box<dyn Error>> {
+        let method = Method::from_bytes(self.method.to_uppercase().as_bytes())?;
+        let no_body = method == Method::GET || method == Method::HEAD || method == Method::DELETE;
+
+        let mut headers = HeaderMap::new();
+        if let Some(h) = &self.headers {
+            for (k, v) in h.as_object().unwrap_or(&serde_json::Map::new()) {
+                headers.insert(
+                    HeaderName::from_bytes(k.as_bytes())?,
+                    HeaderValue::from_str(&value_to_string(v))?,
+                );
+            }
+        }
+
+        let arguments = match &self.params {
+            Some(Value::Object(arguments)) => arguments.clone(),
+            Some(Value::Null) | None => Map::new(),
+            Some(other) => return Err(format!("Arguments must be an object, got {}", other).into()),
+        };
+        let mut path = self.path.clone();
+        let mut query = Vec::new();
+        let mut cookies = Vec::new();
+        let mut fields = Map::new();
+        let mut body = None;
+        for (name, value) in &arguments {
+            match self.parameters.get(name).map(|location| location.as_str()) {
+                Some("path") => {
+                    let value: String =
+                        url::form_urlencoded::byte_serialize(value_to_string(value).as_bytes())
+                            .collect();
+                    path = path.replace(&format!("{{{}}}", name), &value.replace('+', "%20"));
+                }
+                Some("query") => push_pairs(&mut query, name, value),
+                Some("header") => {
+                    headers.insert(
+                        HeaderName::from_bytes(name.as_bytes())?,
+                        HeaderValue::from_str(&value_to_string(value))?,
+                    );
+                }
+                Some("cookie") => cookies.push(format!("{}={}", name, value_to_string(value))),
+                Some("body") => body = Some(value.clone()),
+                _ if no_body => push_pairs(&mut query, name, value),
+                _ => {
+                    fields.insert(name.clone(), value.clone());
+                }
+            }
+        }
+        if let Some(start) = path.find('{') {
+            let name = path[start + 1..].split('}').next().unwrap_or_default();
+            return Err(format!("Missing path parameter {}", name).into());
+        }
+        if !cookies.is_empty() {
+            // Cookies configured with the action headers are kept
+            if let Some(configured) = headers.get(COOKIE).and_then(|c| c.to_str().ok()) {
+                cookies.insert(0, configured.to_string());
+            }
+            headers.insert(COOKIE, HeaderValue::from_str(&cookies.join("; "))?);
+        }
+
+        let url = format!("{}{}", self.domain.trim_end_matches('/'), path);
+        let mut builder = client.request(method, &url).headers(headers);
+        if !query.is_empty() {
+            builder = builder.query(&query);
+        }
+        let body = body.or(if fields.is_empty() {
+            None
+        } else {
+            Some(Value::Object(fields))
+        });
+        if let Some(body) = body {
+            builder = match content_kind(&self.content_type) {
+                Some("application/json") => builder.json(&body),
+                Some(_) => {
+                    let mut pairs = Vec::new();
+                    for (name, value) in body.as_object().ok_or("A form body must be an object")? {
+                        push_pairs(&mut pairs, name, value);
+                    }
+                    builder.form(&pairs)
+                }
+                None => {
+                    return Err(format!("Unsupported content type {}", self.content_type).into())
+                }
+            };
         }
 
-        requests
+        Ok(builder.build()?)
     }
 }
 
@@ -71,19 +473,236 @@ impl OpenAPISpec {
 mod tests {
     use super::*;
     use crate::test_data::OPENAPI_SPEC;
-    use oas3;
+
     #[test]
     fn test_get_functions_and_requests() {
         // Read the OpenAPI spec from a file
-        let openapi_spec = oas3::from_reader(OPENAPI_SPEC.as_bytes()).unwrap();
-        let openapi = OpenAPISpec { openapi_spec };
+        let openapi = OpenAPISpec::new(OPENAPI_SPEC).unwrap();
 
         // Test get_functions
         let functions = openapi.get_functions().unwrap();
         assert!(!functions.is_empty());
 
         // Test get_http_requests
-        let requests = openapi.get_http_requests();
+        let requests = openapi.get_http_requests().unwrap();
         assert!(!requests.is_empty());
+        assert_eq!(
+            requests["getRandomPages"].domain,
+            "https://en.wikipedia.org/w"
+        );
+    }
+
+    const PETS_SPEC: &str = r#"
+openapi: 3.0.0
+info:
+  title: Pets
+  version: 1.0.0
+servers:
+  - url: https://{region}.pets.example/{version}/
+    variables:
+      region:
+        default: eu
+      version:
+        default: v2
+paths:
+  /owners/{ownerId}/pets:
+    parameters:
+      - name: ownerId
+        in: path
+        schema:
+          type: integer
+    post:
+      operationId: addPet
+      summary: Add a pet
+      parameters:
+        - $ref: '#/components/parameters/Trace'
+        - name: session
+          in: cookie
+          schema:
+            type: string
+      requestBody:
+        required: true
+        content:
+          application/json:
+            schema:
+              $ref: '#/components/schemas/Pet'
+      responses:
+        '200':
+          description: ok
+    get:
+      description: List the pets of an owner
+      parameters:
+        - name: tags
+          in: query
+          schema:
+            type: array
+            items:
+              type: string
+      responses:
+        '200':
+          description: ok
+  /login:
+    post:
+      operationId: login
+      x-openai-isConsequential: false
+      requestBody:
+        content:
+          application/x-www-form-urlencoded:
+            schema:
+              type: object
+              properties:
+                user:
+                  type: string
+      responses:
+        '200':
+          description: ok
+  /photos:
+    put:
+      operationId: uploadPhoto
+      requestBody:
+        content:
+          multipart/form-data:
+            schema:
+              type: object
+      responses:
+        '200':
+          description: ok
+components:
+  parameters:
+    Trace:
+      name: X-Trace
+      in: header
+      schema:
+        type: string
+  schemas:
+    Pet:
+      type: object
+      required: [name]
+      properties:
+        name:
+          type: string
+        parent:
+          $ref: '#/components/schemas/Pet'
+        toy:
+          $ref: '#/components/schemas/Toy'
+    Toy:
+      type: string
+      enum: [ball, rope]
+"#;
+
+    #[test]
+    fn test_get_actions() {
+        let openapi = OpenAPISpec::new(PETS_SPEC).unwrap();
+        let actions = openapi.get_actions().unwrap();
+        // uploadPhoto only takes multipart bodies
+        let names: Vec<_> = actions.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["login", "get_owners_ownerId_pets", "addPet"]);
+
+        let list = &actions[1];
+        assert_eq!(
+            list.description.as_deref(),
+            Some("List the pets of an owner")
+        );
+        assert_eq!(list.request.domain, "https://eu.pets.example/v2");
+        assert_eq!(list.parameters["required"], json!(["ownerId"]));
+
+        let add = &actions[2];
+        assert_eq!(add.request.content_type, "application/json");
+        assert_eq!(add.request.parameters["ownerId"], "path");
+        assert_eq!(add.request.parameters["X-Trace"], "header");
+        assert_eq!(add.request.parameters["session"], "cookie");
+        assert!(!add.request.parameters.contains_key("name"));
+        let properties = &add.parameters["properties"];
+        assert_eq!(
+            properties["toy"],
+            json!({ "type": "string", "enum": ["ball", "rope"] })
+        );
+        // The recursive reference accepts anything instead of looping
+        assert_eq!(properties["parent"], json!({}));
+        assert_eq!(add.parameters["required"], json!(["ownerId", "name"]));
+
+        assert!(!list.request.is_consequential);
+        assert!(add.request.is_consequential);
+
+        let login = &actions[0];
+        assert!(!login.request.is_consequential);
+        assert_eq!(
+            login.request.content_type,
+            "application/x-www-form-urlencoded"
+        );
+        assert_eq!(login.parameters["required"], json!([]));
+    }
+
+    #[test]
+    fn test_build_request() {
+        let openapi = OpenAPISpec::new(PETS_SPEC).unwrap();
+        let client = reqwest::Client::new();
+        let mut actions = openapi.get_actions().unwrap();
+
+        let mut add = actions.pop().unwrap().request;
+        add.headers = Some(json!({ "Authorization": "Bearer token" }));
+        add.params = Some(json!({
+            "ownerId": 7,
+            "X-Trace": "abc",
+            "session": "s1",
+            "name": "Rex",
+        }));
+        let request = add.build(&client).unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(
+            request.url().as_str(),
+            "https://eu.pets.example/v2/owners/7/pets"
+        );
+        assert_eq!(request.headers()["x-trace"], "abc");
+        assert_eq!(request.headers()["cookie"], "session=s1");
+        assert_eq!(request.headers()["authorization"], "Bearer token");
+        let body = request.body().unwrap().as_bytes().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(body).unwrap(),
+            json!({ "name": "Rex" })
+        );
+
+        let mut list = actions.pop().unwrap().request;
+        list.params = Some(json!({ "ownerId": "a b", "tags": ["x", "y"] }));
+        let request = list.build(&client).unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://eu.pets.example/v2/owners/a%20b/pets?tags=x&tags=y"
+        );
+        assert!(request.body().is_none());
+
+        list.params = Some(json!({ "tags": ["x"] }));
+        let error = list.build(&client).unwrap_err();
+        assert_eq!(error.to_string(), "Missing path parameter ownerId");
+
+        let mut login = actions.pop().unwrap().request;
+        login.params = Some(json!({ "user": "dave" }));
+        let request = login.build(&client).unwrap();
+        assert_eq!(request.body().unwrap().as_bytes().unwrap(), b"user=dave");
+    }
+
+    #[test]
+    fn test_action_request_from_metadata() {
+        let metadata = json!({
+            "domain": "https://api.weather.gov",
+            "path": "/weather/{city}",
+            "method": "GET",
+            "operation": "getWeather",
+            "is_consequential": false,
+            "content_type": "application/json",
+            "headers": null,
+            "parameters": { "city": "path" },
+        });
+        let request = ActionRequest::from_metadata(&metadata, r#"{"city": "Paris"}"#).unwrap();
+        assert_eq!(request.path, "/weather/{city}");
+        assert_eq!(request.parameters["city"], "path");
+        assert_eq!(request.params, Some(json!({ "city": "Paris" })));
+        let request = request.build(&reqwest::Client::new()).unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://api.weather.gov/weather/Paris"
+        );
+
+        assert!(ActionRequest::from_metadata(&metadata, "{\"city\": ").is_err());
     }
 }
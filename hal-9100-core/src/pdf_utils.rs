@@ -1,91 +1,529 @@
-use lopdf::{Document, Object};
-use std::collections::BTreeMap;
+use log::warn;
+use lopdf::content::Operation;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use regex::Regex;
+use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::path::Path;
-use std::io::{Error as IoError, ErrorKind};
+
+// Width of a glyph when the font does not tell, in thousandths of the font size
+const DEFAULT_GLYPH_WIDTH: f32 = 500.0;
+
+#[derive(Debug, Clone)]
+pub struct PdfPage {
+    pub number: u32,
+    pub text: String,
+    // Set when the page could not be read, the rest of the document is still extracted
+    pub error: Option<String>,
+}
 
 pub fn pdf_to_text(path: &Path) -> Result<String, Box<dyn StdError>> {
+    Ok(join_pages(&pdf_to_pages(path)?))
+}
+
+pub fn pdf_mem_to_text(data: &[u8]) -> Result<String, Box<dyn StdError>> {
+    Ok(join_pages(&pdf_mem_to_pages(data)?))
+}
+
+pub fn pdf_to_pages(path: &Path) -> Result<Vec<PdfPage>, Box<dyn StdError>> {
     let doc = Document::load(path)?;
+    Ok(extract_pages(&doc))
+}
+
+pub fn pdf_mem_to_pages(data: &[u8]) -> Result<Vec<PdfPage>, Box<dyn StdError>> {
+    let doc = Document::load_mem(data)?;
+    Ok(extract_pages(&doc))
+}
 
-    let mut text: BTreeMap<u32, Vec<String>> = BTreeMap::new();
-    let pages: Vec<Result<(u32, Vec<String>), Box<dyn StdError>>> = doc
-        .get_pages()
+fn join_pages(pages: &[PdfPage]) -> String {
+    pages
         .iter()
-        .map(|(page_num, _)| {
-            let page_text = doc.extract_text(&[*page_num]).map_err(|e| {
-                IoError::new(
-                    ErrorKind::Other,
-                    format!("Failed to extract text from page {}: {:?}", page_num, e),
-                )
-            })?;
-            Ok((
-                *page_num, // Dereference page_num here
-                page_text.split('\n')
-                    .map(|s| s.trim_end().to_string())
-                    .collect::<Vec<String>>(),
-            ))
+        .filter(|page| page.error.is_none())
+        .map(|page| page.text.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+fn extract_pages(doc: &Document) -> Vec<PdfPage> {
+    doc.get_pages()
+        .into_iter()
+        .map(|(number, page_id)| {
+            // fall back on lopdf's naive extraction when the content stream can't be laid out
+            let text = match extract_page_layout(doc, page_id) {
+                Ok(text) if !text.trim().is_empty() => Ok(text),
+                _ => doc
+                    .extract_text(&[number])
+                    .map(|text| dehyphenate(text.trim_end()))
+                    .map_err(|e| e.to_string()),
+            };
+            match text {
+                Ok(text) => PdfPage {
+                    number,
+                    text,
+                    error: None,
+                },
+                Err(e) => {
+                    warn!("Failed to extract text from page {}: {}", number, e);
+                    PdfPage {
+                        number,
+                        text: String::new(),
+                        error: Some(e),
+                    }
+                }
+            }
         })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct TextItem {
+    x: f32,
+    x_end: f32,
+    y: f32,
+    size: f32,
+    text: String,
+}
+
+// Affine matrix [a b c d e f] as used by PDF content streams
+type Matrix = [f32; 6];
+
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+fn multiply(m1: &Matrix, m2: &Matrix) -> Matrix {
+    [
+        m1[0] * m2[0] + m1[1] * m2[2],
+        m1[0] * m2[1] + m1[1] * m2[3],
+        m1[2] * m2[0] + m1[3] * m2[2],
+        m1[2] * m2[1] + m1[3] * m2[3],
+        m1[4] * m2[0] + m1[5] * m2[2] + m2[4],
+        m1[4] * m2[1] + m1[5] * m2[3] + m2[5],
+    ]
+}
+
+fn translate(tx: f32, ty: f32) -> Matrix {
+    [1.0, 0.0, 0.0, 1.0, tx, ty]
+}
+
+struct Font {
+    encoding: String,
+    first_char: i64,
+    widths: Vec<f32>,
+}
+
+impl Font {
+    fn from_dictionary(doc: &Document, dictionary: &Dictionary) -> Self {
+        let widths = dictionary
+            .get(b"Widths")
+            .ok()
+            .and_then(|widths| doc.dereference(widths).ok())
+            .and_then(|(_, widths)| widths.as_array().ok())
+            .map(|widths| {
+                widths
+                    .iter()
+                    .map(|width| {
+                        doc.dereference(width)
+                            .ok()
+                            .and_then(|(_, width)| width.as_float().ok())
+                            .unwrap_or(DEFAULT_GLYPH_WIDTH)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Font {
+            encoding: dictionary.get_font_encoding().to_string(),
+            first_char: dictionary
+                .get(b"FirstChar")
+                .ok()
+                .and_then(|c| c.as_i64().ok())
+                .unwrap_or(0),
+            widths,
+        }
+    }
+
+    // Width of the string in text space units, for a font size of 1
+    fn width(&self, bytes: &[u8]) -> f32 {
+        bytes
+            .iter()
+            .map(|byte| {
+                let index = *byte as i64 - self.first_char;
+                if index >= 0 && (index as usize) < self.widths.len() {
+                    self.widths[index as usize]
+                } else {
+                    DEFAULT_GLYPH_WIDTH
+                }
+            })
+            .sum::<f32>()
+            / 1000.0
+    }
+}
+
+// Interprets the text operators of a page content stream and records where each string is drawn
+struct TextState<'a> {
+    fonts: &'a HashMap<Vec<u8>, Font>,
+    font: Option<&'a Font>,
+    font_size: f32,
+    leading: f32,
+    ctm: Matrix,
+    ctm_stack: Vec<Matrix>,
+    text_matrix: Matrix,
+    line_matrix: Matrix,
+    items: Vec<TextItem>,
+}
+
+impl<'a> TextState<'a> {
+    fn new(fonts: &'a HashMap<Vec<u8>, Font>) -> Self {
+        TextState {
+            fonts,
+            font: None,
+            font_size: 0.0,
+            leading: 0.0,
+            ctm: IDENTITY,
+            ctm_stack: Vec::new(),
+            text_matrix: IDENTITY,
+            line_matrix: IDENTITY,
+            items: Vec::new(),
+        }
+    }
+
+    fn move_line(&mut self, tx: f32, ty: f32) {
+        self.line_matrix = multiply(&translate(tx, ty), &self.line_matrix);
+        self.text_matrix = self.line_matrix;
+    }
+
+    fn show(&mut self, bytes: &[u8]) {
+        let encoding = self.font.map(|f| f.encoding.as_str());
+        let text = Document::decode_text(encoding, bytes);
+        let width = match self.font {
+            Some(font) => font.width(bytes),
+            None => bytes.len() as f32 * DEFAULT_GLYPH_WIDTH / 1000.0,
+        } * self.font_size;
+
+        let start = multiply(&self.text_matrix, &self.ctm);
+        self.text_matrix = multiply(&translate(width, 0.0), &self.text_matrix);
+        let end = multiply(&self.text_matrix, &self.ctm);
+
+        if !text.trim().is_empty() {
+            let size = (self.font_size * start[2].hypot(start[3])).abs().max(1.0);
+            self.items.push(TextItem {
+                x: start[4],
+                x_end: end[4].max(start[4]),
+                y: start[5],
+                size,
+                text,
+            });
+        }
+    }
+
+    fn apply(&mut self, operation: &Operation) {
+        let operands = &operation.operands;
+        let float = |i: usize| {
+            operands
+                .get(i)
+                .and_then(|o| o.as_float().ok())
+                .unwrap_or(0.0)
+        };
+        let matrix = || [float(0), float(1), float(2), float(3), float(4), float(5)];
+        match operation.operator.as_str() {
+            "q" => self.ctm_stack.push(self.ctm),
+            "Q" => self.ctm = self.ctm_stack.pop().unwrap_or(IDENTITY),
+            "cm" => self.ctm = multiply(&matrix(), &self.ctm),
+            "BT" => {
+                self.text_matrix = IDENTITY;
+                self.line_matrix = IDENTITY;
+            }
+            "Tf" => {
+                let fonts = self.fonts;
+                self.font = operands
+                    .first()
+                    .and_then(|o| o.as_name().ok())
+                    .and_then(|name| fonts.get(name));
+                self.font_size = float(1);
+            }
+            "TL" => self.leading = float(0),
+            "Td" => self.move_line(float(0), float(1)),
+            "TD" => {
+                self.leading = -float(1);
+                self.move_line(float(0), float(1));
+            }
+            "Tm" => {
+                self.line_matrix = matrix();
+                self.text_matrix = self.line_matrix;
+            }
+            "T*" => self.move_line(0.0, -self.leading),
+            "Tj" | "'" | "\"" => {
+                if operation.operator != "Tj" {
+                    self.move_line(0.0, -self.leading);
+                }
+                if let Some(Object::String(bytes, _)) = operands.last() {
+                    self.show(bytes);
+                }
+            }
+            "TJ" => {
+                if let Some(Ok(elements)) = operands.first().map(|o| o.as_array()) {
+                    for element in elements {
+                        match element {
+                            Object::String(bytes, _) => self.show(bytes),
+                            other => {
+                                // kerning, in thousandths of the font size
+                                let adjustment = other.as_float().unwrap_or(0.0);
+                                let tx = -adjustment / 1000.0 * self.font_size;
+                                self.text_matrix = multiply(&translate(tx, 0.0), &self.text_matrix);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn extract_page_layout(doc: &Document, page_id: ObjectId) -> Result<String, Box<dyn StdError>> {
+    let fonts: HashMap<Vec<u8>, Font> = doc
+        .get_page_fonts(page_id)
+        .into_iter()
+        .map(|(name, dictionary)| (name, Font::from_dictionary(doc, dictionary)))
         .collect();
+    let content = doc.get_and_decode_page_content(page_id)?;
 
-    for page in pages {
-        match page {
-            Ok((page_num, lines)) => {
-                text.insert(page_num, lines);
+    let mut state = TextState::new(&fonts);
+    for operation in &content.operations {
+        state.apply(operation);
+    }
+    Ok(layout_text(state.items))
+}
+
+// Items drawn on the same baseline, left to right
+fn group_lines(mut items: Vec<TextItem>) -> Vec<Vec<TextItem>> {
+    items.sort_by(|a, b| b.y.total_cmp(&a.y).then(a.x.total_cmp(&b.x)));
+    let mut lines: Vec<Vec<TextItem>> = Vec::new();
+    for item in items {
+        match lines.last_mut() {
+            Some(line) if (line[0].y - item.y).abs() <= line[0].size.min(item.size) * 0.5 => {
+                line.push(item)
             }
-            Err(e) => {
-                eprintln!("Error extracting text from page: {}", e);
+            _ => lines.push(vec![item]),
+        }
+    }
+    for line in lines.iter_mut() {
+        line.sort_by(|a, b| a.x.total_cmp(&b.x));
+    }
+    lines
+}
+
+// Merges the items of a line into fragments, a wide horizontal gap starts a new fragment
+fn line_fragments(line: Vec<TextItem>) -> Vec<TextItem> {
+    let mut fragments: Vec<TextItem> = Vec::new();
+    for item in line {
+        match fragments.last_mut() {
+            Some(fragment) if item.x - fragment.x_end < fragment.size * 2.0 => {
+                let gap = item.x - fragment.x_end;
+                if gap > fragment.size * 0.15
+                    && !fragment.text.ends_with(' ')
+                    && !item.text.starts_with(' ')
+                {
+                    fragment.text.push(' ');
+                }
+                fragment.text.push_str(&item.text);
+                fragment.x_end = fragment.x_end.max(item.x_end);
             }
+            _ => fragments.push(item),
         }
     }
+    fragments
+}
 
-    // return joined text
-    let joined_text = text
-        .iter()
-        .map(|(_, lines)| lines.join("\n"))
-        .collect::<Vec<String>>()
-        .join("\n");
-    Ok(joined_text)
+// Looks for a vertical gutter splitting the page in two columns, i.e. an x position in
+// the middle of the page that almost no fragment crosses
+fn find_gutter(fragments: &[TextItem]) -> Option<f32> {
+    const BINS: usize = 100;
+    if fragments.len() < 6 {
+        return None;
+    }
+    let min_x = fragments.iter().map(|f| f.x).fold(f32::MAX, f32::min);
+    let max_x = fragments.iter().map(|f| f.x_end).fold(f32::MIN, f32::max);
+    let width = max_x - min_x;
+    if width <= 0.0 {
+        return None;
+    }
+
+    let mut coverage = [0usize; BINS];
+    for fragment in fragments {
+        let from = (((fragment.x - min_x) / width) * BINS as f32) as usize;
+        let to = (((fragment.x_end - min_x) / width) * BINS as f32) as usize;
+        for bin in coverage
+            .iter_mut()
+            .take(to.min(BINS - 1) + 1)
+            .skip(from.min(BINS - 1))
+        {
+            *bin += 1;
+        }
+    }
+
+    // widest run of (almost) empty bins in the central part of the page, titles and
+    // footers spanning both columns are allowed to cross it
+    let threshold = (fragments.len() / 10).max(1);
+    let mut best: Option<(usize, usize)> = None;
+    let mut run_start = None;
+    for (bin, count) in coverage.iter().enumerate().take(71).skip(30) {
+        if *count <= threshold {
+            run_start.get_or_insert(bin);
+        } else if let Some(start) = run_start.take() {
+            if best.map_or(true, |(s, e)| bin - start > e - s) {
+                best = Some((start, bin));
+            }
+        }
+    }
+    if let Some(start) = run_start {
+        if best.map_or(true, |(s, e)| 71 - start > e - s) {
+            best = Some((start, 71));
+        }
+    }
+
+    let (start, end) = best?;
+    let gutter = min_x + width * (start + end) as f32 / 2.0 / BINS as f32;
+    let left = fragments.iter().filter(|f| f.x_end <= gutter).count();
+    let right = fragments.iter().filter(|f| f.x >= gutter).count();
+    // both columns must hold a fair share of the text
+    if left * 5 >= fragments.len() && right * 5 >= fragments.len() {
+        Some(gutter)
+    } else {
+        None
+    }
 }
 
-pub fn pdf_mem_to_text(data: &[u8]) -> Result<String, Box<dyn StdError>> {
-    let doc = Document::load_mem(data)?;
-    let mut text: BTreeMap<u32, Vec<String>> = BTreeMap::new();
-    let pages: Vec<Result<(u32, Vec<String>), Box<dyn StdError>>> = doc
-        .get_pages()
-        .iter()
-        .map(|(page_num, _)| {
-            let page_text = doc.extract_text(&[*page_num]).map_err(|e| {
-                IoError::new(
-                    ErrorKind::Other,
-                    format!("Failed to extract text from page {}: {:?}", page_num, e),
-                )
-            })?;
-            Ok((
-                *page_num, // Dereference page_num here
-                page_text.split('\n')
-                    .map(|s| s.trim_end().to_string())
-                    .collect::<Vec<String>>(),
-            ))
+// Renders fragments top to bottom, a larger vertical gap than usual starts a new paragraph
+fn render_lines(fragments: Vec<TextItem>) -> String {
+    let lines: Vec<TextItem> = group_lines(fragments)
+        .into_iter()
+        .filter_map(|line| {
+            let mut line = line.into_iter();
+            let mut first = line.next()?;
+            for fragment in line {
+                first.text.push(' ');
+                first.text.push_str(&fragment.text);
+            }
+            Some(first)
         })
         .collect();
 
-    for page in pages {
-        match page {
-            Ok((page_num, lines)) => {
-                text.insert(page_num, lines);
-            }
-            Err(e) => {
-                eprintln!("Error extracting text from page: {}", e);
+    let mut gaps: Vec<f32> = lines.windows(2).map(|w| w[0].y - w[1].y).collect();
+    gaps.sort_by(|a, b| a.total_cmp(b));
+    let usual_gap = match gaps.len() {
+        0 => 0.0,
+        len => gaps[(len - 1) / 2],
+    };
+
+    let mut text = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            text.push('\n');
+            if usual_gap > 0.0 && lines[i - 1].y - line.y > usual_gap * 1.5 {
+                text.push('\n');
             }
         }
+        text.push_str(line.text.trim_end());
     }
+    text
+}
 
-    // return joined text
-    let joined_text = text
-        .iter()
-        .map(|(_, lines)| lines.join("\n"))
-        .collect::<Vec<String>>()
-        .join("\n");
-    Ok(joined_text)
+fn layout_text(items: Vec<TextItem>) -> String {
+    let fragments: Vec<TextItem> = group_lines(items)
+        .into_iter()
+        .flat_map(line_fragments)
+        .collect();
+
+    let text = match find_gutter(&fragments) {
+        None => render_lines(fragments),
+        Some(gutter) => {
+            // Full width fragments above the columns (title, authors) come first,
+            // the ones below (footnotes, page number) last
+            let column_top = fragments
+                .iter()
+                .filter(|f| f.x_end <= gutter || f.x >= gutter)
+                .map(|f| f.y)
+                .fold(f32::MIN, f32::max);
+            let (mut header, mut footer, mut left, mut right) =
+                (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+            for fragment in fragments {
+                if fragment.x_end <= gutter {
+                    left.push(fragment);
+                } else if fragment.x >= gutter {
+                    right.push(fragment);
+                } else if fragment.y > column_top {
+                    header.push(fragment);
+                } else {
+                    footer.push(fragment);
+                }
+            }
+            [header, left, right, footer]
+                .into_iter()
+                .filter(|part| !part.is_empty())
+                .map(render_lines)
+                .collect::<Vec<String>>()
+                .join("\n\n")
+        }
+    };
+    dehyphenate(&text)
 }
 
+// Joins words hyphenated across a line break: "exam-\nple" -> "example"
+fn dehyphenate(text: &str) -> String {
+    let re = Regex::new(r"(\p{L})-\n[ \t]*(\p{Ll})").unwrap();
+    re.replace_all(text, "$1$2").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(x: f32, y: f32, text: &str) -> TextItem {
+        TextItem {
+            x,
+            x_end: x + text.len() as f32 * 5.0,
+            y,
+            size: 10.0,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_dehyphenate() {
+        assert_eq!(
+            dehyphenate("an exam-\nple of a well-\nKnown issue"),
+            "an example of a well-\nKnown issue"
+        );
+    }
+
+    #[test]
+    fn test_layout_single_column() {
+        let items = vec![
+            item(60.0, 700.0, "world"),
+            item(10.0, 700.0, "Hello"),
+            item(10.0, 688.0, "second line"),
+            item(10.0, 650.0, "new paragraph"),
+        ];
+        assert_eq!(
+            layout_text(items),
+            "Hello world\nsecond line\n\nnew paragraph"
+        );
+    }
+
+    #[test]
+    fn test_layout_two_columns() {
+        let title = "A title spanning the whole width of the page";
+        let mut items = vec![item(10.0, 800.0, title)];
+        for i in 0..4 {
+            let y = 700.0 - i as f32 * 12.0;
+            items.push(item(10.0, y, &format!("left {}", i)));
+            items.push(item(400.0, y, &format!("right {}", i)));
+        }
+        assert_eq!(
+            layout_text(items),
+            format!(
+                "{}\n\nleft 0\nleft 1\nleft 2\nleft 3\n\nright 0\nright 1\nright 2\nright 3",
+                title
+            )
+        );
+    }
+}
@@ -4,40 +4,29 @@ use hal_9100_extra::llm::HalLLMRequestArgs;
 use log::error;
 use log::info;
 use serde_json::{self, Value};
-use sqlx::types::JsonValue;
+use sqlx::types::{JsonValue, Uuid};
 use sqlx::PgPool;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use tiktoken_rs::cl100k_base;
 
+use hal_9100_core::extractors::ExtractorRegistry;
 use hal_9100_core::file_storage::FileStorage;
-use hal_9100_core::pdf_utils::pdf_mem_to_text;
 
+use hal_9100_core::chunking::{split_text, ChunkingStrategy};
+use hal_9100_core::encryption::{column_encryption_enabled, decrypt_column, encrypt_column};
 use hal_9100_core::models::PartialChunk;
 
 // logic
 
 // Function to split a string into smaller chunks
 pub fn split_into_chunks(text: &str, chunk_size: usize) -> Vec<PartialChunk> {
-    let bpe = cl100k_base().unwrap();
-    let tokens = bpe.encode_with_special_tokens(text);
-
-    let mut chunks = Vec::new();
-
-    for (sequence, chunk) in tokens.chunks(chunk_size).enumerate() {
-        let chunk_str = bpe.decode(chunk.to_vec()).unwrap();
-        let start_index = (sequence * chunk_size) as i32;
-        let end_index = start_index + chunk_str.len() as i32;
-
-        chunks.push(PartialChunk {
-            sequence: sequence as i32,
-            data: chunk_str,
-            start_index,
-            end_index,
-        });
-    }
-
-    chunks
+    split_text(
+        text,
+        &ChunkingStrategy::FixedToken {
+            chunk_size,
+            chunk_overlap: 0,
+        },
+    )
 }
 
 // TODO: embeddings using either Huggingface Candle or an API
@@ -45,34 +34,49 @@ pub fn split_into_chunks(text: &str, chunk_size: usize) -> Vec<PartialChunk> {
 pub async fn split_and_insert(
     pool: &PgPool,
     text: &str,
-    chunk_size: usize,
+    strategy: &ChunkingStrategy,
+    file_id: &str,
+    metadata: Option<HashMap<String, Value>>,
+) -> Result<Vec<Chunk>, sqlx::Error> {
+    let chunks = split_text(text, strategy);
+    insert_chunks(pool, chunks, file_id, metadata).await
+}
+
+// Insert already split chunks, the chunk metadata (strategy, page, heading...) is merged into the file metadata
+pub async fn insert_chunks(
+    pool: &PgPool,
+    chunks: Vec<PartialChunk>,
     file_id: &str,
     metadata: Option<HashMap<String, Value>>,
 ) -> Result<Vec<Chunk>, sqlx::Error> {
-    let chunks = split_into_chunks(text, chunk_size);
-    let chunks_data: Vec<(i32, String, String, i32, i32, Value)> = chunks
+    let chunks_data: Vec<(Uuid, i32, String, String, i32, i32, Value)> = chunks
         .into_iter()
-        .enumerate()
-        .map(|(_, chunk)| {
+        .map(|chunk| {
+            let mut chunk_metadata = metadata.clone().unwrap_or_default();
+            chunk_metadata.extend(chunk.metadata);
+            // The id is known before the insert, the encrypted data is bound to it
+            let id = Uuid::new_v4();
             (
+                id,
                 chunk.sequence,
-                chunk.data,
+                encrypt_column(&id, &chunk.data),
                 file_id.to_string(),
                 chunk.start_index,
                 chunk.end_index,
-                serde_json::to_value(metadata.clone()).unwrap(),
+                serde_json::to_value(chunk_metadata).unwrap(),
             )
         })
         .collect();
 
     let mut tx = pool.begin().await?;
 
-    for (sequence, chunk, file_id, start_index, end_index, metadata) in chunks_data {
+    for (id, sequence, chunk, file_id, start_index, end_index, metadata) in chunks_data {
         sqlx::query!(
             r#"
-                    INSERT INTO chunks (sequence, data, file_id, start_index, end_index, metadata)
-                    VALUES ($1, $2, $3, $4, $5, $6)
+                    INSERT INTO chunks (id, sequence, data, file_id, start_index, end_index, metadata)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7)
                     "#,
+            id,
             sequence,
             chunk,
             file_id,
@@ -96,23 +100,86 @@ pub async fn split_and_insert(
 
     tx.commit().await?;
 
-    Ok(chunks
+    chunks
         .into_iter()
-        .map(|row| Chunk {
-            id: row.id,
-            sequence: row.sequence,
-            data: row.data,
-            file_id: row.file_id,
-            start_index: row.start_index,
-            end_index: row.end_index,
-            metadata: serde_json::from_value(row.metadata.unwrap_or_default()).unwrap(),
-            created_at: row.created_at,
+        .map(|row| {
+            Ok(Chunk {
+                id: row.id,
+                sequence: row.sequence,
+                data: decrypt_column(&row.id, &row.data)
+                    .map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+                file_id: row.file_id,
+                start_index: row.start_index,
+                end_index: row.end_index,
+                metadata: serde_json::from_value(row.metadata.unwrap_or_default()).unwrap(),
+                created_at: row.created_at,
+            })
         })
-        .collect())
+        .collect()
+}
+
+// Copy the chunks of a file to another one with the same content, returns the number of chunks copied
+pub async fn copy_chunks(
+    pool: &PgPool,
+    from_file_id: &str,
+    to_file_id: &str,
+) -> Result<u64, sqlx::Error> {
+    if column_encryption_enabled() {
+        return copy_encrypted_chunks(pool, from_file_id, to_file_id).await;
+    }
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO chunks (sequence, data, file_id, start_index, end_index, metadata)
+        SELECT sequence, data, $2, start_index, end_index, metadata
+        FROM chunks WHERE file_id = $1
+        "#,
+        from_file_id,
+        to_file_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+// Encrypted data is bound to its row, so every copy is sealed again for its new id
+async fn copy_encrypted_chunks(
+    pool: &PgPool,
+    from_file_id: &str,
+    to_file_id: &str,
+) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let rows = sqlx::query!("SELECT * FROM chunks WHERE file_id = $1", from_file_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    let copied = rows.len() as u64;
+    for row in rows {
+        let data =
+            decrypt_column(&row.id, &row.data).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        let id = Uuid::new_v4();
+        sqlx::query!(
+            r#"
+            INSERT INTO chunks (id, sequence, data, file_id, start_index, end_index, metadata)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            id,
+            row.sequence,
+            encrypt_column(&id, &data),
+            to_file_id,
+            row.start_index,
+            row.end_index,
+            row.metadata,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(copied)
 }
 
+// Only the chunks of the given files are searched
 pub async fn generate_queries_and_fetch_chunks(
     pool: &PgPool,
+    file_ids: &[String],
     client: HalLLMClient,
     mut request: HalLLMRequestArgs,
 ) -> Result<Vec<Chunk>, Box<dyn Error>> {
@@ -173,12 +240,17 @@ Query:";
     // let re = regex::Regex::new(r"\s+").unwrap();
     // let query = re.replace_all(&query, " | ").to_string();
 
+    if column_encryption_enabled() {
+        return search_encrypted_chunks(pool, file_ids, &query).await;
+    }
+
     // Convert the query to tsquery and execute it on the database
     let rows = sqlx::query!(
         r#"
         SELECT * FROM chunks 
-        WHERE to_tsvector(data) @@ to_tsquery($1)
+        WHERE file_id = ANY($1) AND to_tsvector(data) @@ to_tsquery($2)
         "#,
+        file_ids,
         query,
     )
     .fetch_all(pool)
@@ -206,6 +278,206 @@ Query:";
     Ok(chunks)
 }
 
+// Encrypted chunks can't be indexed, so the chunks of the searched files are decrypted and matched
+// against the query here. Nothing decrypted is sent back to postgres.
+async fn search_encrypted_chunks(
+    pool: &PgPool,
+    file_ids: &[String],
+    query: &str,
+) -> Result<Vec<Chunk>, Box<dyn Error>> {
+    let query = match TextQuery::parse(query) {
+        Some(query) => query,
+        None => return Ok(vec![]),
+    };
+    let rows = sqlx::query!("SELECT * FROM chunks WHERE file_id = ANY($1)", file_ids)
+        .fetch_all(pool)
+        .await?;
+    let mut chunks = Vec::new();
+    for row in rows {
+        let data = decrypt_column(&row.id, &row.data)?;
+        if !query.matches(&data) {
+            continue;
+        }
+        chunks.push(Chunk {
+            id: row.id,
+            sequence: row.sequence,
+            data,
+            file_id: row.file_id,
+            start_index: row.start_index,
+            end_index: row.end_index,
+            metadata: match row.metadata {
+                Some(JsonValue::Object(map)) => {
+                    Some(map.into_iter().collect::<HashMap<String, JsonValue>>())
+                }
+                _ => None,
+            },
+            created_at: row.created_at,
+        });
+    }
+    Ok(chunks)
+}
+
+/// A `to_tsquery` query evaluated in process: words combined with `&`, `|`, `!` and parentheses,
+/// `:*` for prefixes and phrase operators read as `&`. Words are compared case insensitively,
+/// without the stemming and stop words of postgres.
+#[derive(Debug, PartialEq)]
+enum TextQuery {
+    Word { word: String, prefix: bool },
+    Not(Box<TextQuery>),
+    And(Box<TextQuery>, Box<TextQuery>),
+    Or(Box<TextQuery>, Box<TextQuery>),
+}
+
+#[derive(Debug, PartialEq)]
+enum QueryToken {
+    Word(String, bool),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl TextQuery {
+    /// Parses a query leniently, stray operators are ignored. `None` if there is no word to look for.
+    fn parse(query: &str) -> Option<TextQuery> {
+        let mut tokens = Vec::new();
+        let mut chars = query.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '&' => tokens.push(QueryToken::And),
+                '|' => tokens.push(QueryToken::Or),
+                '!' => tokens.push(QueryToken::Not),
+                '(' => tokens.push(QueryToken::Open),
+                ')' => tokens.push(QueryToken::Close),
+                // Phrase operators, <-> or <N>
+                '<' => {
+                    for c in chars.by_ref() {
+                        if c == '>' {
+                            break;
+                        }
+                    }
+                    tokens.push(QueryToken::And);
+                }
+                c if c.is_alphanumeric() => {
+                    let mut word = c.to_lowercase().to_string();
+                    while let Some(c) = chars.peek().filter(|c| c.is_alphanumeric()) {
+                        word.extend(c.to_lowercase());
+                        chars.next();
+                    }
+                    // Weights and the prefix flag, e.g. word:*AB
+                    let mut prefix = false;
+                    if chars.peek() == Some(&':') {
+                        chars.next();
+                        while let Some(&c) = chars.peek() {
+                            match c {
+                                '*' => prefix = true,
+                                'a'..='d' | 'A'..='D' => {}
+                                _ => break,
+                            }
+                            chars.next();
+                        }
+                    }
+                    tokens.push(QueryToken::Word(word, prefix));
+                }
+                _ => {}
+            }
+        }
+        let mut tokens = tokens.into_iter().peekable();
+        let mut query = None;
+        // Leftover closing parentheses start a new expression, it's or-ed with the previous one
+        while tokens.peek().is_some() {
+            query = match (query, Self::parse_or(&mut tokens)) {
+                (Some(left), Some(right)) => Some(TextQuery::Or(Box::new(left), Box::new(right))),
+                (left, right) => left.or(right),
+            };
+            if tokens.peek() == Some(&QueryToken::Close) {
+                tokens.next();
+            }
+        }
+        query
+    }
+
+    fn parse_or<I: Iterator<Item = QueryToken>>(
+        tokens: &mut std::iter::Peekable<I>,
+    ) -> Option<TextQuery> {
+        let mut left = Self::parse_and(tokens);
+        while tokens.next_if_eq(&QueryToken::Or).is_some() {
+            left = match (left, Self::parse_and(tokens)) {
+                (Some(left), Some(right)) => Some(TextQuery::Or(Box::new(left), Box::new(right))),
+                (left, right) => left.or(right),
+            };
+        }
+        left
+    }
+
+    fn parse_and<I: Iterator<Item = QueryToken>>(
+        tokens: &mut std::iter::Peekable<I>,
+    ) -> Option<TextQuery> {
+        let mut left = Self::parse_not(tokens);
+        loop {
+            // Words next to each other are and-ed, like plainto_tsquery does
+            match tokens.peek() {
+                Some(QueryToken::And) => {
+                    tokens.next();
+                }
+                Some(QueryToken::Word(..)) | Some(QueryToken::Not) | Some(QueryToken::Open) => {}
+                _ => return left,
+            }
+            left = match (left, Self::parse_not(tokens)) {
+                (Some(left), Some(right)) => Some(TextQuery::And(Box::new(left), Box::new(right))),
+                (left, right) => left.or(right),
+            };
+        }
+    }
+
+    fn parse_not<I: Iterator<Item = QueryToken>>(
+        tokens: &mut std::iter::Peekable<I>,
+    ) -> Option<TextQuery> {
+        match tokens.peek()? {
+            QueryToken::Not => {
+                tokens.next();
+                Self::parse_not(tokens).map(|query| TextQuery::Not(Box::new(query)))
+            }
+            QueryToken::Open => {
+                tokens.next();
+                let query = Self::parse_or(tokens);
+                tokens.next_if_eq(&QueryToken::Close);
+                query
+            }
+            QueryToken::Word(..) => match tokens.next() {
+                Some(QueryToken::Word(word, prefix)) => Some(TextQuery::Word { word, prefix }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        let words: HashSet<String> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect();
+        self.matches_words(&words)
+    }
+
+    fn matches_words(&self, words: &HashSet<String>) -> bool {
+        match self {
+            TextQuery::Word {
+                word,
+                prefix: false,
+            } => words.contains(word),
+            TextQuery::Word { word, prefix: true } => words
+                .iter()
+                .any(|candidate| candidate.starts_with(word.as_str())),
+            TextQuery::Not(query) => !query.matches_words(words),
+            TextQuery::And(left, right) => left.matches_words(words) && right.matches_words(words),
+            TextQuery::Or(left, right) => left.matches_words(words) || right.matches_words(words),
+        }
+    }
+}
+
 // TODO: kinda dirty function could be better
 // This function retrieves file contents given a list of file_ids
 pub async fn retrieve_file_contents(
@@ -213,29 +485,17 @@ pub async fn retrieve_file_contents(
     file_storage: &FileStorage,
 ) -> Vec<String> {
     info!("Retrieving file contents for file_ids: {:?}", file_ids);
+    let extractors = ExtractorRegistry::default();
     let mut file_contents = Vec::new();
     for file_id in file_ids {
         let file_string_content = match file_storage.get_file_content(file_id).await {
             Ok(file_byte_content) => {
-                // info!("Retrieved file from storage: {:?}", file_byte_content);
-                // Check if the file is a PDF
-                if file_id.ends_with(".pdf") {
-                    // If it's a PDF, extract the text
-                    match pdf_mem_to_text(&file_byte_content) {
-                        Ok(text) => text,
-                        Err(e) => {
-                            error!("Failed to extract text from PDF: {}", e);
-                            continue;
-                        }
-                    }
-                } else {
-                    // If it's not a PDF, use the content as is (bytes to string)
-                    match String::from_utf8(file_byte_content.to_vec()) {
-                        Ok(text) => text,
-                        Err(e) => {
-                            error!("Failed to convert bytes to string: {}", e);
-                            continue;
-                        }
+                // The file id keeps the extension of the uploaded file
+                match extractors.extract(None, file_id, &file_byte_content) {
+                    Ok(document) => document.text(),
+                    Err(e) => {
+                        error!("Failed to extract text from {}: {}", file_id, e);
+                        continue;
                     }
                 }
             }
@@ -321,6 +581,24 @@ The president of the Moon is TDB.
         }
     }
 
+    #[test]
+    fn test_text_query() {
+        let text = "Alex pitched BarkByte, AI-driven meal plans for dogs.";
+        let matches = |query: &str| TextQuery::parse(query).unwrap().matches(text);
+
+        assert!(matches("dogs"));
+        assert!(matches("DOGS | cats"));
+        assert!(matches("meal & plans"));
+        assert!(!matches("meal & cats"));
+        assert!(matches("bark:* & !(cats | horses)"));
+        assert!(matches("meal <-> plans"));
+        assert!(matches("meal plans"));
+        assert!(!matches("!dogs"));
+        assert!(matches("| dogs &"));
+        assert!(matches("cats) | dogs"));
+        assert_eq!(TextQuery::parse(" & | "), None);
+    }
+
     #[tokio::test]
     async fn test_insert_chunks_into_db() {
         dotenv().ok();
@@ -329,12 +607,15 @@ The president of the Moon is TDB.
         // Test data
         let text =
             "This is a test string that will be split into chunks and inserted into the database.";
-        let chunk_size = 5;
+        let strategy = ChunkingStrategy::FixedToken {
+            chunk_size: 5,
+            chunk_overlap: 0,
+        };
         let file_name = "test_file";
         let metadata = Some(HashMap::new());
 
         // Call the function
-        let result = split_and_insert(&pool, text, chunk_size, file_name, metadata).await;
+        let result = split_and_insert(&pool, text, &strategy, file_name, metadata).await;
 
         // Check the result
         assert!(result.is_ok(), "Failed to insert chunks into database");
@@ -343,6 +624,10 @@ The president of the Moon is TDB.
 
         // Check the chunks
         assert_eq!(chunks.len(), 4, "Incorrect number of chunks");
+        assert_eq!(
+            chunks[0].metadata.as_ref().unwrap()["chunking_strategy"]["type"],
+            "fixed_token"
+        );
     }
 
     #[tokio::test]
@@ -353,10 +638,13 @@ The president of the Moon is TDB.
 
         // Insert chunks into the database
         let text = "Once upon a time, in the bustling city of San Francisco, a young startup founder named Alex was on a mission. His idea? Disrupt the pet food industry with AI-driven, personalized meal plans for dogs. He called it 'BarkByte'. Alex was a hacker at heart, but he knew the importance of funding. So, he found himself in the sleek, intimidating office of a VC firm, 'CashCow Capital'. The VC, a seasoned player named Richard, was intrigued. 'An AI for dog food, huh? That's... unique.' Alex, undeterred by Richard's skepticism, launched into his pitch. He spoke of market sizes, growth rates, and unit economics. But most importantly, he spoke of his vision - a world where every dog, be it a pampered poodle or a scrappy stray, had access to nutrition that was just right for them. Richard, who was usually hard to impress, found himself nodding along. Maybe it was Alex's passion, or maybe it was the fact that Richard's own dog, a chubby corgi, could do with a better diet. Either way, by the end of the meeting, Alex had secured his first round of funding. And thus, BarkByte was born.";
-        let chunk_size = 5;
+        let strategy = ChunkingStrategy::FixedToken {
+            chunk_size: 5,
+            chunk_overlap: 0,
+        };
         let file_name = "test_file";
         let metadata = Some(HashMap::new());
-        let _ = split_and_insert(&pool, text, chunk_size, file_name, metadata.clone())
+        let _ = split_and_insert(&pool, text, &strategy, file_name, metadata.clone())
             .await
             .unwrap();
 
@@ -370,7 +658,9 @@ The president of the Moon is TDB.
         );
         let mut request = HalLLMRequestArgs::default();
         request.set_last_user_prompt(context.to_string());
-        let result = generate_queries_and_fetch_chunks(&pool, llm_client, request).await;
+        let result =
+            generate_queries_and_fetch_chunks(&pool, &[file_name.to_string()], llm_client, request)
+                .await;
 
         // Check the result
         assert!(
@@ -446,9 +736,13 @@ The president of the Moon is TDB.
             "The PDF content should contain the word 'Abstract'. Instead, it contains: {}",
             file_contents[0]
         );
-        // Check got the end of the pdf too!
+        // Check got the end of the pdf too! Lines are laid out by the extractor, compare words only
+        let words = file_contents[0]
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
         assert!(
-            file_contents[0].contains("For Image Understanding As shown in Fig"),
+            words.contains("For Image Understanding As shown in Fig"),
             "The PDF content should contain the word 'Abstract'. Instead, it contains: {}",
             file_contents[0]
         );
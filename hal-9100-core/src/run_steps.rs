@@ -163,6 +163,29 @@ pub async fn update_step(
     })
 }
 
+/// Merges `metadata` (a JSON object) into the step metadata
+pub async fn set_step_metadata(
+    pool: &PgPool,
+    step_id: &str,
+    metadata: serde_json::Value,
+    user_id: &str,
+) -> Result<(), sqlx::Error> {
+    info!("Updating metadata of step_id: {}", step_id);
+    sqlx::query!(
+        r#"
+        UPDATE run_steps
+        SET metadata = COALESCE(metadata, '{}'::jsonb) || $2
+        WHERE id::text = $1 AND user_id::text = $3
+        "#,
+        step_id,
+        metadata,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 pub async fn set_all_steps_status(
     pool: &PgPool,
     run_id: &str,
@@ -174,6 +197,8 @@ pub async fn set_all_steps_status(
         UPDATE run_steps
         SET status = $1
         WHERE run_id::text = $2 AND user_id::text = $3
+        -- rejected action calls stay cancelled
+        AND status IS DISTINCT FROM 'cancelled'
         RETURNING *
         "#,
         match status {
@@ -11,12 +11,18 @@ use sqlx::PgPool;
 use futures::stream::StreamExt; // Don't forget to import StreamExt
 use hal_9100_core::models::Run;
 use hal_9100_core::models::SubmittedToolCall;
+use hal_9100_core::models::ToolApproval;
 use redis::AsyncCommands;
 use serde_json::json;
 use sqlx::types::Uuid;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::error::Error;
 
+/// Required action of a run waiting for the user to approve, edit or reject consequential
+/// action calls, listed in `submit_tool_outputs.tool_calls`
+pub const SUBMIT_TOOL_APPROVALS: &str = "submit_tool_approvals";
+
 pub async fn get_tool_calls(
     pool: &PgPool,
     tool_call_ids: Vec<&str>,
@@ -71,6 +77,17 @@ pub async fn submit_tool_outputs(
         error!("{}", err_msg);
         return Err(sqlx::Error::Configuration(err_msg.into()));
     }
+    if run
+        .inner
+        .required_action
+        .as_ref()
+        .map(|a| a.r#type.as_str())
+        == Some(SUBMIT_TOOL_APPROVALS)
+    {
+        let err_msg = "Run is waiting for tool approvals, use submit_tool_approvals";
+        error!("{}", err_msg);
+        return Err(sqlx::Error::Configuration(err_msg.into()));
+    }
     // should throw if tool outputs length is not matching all the tool calls asked for
     if run
         .inner
@@ -134,6 +151,104 @@ pub async fn submit_tool_outputs(
     Ok(updated_run)
 }
 
+pub async fn submit_tool_approvals(
+    pool: &PgPool,
+    thread_id: &str,
+    run_id: &str,
+    user_id: &str,
+    approvals: Vec<ToolApproval>,
+    mut con: redis::aio::Connection,
+) -> Result<Run, sqlx::Error> {
+    info!("Submitting tool approvals for run_id: {}", run_id);
+
+    let run = get_run(pool, thread_id, run_id, user_id).await?;
+
+    let required_action = match run.inner.required_action {
+        Some(action)
+            if run.inner.status == RunStatus::RequiresAction
+                && action.r#type == SUBMIT_TOOL_APPROVALS =>
+        {
+            action
+        }
+        _ => {
+            let err_msg = "Run is not waiting for tool approvals";
+            error!("{}", err_msg);
+            return Err(sqlx::Error::Configuration(err_msg.into()));
+        }
+    };
+    // every call waiting for approval needs exactly one decision
+    let required_ids: HashSet<_> = required_action
+        .submit_tool_outputs
+        .tool_calls
+        .iter()
+        .map(|t| t.id.as_str())
+        .collect();
+    let decided_ids: HashSet<_> = approvals.iter().map(|a| a.tool_call_id.as_str()).collect();
+    if required_ids != decided_ids || decided_ids.len() != approvals.len() {
+        let err_msg = "You must submit one decision for each tool call";
+        error!("{}", err_msg);
+        return Err(sqlx::Error::Configuration(err_msg.into()));
+    }
+    for approval in &approvals {
+        if let Some(arguments) = &approval.arguments {
+            if let Err(e) =
+                serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(arguments)
+            {
+                let err_msg = format!(
+                    "The arguments of tool call {} are not a JSON object: {}",
+                    approval.tool_call_id, e
+                );
+                error!("{}", err_msg);
+                return Err(sqlx::Error::Configuration(err_msg.into()));
+            }
+        }
+    }
+
+    // The executor reads the decisions back from the tool calls when the run resumes
+    for approval in &approvals {
+        info!(
+            "Updating tool call for tool_call_id: {}",
+            approval.tool_call_id
+        );
+        sqlx::query!(
+            r#"
+            UPDATE tool_calls
+            SET output = $1
+            WHERE id::text = $2 AND run_id::text = $3 AND user_id::text = $4
+            "#,
+            serde_json::to_string(approval).unwrap(),
+            approval.tool_call_id,
+            run_id,
+            user_id,
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    let ids = serde_json::json!({
+        "run_id": run.inner.id,
+        "thread_id": thread_id,
+        "user_id": user_id
+    });
+
+    con.lpush("run_queue", ids.to_string())
+        .await
+        .map_err(|e| sqlx::Error::Configuration(e.into()))?;
+
+    let updated_run = update_run_status(
+        pool,
+        thread_id,
+        run_id,
+        RunStatus::Queued,
+        user_id,
+        None,
+        None,
+    )
+    .await?;
+
+    Ok(updated_run)
+}
+
 pub async fn create_run_and_produce_to_executor_queue(
     pool: &PgPool,
     thread_id: &str,
@@ -1056,4 +1171,147 @@ mod tests {
 
         println!("result: {:?}", result);
     }
+
+    #[tokio::test]
+    async fn test_submit_tool_approvals() {
+        let (pool, _, _) = setup().await;
+        reset_db(&pool).await;
+        let user_id = Uuid::default().to_string();
+        let assistant = create_assistant(
+            &pool,
+            &Assistant {
+                inner: AssistantObject {
+                    id: "".to_string(),
+                    object: "".to_string(),
+                    created_at: 0,
+                    name: Some("Pet Store".to_string()),
+                    description: None,
+                    model: "claude-2.1".to_string(),
+                    instructions: None,
+                    tools: vec![],
+                    file_ids: vec![],
+                    metadata: None,
+                },
+                user_id: user_id.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        let thread = create_thread(
+            &pool,
+            &Thread {
+                inner: ThreadObject {
+                    id: "".to_string(),
+                    object: "".to_string(),
+                    created_at: 0,
+                    metadata: None,
+                },
+                user_id: user_id.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        let run = create_run(&pool, &thread.inner.id, &assistant.inner.id, "", &user_id)
+            .await
+            .unwrap();
+
+        let tool_call_id = uuid::Uuid::new_v4().to_string();
+        update_run_status(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            RunStatus::RequiresAction,
+            &user_id,
+            Some(RequiredAction {
+                r#type: SUBMIT_TOOL_APPROVALS.to_string(),
+                submit_tool_outputs: SubmitToolOutputs {
+                    tool_calls: vec![RunToolCallObject {
+                        id: tool_call_id.clone(),
+                        r#type: "function".to_string(),
+                        function: FunctionCall {
+                            name: "addPet".to_string(),
+                            arguments: "{\"name\": \"Rex\"}".to_string(),
+                        },
+                    }],
+                },
+            }),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+        let client = redis::Client::open(redis_url).unwrap();
+        let approve = |tool_call_id: &str, arguments: Option<&str>| {
+            vec![ToolApproval {
+                tool_call_id: tool_call_id.to_string(),
+                approved: true,
+                arguments: arguments.map(|a| a.to_string()),
+            }]
+        };
+
+        // Tool outputs are not accepted in place of decisions
+        let result = submit_tool_outputs(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            vec![SubmittedToolCall {
+                id: tool_call_id.clone(),
+                output: "done".to_string(),
+                run_id: run.inner.id.clone(),
+                created_at: 0,
+                user_id: user_id.clone(),
+            }],
+            client.get_async_connection().await.unwrap(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        // Every call needs a decision
+        let unknown_id = uuid::Uuid::new_v4().to_string();
+        let result = submit_tool_approvals(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            approve(&unknown_id, None),
+            client.get_async_connection().await.unwrap(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        // Edited arguments must be a JSON object
+        let result = submit_tool_approvals(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            approve(&tool_call_id, Some("[\"Rex\"]")),
+            client.get_async_connection().await.unwrap(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        let run = submit_tool_approvals(
+            &pool,
+            &thread.inner.id,
+            &run.inner.id,
+            &user_id,
+            approve(&tool_call_id, Some("{\"name\": \"Max\"}")),
+            client.get_async_connection().await.unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(run.inner.status, RunStatus::Queued);
+
+        let tool_calls = get_tool_calls(&pool, vec![tool_call_id.as_str()])
+            .await
+            .unwrap();
+        let approval: ToolApproval = serde_json::from_str(&tool_calls[0].output).unwrap();
+        assert_eq!(
+            approval,
+            approve(&tool_call_id, Some("{\"name\": \"Max\"}"))[0]
+        );
+    }
 }
@@ -0,0 +1,739 @@
+//! Where interpreter code runs, picked with the `code_interpreter_backend` config option.
+//!
+//! "docker" runs it in containers (pooled or not), which needs access to the docker socket.
+//! "bubblewrap" and "nsjail" run it as a subprocess in its own Linux namespaces instead, for
+//! hosts without docker: no network, a read only view of the system and of a Python venv,
+//! rlimits and a seccomp filter. Languages other than Python use the runtimes of the host
+//! (e.g. node), and DuckDB must be installed in the venv.
+
+use async_trait::async_trait;
+use bollard::Docker;
+use bytes::Bytes;
+use hal_9100_core::code_interpreter::{
+    ensure_image, killed, pull_image, run_in_container, sandbox_config, start_sandbox,
+    ExecutionOutput, GeneratedFile, InterpreterError, Language, SandboxFile, SandboxLimits,
+    DATA_DIR, IMAGE, KILLED_EXIT_CODE, MAX_GENERATED_FILES, MAX_GENERATED_FILE_SIZE,
+};
+use hal_9100_core::container_pool::ContainerPool;
+use hal_9100_extra::config::Hal9100Config;
+use log::{info, warn};
+use std::ffi::OsString;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use uuid::Uuid;
+
+// Mounted read only so the venv's interpreter and its shared libraries resolve
+const SYSTEM_DIRS: [&str; 5] = ["/usr", "/lib", "/lib64", "/bin", "/etc/alternatives"];
+// Same ids and paths as in the docker sandbox
+const SANDBOX_UID: &str = "65534";
+const WORK_DIR: &str = "/tmp";
+// Largest file the code may write, in MB
+const MAX_FILE_SIZE_MB: u64 = 1024;
+const MAX_OPEN_FILES: u64 = 256;
+const SIGKILL: i32 = 9;
+// Fd the bubblewrap seccomp filter is read from
+const SECCOMP_FD: &str = "10";
+// Syscalls nsjail denies, on top of what the namespaces already prevent
+const SECCOMP_POLICY: &str = "ERRNO(1) { ptrace, process_vm_readv, process_vm_writev, mount, \
+umount2, pivot_root, chroot, kexec_load, init_module, finit_module, delete_module, reboot, \
+swapon, swapoff, bpf, perf_event_open, keyctl, add_key, request_key, unshare, setns, \
+userfaultfd } DEFAULT ALLOW";
+
+/// Runs interpreter code, with the files under /mnt/data.
+#[async_trait]
+pub trait SandboxBackend: Send + Sync {
+    /// Runs the code fed through stdin (e.g. to `python -`), the files it writes to /mnt/data
+    /// are collected after a success. Timeouts and kills are errors carrying the code.
+    async fn execute(
+        &self,
+        language: Language,
+        code: &str,
+        timeout: Duration,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError>;
+}
+
+fn timed_out(code: &str, timeout: Duration) -> InterpreterError {
+    InterpreterError::new(
+        format!(
+            "Code execution timed out after {} seconds",
+            timeout.as_secs()
+        ),
+        code,
+    )
+}
+
+/// Runs the code in a sandbox borrowed from the pool, or in a fresh container without one (the
+/// pool only holds the Python image). A container that timed out or failed is never reused.
+pub struct DockerSandbox {
+    pool: Option<Arc<ContainerPool>>,
+}
+
+impl DockerSandbox {
+    pub fn new(pool: Option<Arc<ContainerPool>>) -> Self {
+        DockerSandbox { pool }
+    }
+}
+
+#[async_trait]
+impl SandboxBackend for DockerSandbox {
+    async fn execute(
+        &self,
+        language: Language,
+        code: &str,
+        timeout: Duration,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        if let (Some(pool), IMAGE) = (&self.pool, language.image()) {
+            let container = pool.acquire().await?;
+            let run = run_in_container(pool.docker(), container.id(), language, code, files);
+            return match tokio::time::timeout(timeout, run).await {
+                Ok(Ok(output)) => {
+                    container.release().await;
+                    Ok(output)
+                }
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(timed_out(code, timeout)),
+            };
+        }
+
+        let docker = Docker::connect_with_local_defaults()?;
+        ensure_image(language.image()).await?;
+        let mut config = sandbox_config();
+        config.image = Some(language.image().to_string());
+        let mut container = start_sandbox(&docker, config).await?;
+        let run = run_in_container(&docker, container.id(), language, code, files);
+        let result = tokio::time::timeout(timeout, run).await;
+        container.remove().await;
+
+        match result {
+            Ok(output) => output,
+            Err(_) => Err(timed_out(code, timeout)),
+        }
+    }
+}
+
+/// Stands in for a configured backend that failed to start: runs fail instead of falling back to
+/// a docker container, which the namespace backends are there to avoid.
+pub struct UnavailableSandbox {
+    backend: String,
+    error: String,
+}
+
+impl UnavailableSandbox {
+    pub fn new(backend: &str, error: String) -> Self {
+        UnavailableSandbox {
+            backend: backend.to_string(),
+            error,
+        }
+    }
+}
+
+#[async_trait]
+impl SandboxBackend for UnavailableSandbox {
+    async fn execute(
+        &self,
+        _language: Language,
+        _code: &str,
+        _timeout: Duration,
+        _files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        Err(InterpreterError::new(
+            format!(
+                "The {} code interpreter backend failed to start: {}",
+                self.backend, self.error
+            ),
+            "",
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NamespaceTool {
+    Bubblewrap,
+    Nsjail,
+}
+
+/// Runs the code as a subprocess jailed by bubblewrap or nsjail, no docker needed.
+///
+/// The jail sees the system directories and the venv read only, a fresh /tmp and a scratch
+/// directory of the host as /mnt/data. It runs as nobody without network (unless
+/// `network_mode` isn't "none"), under rlimits: address space (`memory_mb`), processes
+/// (`pids_limit`) and CPU time (`cpus` times the timeout).
+pub struct NamespaceSandbox {
+    tool: NamespaceTool,
+    venv: PathBuf,
+    limits: SandboxLimits,
+    /// Compiled BPF program for bubblewrap, nsjail compiles its own policy
+    seccomp_filter: Option<PathBuf>,
+}
+
+impl NamespaceSandbox {
+    /// Checks that the tool and the venv are there.
+    pub fn new(
+        tool: NamespaceTool,
+        venv: impl Into<PathBuf>,
+        limits: SandboxLimits,
+        seccomp_filter: Option<PathBuf>,
+    ) -> Result<Self, InterpreterError> {
+        let sandbox = NamespaceSandbox {
+            tool,
+            venv: venv.into(),
+            limits,
+            seccomp_filter,
+        };
+        let programs: &[&str] = match tool {
+            NamespaceTool::Bubblewrap => &["bwrap", "prlimit"],
+            NamespaceTool::Nsjail => &["nsjail"],
+        };
+        for program in programs {
+            if find_program(program).is_none() {
+                return Err(InterpreterError::new(
+                    format!("{} is not installed", program),
+                    "",
+                ));
+            }
+        }
+        if !sandbox.python().is_file() {
+            return Err(InterpreterError::new(
+                format!("No Python interpreter at {}", sandbox.python().display()),
+                "",
+            ));
+        }
+        if tool == NamespaceTool::Bubblewrap && sandbox.seccomp_filter.is_none() {
+            warn!("No code_interpreter_seccomp_filter set, bubblewrap runs without seccomp");
+        }
+        Ok(sandbox)
+    }
+
+    fn python(&self) -> PathBuf {
+        self.venv.join("bin").join("python3")
+    }
+
+    // Python comes from the venv, the other runtimes are looked up in the PATH of the jail
+    fn program(&self, language: Language) -> Vec<OsString> {
+        let command = language.command();
+        let mut program: Vec<OsString> = match command[0] {
+            "python" => vec![self.python().into()],
+            runtime => vec!["/usr/bin/env".into(), runtime.into()],
+        };
+        program.extend(command[1..].iter().map(OsString::from));
+        program
+    }
+
+    fn read_only_dirs(&self) -> Vec<PathBuf> {
+        SYSTEM_DIRS
+            .iter()
+            .map(PathBuf::from)
+            .chain([self.venv.clone()])
+            .filter(|dir| dir.exists())
+            .collect()
+    }
+
+    fn path_env(&self) -> String {
+        format!("{}:/usr/bin:/bin", self.venv.join("bin").display())
+    }
+
+    // CPU seconds the code may burn before being killed
+    fn cpu_time(&self, timeout: Duration) -> u64 {
+        ((timeout.as_secs() as f64 * self.limits.cpus).ceil() as u64).max(1)
+    }
+
+    // prlimit sets the rlimits and execs bwrap, which execs python in the namespaces
+    fn bubblewrap_command(
+        &self,
+        language: Language,
+        data_dir: &Path,
+        timeout: Duration,
+    ) -> Command {
+        let memory = self.limits.memory_mb * 1024 * 1024;
+        let mut command = Command::new("prlimit");
+        command.args([
+            format!("--as={}", memory),
+            format!("--nproc={}", self.limits.pids_limit),
+            format!("--cpu={}", self.cpu_time(timeout)),
+            format!("--fsize={}", MAX_FILE_SIZE_MB * 1024 * 1024),
+            format!("--nofile={}", MAX_OPEN_FILES),
+            "--".to_string(),
+        ]);
+        // bwrap reads the filter from an fd, the shell opens it
+        if let Some(filter) = &self.seccomp_filter {
+            command.args(["sh", "-c"]);
+            command.arg(format!("exec \"$@\" {}<\"$0\"", SECCOMP_FD));
+            command.arg(filter);
+        }
+        command.args([
+            "bwrap",
+            "--unshare-all",
+            "--unshare-user",
+            "--die-with-parent",
+            "--new-session",
+            "--clearenv",
+            "--uid",
+            SANDBOX_UID,
+            "--gid",
+            SANDBOX_UID,
+            "--hostname",
+            "sandbox",
+        ]);
+        if self.limits.network_mode != "none" {
+            command.arg("--share-net");
+        }
+        for dir in self.read_only_dirs() {
+            command.arg("--ro-bind").arg(&dir).arg(&dir);
+        }
+        command.args(["--proc", "/proc", "--dev", "/dev", "--tmpfs", WORK_DIR]);
+        command.arg("--bind").arg(data_dir).arg(DATA_DIR);
+        command.args(["--chdir", WORK_DIR]);
+        command.args([
+            "--setenv",
+            "HOME",
+            WORK_DIR,
+            "--setenv",
+            "MPLCONFIGDIR",
+            WORK_DIR,
+        ]);
+        command.args(["--setenv", "PATH"]).arg(self.path_env());
+        if self.seccomp_filter.is_some() {
+            command.args(["--seccomp", SECCOMP_FD]);
+        }
+        command.args(self.program(language));
+        command
+    }
+
+    fn nsjail_command(&self, language: Language, data_dir: &Path, timeout: Duration) -> Command {
+        let mut command = Command::new("nsjail");
+        command.args([
+            "--mode",
+            "o",
+            "--really_quiet",
+            "--user",
+            SANDBOX_UID,
+            "--group",
+            SANDBOX_UID,
+            "--hostname",
+            "sandbox",
+        ]);
+        command.args([
+            format!("--time_limit={}", timeout.as_secs() + 1),
+            format!("--rlimit_as={}", self.limits.memory_mb),
+            format!("--rlimit_nproc={}", self.limits.pids_limit),
+            format!("--rlimit_cpu={}", self.cpu_time(timeout)),
+            format!("--rlimit_fsize={}", MAX_FILE_SIZE_MB),
+            format!("--rlimit_nofile={}", MAX_OPEN_FILES),
+        ]);
+        if self.limits.network_mode != "none" {
+            command.arg("--disable_clone_newnet");
+        }
+        for dir in self.read_only_dirs() {
+            command.arg("--bindmount_ro").arg(&dir);
+        }
+        command.args(["--bindmount", "/dev/null", "--bindmount_ro", "/dev/urandom"]);
+        command.args(["--tmpfsmount", WORK_DIR]);
+        command
+            .arg("--bindmount")
+            .arg(format!("{}:{}", data_dir.display(), DATA_DIR));
+        command.args(["--cwd", WORK_DIR]);
+        command.args([
+            format!("--env=HOME={}", WORK_DIR),
+            format!("--env=MPLCONFIGDIR={}", WORK_DIR),
+            format!("--env=PATH={}", self.path_env()),
+        ]);
+        command.args(["--seccomp_string", SECCOMP_POLICY, "--"]);
+        command.args(self.program(language));
+        command
+    }
+}
+
+#[async_trait]
+impl SandboxBackend for NamespaceSandbox {
+    async fn execute(
+        &self,
+        language: Language,
+        code: &str,
+        timeout: Duration,
+        files: &[SandboxFile],
+    ) -> Result<ExecutionOutput, InterpreterError> {
+        let scratch = ScratchDir::create().await?;
+        for file in files {
+            tokio::fs::write(scratch.host_path(&file.path), &file.content).await?;
+        }
+
+        let mut command = match self.tool {
+            NamespaceTool::Bubblewrap => self.bubblewrap_command(language, &scratch.path, timeout),
+            NamespaceTool::Nsjail => self.nsjail_command(language, &scratch.path, timeout),
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        // The runtimes read the whole script before running it
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(code.as_bytes()).await?;
+        }
+        let result = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+            Ok(result) => result?,
+            Err(_) => return Err(timed_out(code, timeout)),
+        };
+
+        let exit_code = result.status.code().map(i64::from);
+        if result.status.signal() == Some(SIGKILL) || exit_code == Some(KILLED_EXIT_CODE) {
+            return Err(killed(code));
+        }
+        let mut output = ExecutionOutput {
+            stdout: String::from_utf8_lossy(&result.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&result.stderr).to_string(),
+            exit_code,
+            ..Default::default()
+        };
+        // e.g. SIGXCPU once the CPU time is used up
+        if let Some(signal) = result.status.signal() {
+            output
+                .stderr
+                .push_str(&format!("\nKilled by signal {}", signal));
+        }
+        if exit_code == Some(0) {
+            let inputs: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+            output.files = collect_host_files(&scratch.path, &inputs)?;
+        }
+        Ok(output)
+    }
+}
+
+/// Host directory mounted as /mnt/data, removed when dropped.
+struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    async fn create() -> Result<Self, InterpreterError> {
+        let path = std::env::temp_dir().join(format!("hal-9100-sandbox-{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(&path).await?;
+        Ok(ScratchDir { path })
+    }
+
+    // Sandbox paths are /mnt/data/<name>, names never hold a directory
+    fn host_path(&self, sandbox_path: &str) -> PathBuf {
+        let name = sandbox_path
+            .strip_prefix(DATA_DIR)
+            .unwrap_or(sandbox_path)
+            .trim_start_matches('/');
+        self.path.join(name)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Regular files written under `dir`, as their /mnt/data path, except the `exclude`d ones.
+/// Symlinks are skipped: the code could point them anywhere on the host.
+fn collect_host_files(
+    dir: &Path,
+    exclude: &[&str],
+) -> Result<Vec<GeneratedFile>, InterpreterError> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let mut entries = std::fs::read_dir(&current)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for path in entries {
+            let metadata = std::fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            let sandbox_path = format!("{}/{}", DATA_DIR, relative.display());
+            if !metadata.is_file()
+                || metadata.len() as usize > MAX_GENERATED_FILE_SIZE
+                || exclude.contains(&sandbox_path.as_str())
+            {
+                continue;
+            }
+            files.push(GeneratedFile {
+                path: sandbox_path,
+                content: Bytes::from(std::fs::read(&path)?),
+            });
+            if files.len() == MAX_GENERATED_FILES {
+                return Ok(files);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn find_program(name: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    })
+}
+
+/// Sets up the backend picked by the `code_interpreter_backend` config option: "docker" (the
+/// default), "bubblewrap" or "nsjail".
+pub async fn start_backend(
+    config: &Hal9100Config,
+) -> Result<Arc<dyn SandboxBackend>, InterpreterError> {
+    let limits = SandboxLimits::from_config(config);
+    let seccomp_filter = config
+        .code_interpreter_seccomp_filter
+        .as_ref()
+        .map(PathBuf::from);
+    let backend: Arc<dyn SandboxBackend> = match config.code_interpreter_backend.as_str() {
+        "docker" => {
+            let pool = if config.code_interpreter_pool_size > 0 {
+                Some(
+                    ContainerPool::start(
+                        config.code_interpreter_pool_size,
+                        config.code_interpreter_max_uses,
+                    )
+                    .await?,
+                )
+            } else {
+                pull_image().await?;
+                None
+            };
+            Arc::new(DockerSandbox::new(pool))
+        }
+        "bubblewrap" => Arc::new(NamespaceSandbox::new(
+            NamespaceTool::Bubblewrap,
+            &config.code_interpreter_venv,
+            limits,
+            seccomp_filter,
+        )?),
+        "nsjail" => Arc::new(NamespaceSandbox::new(
+            NamespaceTool::Nsjail,
+            &config.code_interpreter_venv,
+            limits,
+            seccomp_filter,
+        )?),
+        backend => {
+            return Err(InterpreterError::new(
+                format!(
+                    "Unknown code interpreter backend \"{}\", expected \"docker\", \"bubblewrap\" or \"nsjail\"",
+                    backend
+                ),
+                "",
+            ))
+        }
+    };
+    info!(
+        "Code interpreter runs with the {} backend",
+        config.code_interpreter_backend
+    );
+    Ok(backend)
+}
+
+// Installed by the executor at startup, runs without one use a fresh docker container each time.
+// A backend other than docker that fails to start is installed as an `UnavailableSandbox`.
+static SANDBOX_BACKEND: RwLock<Option<Arc<dyn SandboxBackend>>> = RwLock::new(None);
+
+pub fn set_sandbox_backend(backend: Option<Arc<dyn SandboxBackend>>) {
+    *SANDBOX_BACKEND.write().unwrap() = backend;
+}
+
+pub fn sandbox_backend() -> Option<Arc<dyn SandboxBackend>> {
+    SANDBOX_BACKEND.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_host_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("input.csv"), "a,b").unwrap();
+        std::fs::write(dir.path().join("plot.png"), "png").unwrap();
+        std::fs::create_dir(dir.path().join("out")).unwrap();
+        std::fs::write(dir.path().join("out").join("result.txt"), "42").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.path().join("passwd")).unwrap();
+
+        let files = collect_host_files(dir.path(), &["/mnt/data/input.csv"]).unwrap();
+        let paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["/mnt/data/plot.png", "/mnt/data/out/result.txt"]
+        );
+        assert_eq!(files[1].content, Bytes::from("42"));
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    fn namespace_sandbox(tool: NamespaceTool, network_mode: &str) -> NamespaceSandbox {
+        NamespaceSandbox {
+            tool,
+            venv: PathBuf::from("/opt/venv"),
+            limits: SandboxLimits {
+                memory_mb: 256,
+                cpus: 0.5,
+                pids_limit: 16,
+                network_mode: network_mode.to_string(),
+            },
+            seccomp_filter: Some(PathBuf::from("/etc/seccomp.bpf")),
+        }
+    }
+
+    #[test]
+    fn test_bubblewrap_command() {
+        let sandbox = namespace_sandbox(NamespaceTool::Bubblewrap, "none");
+        let command = sandbox.bubblewrap_command(
+            Language::Python,
+            Path::new("/tmp/data"),
+            Duration::from_secs(10),
+        );
+        assert_eq!(command.as_std().get_program(), "prlimit");
+        let args = args(&command);
+        for arg in [
+            "--as=268435456",
+            "--nproc=16",
+            "--cpu=5",
+            "--unshare-all",
+            "--clearenv",
+        ] {
+            assert!(args.contains(&arg.to_string()), "{} not in {:?}", arg, args);
+        }
+        assert!(!args.contains(&"--share-net".to_string()));
+        // the filter is opened on the fd bwrap reads it from
+        let seccomp = args.iter().position(|arg| arg == "--seccomp").unwrap();
+        assert_eq!(args[seccomp + 1], SECCOMP_FD);
+        assert!(args.contains(&"/etc/seccomp.bpf".to_string()));
+        assert!(args.ends_with(&["/opt/venv/bin/python3".to_string(), "-".to_string()]));
+
+        let sandbox = namespace_sandbox(NamespaceTool::Bubblewrap, "bridge");
+        let command = sandbox.bubblewrap_command(
+            Language::Python,
+            Path::new("/tmp/data"),
+            Duration::from_secs(10),
+        );
+        assert!(args(&command).contains(&"--share-net".to_string()));
+    }
+
+    #[test]
+    fn test_nsjail_command() {
+        let sandbox = namespace_sandbox(NamespaceTool::Nsjail, "none");
+        let command = sandbox.nsjail_command(
+            Language::JavaScript,
+            Path::new("/tmp/data"),
+            Duration::from_secs(10),
+        );
+        assert_eq!(command.as_std().get_program(), "nsjail");
+        let args = args(&command);
+        for arg in [
+            "--time_limit=11",
+            "--rlimit_as=256",
+            "--rlimit_nproc=16",
+            "--rlimit_cpu=5",
+            "/tmp/data:/mnt/data",
+        ] {
+            assert!(args.contains(&arg.to_string()), "{} not in {:?}", arg, args);
+        }
+        assert!(!args.contains(&"--disable_clone_newnet".to_string()));
+        let seccomp = args
+            .iter()
+            .position(|arg| arg == "--seccomp_string")
+            .unwrap();
+        assert_eq!(args[seccomp + 1], SECCOMP_POLICY);
+        assert!(args.ends_with(&[
+            "/usr/bin/env".to_string(),
+            "node".to_string(),
+            "-".to_string()
+        ]));
+
+        let sandbox = namespace_sandbox(NamespaceTool::Nsjail, "bridge");
+        let command = sandbox.nsjail_command(
+            Language::JavaScript,
+            Path::new("/tmp/data"),
+            Duration::from_secs(10),
+        );
+        assert!(args(&command).contains(&"--disable_clone_newnet".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_sandbox() {
+        let sandbox = UnavailableSandbox::new("nsjail", "nsjail is not installed".to_string());
+        let error = sandbox
+            .execute(Language::Python, "print(1)", Duration::from_secs(10), &[])
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("nsjail is not installed"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bubblewrap_sandbox() {
+        if find_program("bwrap").is_none() || find_program("prlimit").is_none() {
+            eprintln!("bubblewrap is not installed, skipping");
+            return;
+        }
+        // the system python stands in for a venv
+        let sandbox = NamespaceSandbox::new(
+            NamespaceTool::Bubblewrap,
+            "/usr",
+            SandboxLimits::default(),
+            None,
+        )
+        .unwrap();
+        let file = SandboxFile {
+            file_id: "file-1".to_string(),
+            path: "/mnt/data/numbers.txt".to_string(),
+            content: Bytes::from("1\n2\n3\n"),
+        };
+        let code = "
+import os, socket
+print(sum(int(line) for line in open('/mnt/data/numbers.txt')))
+print(os.getuid())
+open('/mnt/data/out.txt', 'w').write('done')
+try:
+    open('/usr/evil', 'w')
+except OSError:
+    print('read only')
+try:
+    socket.create_connection(('1.1.1.1', 80), timeout=2)
+except OSError:
+    print('no network')
+";
+        let output = sandbox
+            .execute(Language::Python, code, Duration::from_secs(30), &[file])
+            .await
+            .unwrap();
+        assert_eq!(output.exit_code, Some(0), "{}", output.stderr);
+        assert_eq!(output.stdout, "6\n65534\nread only\nno network\n");
+        assert_eq!(output.files.len(), 1);
+        assert_eq!(output.files[0].path, "/mnt/data/out.txt");
+
+        let result = sandbox
+            .execute(
+                Language::Python,
+                "while True: pass",
+                Duration::from_secs(2),
+                &[],
+            )
+            .await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+    }
+}
@@ -0,0 +1,639 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, TryStreamExt};
+use log::{info, warn};
+use rusty_s3::actions::{
+    AbortMultipartUpload, CompleteMultipartUpload, CreateBucket, CreateMultipartUpload,
+    DeleteObject, GetObject, HeadObject, ListObjectsV2, PutObject, S3Action, UploadPart,
+};
+use rusty_s3::{Bucket, Credentials, UrlStyle};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+use hal_9100_core::file_storage::StoredFile;
+
+const ONE_HOUR: Duration = Duration::from_secs(3600);
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+// S3 parts must be at least 5MiB (except the last one), smaller uploads are a single PUT
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+pub type StorageError = Box<dyn std::error::Error + Send + Sync>;
+pub type ByteStream<'a> = Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + 'a>>;
+
+/// Where file contents are kept. Keys are the file ids, metadata lives in the files table.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn put(&self, key: &str, content: Bytes) -> Result<StoredFile, StorageError>;
+    /// Writes the content as it arrives, nothing is stored if the stream fails.
+    async fn put_stream(
+        &self,
+        key: &str,
+        content: ByteStream<'_>,
+    ) -> Result<StoredFile, StorageError>;
+    async fn head(&self, key: &str) -> Result<StoredFile, StorageError>;
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError>;
+    async fn get_stream(&self, key: &str) -> Result<ByteStream<'static>, StorageError>;
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+    /// Returns up to `max_keys` files after `continuation_token` and the token of the next page.
+    async fn list_page(
+        &self,
+        continuation_token: Option<&str>,
+        max_keys: usize,
+    ) -> Result<(Vec<StoredFile>, Option<String>), StorageError>;
+}
+
+/// Reads a local file chunk by chunk.
+pub fn file_stream(file: tokio::fs::File) -> ByteStream<'static> {
+    Box::pin(stream::try_unfold(file, |mut file| async move {
+        let mut buffer = vec![0; READ_BUFFER_SIZE];
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.truncate(read);
+        Ok(Some((Bytes::from(buffer), file)))
+    }))
+}
+
+// Reads from the stream until the part is full or the stream is over
+async fn fill_part(content: &mut ByteStream<'_>, part: &mut Vec<u8>) -> Result<(), StorageError> {
+    while part.len() < PART_SIZE {
+        match content.try_next().await? {
+            Some(chunk) => part.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+fn not_found(key: &str) -> StorageError {
+    format!("File not found: {}", key).into()
+}
+
+// Local backends page through keys in order, the token being the last key of the previous page
+fn paginate<'a>(
+    keys: impl Iterator<Item = &'a String>,
+    continuation_token: Option<&str>,
+    max_keys: usize,
+) -> (Vec<String>, Option<String>) {
+    let mut keys = keys.filter(|key| continuation_token.map_or(true, |token| key.as_str() > token));
+    let page: Vec<String> = keys.by_ref().take(max_keys).cloned().collect();
+    let next_token = match keys.next() {
+        Some(_) => page.last().cloned(),
+        None => None,
+    };
+    (page, next_token)
+}
+
+pub struct S3Storage {
+    bucket: Bucket,
+    credentials: Credentials,
+    client: reqwest::Client,
+}
+
+impl S3Storage {
+    /// Connects to the bucket, creating it if it doesn't exist yet.
+    pub async fn new(
+        endpoint: &str,
+        bucket_name: String,
+        access_key: String,
+        secret_key: String,
+    ) -> Result<Self, StorageError> {
+        let bucket = Bucket::new(
+            Url::parse(endpoint)?,
+            UrlStyle::Path,
+            bucket_name,
+            "us-east-1",
+        )?;
+        let credentials = Credentials::new(access_key, secret_key);
+        let client = reqwest::Client::new();
+
+        let action = CreateBucket::new(&bucket, &credentials);
+        let response = client.put(action.sign(ONE_HOUR)).send().await?;
+        if response.status().is_success() {
+            info!("Bucket created successfully");
+        } else if response.status() == 409 {
+            warn!("Bucket already exists");
+        } else {
+            return Err(format!(
+                "Unexpected error when creating bucket: {:?}",
+                response.status()
+            )
+            .into());
+        }
+
+        Ok(Self {
+            bucket,
+            credentials,
+            client,
+        })
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        first_part: Vec<u8>,
+        mut content: ByteStream<'_>,
+    ) -> Result<(), StorageError> {
+        let mut etags = Vec::new();
+        let mut part = first_part;
+        loop {
+            let last = part.len() < PART_SIZE;
+            if !part.is_empty() || etags.is_empty() {
+                let part_number = etags.len() as u16 + 1;
+                let upload = UploadPart::new(
+                    &self.bucket,
+                    Some(&self.credentials),
+                    key,
+                    part_number,
+                    upload_id,
+                );
+                let response = self
+                    .client
+                    .put(upload.sign(ONE_HOUR))
+                    .body(std::mem::take(&mut part))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let etag = response
+                    .headers()
+                    .get("etag")
+                    .and_then(|etag| etag.to_str().ok())
+                    .ok_or("Missing ETag in upload part response")?;
+                etags.push(etag.to_string());
+            }
+            if last {
+                break;
+            }
+            fill_part(&mut content, &mut part).await?;
+        }
+
+        let complete = CompleteMultipartUpload::new(
+            &self.bucket,
+            Some(&self.credentials),
+            key,
+            upload_id,
+            etags.iter().map(String::as_str),
+        );
+        let url = complete.sign(ONE_HOUR);
+        self.client
+            .post(url)
+            .body(complete.body())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn get_response(&self, key: &str) -> Result<reqwest::Response, StorageError> {
+        let mut get = GetObject::new(&self.bucket, Some(&self.credentials), key);
+        get.query_mut()
+            .insert("response-cache-control", "no-cache, no-store");
+        let response = self.client.get(get.sign(ONE_HOUR)).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(not_found(key));
+        }
+        Ok(response.error_for_status()?)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn put(&self, key: &str, content: Bytes) -> Result<StoredFile, StorageError> {
+        let put = PutObject::new(&self.bucket, Some(&self.credentials), key);
+        self.client
+            .put(put.sign(ONE_HOUR))
+            .body(content)
+            .send()
+            .await?
+            .error_for_status()?;
+        self.head(key).await
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        mut content: ByteStream<'_>,
+    ) -> Result<StoredFile, StorageError> {
+        let mut first_part = Vec::new();
+        fill_part(&mut content, &mut first_part).await?;
+        if first_part.len() < PART_SIZE {
+            return self.put(key, Bytes::from(first_part)).await;
+        }
+
+        let create = CreateMultipartUpload::new(&self.bucket, Some(&self.credentials), key);
+        let response = self
+            .client
+            .post(create.sign(ONE_HOUR))
+            .send()
+            .await?
+            .error_for_status()?;
+        let multipart = CreateMultipartUpload::parse_response(&response.text().await?)?;
+        let upload_id = multipart.upload_id();
+
+        if let Err(e) = self.upload_parts(key, upload_id, first_part, content).await {
+            let abort =
+                AbortMultipartUpload::new(&self.bucket, Some(&self.credentials), key, upload_id);
+            if let Err(abort_error) = self.client.delete(abort.sign(ONE_HOUR)).send().await {
+                warn!("Failed to abort upload of {}: {}", key, abort_error);
+            }
+            return Err(e);
+        }
+        self.head(key).await
+    }
+
+    async fn head(&self, key: &str) -> Result<StoredFile, StorageError> {
+        let head = HeadObject::new(&self.bucket, Some(&self.credentials), key);
+        let response = self.client.head(head.sign(ONE_HOUR)).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(not_found(key));
+        }
+        let response = response.error_for_status()?;
+
+        // HEAD responses have no body, so the size comes from the header rather than content_length()
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Ok(StoredFile {
+            id: key.to_string(),
+            last_modified: header("last-modified").unwrap_or_default(),
+            size: header("content-length")
+                .and_then(|size| size.parse().ok())
+                .unwrap_or_default(),
+            storage_class: header("x-amz-storage-class"),
+        })
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        let response = self.get_response(key).await?;
+        Ok(response.bytes().await?)
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<ByteStream<'static>, StorageError> {
+        let response = self.get_response(key).await?;
+        Ok(Box::pin(response.bytes_stream().map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::Other, e)
+        })))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let delete = DeleteObject::new(&self.bucket, Some(&self.credentials), key);
+        self.client
+            .delete(delete.sign(ONE_HOUR))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn list_page(
+        &self,
+        continuation_token: Option<&str>,
+        max_keys: usize,
+    ) -> Result<(Vec<StoredFile>, Option<String>), StorageError> {
+        let mut action = ListObjectsV2::new(&self.bucket, Some(&self.credentials));
+        action.with_max_keys(max_keys);
+        if let Some(continuation_token) = continuation_token {
+            action.with_continuation_token(continuation_token);
+        }
+        let response = self
+            .client
+            .get(action.sign(ONE_HOUR))
+            .send()
+            .await?
+            .error_for_status()?;
+        let text = response.text().await?;
+
+        let parsed = ListObjectsV2::parse_response(&text)?;
+        let files = parsed
+            .contents
+            .into_iter()
+            .map(|file| StoredFile {
+                id: file.key,
+                last_modified: file.last_modified,
+                size: file.size,
+                storage_class: file.storage_class,
+            })
+            .collect();
+
+        Ok((files, parsed.next_continuation_token))
+    }
+}
+
+/// Keeps files in a local directory, for setups without an object store (e.g. a Raspberry Pi).
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let root = root.into();
+        tokio::fs::create_dir_all(&root).await?;
+        info!("Storing files in {}", root.display());
+        Ok(Self { root })
+    }
+
+    // Keys are file ids, never paths: anything that could escape the root is rejected
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let is_file_name = Path::new(key).file_name().map_or(false, |name| name == key);
+        if !is_file_name || key.starts_with('.') {
+            return Err(format!("Invalid file id: {}", key).into());
+        }
+        Ok(self.root.join(key))
+    }
+
+    async fn stored_file(&self, key: &str, path: &Path) -> Result<StoredFile, StorageError> {
+        let metadata = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found(key)),
+            Err(e) => return Err(e.into()),
+        };
+        let last_modified: DateTime<Utc> = metadata.modified()?.into();
+        Ok(StoredFile {
+            id: key.to_string(),
+            last_modified: last_modified.to_rfc3339(),
+            size: metadata.len(),
+            storage_class: None,
+        })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(&self, key: &str, content: Bytes) -> Result<StoredFile, StorageError> {
+        let path = self.path(key)?;
+        // Write next to the final file and rename so readers never see a partial file
+        let partial_path = self.root.join(format!(".{}.partial", key));
+        tokio::fs::write(&partial_path, &content).await?;
+        tokio::fs::rename(&partial_path, &path).await?;
+        self.stored_file(key, &path).await
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        mut content: ByteStream<'_>,
+    ) -> Result<StoredFile, StorageError> {
+        let path = self.path(key)?;
+        let partial_path = self.root.join(format!(".{}.partial", key));
+        let written: Result<(), StorageError> = async {
+            let mut file = tokio::fs::File::create(&partial_path).await?;
+            while let Some(chunk) = content.try_next().await? {
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            return Err(e);
+        }
+        tokio::fs::rename(&partial_path, &path).await?;
+        self.stored_file(key, &path).await
+    }
+
+    async fn head(&self, key: &str) -> Result<StoredFile, StorageError> {
+        let path = self.path(key)?;
+        self.stored_file(key, &path).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        let path = self.path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(content) => Ok(Bytes::from(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(not_found(key)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<ByteStream<'static>, StorageError> {
+        let path = self.path(key)?;
+        let file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found(key)),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(file_stream(file))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(not_found(key)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list_page(
+        &self,
+        continuation_token: Option<&str>,
+        max_keys: usize,
+    ) -> Result<(Vec<StoredFile>, Option<String>), StorageError> {
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(key) = entry.file_name().to_str() {
+                // hidden files are uploads in progress
+                if !key.starts_with('.') && entry.file_type().await?.is_file() {
+                    keys.push(key.to_string());
+                }
+            }
+        }
+        keys.sort();
+
+        let (page, next_token) = paginate(keys.iter(), continuation_token, max_keys);
+        let mut files = Vec::with_capacity(page.len());
+        for key in page {
+            let path = self.root.join(&key);
+            files.push(self.stored_file(&key, &path).await?);
+        }
+        Ok((files, next_token))
+    }
+}
+
+/// Keeps files in memory, mostly useful for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: RwLock<BTreeMap<String, (Bytes, DateTime<Utc>)>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn stored_file(key: &str, content: &Bytes, last_modified: &DateTime<Utc>) -> StoredFile {
+        StoredFile {
+            id: key.to_string(),
+            last_modified: last_modified.to_rfc3339(),
+            size: content.len() as u64,
+            storage_class: None,
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn put(&self, key: &str, content: Bytes) -> Result<StoredFile, StorageError> {
+        let now = Utc::now();
+        let file = Self::stored_file(key, &content, &now);
+        self.files
+            .write()
+            .unwrap()
+            .insert(key.to_string(), (content, now));
+        Ok(file)
+    }
+
+    async fn put_stream(
+        &self,
+        key: &str,
+        content: ByteStream<'_>,
+    ) -> Result<StoredFile, StorageError> {
+        let chunks: Vec<Bytes> = content.try_collect().await?;
+        self.put(key, Bytes::from(chunks.concat())).await
+    }
+
+    async fn head(&self, key: &str) -> Result<StoredFile, StorageError> {
+        let files = self.files.read().unwrap();
+        let (content, last_modified) = files.get(key).ok_or_else(|| not_found(key))?;
+        Ok(Self::stored_file(key, content, last_modified))
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, StorageError> {
+        let files = self.files.read().unwrap();
+        let (content, _) = files.get(key).ok_or_else(|| not_found(key))?;
+        Ok(content.clone())
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<ByteStream<'static>, StorageError> {
+        let content = self.get(key).await?;
+        Ok(Box::pin(stream::once(async move { Ok(content) })))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match self.files.write().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(not_found(key)),
+        }
+    }
+
+    async fn list_page(
+        &self,
+        continuation_token: Option<&str>,
+        max_keys: usize,
+    ) -> Result<(Vec<StoredFile>, Option<String>), StorageError> {
+        let files = self.files.read().unwrap();
+        let (page, next_token) = paginate(files.keys(), continuation_token, max_keys);
+        let page = page
+            .iter()
+            .map(|key| {
+                let (content, last_modified) = &files[key];
+                Self::stored_file(key, content, last_modified)
+            })
+            .collect();
+        Ok((page, next_token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Same checks for every local backend, S3 is covered by the file_storage tests
+    async fn check_backend(backend: &dyn StorageBackend) {
+        let file = backend
+            .put("a.txt", Bytes::from_static(b"Hello, world!"))
+            .await
+            .unwrap();
+        assert_eq!(file.size, 13);
+        backend
+            .put("b.txt", Bytes::from_static(b"Hello again"))
+            .await
+            .unwrap();
+        backend
+            .put("c.txt", Bytes::from_static(b"Bye"))
+            .await
+            .unwrap();
+
+        assert_eq!(backend.head("a.txt").await.unwrap().size, 13);
+        assert_eq!(backend.get("b.txt").await.unwrap(), "Hello again");
+        let chunks: Vec<Bytes> = backend
+            .get_stream("a.txt")
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"Hello, world!");
+
+        let (page, token) = backend.list_page(None, 2).await.unwrap();
+        let ids: Vec<_> = page.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, vec!["a.txt", "b.txt"]);
+        let (page, token) = backend.list_page(token.as_deref(), 2).await.unwrap();
+        let ids: Vec<_> = page.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, vec!["c.txt"]);
+        assert!(token.is_none());
+
+        backend.delete("a.txt").await.unwrap();
+        assert!(backend.head("a.txt").await.is_err());
+        assert!(backend.get("a.txt").await.is_err());
+        assert!(backend.delete("a.txt").await.is_err());
+
+        let chunks = vec![
+            Ok(Bytes::from_static(b"Hello, ")),
+            Ok(Bytes::from_static(b"stream")),
+        ];
+        let file = backend
+            .put_stream("d.txt", Box::pin(stream::iter(chunks)))
+            .await
+            .unwrap();
+        assert_eq!(file.size, 13);
+        assert_eq!(backend.get("d.txt").await.unwrap(), "Hello, stream");
+
+        // a failing stream leaves nothing behind
+        let chunks = vec![
+            Ok(Bytes::from_static(b"Hello")),
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "too large")),
+        ];
+        assert!(backend
+            .put_stream("e.txt", Box::pin(stream::iter(chunks)))
+            .await
+            .is_err());
+        assert!(backend.head("e.txt").await.is_err());
+        let (page, _) = backend.list_page(None, 10).await.unwrap();
+        let ids: Vec<_> = page.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, vec!["b.txt", "c.txt", "d.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        check_backend(&MemoryStorage::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = LocalStorage::new(dir.path()).await.unwrap();
+        check_backend(&backend).await;
+
+        // file ids can't be used to reach outside of the storage directory
+        assert!(backend.get("../secret.txt").await.is_err());
+        assert!(backend
+            .put("..", Bytes::from_static(b"nope"))
+            .await
+            .is_err());
+    }
+}
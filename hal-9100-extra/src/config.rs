@@ -7,10 +7,124 @@ pub struct Hal9100Config {
     pub model_api_key: Option<String>,
     pub database_url: String,
     pub redis_url: String,
+    /// Where uploaded files are kept: "s3", "fs" (a local directory) or "memory"
+    #[serde(default = "default_storage")]
+    pub storage: String,
+    /// Directory used by the "fs" storage
+    #[serde(default = "default_storage_path")]
+    pub storage_path: String,
+    #[serde(default)]
     pub s3_endpoint: String,
+    #[serde(default)]
     pub s3_access_key: String,
+    #[serde(default)]
     pub s3_secret_key: String,
+    #[serde(default)]
     pub s3_bucket_name: String,
+    /// Base64 master key (32 bytes) enabling encryption at rest of files, chunks and messages
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    /// File holding the base64 master key, instead of `encryption_key`
+    #[serde(default)]
+    pub encryption_key_file: Option<String>,
+    /// Former master keys, still accepted for decryption until `hal-9100 rotate-keys` is run
+    #[serde(default)]
+    pub encryption_previous_keys: Vec<String>,
+    /// Code interpreter containers kept started by the executor, 0 starts one per execution
+    #[serde(default = "default_code_interpreter_pool_size")]
+    pub code_interpreter_pool_size: usize,
+    /// Executions a pooled container serves before being replaced
+    #[serde(default = "default_code_interpreter_max_uses")]
+    pub code_interpreter_max_uses: usize,
+    /// Memory a code interpreter execution may use, in MB
+    #[serde(default = "default_code_interpreter_memory_mb")]
+    pub code_interpreter_memory_mb: i64,
+    /// CPUs a code interpreter execution may use, e.g. 0.5
+    #[serde(default = "default_code_interpreter_cpus")]
+    pub code_interpreter_cpus: f64,
+    /// Processes and threads a code interpreter execution may start
+    #[serde(default = "default_code_interpreter_pids_limit")]
+    pub code_interpreter_pids_limit: i64,
+    /// Docker network mode of the code interpreter, "none" cuts it off the network (the
+    /// namespace backends only tell "none" from any other mode)
+    #[serde(default = "default_code_interpreter_network_mode")]
+    pub code_interpreter_network_mode: String,
+    /// Keeps a Python kernel per thread, so runs can reuse what previous runs computed
+    #[serde(default)]
+    pub code_interpreter_sessions: bool,
+    /// Seconds after which an idle session is dropped
+    #[serde(default = "default_code_interpreter_session_ttl_secs")]
+    pub code_interpreter_session_ttl_secs: u64,
+    /// Sessions kept at once, the least recently used idle one makes room for a new thread
+    #[serde(default = "default_code_interpreter_max_sessions")]
+    pub code_interpreter_max_sessions: usize,
+    /// Where the code interpreter runs: "docker", "bubblewrap" or "nsjail"
+    #[serde(default = "default_code_interpreter_backend")]
+    pub code_interpreter_backend: String,
+    /// Python venv the "bubblewrap" and "nsjail" backends run the code with, mounted read only
+    #[serde(default = "default_code_interpreter_venv")]
+    pub code_interpreter_venv: String,
+    /// Compiled seccomp BPF filter the "bubblewrap" backend applies
+    #[serde(default)]
+    pub code_interpreter_seccomp_filter: Option<String>,
+}
+
+fn default_storage() -> String {
+    "s3".to_string()
+}
+
+fn default_storage_path() -> String {
+    "./hal-9100-files".to_string()
+}
+
+fn default_code_interpreter_pool_size() -> usize {
+    2
+}
+
+fn default_code_interpreter_max_uses() -> usize {
+    1
+}
+
+fn default_code_interpreter_memory_mb() -> i64 {
+    512
+}
+
+fn default_code_interpreter_cpus() -> f64 {
+    1.0
+}
+
+fn default_code_interpreter_pids_limit() -> i64 {
+    64
+}
+
+fn default_code_interpreter_network_mode() -> String {
+    "none".to_string()
+}
+
+fn default_code_interpreter_session_ttl_secs() -> u64 {
+    30 * 60
+}
+
+fn default_code_interpreter_max_sessions() -> usize {
+    4
+}
+
+fn default_code_interpreter_backend() -> String {
+    "docker".to_string()
+}
+
+fn default_code_interpreter_venv() -> String {
+    "/opt/hal-9100/venv".to_string()
+}
+
+// ENCRYPTION_PREVIOUS_KEYS is a comma separated list
+fn previous_keys_from_env() -> Option<Vec<String>> {
+    env::var("ENCRYPTION_PREVIOUS_KEYS").ok().map(|keys| {
+        keys.split(',')
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect()
+    })
 }
 
 impl Default for Hal9100Config {
@@ -25,11 +139,55 @@ impl Default for Hal9100Config {
             model_api_key: std::env::var("MODEL_API_KEY").ok(),
             database_url: db_url,
             redis_url: std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string()),
+            storage: std::env::var("STORAGE").unwrap_or(default_storage()),
+            storage_path: std::env::var("STORAGE_PATH").unwrap_or(default_storage_path()),
             s3_endpoint: std::env::var("S3_ENDPOINT")
                 .unwrap_or("http://localhost:9000".to_string()),
             s3_access_key: std::env::var("S3_ACCESS_KEY").unwrap_or("minioadmin".to_string()),
             s3_secret_key: std::env::var("S3_SECRET_KEY").unwrap_or("minioadmin".to_string()),
             s3_bucket_name: std::env::var("S3_BUCKET_NAME").unwrap_or("mybucket".to_string()),
+            encryption_key: std::env::var("ENCRYPTION_KEY").ok(),
+            encryption_key_file: std::env::var("ENCRYPTION_KEY_FILE").ok(),
+            encryption_previous_keys: previous_keys_from_env().unwrap_or_default(),
+            code_interpreter_pool_size: std::env::var("CODE_INTERPRETER_POOL_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_code_interpreter_pool_size()),
+            code_interpreter_max_uses: std::env::var("CODE_INTERPRETER_MAX_USES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_code_interpreter_max_uses()),
+            code_interpreter_memory_mb: std::env::var("CODE_INTERPRETER_MEMORY_MB")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_code_interpreter_memory_mb()),
+            code_interpreter_cpus: std::env::var("CODE_INTERPRETER_CPUS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_code_interpreter_cpus()),
+            code_interpreter_pids_limit: std::env::var("CODE_INTERPRETER_PIDS_LIMIT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_code_interpreter_pids_limit()),
+            code_interpreter_network_mode: std::env::var("CODE_INTERPRETER_NETWORK_MODE")
+                .unwrap_or(default_code_interpreter_network_mode()),
+            code_interpreter_sessions: std::env::var("CODE_INTERPRETER_SESSIONS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_default(),
+            code_interpreter_session_ttl_secs: std::env::var("CODE_INTERPRETER_SESSION_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_code_interpreter_session_ttl_secs()),
+            code_interpreter_max_sessions: std::env::var("CODE_INTERPRETER_MAX_SESSIONS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_code_interpreter_max_sessions()),
+            code_interpreter_backend: std::env::var("CODE_INTERPRETER_BACKEND")
+                .unwrap_or(default_code_interpreter_backend()),
+            code_interpreter_venv: std::env::var("CODE_INTERPRETER_VENV")
+                .unwrap_or(default_code_interpreter_venv()),
+            code_interpreter_seccomp_filter: std::env::var("CODE_INTERPRETER_SECCOMP_FILTER").ok(),
         }
     }
 }
@@ -44,10 +202,59 @@ impl Hal9100Config {
         config.model_api_key = env::var("MODEL_API_KEY").ok().or(config.model_api_key);
         config.database_url = env::var("DATABASE_URL").unwrap_or(config.database_url);
         config.redis_url = env::var("REDIS_URL").unwrap_or(config.redis_url);
+        config.storage = env::var("STORAGE").unwrap_or(config.storage);
+        config.storage_path = env::var("STORAGE_PATH").unwrap_or(config.storage_path);
         config.s3_endpoint = env::var("S3_ENDPOINT").unwrap_or(config.s3_endpoint);
         config.s3_access_key = env::var("S3_ACCESS_KEY").unwrap_or(config.s3_access_key);
         config.s3_secret_key = env::var("S3_SECRET_KEY").unwrap_or(config.s3_secret_key);
         config.s3_bucket_name = env::var("S3_BUCKET_NAME").unwrap_or(config.s3_bucket_name);
+        config.encryption_key = env::var("ENCRYPTION_KEY").ok().or(config.encryption_key);
+        config.encryption_key_file = env::var("ENCRYPTION_KEY_FILE")
+            .ok()
+            .or(config.encryption_key_file);
+        config.encryption_previous_keys =
+            previous_keys_from_env().unwrap_or(config.encryption_previous_keys);
+        config.code_interpreter_pool_size = env::var("CODE_INTERPRETER_POOL_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.code_interpreter_pool_size);
+        config.code_interpreter_max_uses = env::var("CODE_INTERPRETER_MAX_USES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.code_interpreter_max_uses);
+        config.code_interpreter_memory_mb = env::var("CODE_INTERPRETER_MEMORY_MB")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.code_interpreter_memory_mb);
+        config.code_interpreter_cpus = env::var("CODE_INTERPRETER_CPUS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.code_interpreter_cpus);
+        config.code_interpreter_pids_limit = env::var("CODE_INTERPRETER_PIDS_LIMIT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.code_interpreter_pids_limit);
+        config.code_interpreter_network_mode = env::var("CODE_INTERPRETER_NETWORK_MODE")
+            .unwrap_or(config.code_interpreter_network_mode);
+        config.code_interpreter_sessions = env::var("CODE_INTERPRETER_SESSIONS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.code_interpreter_sessions);
+        config.code_interpreter_session_ttl_secs = env::var("CODE_INTERPRETER_SESSION_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.code_interpreter_session_ttl_secs);
+        config.code_interpreter_max_sessions = env::var("CODE_INTERPRETER_MAX_SESSIONS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.code_interpreter_max_sessions);
+        config.code_interpreter_backend =
+            env::var("CODE_INTERPRETER_BACKEND").unwrap_or(config.code_interpreter_backend);
+        config.code_interpreter_venv =
+            env::var("CODE_INTERPRETER_VENV").unwrap_or(config.code_interpreter_venv);
+        config.code_interpreter_seccomp_filter = env::var("CODE_INTERPRETER_SECCOMP_FILTER")
+            .ok()
+            .or(config.code_interpreter_seccomp_filter);
 
         config
     }